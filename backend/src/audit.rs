@@ -0,0 +1,135 @@
+//! Append-only record of security-relevant and catalog-changing actions.
+
+use chrono::Utc;
+use serde_json::Value;
+
+use crate::{
+    auth::ClientInfo,
+    db::{models::AuditLogEntry, queries, DbPool},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    Logout,
+    Register,
+    SessionRevoked,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    OAuthUnlinked,
+    EmailVerified,
+    PasswordResetRequested,
+    PasswordReset,
+    MfaEnabled,
+    MfaDisabled,
+    RecoveryCodesRegenerated,
+    AdminChanged,
+    ArtistCreated,
+    ArtistMemberSet,
+    ArtistMemberRemoved,
+    TrackCreated,
+    TrackUpdated,
+    TrackDeleted,
+    TracksMerged,
+    AlbumUploaded,
+    AlbumVersionRestored,
+    PlaylistCreated,
+    PlaylistTrackAdded,
+    PlaylistTrackRemoved,
+}
+
+impl AuditAction {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Login => "auth.login",
+            Self::LoginFailed => "auth.login_failed",
+            Self::Logout => "auth.logout",
+            Self::Register => "auth.register",
+            Self::SessionRevoked => "auth.session_revoke",
+            Self::ApiKeyCreated => "auth.api_key_create",
+            Self::ApiKeyRevoked => "auth.api_key_revoke",
+            Self::OAuthUnlinked => "auth.oauth_unlink",
+            Self::EmailVerified => "auth.email_verify",
+            Self::PasswordResetRequested => "auth.password_reset_request",
+            Self::PasswordReset => "auth.password_reset",
+            Self::MfaEnabled => "auth.mfa_enable",
+            Self::MfaDisabled => "auth.mfa_disable",
+            Self::RecoveryCodesRegenerated => "auth.mfa_recovery_codes",
+            Self::AdminChanged => "user.set_admin",
+            Self::ArtistCreated => "artist.create",
+            Self::ArtistMemberSet => "artist.member_set",
+            Self::ArtistMemberRemoved => "artist.member_remove",
+            Self::TrackCreated => "track.create",
+            Self::TrackUpdated => "track.update",
+            Self::TrackDeleted => "track.delete",
+            Self::TracksMerged => "track.merge",
+            Self::AlbumUploaded => "album.upload",
+            Self::AlbumVersionRestored => "album.version_restore",
+            Self::PlaylistCreated => "playlist.create",
+            Self::PlaylistTrackAdded => "playlist.add_track",
+            Self::PlaylistTrackRemoved => "playlist.remove_track",
+        }
+    }
+}
+
+/// One entry, built up and then passed to [`record`].
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    action: AuditAction,
+    actor_id: Option<String>,
+    target: Option<(&'static str, String)>,
+    diff: Option<Value>,
+}
+
+impl AuditEvent {
+    pub const fn new(action: AuditAction) -> Self {
+        Self {
+            action,
+            actor_id: None,
+            target: None,
+            diff: None,
+        }
+    }
+
+    #[must_use]
+    pub fn actor(mut self, user_id: impl Into<String>) -> Self {
+        self.actor_id = Some(user_id.into());
+        self
+    }
+
+    #[must_use]
+    pub fn target(mut self, target_type: &'static str, target_id: impl Into<String>) -> Self {
+        self.target = Some((target_type, target_id.into()));
+        self
+    }
+
+    /// JSON describing the change, conventionally `{"before": .., "after": ..}`.
+    #[must_use]
+    pub fn diff(mut self, diff: Value) -> Self {
+        self.diff = Some(diff);
+        self
+    }
+}
+
+/// Writes `event`. The action it describes has already happened, so a failed
+/// write is logged rather than failing the request.
+pub async fn record(db: &DbPool, event: AuditEvent, client: &ClientInfo) {
+    let (target_type, target_id) = event.target.unzip();
+    let entry = AuditLogEntry {
+        id: 0,
+        actor_id: event.actor_id,
+        action: event.action.as_str().to_string(),
+        target_type: target_type.map(str::to_string),
+        target_id,
+        ip_address: client.ip_address.clone(),
+        user_agent: client.user_agent.clone(),
+        diff: event.diff.map(|diff| diff.to_string()),
+        created_at: Utc::now(),
+    };
+
+    let result = queries::create_audit_log_entry(db, &entry).await;
+    if let Err(err) = result {
+        tracing::error!("Failed to write audit log entry {}: {err:#}", entry.action);
+    }
+}
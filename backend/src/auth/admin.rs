@@ -0,0 +1,198 @@
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::request::Parts,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    config::SeedAdminConfig,
+    db::{
+        models::{CreateUser, User},
+        queries, DbPool,
+    },
+    handlers::ApiError,
+    AppState,
+};
+
+use super::{api_key::ApiScope, mfa, password, AuthUser, ClientInfo};
+
+/// An authenticated caller whose account has `is_admin` set.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub User);
+
+impl FromRequestParts<Arc<AppState>> for AdminUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(admin) = parts.extensions.get::<Self>() {
+            return Ok(admin.clone());
+        }
+
+        let auth = AuthUser::from_request_parts(parts, state).await?;
+        auth.require_scope(ApiScope::Admin)?;
+
+        let user = queries::get_user_by_id(&state.db, &auth.user_id)
+            .await?
+            .ok_or_else(|| ApiError::unauthorized("Account no longer exists"))?;
+
+        if !user.is_admin {
+            return Err(ApiError::forbidden(
+                "admin_required",
+                "This action requires an admin account",
+            ));
+        }
+        mfa::require_for_admin(&state.db, &state.config.auth, &user).await?;
+
+        let admin = Self(user);
+        parts.extensions.insert(admin.clone());
+
+        Ok(admin)
+    }
+}
+
+/// Route layer that only lets admins through.
+pub async fn require_admin(admin: AdminUser, mut request: Request, next: Next) -> Response {
+    request.extensions_mut().insert(admin);
+    next.run(request).await
+}
+
+/// Makes sure the admin configured through `ADMIN_USERNAME`, `ADMIN_EMAIL` and
+/// `ADMIN_PASSWORD` exists.
+///
+/// An existing account with that username or email is only promoted when its
+/// email is `ADMIN_EMAIL` and verified, and keeps its password. Anyone could
+/// have registered the username first, so any other account stops startup.
+pub async fn ensure_seed_admin(db: &DbPool, seed: &SeedAdminConfig) -> anyhow::Result<()> {
+    let existing = match queries::get_user_by_username_or_email(db, &seed.username).await? {
+        Some(user) => Some(user),
+        None => queries::get_user_by_username_or_email(db, &seed.email).await?,
+    };
+    if let Some(user) = existing {
+        if user.is_admin {
+            return Ok(());
+        }
+        if !user.email.eq_ignore_ascii_case(&seed.email) || !user.email_verified {
+            anyhow::bail!(
+                "ADMIN_USERNAME/ADMIN_EMAIL: account {} already exists and is not an admin; \
+                 it is only promoted once its verified email is ADMIN_EMAIL",
+                user.username
+            );
+        }
+
+        queries::set_user_admin(db, &user.id, true).await?;
+        info!("Promoted seed admin {} to admin", user.username);
+
+        let event = AuditEvent::new(AuditAction::AdminChanged)
+            .target("user", &user.id)
+            .diff(serde_json::json!({
+                "before": { "is_admin": false },
+                "after": { "is_admin": true },
+                "reason": "seed_admin",
+            }));
+        audit::record(db, event, &ClientInfo::default()).await;
+        return Ok(());
+    }
+
+    let plain = seed.password.clone();
+    let password_hash =
+        tokio::task::spawn_blocking(move || password::hash_password(&plain)).await??;
+
+    let mut user = CreateUser {
+        username: seed.username.clone(),
+        email: seed.email.to_lowercase(),
+        password_hash,
+    }
+    .into_user();
+    user.is_admin = true;
+
+    let user = queries::create_user(db, user, false).await?;
+    info!("Created seed admin {}", seed.username);
+
+    let event = AuditEvent::new(AuditAction::Register)
+        .target("user", &user.id)
+        .diff(serde_json::json!({
+            "after": { "username": user.username, "email": user.email, "is_admin": true },
+            "reason": "seed_admin",
+        }));
+    audit::record(db, event, &ClientInfo::default()).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn seed() -> SeedAdminConfig {
+        SeedAdminConfig {
+            username: "admin".to_string(),
+            email: "Admin@Example.com".to_string(),
+            password: "seed-password".to_string(),
+        }
+    }
+
+    async fn register(db: &DbPool, username: &str, email: &str, email_verified: bool) -> User {
+        let mut user = CreateUser {
+            username: username.to_string(),
+            email: email.to_string(),
+            password_hash: String::new(),
+        }
+        .into_user();
+        user.email_verified = email_verified;
+        queries::create_user(db, user, false).await.expect("user")
+    }
+
+    async fn is_admin(db: &DbPool, id: &str) -> bool {
+        queries::get_user_by_id(db, id)
+            .await
+            .expect("lookup")
+            .expect("user")
+            .is_admin
+    }
+
+    #[tokio::test]
+    async fn creates_the_seed_admin() {
+        let db = db::test_pool().await;
+        ensure_seed_admin(&db, &seed()).await.expect("seeded");
+        let user = queries::get_user_by_username_or_email(&db, "admin")
+            .await
+            .expect("lookup")
+            .expect("created");
+        assert!(user.is_admin);
+        assert_eq!(user.email, "admin@example.com");
+
+        // A second boot leaves it alone.
+        ensure_seed_admin(&db, &seed()).await.expect("seeded again");
+    }
+
+    #[tokio::test]
+    async fn promotes_an_account_with_the_verified_seed_email() {
+        let db = db::test_pool().await;
+        let user = register(&db, "admin", "admin@example.com", true).await;
+        ensure_seed_admin(&db, &seed()).await.expect("promoted");
+        assert!(is_admin(&db, &user.id).await);
+    }
+
+    #[tokio::test]
+    async fn refuses_accounts_that_took_the_seed_name_or_email() {
+        for (username, email, verified) in [
+            ("admin", "attacker@example.com", true),
+            ("admin", "admin@example.com", false),
+            ("someone", "admin@example.com", false),
+        ] {
+            let db = db::test_pool().await;
+            let user = register(&db, username, email, verified).await;
+            let err = ensure_seed_admin(&db, &seed()).await.expect_err(username);
+            assert!(err.to_string().contains("ADMIN_EMAIL"), "{err}");
+            assert!(!is_admin(&db, &user.id).await);
+        }
+    }
+}
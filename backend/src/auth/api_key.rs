@@ -0,0 +1,247 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+use uuid::Uuid;
+
+use crate::db::{models::ApiKey, queries, DbPool};
+
+/// Every API key starts with this, which is how the extractor tells keys from JWTs.
+pub const KEY_PREFIX: &str = "nmk_";
+
+const PREFIX_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 40;
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+/// What an API key may be used for. Interactive sessions carry every scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    Read,
+    Stream,
+    Upload,
+    Admin,
+}
+
+impl ApiScope {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Stream => "stream",
+            Self::Upload => "upload",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn join(scopes: &[Self]) -> String {
+        scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Parses the comma-separated form stored in `api_keys.scopes`, ignoring
+    /// unknown entries.
+    pub fn parse_list(scopes: &str) -> Vec<Self> {
+        scopes
+            .split(',')
+            .filter_map(|scope| scope.trim().parse().ok())
+            .collect()
+    }
+}
+
+impl fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ApiScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "stream" => Ok(Self::Stream),
+            "upload" => Ok(Self::Upload),
+            "admin" => Ok(Self::Admin),
+            other => Err(anyhow::anyhow!("Unknown API key scope: {other}")),
+        }
+    }
+}
+
+/// A freshly generated key. `secret` is only ever shown to the user once.
+pub struct GeneratedKey {
+    pub key: ApiKey,
+    pub secret: String,
+}
+
+pub fn generate_key(
+    user_id: &str,
+    name: String,
+    scopes: &[ApiScope],
+    expires_at: Option<DateTime<Utc>>,
+) -> GeneratedKey {
+    let mut rng = rand::thread_rng();
+    let prefix: String = (&mut rng)
+        .sample_iter(&Alphanumeric)
+        .take(PREFIX_LENGTH)
+        .map(char::from)
+        .collect();
+    let secret_part: String = (&mut rng)
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect();
+
+    let prefix = format!("{KEY_PREFIX}{prefix}");
+    let secret = format!("{prefix}_{secret_part}");
+
+    GeneratedKey {
+        key: ApiKey {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            name,
+            prefix,
+            key_hash: hash_key(&secret),
+            scopes: ApiScope::join(scopes),
+            created_at: Utc::now(),
+            last_used_at: None,
+            expires_at,
+            revoked_at: None,
+        },
+        secret,
+    }
+}
+
+pub fn hash_key(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Looks up an active key by its secret and records the use.
+pub async fn authenticate_key(db: &DbPool, secret: &str) -> anyhow::Result<Option<ApiKey>> {
+    let now = Utc::now();
+    let Some(key) = queries::get_api_key_by_hash(db, &hash_key(secret)).await? else {
+        return Ok(None);
+    };
+
+    if !key.is_active(now) {
+        return Ok(None);
+    }
+
+    queries::touch_api_key(
+        db,
+        &key.id,
+        now,
+        now - Duration::seconds(TOUCH_INTERVAL_SECONDS),
+    )
+    .await?;
+
+    Ok(Some(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::CreateUser;
+
+    #[test]
+    fn scope_lists_skip_what_they_do_not_know() {
+        assert_eq!(
+            ApiScope::parse_list("read, stream,,delete,ADMIN,upload"),
+            [ApiScope::Read, ApiScope::Stream, ApiScope::Upload]
+        );
+        assert!(ApiScope::parse_list("").is_empty());
+
+        let all = [
+            ApiScope::Read,
+            ApiScope::Stream,
+            ApiScope::Upload,
+            ApiScope::Admin,
+        ];
+        assert_eq!(ApiScope::join(&all), "read,stream,upload,admin");
+        assert_eq!(ApiScope::parse_list(&ApiScope::join(&all)), all);
+    }
+
+    #[test]
+    fn keys_start_with_their_prefix() {
+        let generated = generate_key("user", "script".to_string(), &[ApiScope::Read], None);
+        assert!(generated.key.prefix.starts_with(KEY_PREFIX));
+        assert_eq!(generated.key.prefix.len(), KEY_PREFIX.len() + PREFIX_LENGTH);
+        assert_eq!(
+            generated.secret.split_once('_').map(|(_, rest)| rest.len()),
+            Some(PREFIX_LENGTH + 1 + SECRET_LENGTH)
+        );
+        assert!(generated
+            .secret
+            .starts_with(&format!("{}_", generated.key.prefix)));
+        assert_eq!(generated.key.key_hash, hash_key(&generated.secret));
+    }
+
+    #[tokio::test]
+    async fn only_the_whole_secret_of_an_active_key_authenticates() {
+        let db = crate::db::test_pool().await;
+        let user = queries::create_user(
+            &db,
+            CreateUser {
+                username: "scripter".to_string(),
+                email: "scripter@example.com".to_string(),
+                password_hash: String::new(),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user");
+        let create = |expires_at| {
+            let generated = generate_key(
+                &user.id,
+                "script".to_string(),
+                &[ApiScope::Read],
+                expires_at,
+            );
+            let db = db.clone();
+            async move {
+                queries::create_api_key(&db, generated.key)
+                    .await
+                    .expect("key");
+                generated.secret
+            }
+        };
+
+        let secret = create(None).await;
+        let key = authenticate_key(&db, &secret)
+            .await
+            .expect("lookup")
+            .expect("active key");
+        assert_eq!(key.user_id, user.id);
+        assert!(key.last_used_at.is_none());
+
+        // The prefix is shown in listings, so it mustn't be enough on its own.
+        assert!(authenticate_key(&db, &key.prefix)
+            .await
+            .expect("lookup")
+            .is_none());
+        let forged = format!("{}_{}", key.prefix, "x".repeat(SECRET_LENGTH));
+        assert!(authenticate_key(&db, &forged)
+            .await
+            .expect("lookup")
+            .is_none());
+
+        queries::revoke_api_key(&db, &key.id, &user.id, Utc::now())
+            .await
+            .expect("revoke");
+        assert!(authenticate_key(&db, &secret)
+            .await
+            .expect("lookup")
+            .is_none());
+
+        let expired = create(Some(Utc::now() - Duration::seconds(1))).await;
+        assert!(authenticate_key(&db, &expired)
+            .await
+            .expect("lookup")
+            .is_none());
+    }
+}
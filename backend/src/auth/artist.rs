@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::{
+    config::AuthConfig,
+    db::{queries, DbPool},
+    handlers::ApiError,
+};
+
+use super::{mfa, ApiScope, AuthUser};
+
+/// What a member may do with an artist's catalog. `Owner` implies all others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtistPermission {
+    Upload,
+    Edit,
+    Delete,
+    ManageMembers,
+    Owner,
+}
+
+impl ArtistPermission {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Edit => "edit",
+            Self::Delete => "delete",
+            Self::ManageMembers => "manage_members",
+            Self::Owner => "owner",
+        }
+    }
+}
+
+impl fmt::Display for ArtistPermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ArtistPermission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upload" => Ok(Self::Upload),
+            "edit" => Ok(Self::Edit),
+            "delete" => Ok(Self::Delete),
+            "manage_members" => Ok(Self::ManageMembers),
+            "owner" => Ok(Self::Owner),
+            other => Err(anyhow::anyhow!("Unknown artist permission: {other}")),
+        }
+    }
+}
+
+/// Permissions `user_id` holds on `artist_id`, empty for non-members.
+pub async fn member_permissions(
+    db: &DbPool,
+    artist_id: &str,
+    user_id: &str,
+) -> anyhow::Result<Vec<ArtistPermission>> {
+    let permissions = queries::get_artist_permissions_for_user(db, artist_id, user_id)
+        .await?
+        .iter()
+        .filter_map(|permission| permission.parse().ok())
+        .collect();
+
+    Ok(permissions)
+}
+
+/// Whether the caller is an admin acting with admin rights, i.e. not through
+/// an API key that lacks the `admin` scope, and with 2FA enabled when
+/// `REQUIRE_ADMIN_2FA` is on.
+pub async fn is_acting_admin(
+    db: &DbPool,
+    config: &AuthConfig,
+    user: &AuthUser,
+) -> anyhow::Result<bool> {
+    if !user.has_scope(ApiScope::Admin) {
+        return Ok(false);
+    }
+
+    let Some(account) = queries::get_user_by_id(db, &user.user_id).await? else {
+        return Ok(false);
+    };
+
+    Ok(account.is_admin && mfa::require_for_admin(db, config, &account).await.is_ok())
+}
+
+/// Admins may do anything. Everyone else needs `permission` (or ownership) on
+/// the artist, and content without an artist is admin-only.
+pub async fn require_permission(
+    db: &DbPool,
+    config: &AuthConfig,
+    user: &AuthUser,
+    artist_id: Option<&str>,
+    permission: ArtistPermission,
+) -> Result<(), ApiError> {
+    if is_acting_admin(db, config, user).await? {
+        return Ok(());
+    }
+
+    let Some(artist_id) = artist_id else {
+        return Err(ApiError::forbidden(
+            "admin_required",
+            "Only admins can change tracks that are not assigned to an artist",
+        ));
+    };
+
+    let held = member_permissions(db, artist_id, &user.user_id).await?;
+    if held.contains(&permission) || held.contains(&ArtistPermission::Owner) {
+        Ok(())
+    } else {
+        Err(ApiError::forbidden(
+            "artist_permission_required",
+            format!("This action requires the '{permission}' permission on the artist"),
+        ))
+    }
+}
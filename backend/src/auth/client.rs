@@ -0,0 +1,62 @@
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use crate::AppState;
+
+/// Where a request came from, recorded on sessions.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequestParts<Arc<AppState>> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let forwarded = state
+            .config
+            .server
+            .trust_proxy_headers
+            .then(|| forwarded_ip(&parts.headers))
+            .flatten();
+
+        let ip_address = forwarded.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        });
+
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Ok(Self {
+            ip_address,
+            user_agent,
+        })
+    }
+}
+
+fn forwarded_ip(headers: &HeaderMap) -> Option<String> {
+    let header_value = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+
+    header_value("cf-connecting-ip")
+        .or_else(|| header_value("x-forwarded-for").and_then(|list| list.split(',').next()))
+        .map(|ip| ip.trim().to_string())
+}
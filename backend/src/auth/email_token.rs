@@ -0,0 +1,424 @@
+//! Single-use links mailed to users to verify their address or reset their
+//! password.
+//!
+//! The link carries a JWT whose `jti` names an `email_tokens` row. The row is
+//! marked used on redemption, so a link works once, and issuing a new one
+//! invalidates the previous ones.
+
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::{AuthConfig, Config},
+    db::{
+        models::{EmailToken, User},
+        queries, DbPool,
+    },
+    handlers::ApiError,
+    mail::{EmailMessage, Mailer},
+};
+
+/// A user can be sent at most one link per purpose in this many seconds.
+const RESEND_INTERVAL_SECONDS: i64 = 60;
+
+const INVALID_LINK: &str = "This link is invalid or has expired";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl EmailTokenPurpose {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "verify_email",
+            Self::ResetPassword => "reset_password",
+        }
+    }
+
+    /// JWT audience, so a token can't be used for the other purpose or as a
+    /// session token.
+    const fn audience(self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "navicore-music:verify_email",
+            Self::ResetPassword => "navicore-music:reset_password",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailTokenClaims {
+    sub: String,
+    jti: String,
+    aud: String,
+    exp: i64,
+    iat: i64,
+}
+
+/// Creates a token for `user` and returns it, or `None` when one was issued
+/// less than a minute ago.
+pub async fn issue(
+    db: &DbPool,
+    config: &AuthConfig,
+    user: &User,
+    purpose: EmailTokenPurpose,
+    lifetime: Duration,
+) -> anyhow::Result<Option<String>> {
+    let now = Utc::now();
+
+    let latest = queries::latest_email_token_at(db, &user.id, purpose.as_str()).await?;
+    if latest.is_some_and(|latest| now - latest < Duration::seconds(RESEND_INTERVAL_SECONDS)) {
+        return Ok(None);
+    }
+
+    let token = queries::create_email_token(
+        db,
+        EmailToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            purpose: purpose.as_str().to_string(),
+            email: user.email.clone(),
+            created_at: now,
+            expires_at: now + lifetime,
+            used_at: None,
+        },
+    )
+    .await?;
+
+    let claims = EmailTokenClaims {
+        sub: token.user_id,
+        jti: token.id,
+        aud: purpose.audience().to_string(),
+        exp: token.expires_at.timestamp(),
+        iat: now.timestamp(),
+    };
+
+    Ok(Some(encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )?))
+}
+
+/// Checks `token` and marks it used, returning the stored token.
+pub async fn redeem(
+    db: &DbPool,
+    config: &AuthConfig,
+    token: &str,
+    purpose: EmailTokenPurpose,
+) -> Result<EmailToken, ApiError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[purpose.audience()]);
+
+    let claims = decode::<EmailTokenClaims>(
+        token,
+        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map_err(|_| ApiError::bad_request(INVALID_LINK))?
+    .claims;
+
+    queries::use_email_token(db, &claims.jti, purpose.as_str(), Utc::now())
+        .await?
+        .filter(|stored| stored.user_id == claims.sub)
+        .ok_or_else(|| ApiError::bad_request(INVALID_LINK))
+}
+
+/// Mails `user` a link to verify their address. Returns `false` when it was
+/// skipped because a link was sent moments ago.
+pub async fn send_verification(
+    db: &DbPool,
+    mailer: &dyn Mailer,
+    config: &Config,
+    user: &User,
+) -> anyhow::Result<bool> {
+    let hours = config.mail.verify_email_ttl_hours;
+    let lifetime = Duration::try_hours(hours)
+        .ok_or_else(|| anyhow::anyhow!("Invalid VERIFY_EMAIL_TTL_HOURS: {hours}"))?;
+
+    let Some(token) = issue(
+        db,
+        &config.auth,
+        user,
+        EmailTokenPurpose::VerifyEmail,
+        lifetime,
+    )
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    let message = EmailMessage {
+        to: user.email.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+             Please confirm this is your email address for Navicore Music by opening this link:\n\n\
+             {}/verify-email?token={token}\n\n\
+             The link expires in {hours} hours. If you didn't create an account, you can ignore this email.\n",
+            user.username, config.mail.app_url
+        ),
+    };
+    mailer.send(&message).await?;
+
+    Ok(true)
+}
+
+/// Mails `user` a link to choose a new password. Returns `false` when it was
+/// skipped because a link was sent moments ago.
+pub async fn send_password_reset(
+    db: &DbPool,
+    mailer: &dyn Mailer,
+    config: &Config,
+    user: &User,
+) -> anyhow::Result<bool> {
+    let minutes = config.mail.password_reset_ttl_minutes;
+    let lifetime = Duration::try_minutes(minutes)
+        .ok_or_else(|| anyhow::anyhow!("Invalid PASSWORD_RESET_TTL_MINUTES: {minutes}"))?;
+
+    let Some(token) = issue(
+        db,
+        &config.auth,
+        user,
+        EmailTokenPurpose::ResetPassword,
+        lifetime,
+    )
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    let message = EmailMessage {
+        to: user.email.clone(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+             Someone asked to reset the password for your Navicore Music account. \
+             To choose a new password, open this link:\n\n\
+             {}/reset-password?token={token}\n\n\
+             The link expires in {minutes} minutes and can only be used once. \
+             If you didn't ask for this, you can ignore this email and your password stays the same.\n",
+            user.username, config.mail.app_url
+        ),
+    };
+    mailer.send(&message).await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{auth::ClientInfo, db::models::CreateUser, mail::FileMailer};
+
+    /// A [`FileMailer`] writing to a fresh directory.
+    struct Outbox {
+        dir: PathBuf,
+        mailer: FileMailer,
+    }
+
+    impl Outbox {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("navicore-mail-{}", Uuid::new_v4()));
+            let mailer = FileMailer::new(
+                dir.to_str().expect("path"),
+                "Navicore <noreply@example.com>",
+            );
+            Self { dir, mailer }
+        }
+
+        /// Tokens of the links sent so far, oldest first.
+        fn tokens(&self) -> Vec<String> {
+            let Ok(entries) = std::fs::read_dir(&self.dir) else {
+                return Vec::new();
+            };
+            let mut paths: Vec<PathBuf> =
+                entries.map(|entry| entry.expect("entry").path()).collect();
+            paths.sort();
+            paths.iter().map(|path| link_token(path)).collect()
+        }
+    }
+
+    impl Drop for Outbox {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn link_token(path: &Path) -> String {
+        // Long lines are sent quoted-printable, which escapes `=` and wraps.
+        let email = std::fs::read_to_string(path)
+            .expect("email")
+            .replace("=\r\n", "")
+            .replace("=3D", "=");
+        let (_, rest) = email.split_once("?token=").expect("link");
+        rest.chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            .collect()
+    }
+
+    async fn user(db: &DbPool) -> User {
+        queries::create_user(
+            db,
+            CreateUser {
+                username: "listener".to_string(),
+                email: "listener@example.com".to_string(),
+                password_hash: "not-a-real-hash".to_string(),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user")
+    }
+
+    /// Makes the user's links old enough that another may be sent.
+    async fn backdate_links(db: &DbPool, user_id: &str) {
+        sqlx::query(r"UPDATE email_tokens SET created_at = ? WHERE user_id = ?")
+            .bind(Utc::now() - Duration::seconds(RESEND_INTERVAL_SECONDS + 1))
+            .bind(user_id)
+            .execute(db)
+            .await
+            .expect("backdate");
+    }
+
+    #[tokio::test]
+    async fn mailed_links_work_once() {
+        let db = crate::db::test_pool().await;
+        let config = Config::for_tests();
+        let outbox = Outbox::new();
+        let user = user(&db).await;
+
+        assert!(send_verification(&db, &outbox.mailer, &config, &user)
+            .await
+            .expect("send"));
+        // Asking again straight away doesn't send another link.
+        assert!(!send_verification(&db, &outbox.mailer, &config, &user)
+            .await
+            .expect("send"));
+        let tokens = outbox.tokens();
+        assert_eq!(tokens.len(), 1);
+
+        let redeemed = redeem(
+            &db,
+            &config.auth,
+            &tokens[0],
+            EmailTokenPurpose::VerifyEmail,
+        )
+        .await
+        .expect("first use");
+        assert_eq!(
+            (redeemed.user_id.as_str(), redeemed.email.as_str()),
+            (user.id.as_str(), user.email.as_str())
+        );
+        assert!(redeem(
+            &db,
+            &config.auth,
+            &tokens[0],
+            EmailTokenPurpose::VerifyEmail
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn a_new_link_replaces_the_last_one() {
+        let db = crate::db::test_pool().await;
+        let config = Config::for_tests();
+        let user = user(&db).await;
+        let issue_reset = || {
+            issue(
+                &db,
+                &config.auth,
+                &user,
+                EmailTokenPurpose::ResetPassword,
+                Duration::minutes(30),
+            )
+        };
+
+        let first = issue_reset().await.expect("issue").expect("token");
+        backdate_links(&db, &user.id).await;
+        let second = issue_reset().await.expect("issue").expect("token");
+
+        assert!(
+            redeem(&db, &config.auth, &first, EmailTokenPurpose::ResetPassword)
+                .await
+                .is_err()
+        );
+        assert!(
+            redeem(&db, &config.auth, &second, EmailTokenPurpose::ResetPassword)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn links_only_work_for_their_purpose() {
+        let db = crate::db::test_pool().await;
+        let config = Config::for_tests();
+        let outbox = Outbox::new();
+        let user = user(&db).await;
+
+        send_password_reset(&db, &outbox.mailer, &config, &user)
+            .await
+            .expect("send");
+        let reset = outbox.tokens().pop().expect("reset link");
+        assert!(
+            redeem(&db, &config.auth, &reset, EmailTokenPurpose::VerifyEmail)
+                .await
+                .is_err()
+        );
+        // Trying it for the wrong purpose didn't use it up.
+        assert!(
+            redeem(&db, &config.auth, &reset, EmailTokenPurpose::ResetPassword)
+                .await
+                .is_ok()
+        );
+
+        let client = ClientInfo {
+            ip_address: None,
+            user_agent: None,
+        };
+        let (_, session) =
+            crate::auth::session::start_session(&db, &config.auth, &user.id, &client)
+                .await
+                .expect("session");
+        assert!(redeem(
+            &db,
+            &config.auth,
+            &session.token,
+            EmailTokenPurpose::ResetPassword
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_links_are_refused() {
+        let db = crate::db::test_pool().await;
+        let config = Config::for_tests();
+        let user = user(&db).await;
+
+        // Still within the JWT leeway, so only the stored expiry refuses it.
+        let token = issue(
+            &db,
+            &config.auth,
+            &user,
+            EmailTokenPurpose::VerifyEmail,
+            Duration::seconds(-1),
+        )
+        .await
+        .expect("issue")
+        .expect("token");
+        assert!(
+            redeem(&db, &config.auth, &token, EmailTokenPurpose::VerifyEmail)
+                .await
+                .is_err()
+        );
+    }
+}
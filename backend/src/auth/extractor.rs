@@ -0,0 +1,192 @@
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{handlers::ApiError, AppState};
+
+use super::{
+    api_key::{self, ApiScope, KEY_PREFIX},
+    session, token,
+};
+
+/// Cookie set by the web client, see `SESSION_ARCHITECTURE.md`.
+pub const AUTH_COOKIE: &str = "auth_token";
+
+/// Header scripts can use instead of `Authorization: Bearer <key>`.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// How the caller proved who they are. Stored with background uploads, which
+/// run with the uploader's rights.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credential {
+    /// A login JWT backed by an active session.
+    Session { session_id: String },
+    /// A personal API key limited to `scopes`.
+    ApiKey { scopes: Vec<ApiScope> },
+}
+
+/// The authenticated caller, taken from an `Authorization: Bearer` header, the
+/// `auth_token` cookie or an `X-API-Key` header.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub credential: Credential,
+}
+
+impl AuthUser {
+    pub fn session_id(&self) -> Option<&str> {
+        match &self.credential {
+            Credential::Session { session_id } => Some(session_id),
+            Credential::ApiKey { .. } => None,
+        }
+    }
+
+    pub fn has_scope(&self, scope: ApiScope) -> bool {
+        match &self.credential {
+            Credential::Session { .. } => true,
+            Credential::ApiKey { scopes, .. } => scopes.contains(&scope),
+        }
+    }
+
+    pub fn require_scope(&self, scope: ApiScope) -> Result<(), ApiError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(
+                "insufficient_scope",
+                format!("This API key does not have the '{scope}' scope"),
+            ))
+        }
+    }
+
+    /// Rejects API keys, for endpoints that manage credentials themselves.
+    pub fn require_session(&self) -> Result<&str, ApiError> {
+        self.session_id().ok_or_else(|| {
+            ApiError::forbidden(
+                "session_required",
+                "This action requires logging in, API keys cannot be used",
+            )
+        })
+    }
+
+    async fn authenticate(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+        token: &str,
+    ) -> Result<Self, ApiError> {
+        let user = if token.starts_with(KEY_PREFIX) {
+            let key = api_key::authenticate_key(&state.db, token)
+                .await?
+                .ok_or_else(|| ApiError::unauthorized("Invalid, expired or revoked API key"))?;
+
+            Self {
+                user_id: key.user_id,
+                credential: Credential::ApiKey {
+                    scopes: ApiScope::parse_list(&key.scopes),
+                },
+            }
+        } else {
+            let claims = token::decode_token(&state.config.auth, token)
+                .map_err(|_| ApiError::unauthorized("Invalid or expired token"))?;
+
+            let session = session::validate_session(&state.db, &claims.sid, &claims.sub)
+                .await?
+                .ok_or_else(|| ApiError::unauthorized("Session has ended, please log in again"))?;
+
+            Self {
+                user_id: session.user_id,
+                credential: Credential::Session {
+                    session_id: session.id,
+                },
+            }
+        };
+        parts.extensions.insert(user.clone());
+
+        Ok(user)
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<Self>() {
+            return Ok(user.clone());
+        }
+
+        let token = request_token(&parts.headers)
+            .ok_or_else(|| ApiError::unauthorized("Authentication required"))?;
+
+        Self::authenticate(parts, state, &token).await
+    }
+}
+
+/// `Option<AuthUser>` is `None` for anonymous requests, but a token that is
+/// present and invalid is still rejected.
+impl OptionalFromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<Self>() {
+            return Ok(Some(user.clone()));
+        }
+
+        match request_token(&parts.headers) {
+            Some(token) => Self::authenticate(parts, state, &token).await.map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Route layer that rejects requests without a valid token.
+pub async fn require_auth(user: AuthUser, mut request: Request, next: Next) -> Response {
+    request.extensions_mut().insert(user);
+    next.run(request).await
+}
+
+fn request_token(headers: &HeaderMap) -> Option<String> {
+    bearer_token(headers)
+        .or_else(|| header_token(headers, API_KEY_HEADER))
+        .or_else(|| cookie_value(headers, AUTH_COOKIE))
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    (scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty())
+        .then(|| token.trim().to_string())
+}
+
+fn header_token(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+/// Value of the cookie `name`, if it is set and not empty.
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, value)| *key == name && !value.is_empty())
+        .map(|(_, value)| value.to_string())
+}
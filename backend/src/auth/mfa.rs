@@ -0,0 +1,393 @@
+//! TOTP two-factor authentication.
+//!
+//! Enrolment stores a pending secret that only becomes active once the user
+//! proves their authenticator produces matching codes. With 2FA on, a correct
+//! password only earns a short-lived challenge token, exchanged for a session
+//! at `POST /api/v1/auth/login/mfa` together with a code.
+
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    config::AuthConfig,
+    db::{
+        models::{User, UserTotp},
+        queries, DbPool,
+    },
+    handlers::ApiError,
+};
+
+use super::token::IssuedToken;
+
+/// Shown as the account's issuer in authenticator apps.
+const ISSUER: &str = "Navicore Music";
+const STEP_SECONDS: i64 = 30;
+const DIGITS: usize = 6;
+const SECRET_BYTES: usize = 20;
+/// Codes from one step either side of now are accepted, for clock drift.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Lowercase letters and digits without look-alikes (0/o, 1/l/i).
+const RECOVERY_CODE_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+const RECOVERY_CODE_LENGTH: usize = 16;
+
+const CHALLENGE_LIFETIME_MINUTES: i64 = 5;
+const CHALLENGE_AUDIENCE: &str = "navicore-music:mfa";
+
+/// How the second factor was proven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MfaMethod {
+    Totp,
+    RecoveryCode,
+}
+
+impl MfaMethod {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Totp => "totp",
+            Self::RecoveryCode => "recovery_code",
+        }
+    }
+}
+
+/// A pending enrolment to show the user once.
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    /// Base32 secret, for apps that can't scan the URI.
+    pub secret: String,
+    /// `otpauth://` URI, usually rendered as a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Claims of the token handed out between the password and the code.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    pub sub: String,
+    /// First factor, e.g. `password` or `oauth:github`, recorded in the audit log.
+    pub method: String,
+    /// Where an external login was headed, returned once the code is accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_to: Option<String>,
+    aud: String,
+    exp: i64,
+    iat: i64,
+}
+
+fn totp_for(secret: &str, account_name: &str) -> anyhow::Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| anyhow::anyhow!("Invalid TOTP secret: {err}"))?;
+
+    // Drift is handled in `matching_step`, so each step is checked on its own.
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        u64::try_from(STEP_SECONDS)?,
+        secret,
+        Some(ISSUER.to_string()),
+        account_name.to_string(),
+    )?)
+}
+
+/// Time step `code` was generated for, if it matches one near now.
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = Utc::now().timestamp() / STEP_SECONDS;
+    (current - ALLOWED_DRIFT_STEPS..=current + ALLOWED_DRIFT_STEPS)
+        .find(|step| u64::try_from(step * STEP_SECONDS).is_ok_and(|time| totp.check(code, time)))
+}
+
+pub async fn is_enabled(db: &DbPool, user_id: &str) -> anyhow::Result<bool> {
+    Ok(queries::get_user_totp(db, user_id)
+        .await?
+        .is_some_and(|totp| totp.enabled_at.is_some()))
+}
+
+/// Starts enrolment with a new secret, replacing any earlier pending one.
+/// Returns `None` if 2FA is already enabled.
+pub async fn begin_enrollment(db: &DbPool, user: &User) -> anyhow::Result<Option<Enrollment>> {
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill(&mut bytes);
+    let secret = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+    let totp = totp_for(&secret, &user.username)?;
+
+    let stored = queries::upsert_pending_totp(
+        db,
+        &UserTotp {
+            user_id: user.id.clone(),
+            secret: secret.clone(),
+            enabled_at: None,
+            created_at: Utc::now(),
+        },
+    )
+    .await?;
+
+    Ok(stored.then(|| Enrollment {
+        secret,
+        otpauth_uri: totp.get_url(),
+    }))
+}
+
+/// Confirms a pending enrolment with a code from the authenticator and returns
+/// the new recovery codes.
+pub async fn activate(db: &DbPool, user: &User, code: &str) -> Result<Vec<String>, ApiError> {
+    let pending = queries::get_user_totp(db, &user.id)
+        .await?
+        .filter(|totp| totp.enabled_at.is_none())
+        .ok_or_else(|| ApiError::conflict("No pending two-factor enrolment, start one first"))?;
+
+    let totp = totp_for(&pending.secret, &user.username)?;
+    let step = matching_step(&totp, code)
+        .ok_or_else(|| ApiError::bad_request("Invalid authentication code"))?;
+
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    if !queries::enable_totp(db, &user.id, step, &hashes, Utc::now()).await? {
+        return Err(ApiError::conflict(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    Ok(codes)
+}
+
+/// Checks a TOTP or recovery code for a user with 2FA enabled, using it up.
+pub async fn verify(db: &DbPool, user: &User, code: &str) -> anyhow::Result<Option<MfaMethod>> {
+    let Some(enrolled) = queries::get_user_totp(db, &user.id)
+        .await?
+        .filter(|totp| totp.enabled_at.is_some())
+    else {
+        return Ok(None);
+    };
+
+    let totp = totp_for(&enrolled.secret, &user.username)?;
+    if let Some(step) = matching_step(&totp, code) {
+        // Refuses a code that was already used, including from a later step.
+        let fresh = queries::advance_totp_step(db, &user.id, step).await?;
+        return Ok(fresh.then_some(MfaMethod::Totp));
+    }
+
+    let redeemed =
+        queries::use_recovery_code(db, &user.id, &hash_recovery_code(code), Utc::now()).await?;
+    Ok(redeemed.then_some(MfaMethod::RecoveryCode))
+}
+
+/// Replaces the user's recovery codes and returns the new ones.
+pub async fn regenerate_recovery_codes(db: &DbPool, user_id: &str) -> anyhow::Result<Vec<String>> {
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    queries::replace_recovery_codes(db, user_id, &hashes, Utc::now()).await?;
+
+    Ok(codes)
+}
+
+/// Rejects admins without 2FA when `REQUIRE_ADMIN_2FA` is on.
+pub async fn require_for_admin(
+    db: &DbPool,
+    config: &AuthConfig,
+    user: &User,
+) -> Result<(), ApiError> {
+    if !config.require_admin_2fa || !user.is_admin || is_enabled(db, &user.id).await? {
+        return Ok(());
+    }
+
+    Err(ApiError::forbidden(
+        "mfa_required",
+        "Admin accounts must enable two-factor authentication first",
+    ))
+}
+
+/// Codes look like `abcd-efgh-jkmn-pqrs`.
+fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: Vec<char> = (0..RECOVERY_CODE_LENGTH)
+                .map(|_| {
+                    char::from(
+                        RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())],
+                    )
+                })
+                .collect();
+            chars
+                .chunks(4)
+                .map(|group| group.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// Hashes the code ignoring case, dashes and spaces, so it can be typed loosely.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// Issues the token that stands in for a verified first factor.
+pub fn issue_challenge(
+    config: &AuthConfig,
+    user_id: &str,
+    method: &str,
+    redirect_to: Option<String>,
+) -> anyhow::Result<IssuedToken> {
+    let now = Utc::now();
+    let lifetime = Duration::minutes(CHALLENGE_LIFETIME_MINUTES);
+
+    let claims = MfaChallenge {
+        sub: user_id.to_string(),
+        method: method.to_string(),
+        redirect_to,
+        aud: CHALLENGE_AUDIENCE.to_string(),
+        exp: (now + lifetime).timestamp(),
+        iat: now.timestamp(),
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )?;
+
+    Ok(IssuedToken {
+        token,
+        expires_in: lifetime.num_seconds(),
+    })
+}
+
+pub fn decode_challenge(config: &AuthConfig, token: &str) -> Result<MfaChallenge, ApiError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[CHALLENGE_AUDIENCE]);
+
+    decode::<MfaChallenge>(
+        token,
+        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+        &validation,
+    )
+    .map(|data| data.claims)
+    .map_err(|_| ApiError::unauthorized("Login has expired, please start again"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::token, config::Config, db, db::models::CreateUser};
+
+    async fn enrolled_user(db: &DbPool) -> (User, TOTP, Vec<String>) {
+        let user = queries::create_user(
+            db,
+            CreateUser {
+                username: "listener".to_string(),
+                email: "listener@example.com".to_string(),
+                password_hash: String::new(),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user");
+        let enrollment = begin_enrollment(db, &user)
+            .await
+            .expect("enrol")
+            .expect("not enrolled yet");
+        let totp = totp_for(&enrollment.secret, &user.username).expect("totp");
+        let codes = activate(db, &user, &code_at(&totp, 0))
+            .await
+            .expect("activate");
+        (user, totp, codes)
+    }
+
+    /// The code `steps` steps from now.
+    fn code_at(totp: &TOTP, steps: i64) -> String {
+        let time = (Utc::now().timestamp() / STEP_SECONDS + steps) * STEP_SECONDS;
+        totp.generate(u64::try_from(time).expect("time"))
+    }
+
+    #[tokio::test]
+    async fn each_code_works_once() {
+        let db = db::test_pool().await;
+        let (user, totp, _) = enrolled_user(&db).await;
+
+        // The code that turned 2FA on can't log in as well.
+        assert_eq!(
+            verify(&db, &user, &code_at(&totp, 0))
+                .await
+                .expect("verify"),
+            None
+        );
+
+        let next = code_at(&totp, 1);
+        assert_eq!(
+            verify(&db, &user, &next).await.expect("verify"),
+            Some(MfaMethod::Totp)
+        );
+        assert_eq!(verify(&db, &user, &next).await.expect("verify"), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_codes_outside_the_drift_window() {
+        let db = db::test_pool().await;
+        let (user, totp, _) = enrolled_user(&db).await;
+
+        let too_late = code_at(&totp, ALLOWED_DRIFT_STEPS + 1);
+        assert_eq!(verify(&db, &user, &too_late).await.expect("verify"), None);
+        assert_eq!(verify(&db, &user, "12345").await.expect("verify"), None);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_single_use() {
+        let db = db::test_pool().await;
+        let (user, _, codes) = enrolled_user(&db).await;
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+
+        // Typed loosely, it's still the same code.
+        let loose = codes[0].to_uppercase().replace('-', " ");
+        assert_eq!(
+            verify(&db, &user, &loose).await.expect("verify"),
+            Some(MfaMethod::RecoveryCode)
+        );
+        assert_eq!(verify(&db, &user, &codes[0]).await.expect("verify"), None);
+
+        let fresh = regenerate_recovery_codes(&db, &user.id)
+            .await
+            .expect("regenerate");
+        assert_eq!(verify(&db, &user, &codes[1]).await.expect("verify"), None);
+        assert_eq!(
+            verify(&db, &user, &fresh[0]).await.expect("verify"),
+            Some(MfaMethod::RecoveryCode)
+        );
+    }
+
+    #[test]
+    fn challenges_are_not_session_tokens() {
+        let config = Config::for_tests().auth;
+        let challenge = issue_challenge(&config, "user", "password", None).expect("challenge");
+        let decoded = decode_challenge(&config, &challenge.token).expect("decode");
+        assert_eq!(
+            (decoded.sub.as_str(), decoded.method.as_str()),
+            ("user", "password")
+        );
+
+        let session =
+            token::issue_token(&config, "user", "session", Utc::now() + Duration::hours(1))
+                .expect("token");
+        assert!(decode_challenge(&config, &session.token).is_err());
+        assert!(token::decode_token(&config, &challenge.token).is_err());
+    }
+}
@@ -0,0 +1,18 @@
+pub mod admin;
+pub mod api_key;
+pub mod artist;
+pub mod client;
+pub mod email_token;
+pub mod extractor;
+pub mod mfa;
+pub mod oauth;
+pub mod password;
+pub mod rate_limit;
+pub mod session;
+pub mod token;
+
+pub use admin::{require_admin, AdminUser};
+pub use api_key::ApiScope;
+pub use artist::ArtistPermission;
+pub use client::ClientInfo;
+pub use extractor::{require_auth, AuthUser};
@@ -0,0 +1,111 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::config::OAuthProviderConfig;
+
+use super::{request_token, AuthorizationRequest, ExternalIdentity, OAuthProvider};
+
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+    id: i64,
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// GitHub OAuth apps. GitHub has no ID token, so the identity comes from the
+/// REST API using the access token.
+pub struct GithubProvider {
+    config: OAuthProviderConfig,
+    http: reqwest::Client,
+}
+
+impl GithubProvider {
+    pub const fn new(config: OAuthProviderConfig, http: reqwest::Client) -> Self {
+        Self { config, http }
+    }
+
+    async fn api<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        access_token: &str,
+    ) -> anyhow::Result<T> {
+        let api_url = self
+            .config
+            .api_url
+            .as_deref()
+            .unwrap_or("https://api.github.com");
+
+        Ok(self
+            .http
+            .get(format!("{api_url}{path}"))
+            .bearer_auth(access_token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for GithubProvider {
+    async fn authorize_url(&self, request: &AuthorizationRequest<'_>) -> anyhow::Result<String> {
+        let scope = self.config.scopes.join(" ");
+
+        let url = reqwest::Url::parse_with_params(
+            &format!("{}/login/oauth/authorize", self.config.issuer_url),
+            &[
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", request.redirect_uri),
+                ("scope", scope.as_str()),
+                ("state", request.state),
+                ("code_challenge", request.pkce_challenge().as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.into())
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        request: &AuthorizationRequest<'_>,
+    ) -> anyhow::Result<ExternalIdentity> {
+        let token_url = format!("{}/login/oauth/access_token", self.config.issuer_url);
+        let tokens = request_token(&self.http, &token_url, &self.config, code, request).await?;
+
+        let profile: serde_json::Value = self.api("/user", &tokens.access_token).await?;
+        let user: GithubUser = serde_json::from_value(profile.clone())?;
+
+        // The profile email is optional and may be unverified, the emails
+        // endpoint says which one is the verified primary address.
+        let emails: Vec<GithubEmail> = self
+            .api("/user/emails", &tokens.access_token)
+            .await
+            .unwrap_or_default();
+        let primary = emails
+            .into_iter()
+            .find(|email| email.primary && email.verified);
+
+        Ok(ExternalIdentity {
+            subject: user.id.to_string(),
+            email_verified: primary.is_some(),
+            email: primary.map(|email| email.email).or_else(|| {
+                profile
+                    .get("email")
+                    .and_then(|email| email.as_str())
+                    .map(str::to_string)
+            }),
+            preferred_username: Some(user.login),
+            profile,
+        })
+    }
+}
@@ -0,0 +1,473 @@
+//! Login through external identity providers.
+//!
+//! Every provider runs the authorization-code flow with PKCE. The browser is
+//! sent to [`OAuthProvider::authorize_url`], and the callback hands the code to
+//! [`OAuthProvider::exchange_code`], which returns the verified
+//! [`ExternalIdentity`]. Identities are linked to `users` rows through
+//! `user_oauth`.
+//!
+//! The `state` parameter alone doesn't say who started a flow, so
+//! [`begin_authorization`] also hands out a browser token that goes in a
+//! cookie, and the callback is refused without it. Otherwise a callback URL
+//! someone else started could log a victim into the attacker's account, or link
+//! the victim's provider account to it.
+
+mod github;
+mod oidc;
+#[cfg(test)]
+mod tests;
+
+use async_trait::async_trait;
+use axum::http::HeaderValue;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, sync::Arc};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{AuthConfig, OAuthConfig, OAuthProviderConfig, OAuthProviderKind},
+    db::{
+        self,
+        models::{CreateUser, OAuthIdentity, OAuthState, User},
+        queries, DbPool,
+    },
+    handlers::ApiError,
+};
+
+pub use github::GithubProvider;
+pub use oidc::OidcProvider;
+
+/// How long a user has to finish logging in at the provider.
+const STATE_LIFETIME_MINUTES: i64 = 10;
+
+/// Cookie holding the browser token of the flow in progress.
+pub const BROWSER_COOKIE: &str = "oauth_browser";
+
+/// Everything a provider needs to build the authorization URL and to redeem
+/// the code it returns.
+pub struct AuthorizationRequest<'a> {
+    pub redirect_uri: &'a str,
+    pub state: &'a str,
+    pub nonce: &'a str,
+    pub pkce_verifier: &'a str,
+}
+
+impl AuthorizationRequest<'_> {
+    /// The S256 PKCE challenge for `pkce_verifier`.
+    pub fn pkce_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.pkce_verifier.as_bytes()))
+    }
+}
+
+/// The account a provider vouched for.
+#[derive(Debug, Clone)]
+pub struct ExternalIdentity {
+    /// Stable id of the account at the provider (`sub` for OIDC).
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    /// Raw profile, stored in `user_oauth.provider_data`.
+    pub profile: serde_json::Value,
+}
+
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// URL to redirect the browser to.
+    async fn authorize_url(&self, request: &AuthorizationRequest<'_>) -> anyhow::Result<String>;
+
+    /// Redeems the callback `code` and returns the verified identity.
+    async fn exchange_code(
+        &self,
+        code: &str,
+        request: &AuthorizationRequest<'_>,
+    ) -> anyhow::Result<ExternalIdentity>;
+}
+
+/// Configured providers by name.
+#[derive(Clone, Default)]
+pub struct OAuthProviders {
+    providers: BTreeMap<String, Arc<dyn OAuthProvider>>,
+}
+
+impl OAuthProviders {
+    pub fn from_config(config: &OAuthConfig) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("navicore-music/", env!("CARGO_PKG_VERSION")))
+            .timeout(std::time::Duration::from_secs(15))
+            .build()?;
+
+        let providers = config
+            .providers
+            .iter()
+            .map(|provider| {
+                let built: Arc<dyn OAuthProvider> = match provider.kind {
+                    OAuthProviderKind::Oidc | OAuthProviderKind::Google => {
+                        Arc::new(OidcProvider::new(provider.clone(), http.clone()))
+                    }
+                    OAuthProviderKind::Github => {
+                        Arc::new(GithubProvider::new(provider.clone(), http.clone()))
+                    }
+                };
+                (provider.name.clone(), built)
+            })
+            .collect();
+
+        Ok(Self { providers })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn OAuthProvider>> {
+        self.providers.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.providers.keys().map(String::as_str)
+    }
+}
+
+/// Callback URL registered with the provider for `provider`.
+pub fn redirect_uri(config: &OAuthConfig, provider: &str) -> String {
+    format!(
+        "{}/api/v1/auth/oauth/{provider}/callback",
+        config.redirect_base_url
+    )
+}
+
+/// Only same-site paths are allowed after login, never another origin.
+pub fn sanitize_redirect(redirect_to: Option<&str>) -> String {
+    redirect_to
+        .filter(|path| path.starts_with('/') && !path.starts_with("//") && !path.contains('\\'))
+        .unwrap_or("/")
+        .to_string()
+}
+
+/// An authorization attempt that was just recorded.
+pub struct StartedAuthorization {
+    /// Provider URL to send the browser to.
+    pub url: String,
+    /// Goes in the [`BROWSER_COOKIE`], the callback must present it.
+    pub browser_token: String,
+}
+
+/// `Set-Cookie` value binding a flow to this browser. `SameSite=Lax` still
+/// sends it on the provider's top-level redirect back to the callback.
+fn browser_cookie(config: &AuthConfig, token: &str, max_age: i64) -> anyhow::Result<HeaderValue> {
+    let mut cookie =
+        format!("{BROWSER_COOKIE}={token}; Path=/api/v1/auth/oauth; HttpOnly; SameSite=Lax; Max-Age={max_age}");
+    if config.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+
+    Ok(HeaderValue::from_str(&cookie)?)
+}
+
+/// `Set-Cookie` value for a browser token that lasts as long as its state.
+pub fn start_browser_cookie(config: &AuthConfig, token: &str) -> anyhow::Result<HeaderValue> {
+    browser_cookie(config, token, STATE_LIFETIME_MINUTES * 60)
+}
+
+/// `Set-Cookie` value removing the browser token once the flow is over.
+pub fn clear_browser_cookie(config: &AuthConfig) -> anyhow::Result<HeaderValue> {
+    browser_cookie(config, "", 0)
+}
+
+/// Records a new authorization attempt and returns the provider URL to visit,
+/// with the token the browser has to keep for the callback.
+pub async fn begin_authorization(
+    db: &DbPool,
+    config: &OAuthConfig,
+    provider_name: &str,
+    provider: &dyn OAuthProvider,
+    redirect_to: String,
+    link_user_id: Option<String>,
+) -> anyhow::Result<StartedAuthorization> {
+    let now = Utc::now();
+    queries::delete_expired_oauth_states(db, now).await?;
+
+    let browser_token = random_token(32);
+
+    let state = queries::create_oauth_state(
+        db,
+        OAuthState {
+            state: random_token(32),
+            provider: provider_name.to_string(),
+            pkce_verifier: random_token(64),
+            nonce: random_token(32),
+            redirect_to,
+            link_user_id,
+            browser_hash: hash_browser_token(&browser_token),
+            created_at: now,
+            expires_at: now + Duration::minutes(STATE_LIFETIME_MINUTES),
+        },
+    )
+    .await?;
+
+    let redirect_uri = redirect_uri(config, provider_name);
+    let url = provider
+        .authorize_url(&AuthorizationRequest {
+            redirect_uri: &redirect_uri,
+            state: &state.state,
+            nonce: &state.nonce,
+            pkce_verifier: &state.pkce_verifier,
+        })
+        .await?;
+
+    Ok(StartedAuthorization { url, browser_token })
+}
+
+/// What the provider's redirect brought back to the callback.
+pub struct Callback<'a> {
+    pub state: &'a str,
+    pub code: &'a str,
+    /// The [`BROWSER_COOKIE`], the flow must have been started with it.
+    pub browser_token: &'a str,
+}
+
+/// Consumes the callback's state, redeems its code and returns the local user
+/// it maps to, along with where to send the browser.
+pub async fn complete_authorization(
+    db: &DbPool,
+    config: &OAuthConfig,
+    first_user_is_admin: bool,
+    provider_name: &str,
+    provider: &dyn OAuthProvider,
+    callback: &Callback<'_>,
+) -> Result<(User, String), ApiError> {
+    let pending = queries::take_oauth_state(db, callback.state)
+        .await?
+        .filter(|pending| pending.provider == provider_name && pending.expires_at > Utc::now())
+        .ok_or_else(|| {
+            ApiError::bad_request("Login request is invalid or has expired, please try again")
+        })?;
+
+    let presented = hash_browser_token(callback.browser_token);
+    if !bool::from(presented.as_bytes().ct_eq(pending.browser_hash.as_bytes())) {
+        return Err(ApiError::bad_request(
+            "Login request was started in another browser, please try again",
+        ));
+    }
+
+    let redirect_uri = redirect_uri(config, provider_name);
+    let identity = provider
+        .exchange_code(
+            callback.code,
+            &AuthorizationRequest {
+                redirect_uri: &redirect_uri,
+                state: &pending.state,
+                nonce: &pending.nonce,
+                pkce_verifier: &pending.pkce_verifier,
+            },
+        )
+        .await
+        .map_err(|err| {
+            tracing::warn!("OAuth login with {provider_name} failed: {err:#}");
+            ApiError::unauthorized(format!("Could not verify the {provider_name} login"))
+        })?;
+
+    let user = resolve_user(
+        db,
+        provider_name,
+        &identity,
+        pending.link_user_id.as_deref(),
+        first_user_is_admin,
+    )
+    .await?;
+
+    Ok((user, pending.redirect_to))
+}
+
+/// Finds the account for `identity`, linking or creating one when needed.
+///
+/// An identity already linked wins. Otherwise it is linked to the account that
+/// started the flow, then to an account with the same email when both the
+/// provider and this server have verified it, and failing both a new account
+/// is created. An unverified local email could have been registered by anyone,
+/// so that account has to log in and link the provider itself.
+async fn resolve_user(
+    db: &DbPool,
+    provider_name: &str,
+    identity: &ExternalIdentity,
+    link_user_id: Option<&str>,
+    first_user_is_admin: bool,
+) -> Result<User, ApiError> {
+    let email = identity
+        .email
+        .as_deref()
+        .map(|email| email.trim().to_lowercase());
+
+    let existing =
+        queries::get_user_by_oauth_identity(db, provider_name, &identity.subject).await?;
+    let user = match (existing, link_user_id) {
+        (Some(user), Some(link_user_id)) if user.id != link_user_id => {
+            return Err(ApiError::conflict(format!(
+                "This {provider_name} account is already linked to another user"
+            )));
+        }
+        (Some(user), _) => user,
+        (None, Some(link_user_id)) => queries::get_user_by_id(db, link_user_id)
+            .await?
+            .ok_or_else(|| ApiError::not_found("User not found"))?,
+        (None, None) => {
+            let verified_match = match email.as_deref() {
+                Some(email) if identity.email_verified => {
+                    queries::get_user_by_email(db, email).await?
+                }
+                _ => None,
+            };
+            match verified_match {
+                Some(user) if user.email_verified => user,
+                Some(_) => {
+                    return Err(ApiError::conflict(format!(
+                        "An account with this email already exists, log in and link {provider_name} from your account settings"
+                    )));
+                }
+                None => create_user(db, identity, email, first_user_is_admin).await?,
+            }
+        }
+    };
+
+    let now = Utc::now();
+    let linked = queries::upsert_oauth_identity(
+        db,
+        OAuthIdentity {
+            user_id: user.id.clone(),
+            provider: provider_name.to_string(),
+            provider_user_id: identity.subject.clone(),
+            email: identity.email.clone(),
+            provider_data: Some(identity.profile.to_string()),
+            created_at: now,
+            updated_at: now,
+        },
+    )
+    .await;
+
+    match linked {
+        Ok(_) => Ok(user),
+        // The user already has a different account from this provider linked.
+        Err(err) if db::is_unique_violation(&err) => Err(ApiError::conflict(format!(
+            "Another {provider_name} account is already linked to this user"
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn create_user(
+    db: &DbPool,
+    identity: &ExternalIdentity,
+    email: Option<String>,
+    first_user_is_admin: bool,
+) -> Result<User, ApiError> {
+    let email = email.ok_or_else(|| {
+        ApiError::bad_request("The provider did not share an email address for this account")
+    })?;
+    if queries::email_exists(db, &email).await? {
+        return Err(ApiError::conflict(
+            "An account with this email already exists, log in and link the provider instead",
+        ));
+    }
+
+    let base = username_base(identity.preferred_username.as_deref(), &email);
+    let mut username = base.clone();
+    let mut suffix = 1;
+    while queries::username_exists(db, &username).await? {
+        suffix += 1;
+        username = format!("{base}{suffix}");
+    }
+
+    // An empty hash never verifies, these accounts log in through the provider.
+    let mut user = CreateUser {
+        username,
+        email,
+        password_hash: String::new(),
+    }
+    .into_user();
+    user.email_verified = identity.email_verified;
+
+    let user = queries::create_user(db, user, first_user_is_admin).await?;
+    tracing::info!("Created user {} from an external login", user.username);
+
+    Ok(user)
+}
+
+/// Derives a username that passes registration's rules from the provider's
+/// suggestion or the email's local part.
+fn username_base(preferred: Option<&str>, email: &str) -> String {
+    let candidate = preferred
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+
+    let mut base: String = candidate
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(24)
+        .collect();
+
+    while base.len() < 3 {
+        base.push('0');
+    }
+    base
+}
+
+fn hash_browser_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Token endpoint response shared by OAuth 2.0 and OIDC.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+/// Redeems an authorization code at `token_url`.
+async fn request_token(
+    http: &reqwest::Client,
+    token_url: &str,
+    provider: &OAuthProviderConfig,
+    code: &str,
+    request: &AuthorizationRequest<'_>,
+) -> anyhow::Result<TokenResponse> {
+    let response = http
+        .post(token_url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", request.redirect_uri),
+            ("client_id", &provider.client_id),
+            ("client_secret", &provider.client_secret),
+            ("code_verifier", request.pkce_verifier),
+        ])
+        .send()
+        .await?;
+
+    let status = response.status();
+    let body: serde_json::Value = response.json().await?;
+    // GitHub reports failures with a 200 and an `error` field.
+    if let Some(error) = body.get("error") {
+        anyhow::bail!("Token request failed ({status}): {error}");
+    }
+    if !status.is_success() {
+        anyhow::bail!("Token request failed with {status}");
+    }
+
+    Ok(serde_json::from_value(body)?)
+}
@@ -0,0 +1,240 @@
+use async_trait::async_trait;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::config::OAuthProviderConfig;
+
+use super::{request_token, AuthorizationRequest, ExternalIdentity, OAuthProvider};
+
+/// Endpoints from the issuer's `/.well-known/openid-configuration`.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<serde_json::Value>,
+    preferred_username: Option<String>,
+}
+
+/// A generic OIDC provider, configured from discovery.
+pub struct OidcProvider {
+    config: OAuthProviderConfig,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OAuthProviderConfig, http: reqwest::Client) -> Self {
+        Self {
+            config,
+            http,
+            metadata: OnceCell::new(),
+            jwks: RwLock::new(None),
+        }
+    }
+
+    async fn metadata(&self) -> anyhow::Result<&ProviderMetadata> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer_url
+                );
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(&url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+
+                if metadata.issuer.trim_end_matches('/') != self.config.issuer_url {
+                    anyhow::bail!(
+                        "Discovery document issuer {} does not match {}",
+                        metadata.issuer,
+                        self.config.issuer_url
+                    );
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// Key for `kid`, refetching the JWKS once in case the issuer rotated keys.
+    async fn signing_key(&self, kid: Option<&str>) -> anyhow::Result<DecodingKey> {
+        let find = |jwks: &JwkSet| {
+            kid.map_or_else(|| jwks.keys.first().cloned(), |kid| jwks.find(kid).cloned())
+        };
+
+        let cached = self.jwks.read().await.as_ref().and_then(find);
+        if let Some(jwk) = cached {
+            return Ok(DecodingKey::from_jwk(&jwk)?);
+        }
+
+        let metadata = self.metadata().await?;
+        let jwks: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwk = find(&jwks);
+        *self.jwks.write().await = Some(jwks);
+
+        let jwk = jwk.ok_or_else(|| anyhow::anyhow!("No signing key matches the ID token"))?;
+        Ok(DecodingKey::from_jwk(&jwk)?)
+    }
+
+    async fn verify_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> anyhow::Result<(IdTokenClaims, serde_json::Value)> {
+        let metadata = self.metadata().await?;
+        // Unsigned (`none`) tokens fail here, there is no such `Algorithm`.
+        let header = decode_header(id_token)?;
+
+        // The header is the attacker's to choose, only configured algorithms
+        // count. Otherwise a token "signed" with HS256 and the client secret,
+        // or with an RSA public key as an HMAC secret, would pass.
+        let accepted = &self.config.id_token_algorithms;
+        if !accepted.contains(&header.alg) {
+            anyhow::bail!(
+                "ID token is signed with {:?}, which is not accepted from this provider",
+                header.alg
+            );
+        }
+
+        // HMAC-signed ID tokens use the client secret as the key (OIDC Core 10.1).
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                DecodingKey::from_secret(self.config.client_secret.as_bytes())
+            }
+            _ => self.signing_key(header.kid.as_deref()).await?,
+        };
+
+        // Only the one algorithm checked above, `decode` refuses lists that
+        // mix families such as RS256 and ES256.
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_required_spec_claims(&["exp", "iat", "iss", "aud", "sub"]);
+
+        let profile = decode::<serde_json::Value>(id_token, &key, &validation)?.claims;
+        let claims: IdTokenClaims = serde_json::from_value(profile.clone())?;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            anyhow::bail!("ID token nonce does not match the login request");
+        }
+
+        Ok((claims, profile))
+    }
+
+    /// Fills in claims some issuers only return from the userinfo endpoint.
+    async fn userinfo(
+        &self,
+        access_token: &str,
+        subject: &str,
+    ) -> anyhow::Result<Option<(IdTokenClaims, serde_json::Value)>> {
+        let Some(url) = &self.metadata().await?.userinfo_endpoint else {
+            return Ok(None);
+        };
+
+        let profile: serde_json::Value = self
+            .http
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let claims: IdTokenClaims = serde_json::from_value(profile.clone())?;
+
+        // Userinfo responses for a different subject must be ignored (OIDC Core 5.3.2).
+        Ok((claims.sub == subject).then_some((claims, profile)))
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for OidcProvider {
+    async fn authorize_url(&self, request: &AuthorizationRequest<'_>) -> anyhow::Result<String> {
+        let metadata = self.metadata().await?;
+        let scope = self.config.scopes.join(" ");
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", request.redirect_uri),
+                ("scope", scope.as_str()),
+                ("state", request.state),
+                ("nonce", request.nonce),
+                ("code_challenge", request.pkce_challenge().as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.into())
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        request: &AuthorizationRequest<'_>,
+    ) -> anyhow::Result<ExternalIdentity> {
+        let metadata = self.metadata().await?;
+        let tokens = request_token(
+            &self.http,
+            &metadata.token_endpoint,
+            &self.config,
+            code,
+            request,
+        )
+        .await?;
+        let id_token = tokens
+            .id_token
+            .ok_or_else(|| anyhow::anyhow!("Token response has no ID token"))?;
+
+        let (mut claims, mut profile) = self.verify_id_token(&id_token, request.nonce).await?;
+
+        if claims.email.is_none() {
+            if let Some((info, info_profile)) =
+                self.userinfo(&tokens.access_token, &claims.sub).await?
+            {
+                claims.email = info.email;
+                claims.email_verified = info.email_verified;
+                claims.preferred_username = claims.preferred_username.or(info.preferred_username);
+                profile = info_profile;
+            }
+        }
+
+        // Some issuers send `"true"` as a string.
+        let email_verified = claims.email_verified.is_some_and(|verified| {
+            verified.as_bool() == Some(true) || verified.as_str() == Some("true")
+        });
+
+        Ok(ExternalIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified,
+            preferred_username: claims.preferred_username,
+            profile,
+        })
+    }
+}
@@ -0,0 +1,566 @@
+//! The OIDC login flow against an in-process mock issuer that serves
+//! discovery, JWKS, token and userinfo endpoints, checks PKCE and can be told
+//! to hand out bad ID tokens.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Form, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{OAuthConfig, OAuthProviderConfig, OAuthProviderKind},
+    db::{
+        self,
+        models::{CreateUser, User},
+        queries, DbPool,
+    },
+    handlers::ApiError,
+};
+
+use super::{begin_authorization, complete_authorization, Callback, OidcProvider};
+
+const PROVIDER: &str = "mock";
+const CLIENT_ID: &str = "navicore-test";
+const CLIENT_SECRET: &str = "test-secret";
+const KEY_ID: &str = "test-key";
+/// Private half of the issuer's signing key, only ever used here.
+const SIGNING_KEY: &str = include_str!("testdata/issuer_key.pem");
+/// Modulus of [`SIGNING_KEY`], as published in the JWKS.
+const SIGNING_KEY_N: &str = "s5Ui6aBzmsuehI2kbcrs6xtSjpCa9o94KnBYYp4_JyB8byk8FBESrNzlt8BKcpule6I4Doop6H3ci5vUb1IRfpZgHpbe8x75qgueTwLMY0mAuLh7MbTPqKNd0QsVV7w66MKfaOekc64GakE8J-sjGGtlQ-WtRok3Scj6i_PI-WMkdEGP4y5igx-SJmXxVKLVdVGzsMO0sLeOTZjFHIHWykidDMCDydcZ6VY0cU6pSrCSFMK8vBmZUDujVS48OGHrudi3oBPLTEo7tdciDs0cHu6PVVwn0rVDLqnkTO7cv6ZQ1xXTdScZzzHRAab2t2EDFtRsBXvNoDxbHcFqyMWgVw";
+
+/// The account the issuer vouches for on the next login.
+#[derive(Debug, Clone)]
+struct Profile {
+    subject: String,
+    /// Put in the ID token.
+    email: Option<String>,
+    email_verified: bool,
+    /// Only returned by the userinfo endpoint.
+    userinfo_email: Option<String>,
+}
+
+impl Profile {
+    fn new(subject: &str, email: &str) -> Self {
+        Self {
+            subject: subject.to_string(),
+            email: Some(email.to_string()),
+            email_verified: true,
+            userinfo_email: None,
+        }
+    }
+}
+
+/// How the issuer spoils the ID token it hands out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tamper {
+    None,
+    Nonce,
+    Audience,
+    Issuer,
+    /// HS256 with the client secret, which the client could forge itself.
+    Hs256,
+    /// `alg: none` and no signature.
+    Unsigned,
+}
+
+struct Grant {
+    challenge: String,
+    nonce: String,
+    redirect_uri: String,
+    profile: Profile,
+}
+
+struct Issuer {
+    url: String,
+    profile: Mutex<Profile>,
+    tamper: Mutex<Tamper>,
+    grants: Mutex<HashMap<String, Grant>>,
+    /// Profiles by access token, for userinfo.
+    sessions: Mutex<HashMap<String, Profile>>,
+}
+
+async fn start_issuer() -> Arc<Issuer> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let issuer = Arc::new(Issuer {
+        url: format!("http://{}", listener.local_addr().expect("address")),
+        profile: Mutex::new(Profile::new("subject-1", "alice@example.com")),
+        tamper: Mutex::new(Tamper::None),
+        grants: Mutex::default(),
+        sessions: Mutex::default(),
+    });
+
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/jwks", get(jwks))
+        .route("/userinfo", get(userinfo))
+        .with_state(Arc::clone(&issuer));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    issuer
+}
+
+async fn discovery(State(issuer): State<Arc<Issuer>>) -> Json<serde_json::Value> {
+    let url = &issuer.url;
+    Json(json!({
+        "issuer": url,
+        "authorization_endpoint": format!("{url}/authorize"),
+        "token_endpoint": format!("{url}/token"),
+        "jwks_uri": format!("{url}/jwks"),
+        "userinfo_endpoint": format!("{url}/userinfo"),
+    }))
+}
+
+/// Approves every request straight away for the current profile.
+async fn authorize(
+    State(issuer): State<Arc<Issuer>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+    if param("response_type") != "code"
+        || param("client_id") != CLIENT_ID
+        || param("code_challenge_method") != "S256"
+    {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let code = uuid::Uuid::new_v4().to_string();
+    let redirect_uri = param("redirect_uri");
+    let location = reqwest::Url::parse_with_params(
+        &redirect_uri,
+        &[("code", code.as_str()), ("state", &param("state"))],
+    )
+    .expect("redirect URI");
+    let grant = Grant {
+        challenge: param("code_challenge"),
+        nonce: param("nonce"),
+        redirect_uri,
+        profile: issuer.profile.lock().unwrap().clone(),
+    };
+    issuer.grants.lock().unwrap().insert(code, grant);
+
+    Redirect::to(location.as_str()).into_response()
+}
+
+async fn token(
+    State(issuer): State<Arc<Issuer>>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+    let invalid = |description: &str| {
+        let body = json!({ "error": "invalid_grant", "error_description": description });
+        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+    };
+
+    let Some(grant) = issuer.grants.lock().unwrap().remove(field("code")) else {
+        return invalid("unknown code");
+    };
+    if field("client_id") != CLIENT_ID || field("client_secret") != CLIENT_SECRET {
+        return invalid("bad client credentials");
+    }
+    if field("redirect_uri") != grant.redirect_uri {
+        return invalid("redirect_uri differs from the authorization request");
+    }
+    if URL_SAFE_NO_PAD.encode(Sha256::digest(field("code_verifier"))) != grant.challenge {
+        return invalid("PKCE verification failed");
+    }
+
+    let tamper = *issuer.tamper.lock().unwrap();
+    let profile = grant.profile;
+    let now = Utc::now().timestamp();
+    let mut claims = json!({
+        "iss": if tamper == Tamper::Issuer { "https://evil.example" } else { issuer.url.as_str() },
+        "aud": if tamper == Tamper::Audience { "someone-else" } else { CLIENT_ID },
+        "sub": profile.subject,
+        "nonce": if tamper == Tamper::Nonce { "another-nonce" } else { grant.nonce.as_str() },
+        "iat": now,
+        "exp": now + 300,
+        "email_verified": profile.email_verified,
+    });
+    if let Some(email) = &profile.email {
+        claims["email"] = json!(email);
+    }
+    let id_token = match tamper {
+        Tamper::Hs256 => {
+            let key = EncodingKey::from_secret(CLIENT_SECRET.as_bytes());
+            jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &key)
+                .expect("HS256 token")
+        }
+        Tamper::Unsigned => {
+            let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+            format!("{header}.{}.", URL_SAFE_NO_PAD.encode(claims.to_string()))
+        }
+        Tamper::None | Tamper::Nonce | Tamper::Audience | Tamper::Issuer => {
+            let key = EncodingKey::from_rsa_pem(SIGNING_KEY.as_bytes()).expect("signing key");
+            let header = Header {
+                kid: Some(KEY_ID.to_string()),
+                ..Header::new(Algorithm::RS256)
+            };
+            jsonwebtoken::encode(&header, &claims, &key).expect("RS256 token")
+        }
+    };
+
+    let access_token = uuid::Uuid::new_v4().to_string();
+    issuer
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(access_token.clone(), profile);
+
+    Json(json!({ "access_token": access_token, "token_type": "Bearer", "id_token": id_token }))
+        .into_response()
+}
+
+async fn jwks() -> Json<serde_json::Value> {
+    Json(json!({
+        "keys": [{ "kty": "RSA", "use": "sig", "alg": "RS256", "kid": KEY_ID, "n": SIGNING_KEY_N, "e": "AQAB" }]
+    }))
+}
+
+async fn userinfo(State(issuer): State<Arc<Issuer>>, headers: HeaderMap) -> Response {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    let Some(profile) = issuer.sessions.lock().unwrap().get(token).cloned() else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    Json(json!({
+        "sub": profile.subject,
+        "email": profile.userinfo_email,
+        "email_verified": true,
+    }))
+    .into_response()
+}
+
+/// The server side of the flow, talking to a fresh issuer.
+struct Harness {
+    db: DbPool,
+    config: OAuthConfig,
+    provider: OidcProvider,
+    issuer: Arc<Issuer>,
+    /// Plays the browser, which must not follow the issuer's redirect.
+    browser: reqwest::Client,
+}
+
+/// A flow the issuer approved, as the callback receives it.
+struct Approved {
+    state: String,
+    code: String,
+    browser_token: String,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let issuer = start_issuer().await;
+        let provider_config = OAuthProviderConfig {
+            name: PROVIDER.to_string(),
+            kind: OAuthProviderKind::Oidc,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            issuer_url: issuer.url.clone(),
+            api_url: None,
+            scopes: vec!["openid".to_string(), "email".to_string()],
+            id_token_algorithms: vec![Algorithm::RS256, Algorithm::ES256],
+        };
+
+        Self {
+            db: db::test_pool().await,
+            config: OAuthConfig {
+                redirect_base_url: "http://navicore.test".to_string(),
+                frontend_url: None,
+                providers: vec![provider_config.clone()],
+            },
+            provider: OidcProvider::new(provider_config, reqwest::Client::new()),
+            issuer,
+            browser: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("client"),
+        }
+    }
+
+    fn sign_in_as(&self, profile: Profile) {
+        *self.issuer.profile.lock().unwrap() = profile;
+    }
+
+    fn tamper(&self, tamper: Tamper) {
+        *self.issuer.tamper.lock().unwrap() = tamper;
+    }
+
+    /// Starts a flow and has the issuer approve it.
+    async fn approve(&self, link_user_id: Option<String>) -> Approved {
+        let started = begin_authorization(
+            &self.db,
+            &self.config,
+            PROVIDER,
+            &self.provider,
+            "/library".to_string(),
+            link_user_id,
+        )
+        .await
+        .expect("authorization starts");
+
+        let response = self
+            .browser
+            .get(&started.url)
+            .send()
+            .await
+            .expect("issuer reachable");
+        assert_eq!(
+            response.status(),
+            StatusCode::SEE_OTHER,
+            "issuer refused {}",
+            started.url
+        );
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .expect("location");
+        let callback = reqwest::Url::parse(location).expect("callback URL");
+        assert_eq!(callback.path(), "/api/v1/auth/oauth/mock/callback");
+        let param = |name: &str| {
+            callback
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .expect("callback parameter")
+        };
+
+        Approved {
+            state: param("state"),
+            code: param("code"),
+            browser_token: started.browser_token,
+        }
+    }
+
+    async fn complete(&self, approved: &Approved) -> Result<(User, String), ApiError> {
+        let callback = Callback {
+            state: &approved.state,
+            code: &approved.code,
+            browser_token: &approved.browser_token,
+        };
+        complete_authorization(
+            &self.db,
+            &self.config,
+            false,
+            PROVIDER,
+            &self.provider,
+            &callback,
+        )
+        .await
+    }
+
+    async fn login(&self) -> Result<(User, String), ApiError> {
+        let approved = self.approve(None).await;
+        self.complete(&approved).await
+    }
+
+    async fn local_user(&self, username: &str, email: &str, email_verified: bool) -> User {
+        let mut user = CreateUser {
+            username: username.to_string(),
+            email: email.to_string(),
+            password_hash: "not-a-real-hash".to_string(),
+        }
+        .into_user();
+        user.email_verified = email_verified;
+        queries::create_user(&self.db, user, false)
+            .await
+            .expect("user")
+    }
+
+    async fn linked_providers(&self, user_id: &str) -> Vec<String> {
+        queries::get_oauth_identities_for_user(&self.db, user_id)
+            .await
+            .expect("identities")
+            .into_iter()
+            .map(|identity| identity.provider)
+            .collect()
+    }
+}
+
+fn status(err: ApiError) -> StatusCode {
+    err.into_response().status()
+}
+
+#[tokio::test]
+async fn creates_an_account_for_a_new_identity() {
+    let harness = Harness::new().await;
+
+    let (user, redirect_to) = harness.login().await.expect("login succeeds");
+    assert_eq!(redirect_to, "/library");
+    assert_eq!(user.email, "alice@example.com");
+    assert_eq!(user.username, "alice");
+    assert!(user.email_verified);
+    assert!(user.password_hash.is_empty());
+    assert_eq!(harness.linked_providers(&user.id).await, [PROVIDER]);
+
+    // The same identity logs in to the same account.
+    let (again, _) = harness.login().await.expect("second login succeeds");
+    assert_eq!(again.id, user.id);
+}
+
+#[tokio::test]
+async fn reads_the_email_from_userinfo_when_the_id_token_has_none() {
+    let harness = Harness::new().await;
+    harness.sign_in_as(Profile {
+        email: None,
+        userinfo_email: Some("bob@example.com".to_string()),
+        ..Profile::new("subject-2", "")
+    });
+
+    let (user, _) = harness.login().await.expect("login succeeds");
+    assert_eq!(user.email, "bob@example.com");
+}
+
+#[tokio::test]
+async fn sends_the_pkce_verifier_of_the_challenge() {
+    let harness = Harness::new().await;
+
+    let approved = harness.approve(None).await;
+    sqlx::query("UPDATE oauth_states SET pkce_verifier = 'not-the-verifier' WHERE state = ?")
+        .bind(&approved.state)
+        .execute(&harness.db)
+        .await
+        .expect("update");
+
+    let err = harness
+        .complete(&approved)
+        .await
+        .expect_err("the issuer refuses the code");
+    assert_eq!(status(err), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn rejects_unknown_reused_and_foreign_states() {
+    let harness = Harness::new().await;
+
+    let approved = harness.approve(None).await;
+    let unknown = Approved {
+        state: "not-a-state".to_string(),
+        ..harness.approve(None).await
+    };
+    assert_eq!(
+        status(harness.complete(&unknown).await.expect_err("unknown state")),
+        StatusCode::BAD_REQUEST
+    );
+
+    // A callback URL opened in a browser that didn't start the flow.
+    let other_browser = Approved {
+        browser_token: "someone-elses-cookie".to_string(),
+        ..approved
+    };
+    assert_eq!(
+        status(
+            harness
+                .complete(&other_browser)
+                .await
+                .expect_err("other browser")
+        ),
+        StatusCode::BAD_REQUEST
+    );
+
+    // The state was used up by that attempt.
+    let approved = Approved {
+        browser_token: harness.approve(None).await.browser_token,
+        ..other_browser
+    };
+    assert_eq!(
+        status(harness.complete(&approved).await.expect_err("reused state")),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn rejects_a_nonce_from_another_request() {
+    let harness = Harness::new().await;
+    harness.tamper(Tamper::Nonce);
+
+    let err = harness.login().await.expect_err("nonce mismatch");
+    assert_eq!(status(err), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn rejects_bad_id_tokens() {
+    for tamper in [
+        Tamper::Hs256,
+        Tamper::Unsigned,
+        Tamper::Audience,
+        Tamper::Issuer,
+    ] {
+        let harness = Harness::new().await;
+        harness.tamper(tamper);
+
+        let err = harness.login().await.expect_err("bad token");
+        assert_eq!(status(err), StatusCode::UNAUTHORIZED, "{tamper:?}");
+        let created = queries::get_user_by_email(&harness.db, "alice@example.com")
+            .await
+            .expect("query");
+        assert!(created.is_none(), "{tamper:?} created an account");
+    }
+}
+
+#[tokio::test]
+async fn links_the_identity_to_the_account_that_asked() {
+    let harness = Harness::new().await;
+    let carol = harness
+        .local_user("carol", "carol@example.com", false)
+        .await;
+
+    let approved = harness.approve(Some(carol.id.clone())).await;
+    let (user, _) = harness.complete(&approved).await.expect("link succeeds");
+    assert_eq!(user.id, carol.id);
+    assert_eq!(harness.linked_providers(&carol.id).await, [PROVIDER]);
+
+    // Logging in with the identity now reaches carol.
+    let (user, _) = harness.login().await.expect("login succeeds");
+    assert_eq!(user.id, carol.id);
+
+    // Nobody else can claim it.
+    let dave = harness.local_user("dave", "dave@example.com", true).await;
+    let approved = harness.approve(Some(dave.id.clone())).await;
+    assert_eq!(
+        status(
+            harness
+                .complete(&approved)
+                .await
+                .expect_err("already linked")
+        ),
+        StatusCode::CONFLICT
+    );
+}
+
+#[tokio::test]
+async fn links_by_email_only_when_verified_on_both_sides() {
+    let harness = Harness::new().await;
+    let alice = harness.local_user("alice", "alice@example.com", true).await;
+    harness.sign_in_as(Profile::new("subject-1", "Alice@Example.com"));
+
+    let (user, _) = harness.login().await.expect("login succeeds");
+    assert_eq!(user.id, alice.id);
+
+    // An address anyone could have registered is not enough.
+    let harness = Harness::new().await;
+    harness
+        .local_user("squatter", "alice@example.com", false)
+        .await;
+    let err = harness.login().await.expect_err("unverified local email");
+    assert_eq!(status(err), StatusCode::CONFLICT);
+}
@@ -0,0 +1,70 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use std::sync::LazyLock;
+
+/// Hash checked for logins that have no password to check, made with the
+/// same parameters as real ones so it takes as long.
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("navicore-dummy-password").unwrap_or_default());
+
+/// Hashes `password` into an argon2id PHC string with a fresh random salt.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {e}"))?;
+
+    Ok(hash.to_string())
+}
+
+/// Checks `password` against an argon2 PHC string.
+///
+/// Hashes that cannot be parsed (for example the SHA-256 digests written by the
+/// old worker scripts) never verify. Accounts created through an external
+/// provider store an empty hash and have no password.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    if password_hash.is_empty() {
+        verify_dummy(password);
+        return false;
+    }
+
+    let Ok(parsed) = PasswordHash::new(password_hash) else {
+        tracing::warn!("Stored password hash is not a valid argon2 PHC string");
+        return false;
+    };
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok()
+}
+
+/// Takes as long as checking a real password and always fails, so the
+/// response time of a login doesn't tell whether the account exists.
+pub fn verify_dummy(password: &str) {
+    if let Ok(parsed) = PasswordHash::new(&DUMMY_HASH) {
+        let _ = Argon2::default().verify_password(password.as_bytes(), &parsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_only_the_hashed_password() {
+        let hash = hash_password("correct horse").expect("hash");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("", ""));
+        assert!(!verify_password("correct horse", "5e884898da28047151d0e56f8dc6292773603d0d"));
+    }
+
+    #[test]
+    fn dummy_hash_is_a_real_argon2_hash() {
+        let parsed = PasswordHash::new(&DUMMY_HASH).expect("dummy hash parses");
+        assert_eq!(parsed.algorithm, argon2::Algorithm::Argon2id.ident());
+    }
+}
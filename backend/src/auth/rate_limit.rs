@@ -0,0 +1,364 @@
+use chrono::{DateTime, Duration, Utc};
+use std::time::Duration as StdDuration;
+use tracing::{info, warn};
+
+use crate::{
+    config::RateLimitConfig,
+    db::{queries, DbPool},
+    handlers::ApiError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthAction {
+    Login,
+    Register,
+}
+
+impl AuthAction {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::Register => "register",
+        }
+    }
+}
+
+/// Attempts are matched on a trimmed, lowercase login name.
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Rejects a login when the IP is over its sliding window or the account is
+/// locked after repeated failures.
+///
+/// The lockout is keyed on the account `user_id` the login resolved to, so
+/// typing its username or its email counts the same. A `login` that matches
+/// no account is locked the same way by name, so a lockout doesn't tell
+/// whether the account exists.
+pub async fn check_login(
+    db: &DbPool,
+    config: &RateLimitConfig,
+    ip_address: Option<&str>,
+    login: &str,
+    user_id: Option<&str>,
+) -> Result<(), ApiError> {
+    let now = Utc::now();
+    let window_start = now - Duration::seconds(config.window_secs);
+
+    check_ip(
+        db,
+        config,
+        AuthAction::Login,
+        ip_address,
+        config.login_max_per_ip,
+        now,
+    )
+    .await?;
+
+    let (failures, last_failure) =
+        queries::recent_login_failures(db, user_id, &normalize_username(login), window_start)
+            .await?;
+
+    if let Some(last_failure) = last_failure.filter(|_| failures >= config.login_max_failures) {
+        let locked_until = last_failure + Duration::seconds(config.lockout_secs);
+        if locked_until > now {
+            return Err(ApiError::too_many_requests(
+                "Too many failed logins for this account, try again later",
+                seconds_until(locked_until, now),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Rejects a registration when the IP is over its sliding window.
+pub async fn check_register(
+    db: &DbPool,
+    config: &RateLimitConfig,
+    ip_address: Option<&str>,
+) -> Result<(), ApiError> {
+    check_ip(
+        db,
+        config,
+        AuthAction::Register,
+        ip_address,
+        config.register_max_per_ip,
+        Utc::now(),
+    )
+    .await
+}
+
+/// Records an attempt with the name typed and, for logins to an existing
+/// account, its `user_id`.
+pub async fn record_attempt(
+    db: &DbPool,
+    action: AuthAction,
+    username: Option<&str>,
+    user_id: Option<&str>,
+    ip_address: Option<&str>,
+    success: bool,
+) -> anyhow::Result<()> {
+    let username = username.map(normalize_username);
+    queries::record_auth_attempt(
+        db,
+        action.as_str(),
+        username.as_deref(),
+        user_id,
+        ip_address,
+        success,
+        Utc::now(),
+    )
+    .await
+}
+
+/// Periodically deletes attempts too old to affect any limit.
+pub fn spawn_cleanup_task(db: DbPool, config: &RateLimitConfig, interval_secs: u64) {
+    let retention = Duration::seconds(config.window_secs.max(config.lockout_secs));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(StdDuration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let result = queries::delete_auth_attempts_before(&db, Utc::now() - retention).await;
+            match result {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {removed} old auth attempts"),
+                Err(err) => warn!("Auth attempt cleanup failed: {err:#}"),
+            }
+        }
+    });
+}
+
+async fn check_ip(
+    db: &DbPool,
+    config: &RateLimitConfig,
+    action: AuthAction,
+    ip_address: Option<&str>,
+    max_attempts: i64,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let Some(ip_address) = ip_address else {
+        return Ok(());
+    };
+    let window = Duration::seconds(config.window_secs);
+
+    // Once the max-th most recent attempt leaves the window there is room again.
+    let oldest_counted = queries::nth_recent_attempt_from_ip(
+        db,
+        action.as_str(),
+        ip_address,
+        now - window,
+        max_attempts,
+    )
+    .await?;
+
+    oldest_counted.map_or(Ok(()), |attempted_at| {
+        Err(ApiError::too_many_requests(
+            format!(
+                "Too many {} attempts from this address, try again later",
+                action.as_str()
+            ),
+            seconds_until(attempted_at + window, now),
+        ))
+    })
+}
+
+fn seconds_until(until: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+    // Round up so clients never retry a moment too early.
+    let millis = (until - now).num_milliseconds();
+    (millis + 999) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::*;
+    use crate::db::{
+        self,
+        models::{CreateUser, User},
+    };
+
+    const IP: Option<&str> = Some("192.0.2.1");
+
+    fn limits() -> RateLimitConfig {
+        RateLimitConfig {
+            window_secs: 900,
+            login_max_per_ip: 100,
+            login_max_failures: 3,
+            lockout_secs: 600,
+            register_max_per_ip: 5,
+        }
+    }
+
+    async fn alice(db: &DbPool) -> User {
+        let user = CreateUser {
+            username: "Alice".to_string(),
+            email: "alice@example.com".to_string(),
+            password_hash: String::new(),
+        }
+        .into_user();
+        queries::create_user(db, user, false).await.expect("user")
+    }
+
+    async fn fail(db: &DbPool, login: &str, user: Option<&User>, at: DateTime<Utc>) {
+        let user_id = user.map(|user| user.id.as_str());
+        queries::record_auth_attempt(db, "login", Some(login), user_id, IP, false, at)
+            .await
+            .expect("recorded");
+    }
+
+    async fn check(
+        db: &DbPool,
+        config: &RateLimitConfig,
+        login: &str,
+        user: Option<&User>,
+    ) -> Result<(), ApiError> {
+        check_login(db, config, IP, login, user.map(|user| user.id.as_str())).await
+    }
+
+    fn status(result: Result<(), ApiError>) -> StatusCode {
+        result
+            .err()
+            .map_or(StatusCode::OK, |err| err.into_response().status())
+    }
+
+    #[tokio::test]
+    async fn locks_the_account_whichever_name_is_typed() {
+        let db = db::test_pool().await;
+        let user = alice(&db).await;
+        let now = Utc::now();
+        fail(&db, "alice", Some(&user), now).await;
+        fail(&db, "alice@example.com", Some(&user), now).await;
+        assert_eq!(
+            status(check(&db, &limits(), "ALICE", Some(&user)).await),
+            StatusCode::OK
+        );
+
+        fail(&db, "Alice", Some(&user), now).await;
+        for login in ["alice", "alice@example.com"] {
+            assert_eq!(
+                status(check(&db, &limits(), login, Some(&user)).await),
+                StatusCode::TOO_MANY_REQUESTS,
+                "{login}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn locks_unknown_names_like_accounts() {
+        let db = db::test_pool().await;
+        let now = Utc::now();
+        for _ in 0..3 {
+            fail(&db, "nobody", None, now).await;
+        }
+        assert_eq!(
+            status(check(&db, &limits(), "Nobody", None).await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(check(&db, &limits(), "somebody", None).await),
+            StatusCode::OK
+        );
+
+        // An account with that name has its own count.
+        let user = alice(&db).await;
+        for _ in 0..3 {
+            fail(&db, "alice", None, now).await;
+        }
+        assert_eq!(
+            status(check(&db, &limits(), "alice", Some(&user)).await),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_failures_outside_the_window_and_after_the_lockout() {
+        let db = db::test_pool().await;
+        let user = alice(&db).await;
+        let now = Utc::now();
+        let config = limits();
+
+        // Spread over more than the window, so never three inside it.
+        for minutes in [40, 20, 1] {
+            fail(&db, "alice", Some(&user), now - Duration::minutes(minutes)).await;
+        }
+        assert_eq!(
+            status(check(&db, &config, "alice", Some(&user)).await),
+            StatusCode::OK
+        );
+
+        // Three inside the window, but the lockout after the last has passed.
+        let db = db::test_pool().await;
+        let user = alice(&db).await;
+        for minutes in [14, 13, 12] {
+            fail(&db, "alice", Some(&user), now - Duration::minutes(minutes)).await;
+        }
+        assert_eq!(
+            status(check(&db, &config, "alice", Some(&user)).await),
+            StatusCode::OK
+        );
+        let longer = RateLimitConfig {
+            lockout_secs: 800,
+            ..limits()
+        };
+        assert_eq!(
+            status(check(&db, &longer, "alice", Some(&user)).await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn successful_login_lifts_the_lockout() {
+        let db = db::test_pool().await;
+        let user = alice(&db).await;
+        let now = Utc::now() - Duration::seconds(5);
+        for _ in 0..3 {
+            fail(&db, "alice", Some(&user), now).await;
+        }
+        assert_eq!(
+            status(check(&db, &limits(), "alice", Some(&user)).await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // E.g. a password reset, which counts as a successful login.
+        record_attempt(
+            &db,
+            AuthAction::Login,
+            Some("alice"),
+            Some(&user.id),
+            IP,
+            true,
+        )
+        .await
+        .expect("recorded");
+        assert_eq!(
+            status(check(&db, &limits(), "alice", Some(&user)).await),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_attempts_per_ip() {
+        let db = db::test_pool().await;
+        let config = RateLimitConfig {
+            login_max_per_ip: 4,
+            ..limits()
+        };
+        let now = Utc::now();
+        for login in ["a", "b", "c", "d"] {
+            fail(&db, login, None, now).await;
+        }
+        let response = check(&db, &config, "e", None)
+            .await
+            .expect_err("over the IP limit")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+        assert_eq!(
+            status(check_login(&db, &config, Some("192.0.2.2"), "e", None).await),
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use axum::http::HeaderValue;
+use chrono::{Duration, Utc};
+use std::time::Duration as StdDuration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::AuthConfig,
+    db::{models::Session, queries, DbPool},
+};
+
+use super::{
+    client::ClientInfo,
+    extractor::AUTH_COOKIE,
+    token::{self, IssuedToken},
+};
+
+/// `last_seen_at` is only rewritten when it is older than this.
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+/// Creates a session for `user_id` and the JWT that refers to it.
+pub async fn start_session(
+    db: &DbPool,
+    config: &AuthConfig,
+    user_id: &str,
+    client: &ClientInfo,
+) -> anyhow::Result<(Session, IssuedToken)> {
+    let now = Utc::now();
+    let lifetime = Duration::try_hours(config.jwt_expiry_hours)
+        .ok_or_else(|| anyhow::anyhow!("Invalid JWT expiry: {} hours", config.jwt_expiry_hours))?;
+
+    let session = queries::create_session(
+        db,
+        Session {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            ip_address: client.ip_address.clone(),
+            user_agent: client.user_agent.clone(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + lifetime,
+            revoked_at: None,
+        },
+    )
+    .await?;
+
+    let issued = token::issue_token(config, user_id, &session.id, session.expires_at)?;
+
+    Ok((session, issued))
+}
+
+/// Returns the session if it belongs to `user_id` and is still active.
+pub async fn validate_session(
+    db: &DbPool,
+    session_id: &str,
+    user_id: &str,
+) -> anyhow::Result<Option<Session>> {
+    let now = Utc::now();
+    let Some(session) = queries::get_session_by_id(db, session_id).await? else {
+        return Ok(None);
+    };
+
+    if session.user_id != user_id || !session.is_active(now) {
+        return Ok(None);
+    }
+
+    queries::touch_session(
+        db,
+        &session.id,
+        now,
+        now - Duration::seconds(TOUCH_INTERVAL_SECONDS),
+    )
+    .await?;
+
+    Ok(Some(session))
+}
+
+/// `Set-Cookie` value carrying `token` as the `auth_token` cookie.
+pub fn session_cookie(
+    config: &AuthConfig,
+    token: &str,
+    max_age: i64,
+) -> anyhow::Result<HeaderValue> {
+    let mut cookie = format!("{AUTH_COOKIE}={token}; Path=/; HttpOnly; Max-Age={max_age}");
+    if config.cookie_secure {
+        cookie.push_str("; Secure; SameSite=None");
+    } else {
+        cookie.push_str("; SameSite=Lax");
+    }
+    if let Some(domain) = &config.cookie_domain {
+        cookie.push_str("; Domain=");
+        cookie.push_str(domain);
+    }
+
+    Ok(HeaderValue::from_str(&cookie)?)
+}
+
+/// `Set-Cookie` value that removes the `auth_token` cookie.
+pub fn clear_session_cookie(config: &AuthConfig) -> anyhow::Result<HeaderValue> {
+    session_cookie(config, "", 0)
+}
+
+/// Periodically deletes expired and revoked sessions, and expired email tokens.
+pub fn spawn_cleanup_task(db: DbPool, interval_secs: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(StdDuration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let result = queries::delete_stale_sessions(&db, Utc::now()).await;
+            match result {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {removed} expired or revoked sessions"),
+                Err(err) => warn!("Session cleanup failed: {err:#}"),
+            }
+
+            let result = queries::delete_stale_email_tokens(&db, Utc::now()).await;
+            match result {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {removed} expired email tokens"),
+                Err(err) => warn!("Email token cleanup failed: {err:#}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        extract::FromRequestParts,
+        http::{header, Request, StatusCode},
+        response::IntoResponse,
+    };
+
+    use super::*;
+    use crate::{auth::AuthUser, db::models::CreateUser, storage::mock::MockBucket, AppState};
+
+    async fn user(db: &DbPool, username: &str) -> String {
+        queries::create_user(
+            db,
+            CreateUser {
+                username: username.to_string(),
+                email: format!("{username}@example.com"),
+                password_hash: String::new(),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user")
+        .id
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            ip_address: None,
+            user_agent: Some("tests".to_string()),
+        }
+    }
+
+    /// Status of a request made with `token`, or the user it authenticated.
+    async fn authenticate(state: &Arc<AppState>, token: &str) -> Result<String, StatusCode> {
+        let (mut parts, ()) = Request::builder()
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(())
+            .expect("request")
+            .into_parts();
+        let user = AuthUser::from_request_parts(&mut parts, state).await;
+        user.map(|user| user.user_id)
+            .map_err(|err| err.into_response().status())
+    }
+
+    #[tokio::test]
+    async fn tokens_stop_working_once_their_session_is_revoked() {
+        let db = crate::db::test_pool().await;
+        let storage = MockBucket::default().serve().await;
+        let state = Arc::new(AppState::for_tests(db.clone(), storage));
+        let user_id = user(&db, "listener").await;
+        let (kept, kept_token) = start_session(&db, &state.config.auth, &user_id, &client())
+            .await
+            .expect("session");
+        let (revoked, revoked_token) = start_session(&db, &state.config.auth, &user_id, &client())
+            .await
+            .expect("session");
+
+        assert_eq!(
+            authenticate(&state, &revoked_token.token).await,
+            Ok(user_id.clone())
+        );
+        assert!(
+            queries::revoke_session(&db, &revoked.id, &user_id, Utc::now())
+                .await
+                .expect("revoke")
+        );
+        // The JWT itself is still valid, only the session behind it ended.
+        assert!(token::decode_token(&state.config.auth, &revoked_token.token).is_ok());
+        assert_eq!(
+            authenticate(&state, &revoked_token.token).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            authenticate(&state, &kept_token.token).await,
+            Ok(user_id.clone())
+        );
+
+        queries::revoke_all_sessions_for_user(&db, &user_id, Utc::now())
+            .await
+            .expect("revoke all");
+        assert!(validate_session(&db, &kept.id, &user_id)
+            .await
+            .expect("validate")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn sessions_belong_to_one_user_and_expire() {
+        let db = crate::db::test_pool().await;
+        let config = crate::config::Config::for_tests().auth;
+        let user_id = user(&db, "listener").await;
+        let other_id = user(&db, "other").await;
+        let (session, _) = start_session(&db, &config, &user_id, &client())
+            .await
+            .expect("session");
+
+        assert!(validate_session(&db, &session.id, &user_id)
+            .await
+            .expect("validate")
+            .is_some());
+        // A token naming someone else's session doesn't log in as either.
+        assert!(validate_session(&db, &session.id, &other_id)
+            .await
+            .expect("validate")
+            .is_none());
+
+        sqlx::query(r"UPDATE sessions SET expires_at = ? WHERE id = ?")
+            .bind(Utc::now() - Duration::seconds(1))
+            .bind(&session.id)
+            .execute(&db)
+            .await
+            .expect("expire");
+        assert!(validate_session(&db, &session.id, &user_id)
+            .await
+            .expect("validate")
+            .is_none());
+        assert_eq!(
+            queries::delete_stale_sessions(&db, Utc::now())
+                .await
+                .expect("cleanup"),
+            1
+        );
+    }
+}
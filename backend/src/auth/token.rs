@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuthConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    /// Server-side session backing this token.
+    pub sid: String,
+    pub exp: i64,
+    pub iat: i64,
+}
+
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_in: i64,
+}
+
+pub fn issue_token(
+    config: &AuthConfig,
+    user_id: &str,
+    session_id: &str,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<IssuedToken> {
+    let now = Utc::now();
+
+    let claims = Claims {
+        sub: user_id.to_string(),
+        sid: session_id.to_string(),
+        exp: expires_at.timestamp(),
+        iat: now.timestamp(),
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )?;
+
+    Ok(IssuedToken {
+        token,
+        expires_in: (expires_at - now).num_seconds(),
+    })
+}
+
+/// Validates the signature and expiry of `token` and returns its claims.
+pub fn decode_token(config: &AuthConfig, token: &str) -> anyhow::Result<Claims> {
+    let data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+        &Validation::default(),
+    )?;
+
+    Ok(data.claims)
+}
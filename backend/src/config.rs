@@ -1,18 +1,60 @@
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{env, str::FromStr};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub storage: StorageConfig,
+    pub upload: UploadConfig,
     pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub oauth: OAuthConfig,
+    pub mail: MailConfig,
+    pub library: LibraryConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UploadConfig {
+    /// Where uploads are written while they are checked, before going to storage.
+    pub spool_dir: String,
+    pub max_file_bytes: u64,
+    pub max_zip_bytes: u64,
+    /// Album uploads processed at the same time.
+    pub workers: usize,
+    /// Hours a chunked upload stays open without receiving a part.
+    pub chunked_ttl_hours: i64,
+    /// Seconds between sweeps for expired chunked uploads and orphaned
+    /// multipart uploads in storage.
+    pub chunked_cleanup_interval_secs: u64,
+    /// Extra file name patterns, see [`crate::ingest::filename`].
+    pub filename_patterns: Vec<String>,
+}
+
+/// Scanning a directory of audio files into the catalog, see
+/// [`crate::ingest::library`].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LibraryConfig {
+    /// Directory to scan, scanning is off without one.
+    pub root: Option<String>,
+    /// Rescan when inotify reports changes under `root`.
+    pub watch: bool,
+    /// Seconds between full rescans, `0` for none. Needed for network mounts,
+    /// where inotify doesn't see changes made by other machines.
+    pub scan_interval_secs: u64,
+    /// Files modified this recently are left for a later scan, so a file
+    /// still being copied isn't ingested half written.
+    pub settle_secs: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Take the client IP from `CF-Connecting-IP`/`X-Forwarded-For`. Only
+    /// enable behind a proxy that overwrites those headers.
+    pub trust_proxy_headers: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -31,16 +73,279 @@ pub struct StorageConfig {
 pub struct AuthConfig {
     pub jwt_secret: String,
     pub jwt_expiry_hours: i64,
+    /// Makes the first account created through registration an admin.
+    pub first_user_is_admin: bool,
+    /// Admin account ensured at startup, for deployments that don't register.
+    pub seed_admin: Option<SeedAdminConfig>,
+    /// Admins can only use admin rights once they have enabled TOTP.
+    pub require_admin_2fa: bool,
+    /// Domain attribute for the `auth_token` cookie, e.g. `.navicore.tech`.
+    pub cookie_domain: Option<String>,
+    pub cookie_secure: bool,
+    pub session_cleanup_interval_secs: u64,
+}
+
+/// Brute-force protection for login and registration.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// Length of the sliding window, in seconds.
+    pub window_secs: i64,
+    /// Login attempts allowed from one IP per window.
+    pub login_max_per_ip: i64,
+    /// Failed logins to one account before it is locked.
+    pub login_max_failures: i64,
+    /// How long an account stays locked after its last failure, in seconds.
+    pub lockout_secs: i64,
+    /// Registrations allowed from one IP per window.
+    pub register_max_per_ip: i64,
+}
+
+/// External identity providers, see `OAUTH_PROVIDERS`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthConfig {
+    /// Public base URL of this backend, used to build callback URLs.
+    pub redirect_base_url: String,
+    /// Where the browser lands after logging in, prefixed to `redirect_to`.
+    pub frontend_url: Option<String>,
+    pub providers: Vec<OAuthProviderConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthProviderKind {
+    /// Any OIDC issuer that publishes a discovery document.
+    Oidc,
+    /// OIDC with Google's issuer as the default.
+    Google,
+    /// GitHub's OAuth apps, which predate OIDC.
+    Github,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthProviderConfig {
+    /// Name used in the `/auth/oauth/{provider}` routes and `user_oauth` rows.
+    pub name: String,
+    pub kind: OAuthProviderKind,
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    pub client_secret: String,
+    /// OIDC issuer, or the web host for GitHub.
+    pub issuer_url: String,
+    /// REST API base for providers that are not OIDC.
+    pub api_url: Option<String>,
+    pub scopes: Vec<String>,
+    /// Algorithms OIDC ID tokens may be signed with, whatever their header
+    /// says. HMAC ones use the client secret as the key, so they must be
+    /// listed explicitly.
+    pub id_token_algorithms: Vec<Algorithm>,
+}
+
+impl OAuthProviderConfig {
+    /// Reads `OAUTH_<NAME>_*` for one entry of `OAUTH_PROVIDERS`.
+    fn from_env(name: &str) -> anyhow::Result<Self> {
+        let var = |suffix: &str| env::var(format!("OAUTH_{}_{suffix}", name.to_uppercase()));
+
+        let kind = match var("KIND").unwrap_or_else(|_| name.to_string()).as_str() {
+            "google" => OAuthProviderKind::Google,
+            "github" => OAuthProviderKind::Github,
+            _ => OAuthProviderKind::Oidc,
+        };
+        let issuer_url = match (var("ISSUER_URL"), kind) {
+            (Ok(url), _) => url,
+            (Err(_), OAuthProviderKind::Google) => "https://accounts.google.com".to_string(),
+            (Err(_), OAuthProviderKind::Github) => "https://github.com".to_string(),
+            (Err(_), OAuthProviderKind::Oidc) => {
+                anyhow::bail!("OAUTH_{}_ISSUER_URL is required", name.to_uppercase())
+            }
+        };
+        let api_url = var("API_URL").ok().or_else(|| {
+            (kind == OAuthProviderKind::Github).then(|| "https://api.github.com".to_string())
+        });
+        let default_scopes = match kind {
+            OAuthProviderKind::Github => "read:user user:email",
+            OAuthProviderKind::Oidc | OAuthProviderKind::Google => "openid email profile",
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+            client_id: var("CLIENT_ID")
+                .map_err(|_| anyhow::anyhow!("OAUTH_{}_CLIENT_ID is required", name.to_uppercase()))?,
+            client_secret: var("CLIENT_SECRET").map_err(|_| {
+                anyhow::anyhow!("OAUTH_{}_CLIENT_SECRET is required", name.to_uppercase())
+            })?,
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            api_url: api_url.map(|url| url.trim_end_matches('/').to_string()),
+            scopes: var("SCOPES")
+                .unwrap_or_else(|_| default_scopes.to_string())
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            id_token_algorithms: var("ID_TOKEN_ALGORITHMS")
+                .unwrap_or_else(|_| "RS256 ES256".to_string())
+                .split_whitespace()
+                .map(|alg| {
+                    Algorithm::from_str(alg).map_err(|_| {
+                        anyhow::anyhow!("OAUTH_{}_ID_TOKEN_ALGORITHMS: unknown algorithm {alg}", name.to_uppercase())
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+/// Outgoing email, see `MAIL_TRANSPORT`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// `From` header, e.g. `Navicore Music <noreply@navicore.tech>`.
+    pub from: String,
+    /// Base URL of the web app, links in emails point at its pages.
+    pub app_url: String,
+    pub smtp: Option<SmtpConfig>,
+    /// Where the file transport writes messages.
+    pub file_dir: String,
+    pub verify_email_ttl_hours: i64,
+    pub password_reset_ttl_minutes: i64,
+}
+
+impl UploadConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            spool_dir: env::var("UPLOAD_SPOOL_DIR")
+                .unwrap_or_else(|_| "data/uploads".to_string()),
+            max_file_bytes: env::var("MAX_UPLOAD_FILE_BYTES")
+                .unwrap_or_else(|_| (500 * 1024 * 1024).to_string())
+                .parse()?,
+            max_zip_bytes: env::var("MAX_UPLOAD_ZIP_BYTES")
+                .unwrap_or_else(|_| (2u64 * 1024 * 1024 * 1024).to_string())
+                .parse()?,
+            workers: env::var("UPLOAD_WORKERS")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()?,
+            chunked_ttl_hours: env::var("CHUNKED_UPLOAD_TTL_HOURS")
+                .unwrap_or_else(|_| "24".to_string())
+                .parse()?,
+            chunked_cleanup_interval_secs: env::var("CHUNKED_UPLOAD_CLEANUP_INTERVAL_SECS")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse()?,
+            filename_patterns: env::var("FILENAME_PATTERNS")
+                .unwrap_or_default()
+                .split(';')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+impl LibraryConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            root: env::var("LIBRARY_DIR").ok().filter(|root| !root.trim().is_empty()),
+            watch: env::var("LIBRARY_WATCH")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
+            scan_interval_secs: env::var("LIBRARY_SCAN_INTERVAL_SECS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()?,
+            settle_secs: env::var("LIBRARY_SETTLE_SECS")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()?,
+        })
+    }
+}
+
+impl MailConfig {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            transport: match env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "log".to_string()).as_str() {
+                "smtp" => MailTransport::Smtp,
+                "file" => MailTransport::File,
+                "log" => MailTransport::Log,
+                other => anyhow::bail!("Unknown MAIL_TRANSPORT: {other}"),
+            },
+            from: env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "Navicore Music <noreply@localhost>".to_string()),
+            app_url: env::var("APP_URL")
+                .unwrap_or_else(|_| "http://localhost:8080".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            smtp: match env::var("SMTP_HOST") {
+                Ok(host) => Some(SmtpConfig {
+                    host,
+                    port: env::var("SMTP_PORT")
+                        .unwrap_or_else(|_| "587".to_string())
+                        .parse()?,
+                    username: env::var("SMTP_USERNAME").ok(),
+                    password: env::var("SMTP_PASSWORD").ok(),
+                    tls: env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string()),
+                }),
+                Err(_) => None,
+            },
+            file_dir: env::var("MAIL_DIR").unwrap_or_else(|_| "data/mail".to_string()),
+            verify_email_ttl_hours: env::var("VERIFY_EMAIL_TTL_HOURS")
+                .unwrap_or_else(|_| "48".to_string())
+                .parse()?,
+            password_reset_ttl_minutes: env::var("PASSWORD_RESET_TTL_MINUTES")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    /// Writes each message to `file_dir`, for development and tests.
+    File,
+    /// Only logs messages.
+    Log,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// `starttls`, `tls` or `none`.
+    pub tls: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SeedAdminConfig {
+    pub username: String,
+    pub email: String,
+    #[serde(skip_serializing)]
+    pub password: String,
 }
 
 impl Config {
     pub fn from_env() -> anyhow::Result<Self> {
-        Ok(Config {
+        Self::from_env_with_secret(jwt_secret()?)
+    }
+
+    /// The configuration for tests: the environment's, which is normally
+    /// empty so every default applies, with a fixed `JWT_SECRET`.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self::from_env_with_secret("test-jwt-secret".to_string()).expect("test configuration")
+    }
+
+    fn from_env_with_secret(jwt_secret: String) -> anyhow::Result<Self> {
+        Ok(Self {
             server: ServerConfig {
                 host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
                 port: env::var("SERVER_PORT")
                     .unwrap_or_else(|_| "3000".to_string())
                     .parse()?,
+                trust_proxy_headers: env::var("TRUST_PROXY_HEADERS")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
             },
             database: DatabaseConfig {
                 url: env::var("DATABASE_URL")
@@ -52,13 +357,86 @@ impl Config {
                 region: env::var("R2_REGION").unwrap_or_else(|_| "auto".to_string()),
                 endpoint_url: env::var("R2_ENDPOINT_URL").ok(),
             },
+            upload: UploadConfig::from_env()?,
             auth: AuthConfig {
-                jwt_secret: env::var("JWT_SECRET")
-                    .unwrap_or_else(|_| "dev-jwt-secret".to_string()),
+                jwt_secret,
                 jwt_expiry_hours: env::var("JWT_EXPIRY_HOURS")
                     .unwrap_or_else(|_| "24".to_string())
                     .parse()?,
+                first_user_is_admin: env::var("FIRST_USER_IS_ADMIN")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                seed_admin: match (
+                    env::var("ADMIN_USERNAME"),
+                    env::var("ADMIN_EMAIL"),
+                    env::var("ADMIN_PASSWORD"),
+                ) {
+                    (Ok(username), Ok(email), Ok(password)) => Some(SeedAdminConfig {
+                        username,
+                        email,
+                        password,
+                    }),
+                    _ => None,
+                },
+                require_admin_2fa: env::var("REQUIRE_ADMIN_2FA")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()?,
+                cookie_domain: env::var("COOKIE_DOMAIN").ok(),
+                cookie_secure: env::var("COOKIE_SECURE")
+                    .unwrap_or_else(|_| "true".to_string())
+                    .parse()?,
+                session_cleanup_interval_secs: env::var("SESSION_CLEANUP_INTERVAL_SECS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
+            },
+            rate_limit: RateLimitConfig {
+                window_secs: env::var("RATE_LIMIT_WINDOW_SECS")
+                    .unwrap_or_else(|_| "900".to_string())
+                    .parse()?,
+                login_max_per_ip: env::var("LOGIN_MAX_PER_IP")
+                    .unwrap_or_else(|_| "20".to_string())
+                    .parse()?,
+                login_max_failures: env::var("LOGIN_MAX_FAILURES")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse()?,
+                lockout_secs: env::var("LOGIN_LOCKOUT_SECS")
+                    .unwrap_or_else(|_| "900".to_string())
+                    .parse()?,
+                register_max_per_ip: env::var("REGISTER_MAX_PER_IP")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse()?,
+            },
+            mail: MailConfig::from_env()?,
+            library: LibraryConfig::from_env()?,
+            oauth: OAuthConfig {
+                redirect_base_url: env::var("OAUTH_REDIRECT_BASE_URL")
+                    .unwrap_or_else(|_| "http://localhost:3000".to_string())
+                    .trim_end_matches('/')
+                    .to_string(),
+                frontend_url: env::var("OAUTH_FRONTEND_URL")
+                    .ok()
+                    .map(|url| url.trim_end_matches('/').to_string()),
+                providers: env::var("OAUTH_PROVIDERS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(OAuthProviderConfig::from_env)
+                    .collect::<anyhow::Result<_>>()?,
             },
         })
     }
-}
\ No newline at end of file
+}
+
+/// `JWT_SECRET`, which anyone could forge tokens without, so it is only
+/// optional with `DEV_MODE=true`.
+fn jwt_secret() -> anyhow::Result<String> {
+    match env::var("JWT_SECRET") {
+        Ok(secret) if !secret.is_empty() => Ok(secret),
+        _ if env::var("DEV_MODE").is_ok_and(|dev| dev == "true") => {
+            tracing::warn!("JWT_SECRET is not set, using the development secret");
+            Ok("dev-jwt-secret".to_string())
+        }
+        _ => anyhow::bail!("JWT_SECRET is required, or set DEV_MODE=true to use a development secret"),
+    }
+}
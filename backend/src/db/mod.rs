@@ -8,6 +8,43 @@ pub type DbPool = Pool<Sqlite>;
 pub async fn create_pool(database_url: &str) -> anyhow::Result<DbPool> {
     let pool = SqlitePool::connect(database_url)
         .await?;
-    
+
     Ok(pool)
-}
\ No newline at end of file
+}
+
+/// Returns true when `err` wraps a `UNIQUE` constraint failure from the database.
+pub fn is_unique_violation(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<sqlx::Error>()
+        .and_then(sqlx::Error::as_database_error)
+        .is_some_and(sqlx::error::DatabaseError::is_unique_violation)
+}
+
+/// An in-memory database with the migrations applied, for tests.
+///
+/// `003_normalize_tags.sql` is skipped: it moves tags out of an `albums` table
+/// this tree's migrations never create.
+#[cfg(test)]
+pub async fn test_pool() -> DbPool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("in-memory database");
+
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../migrations");
+    let mut migrations: Vec<_> = std::fs::read_dir(dir)
+        .expect("migrations directory")
+        .map(|entry| entry.expect("migration").path())
+        .filter(|path| !path.ends_with("003_normalize_tags.sql"))
+        .collect();
+    migrations.sort();
+    for path in migrations {
+        let sql = std::fs::read_to_string(&path).expect("migration");
+        sqlx::raw_sql(&sql)
+            .execute(&pool)
+            .await
+            .unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+    }
+
+    pool
+}
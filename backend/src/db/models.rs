@@ -8,9 +8,20 @@ pub struct Track {
     pub id: String,
     pub title: String,
     pub artist: String,
+    /// Artist profile that controls who may change this track. Tracks without
+    /// one can only be changed by admins.
+    pub artist_id: Option<String>,
     pub album: String,
+    /// Album record the track was uploaded into, `album` keeps its title.
+    pub album_id: Option<String>,
     pub duration: i32,
     pub file_path: String,
+    /// Name of the uploaded file, e.g. `01-song.flac` from an album ZIP.
+    pub original_filename: Option<String>,
+    /// Format the file was uploaded in, e.g. `wav`.
+    pub original_format: Option<String>,
+    /// Format of the stored file, `flac` for a WAV or AIFF that was converted.
+    pub stored_format: Option<String>,
     pub cover_art_path: Option<String>,
     pub genre: Option<String>,
     pub year: Option<i32>,
@@ -19,9 +30,99 @@ pub struct Track {
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub normalized_name: String,
+    pub bio: Option<String>,
+    pub website: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Album {
+    pub id: String,
+    pub artist_id: String,
+    pub title: String,
+    #[serde(skip_serializing)]
+    pub normalized_title: String,
+    pub cover_art_path: Option<String>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    /// The version whose tracks are in the catalog.
+    pub current_version_id: Option<String>,
+    /// JSON, see `ingest::provenance`.
+    #[serde(skip_serializing)]
+    pub metadata_sources: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Album {
+    pub fn new(artist_id: &str, title: &str, creator_id: &str) -> Self {
+        let now = Utc::now();
+        let title = title.trim().to_string();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            artist_id: artist_id.to_string(),
+            normalized_title: title.to_lowercase(),
+            title,
+            cover_art_path: None,
+            year: None,
+            genre: None,
+            current_version_id: None,
+            metadata_sources: None,
+            created_by: Some(creator_id.to_string()),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+/// An album's track list as of one upload, see `ingest::versions`.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct AlbumVersion {
+    pub id: String,
+    pub album_id: String,
+    pub version_number: i64,
+    /// The manifest's version, e.g. `demo-v3`.
+    pub label: Option<String>,
+    /// `draft` or `released`.
+    pub release_status: String,
+    pub upload_id: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A track as it was in an album version.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct AlbumVersionTrack {
+    pub track_id: String,
+    pub title: String,
+    pub track_number: Option<i32>,
+    pub duration: i32,
+    pub file_path: String,
+    pub original_filename: Option<String>,
+}
+
+/// One permission held by a member of an artist, with the member's username.
+#[derive(Debug, Clone, FromRow)]
+pub struct ArtistMemberPermission {
+    pub user_id: String,
+    pub username: String,
+    pub permission: String,
+    pub granted_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct Playlist {
     pub id: String,
+    pub user_id: Option<String>,
     pub name: String,
     pub description: Option<String>,
     pub created_at: DateTime<Utc>,
@@ -41,12 +142,192 @@ pub struct User {
     pub id: String,
     pub username: String,
     pub email: String,
+    /// Set once the user proved they can read mail sent to `email`.
+    pub email_verified: bool,
     pub password_hash: String,
     pub is_admin: bool,
     pub created_at: DateTime<Utc>,
     pub last_login: Option<DateTime<Utc>>,
 }
 
+impl TryFrom<User> for navicore_music_shared::User {
+    type Error = uuid::Error;
+
+    fn try_from(user: User) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: Uuid::parse_str(&user.id)?,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified,
+            password_hash: user.password_hash,
+            is_admin: user.is_admin,
+            created_at: user.created_at,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at > now
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ApiKey {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// A provider account linked to a local user.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct OAuthIdentity {
+    pub user_id: String,
+    pub provider: String,
+    pub provider_user_id: String,
+    pub email: Option<String>,
+    #[serde(skip_serializing)]
+    pub provider_data: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct OAuthState {
+    pub state: String,
+    pub provider: String,
+    pub pkce_verifier: String,
+    pub nonce: String,
+    pub redirect_to: String,
+    pub link_user_id: Option<String>,
+    /// Hex SHA-256 of the cookie set in the browser that started the flow.
+    pub browser_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A user's TOTP authenticator. Never serialized, the secret stays server-side.
+#[derive(Debug, Clone, FromRow)]
+pub struct UserTotp {
+    pub user_id: String,
+    /// Base32 shared secret.
+    pub secret: String,
+    /// `None` while enrolment is pending.
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A verification or password reset token that was mailed to a user.
+#[derive(Debug, Clone, FromRow)]
+pub struct EmailToken {
+    /// The token's `jti`.
+    pub id: String,
+    pub user_id: String,
+    /// `verify_email` or `reset_password`.
+    pub purpose: String,
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub actor_id: Option<String>,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// JSON text.
+    pub diff: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A background album upload, see `ingest::jobs`.
+#[derive(Debug, Clone, FromRow)]
+pub struct UploadJob {
+    pub id: String,
+    pub user_id: String,
+    /// JSON text.
+    pub credential: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub status: String,
+    pub stage: Option<String>,
+    pub zip_path: String,
+    /// JSON text.
+    pub options: String,
+    /// JSON text.
+    pub files: String,
+    pub album_id: Option<String>,
+    /// JSON text.
+    pub error: Option<String>,
+    pub attempts: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// A file being uploaded in parts, see `ingest::chunked`.
+#[derive(Debug, Clone, FromRow)]
+pub struct ChunkedUpload {
+    pub id: String,
+    pub user_id: String,
+    pub kind: String,
+    pub file_name: String,
+    pub total_bytes: i64,
+    pub part_bytes: i64,
+    pub storage_key: String,
+    pub storage_upload_id: String,
+    /// JSON text.
+    pub metadata: Option<String>,
+    pub status: String,
+    pub assembled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ChunkedUploadPart {
+    #[serde(skip_serializing)]
+    pub upload_id: String,
+    pub part_number: i64,
+    pub size: i64,
+    pub checksum_sha256: String,
+    #[serde(skip_serializing)]
+    pub etag: String,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct PlayHistory {
     pub id: i64,
@@ -56,12 +337,30 @@ pub struct PlayHistory {
     pub play_duration: Option<i32>,
 }
 
+/// A file under the library directory, see `ingest::library`.
+#[derive(Debug, Clone, FromRow)]
+pub struct LibraryFile {
+    /// Relative to the library directory, with `/` separators.
+    pub path: String,
+    /// Hex SHA-256 of the contents.
+    pub content_hash: String,
+    pub size: i64,
+    pub modified_at: DateTime<Utc>,
+    /// `None` once the track was deleted through the API, which the scanner
+    /// leaves alone until the file changes.
+    pub track_id: Option<String>,
+    pub scanned_at: DateTime<Utc>,
+}
+
+/// A track for a file already in storage. Title, artist, album and duration
+/// may be left out to have them read from the file's tags.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateTrack {
-    pub title: String,
-    pub artist: String,
-    pub album: String,
-    pub duration: i32,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub artist_id: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<i32>,
     pub file_path: String,
     pub cover_art_path: Option<String>,
     pub genre: Option<String>,
@@ -70,15 +369,30 @@ pub struct CreateTrack {
 }
 
 impl CreateTrack {
+    /// Whether a required field is missing, blank fields included.
+    pub fn is_incomplete(&self) -> bool {
+        let blank = |value: &Option<String>| value.as_deref().is_none_or(|value| value.trim().is_empty());
+
+        blank(&self.title)
+            || blank(&self.album)
+            || self.duration.is_none()
+            || (self.artist_id.is_none() && blank(&self.artist))
+    }
+
     pub fn into_track(self) -> Track {
         let now = Utc::now();
         Track {
             id: Uuid::new_v4().to_string(),
-            title: self.title,
-            artist: self.artist,
-            album: self.album,
-            duration: self.duration,
+            title: self.title.unwrap_or_default(),
+            artist: self.artist.unwrap_or_default(),
+            artist_id: self.artist_id,
+            album: self.album.unwrap_or_default(),
+            album_id: None,
+            duration: self.duration.unwrap_or(0),
             file_path: self.file_path,
+            original_filename: None,
+            original_format: None,
+            stored_format: None,
             cover_art_path: self.cover_art_path,
             genre: self.genre,
             year: self.year,
@@ -89,6 +403,86 @@ impl CreateTrack {
     }
 }
 
+/// Partial track update, fields that are absent are left unchanged.
+#[allow(clippy::option_option)]
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateTrack {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// `null` detaches the track from its artist.
+    #[serde(default, deserialize_with = "present")]
+    pub artist_id: Option<Option<String>>,
+    pub album: Option<String>,
+    pub cover_art_path: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub track_number: Option<i32>,
+}
+
+impl UpdateTrack {
+    pub fn apply(self, track: &mut Track) {
+        if let Some(title) = self.title {
+            track.title = title;
+        }
+        if let Some(artist) = self.artist {
+            track.artist = artist;
+        }
+        if let Some(artist_id) = self.artist_id {
+            track.artist_id = artist_id;
+        }
+        if let Some(album) = self.album {
+            track.album = album;
+        }
+        if self.cover_art_path.is_some() {
+            track.cover_art_path = self.cover_art_path;
+        }
+        if self.genre.is_some() {
+            track.genre = self.genre;
+        }
+        if self.year.is_some() {
+            track.year = self.year;
+        }
+        if self.track_number.is_some() {
+            track.track_number = self.track_number;
+        }
+        track.updated_at = Utc::now();
+    }
+}
+
+/// Tells a field sent as `null` (`Some(None)`) apart from a missing one (`None`).
+#[allow(clippy::option_option)]
+fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateArtist {
+    pub name: String,
+    pub bio: Option<String>,
+    pub website: Option<String>,
+}
+
+impl CreateArtist {
+    pub fn into_artist(self, creator_id: &str) -> Artist {
+        let now = Utc::now();
+        let name = self.name.trim().to_string();
+        Artist {
+            id: Uuid::new_v4().to_string(),
+            normalized_name: name.to_lowercase(),
+            name,
+            bio: self.bio,
+            website: self.website,
+            created_by: Some(creator_id.to_string()),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreatePlaylist {
     pub name: String,
@@ -96,14 +490,37 @@ pub struct CreatePlaylist {
 }
 
 impl CreatePlaylist {
-    pub fn into_playlist(self) -> Playlist {
+    pub fn into_playlist(self, owner_id: &str) -> Playlist {
         let now = Utc::now();
         Playlist {
             id: Uuid::new_v4().to_string(),
+            user_id: Some(owner_id.to_string()),
             name: self.name,
             description: self.description,
             created_at: now,
             updated_at: now,
         }
     }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateUser {
+    pub username: String,
+    pub email: String,
+    pub password_hash: String,
+}
+
+impl CreateUser {
+    pub fn into_user(self) -> User {
+        User {
+            id: Uuid::new_v4().to_string(),
+            username: self.username,
+            email: self.email,
+            email_verified: false,
+            password_hash: self.password_hash,
+            is_admin: false,
+            created_at: Utc::now(),
+            last_login: None,
+        }
+    }
 }
\ No newline at end of file
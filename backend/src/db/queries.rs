@@ -1,13 +1,15 @@
 use super::models::*;
 use super::DbPool;
+use chrono::{DateTime, Utc};
 use sqlx::{query, query_as};
 
 pub async fn get_all_tracks(pool: &DbPool) -> anyhow::Result<Vec<Track>> {
     let tracks = query_as::<_, Track>(
         r#"
-        SELECT id, title, artist, album, duration, file_path, cover_art_path, 
-               genre, year, track_number, created_at, updated_at
+        SELECT id, title, artist, artist_id, album, album_id, duration, file_path, original_filename,
+               original_format, stored_format, cover_art_path, genre, year, track_number, created_at, updated_at
         FROM tracks
+        WHERE superseded_at IS NULL
         ORDER BY artist, album, track_number
         "#
     )
@@ -20,8 +22,8 @@ pub async fn get_all_tracks(pool: &DbPool) -> anyhow::Result<Vec<Track>> {
 pub async fn get_track_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<Track>> {
     let track = query_as::<_, Track>(
         r#"
-        SELECT id, title, artist, album, duration, file_path, cover_art_path, 
-               genre, year, track_number, created_at, updated_at
+        SELECT id, title, artist, artist_id, album, album_id, duration, file_path, original_filename,
+               original_format, stored_format, cover_art_path, genre, year, track_number, created_at, updated_at
         FROM tracks
         WHERE id = ?
         "#
@@ -33,30 +35,51 @@ pub async fn get_track_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<T
     Ok(track)
 }
 
-pub async fn create_track(pool: &DbPool, track: Track) -> anyhow::Result<Track> {
+/// Inserts `track` with the JSON `sources` of its metadata, adding it to the
+/// current version of its album.
+pub async fn create_track(pool: &DbPool, track: Track, sources: &str) -> anyhow::Result<Track> {
+    let mut tx = pool.begin().await?;
+    insert_track(&mut *tx, &track).await?;
+    set_track_sources(&mut tx, &track.id, sources).await?;
+    sync_current_version_track(&mut tx, &track.id).await?;
+    tx.commit().await?;
+
+    Ok(track)
+}
+
+async fn insert_track<'e>(
+    executor: impl sqlx::SqliteExecutor<'e>,
+    track: &Track,
+) -> anyhow::Result<()> {
     query(
         r#"
-        INSERT INTO tracks (id, title, artist, album, duration, file_path, 
-                          cover_art_path, genre, year, track_number, created_at, updated_at)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        INSERT INTO tracks (id, title, artist, artist_id, album, album_id, duration, file_path, 
+                          original_filename, original_format, stored_format, cover_art_path, genre, year,
+                          track_number, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#
     )
     .bind(&track.id)
     .bind(&track.title)
     .bind(&track.artist)
+    .bind(&track.artist_id)
     .bind(&track.album)
+    .bind(&track.album_id)
     .bind(&track.duration)
     .bind(&track.file_path)
+    .bind(&track.original_filename)
+    .bind(&track.original_format)
+    .bind(&track.stored_format)
     .bind(&track.cover_art_path)
     .bind(&track.genre)
     .bind(&track.year)
     .bind(&track.track_number)
     .bind(&track.created_at)
     .bind(&track.updated_at)
-    .execute(pool)
+    .execute(executor)
     .await?;
-    
-    Ok(track)
+
+    Ok(())
 }
 
 pub async fn delete_track(pool: &DbPool, id: &str) -> anyhow::Result<bool> {
@@ -68,6 +91,149 @@ pub async fn delete_track(pool: &DbPool, id: &str) -> anyhow::Result<bool> {
     Ok(result.rows_affected() > 0)
 }
 
+/// Saves `track`'s metadata together with a new stored file, which the
+/// current version of its album follows. Earlier versions keep their audio.
+pub async fn update_track_file(
+    pool: &DbPool,
+    track: &Track,
+    sources: &str,
+) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
+    let result = query(
+        r"
+        UPDATE tracks
+        SET title = ?, genre = ?, year = ?, track_number = ?, duration = ?, file_path = ?,
+            original_filename = ?, original_format = ?, stored_format = ?, updated_at = ?
+        WHERE id = ?
+        ",
+    )
+    .bind(&track.title)
+    .bind(&track.genre)
+    .bind(track.year)
+    .bind(track.track_number)
+    .bind(track.duration)
+    .bind(&track.file_path)
+    .bind(&track.original_filename)
+    .bind(&track.original_format)
+    .bind(&track.stored_format)
+    .bind(track.updated_at)
+    .bind(&track.id)
+    .execute(&mut *tx)
+    .await?;
+    set_track_sources(&mut tx, &track.id, sources).await?;
+    sync_current_version_track(&mut tx, &track.id).await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Saves `track`'s metadata and its JSON `sources`, which the current version
+/// of its album follows.
+pub async fn update_track(pool: &DbPool, track: &Track, sources: &str) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
+    let result = query(
+        r"
+        UPDATE tracks
+        SET title = ?, artist = ?, artist_id = ?, album = ?, album_id = ?, cover_art_path = ?,
+            genre = ?, year = ?, track_number = ?, updated_at = ?
+        WHERE id = ?
+        ",
+    )
+    .bind(&track.title)
+    .bind(&track.artist)
+    .bind(&track.artist_id)
+    .bind(&track.album)
+    .bind(&track.album_id)
+    .bind(&track.cover_art_path)
+    .bind(&track.genre)
+    .bind(track.year)
+    .bind(track.track_number)
+    .bind(track.updated_at)
+    .bind(&track.id)
+    .execute(&mut *tx)
+    .await?;
+    set_track_sources(&mut tx, &track.id, sources).await?;
+    sync_current_version_track(&mut tx, &track.id).await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn set_track_sources(
+    tx: &mut sqlx::SqliteConnection,
+    track_id: &str,
+    sources: &str,
+) -> anyhow::Result<()> {
+    query(r"UPDATE tracks SET metadata_sources = ? WHERE id = ?")
+        .bind(sources)
+        .bind(track_id)
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+/// The JSON sources of a track's metadata, `None` if unknown.
+pub async fn get_track_sources(pool: &DbPool, track_id: &str) -> anyhow::Result<Option<String>> {
+    let row: Option<(Option<String>,)> =
+        query_as(r"SELECT metadata_sources FROM tracks WHERE id = ?")
+            .bind(track_id)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(row.and_then(|(sources,)| sources))
+}
+
+/// `(track_id, sources)` for the album's current tracks.
+pub async fn get_album_track_sources(
+    pool: &DbPool,
+    album_id: &str,
+) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    let sources = query_as::<_, (String, Option<String>)>(
+        r"SELECT id, metadata_sources FROM tracks WHERE album_id = ? AND superseded_at IS NULL",
+    )
+    .bind(album_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sources)
+}
+
+/// Puts the track as it is now into the current version of its album, and
+/// takes it out of the current version of any album it left.
+async fn sync_current_version_track(
+    tx: &mut sqlx::SqliteConnection,
+    track_id: &str,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        DELETE FROM album_version_tracks
+        WHERE track_id = ?
+          AND version_id IN (SELECT current_version_id FROM albums WHERE current_version_id IS NOT NULL)
+        "
+    )
+    .bind(track_id)
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+        r"
+        INSERT INTO album_version_tracks (version_id, track_id, title, track_number, duration, file_path,
+                                          original_filename, original_format, stored_format, metadata_sources)
+        SELECT a.current_version_id, t.id, t.title, t.track_number, t.duration, t.file_path, t.original_filename,
+               t.original_format, t.stored_format, t.metadata_sources
+        FROM tracks t
+        JOIN albums a ON a.id = t.album_id
+        WHERE t.id = ? AND a.current_version_id IS NOT NULL AND t.superseded_at IS NULL
+        "
+    )
+    .bind(track_id)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
 pub async fn search_tracks(
     pool: &DbPool,
     search_query: &str,
@@ -75,10 +241,10 @@ pub async fn search_tracks(
     let search_term = format!("%{}%", search_query);
     let tracks = query_as::<_, Track>(
         r#"
-        SELECT id, title, artist, album, duration, file_path, cover_art_path, 
-               genre, year, track_number, created_at, updated_at
+        SELECT id, title, artist, artist_id, album, album_id, duration, file_path, original_filename,
+               original_format, stored_format, cover_art_path, genre, year, track_number, created_at, updated_at
         FROM tracks
-        WHERE title LIKE ? OR artist LIKE ? OR album LIKE ?
+        WHERE (title LIKE ? OR artist LIKE ? OR album LIKE ?) AND superseded_at IS NULL
         ORDER BY artist, album, track_number
         "#
     )
@@ -94,7 +260,7 @@ pub async fn search_tracks(
 pub async fn get_all_playlists(pool: &DbPool) -> anyhow::Result<Vec<Playlist>> {
     let playlists = query_as::<_, Playlist>(
         r#"
-        SELECT id, name, description, created_at, updated_at
+        SELECT id, user_id, name, description, created_at, updated_at
         FROM playlists
         ORDER BY name
         "#
@@ -108,7 +274,7 @@ pub async fn get_all_playlists(pool: &DbPool) -> anyhow::Result<Vec<Playlist>> {
 pub async fn get_playlist_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<Playlist>> {
     let playlist = query_as::<_, Playlist>(
         r#"
-        SELECT id, name, description, created_at, updated_at
+        SELECT id, user_id, name, description, created_at, updated_at
         FROM playlists
         WHERE id = ?
         "#
@@ -123,11 +289,12 @@ pub async fn get_playlist_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Optio
 pub async fn create_playlist(pool: &DbPool, playlist: Playlist) -> anyhow::Result<Playlist> {
     query(
         r#"
-        INSERT INTO playlists (id, name, description, created_at, updated_at)
-        VALUES (?, ?, ?, ?, ?)
+        INSERT INTO playlists (id, user_id, name, description, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?)
         "#
     )
     .bind(&playlist.id)
+    .bind(&playlist.user_id)
     .bind(&playlist.name)
     .bind(&playlist.description)
     .bind(&playlist.created_at)
@@ -141,8 +308,9 @@ pub async fn create_playlist(pool: &DbPool, playlist: Playlist) -> anyhow::Resul
 pub async fn get_playlist_tracks(pool: &DbPool, playlist_id: &str) -> anyhow::Result<Vec<Track>> {
     let tracks = query_as::<_, Track>(
         r#"
-        SELECT t.id, t.title, t.artist, t.album, t.duration, t.file_path, 
-               t.cover_art_path, t.genre, t.year, t.track_number, 
+        SELECT t.id, t.title, t.artist, t.artist_id, t.album, t.album_id, t.duration, t.file_path, 
+               t.original_filename, t.original_format, t.stored_format, t.cover_art_path, t.genre, t.year,
+               t.track_number, 
                t.created_at, t.updated_at
         FROM tracks t
         INNER JOIN playlist_tracks pt ON t.id = pt.track_id
@@ -216,4 +384,2543 @@ pub async fn record_play(
     .await?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Finds a user by exact username, or by email in any case, as emails are
+/// stored lowercased.
+pub async fn get_user_by_username_or_email(
+    pool: &DbPool,
+    login: &str,
+) -> anyhow::Result<Option<User>> {
+    let user = query_as::<_, User>(
+        r"
+        SELECT id, username, email, email_verified, password_hash, is_admin, created_at, last_login
+        FROM users
+        WHERE username = ? OR email = ?
+        ",
+    )
+    .bind(login)
+    .bind(login.trim().to_lowercase())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn update_last_login(
+    pool: &DbPool,
+    user_id: &str,
+    logged_in_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(r"UPDATE users SET last_login = ? WHERE id = ?")
+        .bind(logged_in_at)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn username_exists(pool: &DbPool, username: &str) -> anyhow::Result<bool> {
+    let row: Option<(i64,)> = query_as(r"SELECT 1 FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+pub async fn email_exists(pool: &DbPool, email: &str) -> anyhow::Result<bool> {
+    let row: Option<(i64,)> = query_as(r"SELECT 1 FROM users WHERE email = ?")
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+/// Inserts `user`. With `promote_if_first` the account is made an admin when
+/// the table is empty, decided inside the INSERT so concurrent first
+/// registrations can't both win.
+pub async fn create_user(
+    pool: &DbPool,
+    mut user: User,
+    promote_if_first: bool,
+) -> anyhow::Result<User> {
+    let (is_admin,): (bool,) = query_as(
+        r"
+        INSERT INTO users (id, username, email, email_verified, password_hash, is_admin,
+                           created_at, last_login)
+        SELECT ?, ?, ?, ?, ?, ? OR (? AND NOT EXISTS (SELECT 1 FROM users)), ?, ?
+        RETURNING is_admin
+        ",
+    )
+    .bind(&user.id)
+    .bind(&user.username)
+    .bind(&user.email)
+    .bind(user.email_verified)
+    .bind(&user.password_hash)
+    .bind(user.is_admin)
+    .bind(promote_if_first)
+    .bind(user.created_at)
+    .bind(user.last_login)
+    .fetch_one(pool)
+    .await?;
+
+    user.is_admin = is_admin;
+    Ok(user)
+}
+
+pub async fn get_user_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<User>> {
+    let user = query_as::<_, User>(
+        r"
+        SELECT id, username, email, email_verified, password_hash, is_admin, created_at, last_login
+        FROM users
+        WHERE id = ?
+        ",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn get_all_users(pool: &DbPool) -> anyhow::Result<Vec<User>> {
+    let users = query_as::<_, User>(
+        r"
+        SELECT id, username, email, email_verified, password_hash, is_admin, created_at, last_login
+        FROM users
+        ORDER BY username
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(users)
+}
+
+pub async fn set_user_admin(pool: &DbPool, id: &str, is_admin: bool) -> anyhow::Result<bool> {
+    let result = query(r"UPDATE users SET is_admin = ? WHERE id = ?")
+        .bind(is_admin)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn count_admins(pool: &DbPool) -> anyhow::Result<i64> {
+    let (count,): (i64,) = query_as(r"SELECT COUNT(*) FROM users WHERE is_admin = 1")
+        .fetch_one(pool)
+        .await?;
+
+    Ok(count)
+}
+
+pub async fn get_play_history_for_user(
+    pool: &DbPool,
+    user_id: &str,
+) -> anyhow::Result<Vec<PlayHistory>> {
+    let history = query_as::<_, PlayHistory>(
+        r"
+        SELECT id, track_id, user_id, played_at, play_duration
+        FROM play_history
+        WHERE user_id = ?
+        ORDER BY played_at DESC
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(history)
+}
+
+pub async fn clear_play_history_for_user(pool: &DbPool, user_id: &str) -> anyhow::Result<u64> {
+    let result = query(r"DELETE FROM play_history WHERE user_id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn create_session(pool: &DbPool, session: Session) -> anyhow::Result<Session> {
+    query(
+        r"
+        INSERT INTO sessions (id, user_id, ip_address, user_agent, created_at,
+                              last_seen_at, expires_at, revoked_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&session.id)
+    .bind(&session.user_id)
+    .bind(&session.ip_address)
+    .bind(&session.user_agent)
+    .bind(session.created_at)
+    .bind(session.last_seen_at)
+    .bind(session.expires_at)
+    .bind(session.revoked_at)
+    .execute(pool)
+    .await?;
+
+    Ok(session)
+}
+
+pub async fn get_session_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<Session>> {
+    let session = query_as::<_, Session>(
+        r"
+        SELECT id, user_id, ip_address, user_agent, created_at, last_seen_at,
+               expires_at, revoked_at
+        FROM sessions
+        WHERE id = ?
+        ",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(session)
+}
+
+pub async fn get_active_sessions_for_user(
+    pool: &DbPool,
+    user_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Vec<Session>> {
+    let sessions = query_as::<_, Session>(
+        r"
+        SELECT id, user_id, ip_address, user_agent, created_at, last_seen_at,
+               expires_at, revoked_at
+        FROM sessions
+        WHERE user_id = ? AND revoked_at IS NULL AND expires_at > ?
+        ORDER BY last_seen_at DESC
+        ",
+    )
+    .bind(user_id)
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sessions)
+}
+
+/// Bumps `last_seen_at`, skipping the write when it was updated after `stale_before`.
+pub async fn touch_session(
+    pool: &DbPool,
+    id: &str,
+    now: DateTime<Utc>,
+    stale_before: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(r"UPDATE sessions SET last_seen_at = ? WHERE id = ? AND last_seen_at < ?")
+        .bind(now)
+        .bind(id)
+        .bind(stale_before)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn revoke_session(
+    pool: &DbPool,
+    id: &str,
+    user_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<bool> {
+    let result = query(
+        r"
+        UPDATE sessions SET revoked_at = ?
+        WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+        ",
+    )
+    .bind(now)
+    .bind(id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn revoke_all_sessions_for_user(
+    pool: &DbPool,
+    user_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let result =
+        query(r"UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn revoke_other_sessions_for_user(
+    pool: &DbPool,
+    user_id: &str,
+    keep_session_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let result = query(
+        r"
+        UPDATE sessions SET revoked_at = ?
+        WHERE user_id = ? AND id != ? AND revoked_at IS NULL
+        ",
+    )
+    .bind(now)
+    .bind(user_id)
+    .bind(keep_session_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_stale_sessions(pool: &DbPool, now: DateTime<Utc>) -> anyhow::Result<u64> {
+    let result = query(r"DELETE FROM sessions WHERE expires_at <= ? OR revoked_at IS NOT NULL")
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn create_api_key(pool: &DbPool, key: ApiKey) -> anyhow::Result<ApiKey> {
+    query(
+        r"
+        INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes, created_at,
+                              last_used_at, expires_at, revoked_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&key.id)
+    .bind(&key.user_id)
+    .bind(&key.name)
+    .bind(&key.prefix)
+    .bind(&key.key_hash)
+    .bind(&key.scopes)
+    .bind(key.created_at)
+    .bind(key.last_used_at)
+    .bind(key.expires_at)
+    .bind(key.revoked_at)
+    .execute(pool)
+    .await?;
+
+    Ok(key)
+}
+
+pub async fn get_api_key_by_hash(pool: &DbPool, key_hash: &str) -> anyhow::Result<Option<ApiKey>> {
+    let key = query_as::<_, ApiKey>(
+        r"
+        SELECT id, user_id, name, prefix, key_hash, scopes, created_at, last_used_at,
+               expires_at, revoked_at
+        FROM api_keys
+        WHERE key_hash = ?
+        ",
+    )
+    .bind(key_hash)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(key)
+}
+
+pub async fn get_api_keys_for_user(pool: &DbPool, user_id: &str) -> anyhow::Result<Vec<ApiKey>> {
+    let keys = query_as::<_, ApiKey>(
+        r"
+        SELECT id, user_id, name, prefix, key_hash, scopes, created_at, last_used_at,
+               expires_at, revoked_at
+        FROM api_keys
+        WHERE user_id = ? AND revoked_at IS NULL
+        ORDER BY created_at DESC
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(keys)
+}
+
+/// Records a use of the key, skipping the write when it was updated after `stale_before`.
+pub async fn touch_api_key(
+    pool: &DbPool,
+    id: &str,
+    now: DateTime<Utc>,
+    stale_before: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        UPDATE api_keys SET last_used_at = ?
+        WHERE id = ? AND (last_used_at IS NULL OR last_used_at < ?)
+        ",
+    )
+    .bind(now)
+    .bind(id)
+    .bind(stale_before)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn revoke_api_key(
+    pool: &DbPool,
+    id: &str,
+    user_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<bool> {
+    let result = query(
+        r"
+        UPDATE api_keys SET revoked_at = ?
+        WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+        ",
+    )
+    .bind(now)
+    .bind(id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn record_auth_attempt(
+    pool: &DbPool,
+    action: &str,
+    username: Option<&str>,
+    user_id: Option<&str>,
+    ip_address: Option<&str>,
+    success: bool,
+    attempted_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO auth_attempts (action, username, user_id, ip_address, success, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(action)
+    .bind(username)
+    .bind(user_id)
+    .bind(ip_address)
+    .bind(success)
+    .bind(attempted_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Time of the `nth` most recent (1-based) `action` attempt from `ip_address`
+/// after `since`, if there were that many.
+pub async fn nth_recent_attempt_from_ip(
+    pool: &DbPool,
+    action: &str,
+    ip_address: &str,
+    since: DateTime<Utc>,
+    nth: i64,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let row: Option<(DateTime<Utc>,)> = query_as(
+        r"
+        SELECT created_at FROM auth_attempts
+        WHERE action = ? AND ip_address = ? AND created_at > ?
+        ORDER BY created_at DESC
+        LIMIT 1 OFFSET ?
+        ",
+    )
+    .bind(action)
+    .bind(ip_address)
+    .bind(since)
+    .bind(nth - 1)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|(attempted_at,)| attempted_at))
+}
+
+/// Failed logins to account `user_id` after `since` and after its last
+/// successful login, with the time of the latest one. Without an account,
+/// failures of the typed `username` that matched none.
+pub async fn recent_login_failures(
+    pool: &DbPool,
+    user_id: Option<&str>,
+    username: &str,
+    since: DateTime<Utc>,
+) -> anyhow::Result<(i64, Option<DateTime<Utc>>)> {
+    let row: (i64, Option<DateTime<Utc>>) = query_as(
+        r"
+        SELECT COUNT(*), MAX(created_at) FROM auth_attempts
+        WHERE action = 'login' AND success = 0 AND created_at > ?
+          AND (user_id = ? OR (? IS NULL AND user_id IS NULL AND username = ?))
+          AND created_at > COALESCE(
+              (SELECT MAX(created_at) FROM auth_attempts
+               WHERE action = 'login' AND user_id = ? AND success = 1),
+              '')
+        ",
+    )
+    .bind(since)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(username)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row)
+}
+
+pub async fn delete_auth_attempts_before(
+    pool: &DbPool,
+    cutoff: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let result = query(r"DELETE FROM auth_attempts WHERE created_at < ?")
+        .bind(cutoff)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn get_user_by_email(pool: &DbPool, email: &str) -> anyhow::Result<Option<User>> {
+    let user = query_as::<_, User>(
+        r"
+        SELECT id, username, email, email_verified, password_hash, is_admin, created_at, last_login
+        FROM users
+        WHERE email = ?
+        ",
+    )
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn set_user_password(
+    pool: &DbPool,
+    id: &str,
+    password_hash: &str,
+) -> anyhow::Result<bool> {
+    let result = query(r"UPDATE users SET password_hash = ? WHERE id = ?")
+        .bind(password_hash)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Marks the user's email verified, as long as it is still `email`.
+pub async fn set_email_verified(pool: &DbPool, id: &str, email: &str) -> anyhow::Result<bool> {
+    let result = query(r"UPDATE users SET email_verified = 1 WHERE id = ? AND email = ?")
+        .bind(id)
+        .bind(email)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Stores `token`, invalidating the user's earlier unused tokens for the same
+/// purpose so only the newest link works.
+pub async fn create_email_token(pool: &DbPool, token: EmailToken) -> anyhow::Result<EmailToken> {
+    let mut tx = pool.begin().await?;
+
+    query(
+        r"
+        UPDATE email_tokens SET used_at = ?
+        WHERE user_id = ? AND purpose = ? AND used_at IS NULL
+        ",
+    )
+    .bind(token.created_at)
+    .bind(&token.user_id)
+    .bind(&token.purpose)
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+        r"
+        INSERT INTO email_tokens (id, user_id, purpose, email, created_at, expires_at, used_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&token.id)
+    .bind(&token.user_id)
+    .bind(&token.purpose)
+    .bind(&token.email)
+    .bind(token.created_at)
+    .bind(token.expires_at)
+    .bind(token.used_at)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(token)
+}
+
+pub async fn latest_email_token_at(
+    pool: &DbPool,
+    user_id: &str,
+    purpose: &str,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let row: (Option<DateTime<Utc>>,) =
+        query_as(r"SELECT MAX(created_at) FROM email_tokens WHERE user_id = ? AND purpose = ?")
+            .bind(user_id)
+            .bind(purpose)
+            .fetch_one(pool)
+            .await?;
+
+    Ok(row.0)
+}
+
+/// Marks the token used and returns it, if it was unused and unexpired.
+pub async fn use_email_token(
+    pool: &DbPool,
+    id: &str,
+    purpose: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<EmailToken>> {
+    let token = query_as::<_, EmailToken>(
+        r"
+        UPDATE email_tokens SET used_at = ?
+        WHERE id = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING id, user_id, purpose, email, created_at, expires_at, used_at
+        ",
+    )
+    .bind(now)
+    .bind(id)
+    .bind(purpose)
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(token)
+}
+
+pub async fn delete_stale_email_tokens(pool: &DbPool, now: DateTime<Utc>) -> anyhow::Result<u64> {
+    let result = query(r"DELETE FROM email_tokens WHERE expires_at <= ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn get_user_totp(pool: &DbPool, user_id: &str) -> anyhow::Result<Option<UserTotp>> {
+    let totp = query_as::<_, UserTotp>(
+        r"
+        SELECT user_id, secret, enabled_at, created_at
+        FROM user_totp
+        WHERE user_id = ?
+        ",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(totp)
+}
+
+/// Stores a pending enrolment, replacing an earlier unconfirmed one. Returns
+/// `false` if the user already has an active authenticator.
+pub async fn upsert_pending_totp(pool: &DbPool, totp: &UserTotp) -> anyhow::Result<bool> {
+    let result = query(
+        r"
+        INSERT INTO user_totp (user_id, secret, enabled_at, last_used_step, created_at)
+        VALUES (?, ?, NULL, NULL, ?)
+        ON CONFLICT (user_id) DO UPDATE SET
+            secret = excluded.secret,
+            last_used_step = NULL,
+            created_at = excluded.created_at
+        WHERE user_totp.enabled_at IS NULL
+        ",
+    )
+    .bind(&totp.user_id)
+    .bind(&totp.secret)
+    .bind(totp.created_at)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Activates a pending enrolment along with a fresh set of recovery codes.
+pub async fn enable_totp(
+    pool: &DbPool,
+    user_id: &str,
+    step: i64,
+    code_hashes: &[String],
+    now: DateTime<Utc>,
+) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    let result = query(
+        r"
+        UPDATE user_totp SET enabled_at = ?, last_used_step = ?
+        WHERE user_id = ? AND enabled_at IS NULL
+        ",
+    )
+    .bind(now)
+    .bind(step)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    insert_recovery_codes(&mut tx, user_id, code_hashes, now).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Records `step` as used, failing if it (or a later step) already was.
+pub async fn advance_totp_step(pool: &DbPool, user_id: &str, step: i64) -> anyhow::Result<bool> {
+    let result = query(
+        r"
+        UPDATE user_totp SET last_used_step = ?
+        WHERE user_id = ? AND enabled_at IS NOT NULL
+          AND (last_used_step IS NULL OR last_used_step < ?)
+        ",
+    )
+    .bind(step)
+    .bind(user_id)
+    .bind(step)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_user_totp(pool: &DbPool, user_id: &str) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    query(r"DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    let result = query(r"DELETE FROM user_totp WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Replaces all of the user's recovery codes.
+pub async fn replace_recovery_codes(
+    pool: &DbPool,
+    user_id: &str,
+    code_hashes: &[String],
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    insert_recovery_codes(&mut tx, user_id, code_hashes, now).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn insert_recovery_codes(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    user_id: &str,
+    code_hashes: &[String],
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(r"DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    for code_hash in code_hashes {
+        query(r"INSERT INTO recovery_codes (user_id, code_hash, created_at) VALUES (?, ?, ?)")
+            .bind(user_id)
+            .bind(code_hash)
+            .bind(now)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Marks the code used, returning `false` if it doesn't exist or was used.
+pub async fn use_recovery_code(
+    pool: &DbPool,
+    user_id: &str,
+    code_hash: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<bool> {
+    let result = query(
+        r"
+        UPDATE recovery_codes SET used_at = ?
+        WHERE user_id = ? AND code_hash = ? AND used_at IS NULL
+        ",
+    )
+    .bind(now)
+    .bind(user_id)
+    .bind(code_hash)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn count_unused_recovery_codes(pool: &DbPool, user_id: &str) -> anyhow::Result<i64> {
+    let (count,): (i64,) =
+        query_as(r"SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used_at IS NULL")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await?;
+
+    Ok(count)
+}
+
+pub async fn create_oauth_state(pool: &DbPool, state: OAuthState) -> anyhow::Result<OAuthState> {
+    query(
+        r"
+        INSERT INTO oauth_states (state, provider, pkce_verifier, nonce, redirect_to,
+                                  link_user_id, browser_hash, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&state.state)
+    .bind(&state.provider)
+    .bind(&state.pkce_verifier)
+    .bind(&state.nonce)
+    .bind(&state.redirect_to)
+    .bind(&state.link_user_id)
+    .bind(&state.browser_hash)
+    .bind(state.created_at)
+    .bind(state.expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok(state)
+}
+
+/// Deletes and returns the state, so each one can only be used once.
+pub async fn take_oauth_state(pool: &DbPool, state: &str) -> anyhow::Result<Option<OAuthState>> {
+    let state = query_as::<_, OAuthState>(
+        r"
+        DELETE FROM oauth_states
+        WHERE state = ?
+        RETURNING state, provider, pkce_verifier, nonce, redirect_to, link_user_id,
+                  browser_hash, created_at, expires_at
+        ",
+    )
+    .bind(state)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(state)
+}
+
+pub async fn delete_expired_oauth_states(pool: &DbPool, now: DateTime<Utc>) -> anyhow::Result<u64> {
+    let result = query(r"DELETE FROM oauth_states WHERE expires_at <= ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn get_user_by_oauth_identity(
+    pool: &DbPool,
+    provider: &str,
+    provider_user_id: &str,
+) -> anyhow::Result<Option<User>> {
+    let user = query_as::<_, User>(
+        r"
+        SELECT u.id, u.username, u.email, u.email_verified, u.password_hash, u.is_admin,
+               u.created_at, u.last_login
+        FROM users u
+        JOIN user_oauth o ON o.user_id = u.id
+        WHERE o.provider = ? AND o.provider_user_id = ?
+        ",
+    )
+    .bind(provider)
+    .bind(provider_user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user)
+}
+
+/// Links the identity, or refreshes the stored profile if it is already linked.
+pub async fn upsert_oauth_identity(
+    pool: &DbPool,
+    identity: OAuthIdentity,
+) -> anyhow::Result<OAuthIdentity> {
+    query(
+        r"
+        INSERT INTO user_oauth (user_id, provider, provider_user_id, email, provider_data,
+                                created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (provider, provider_user_id) DO UPDATE SET
+            email = excluded.email,
+            provider_data = excluded.provider_data,
+            updated_at = excluded.updated_at
+        ",
+    )
+    .bind(&identity.user_id)
+    .bind(&identity.provider)
+    .bind(&identity.provider_user_id)
+    .bind(&identity.email)
+    .bind(&identity.provider_data)
+    .bind(identity.created_at)
+    .bind(identity.updated_at)
+    .execute(pool)
+    .await?;
+
+    Ok(identity)
+}
+
+pub async fn get_oauth_identities_for_user(
+    pool: &DbPool,
+    user_id: &str,
+) -> anyhow::Result<Vec<OAuthIdentity>> {
+    let identities = query_as::<_, OAuthIdentity>(
+        r"
+        SELECT user_id, provider, provider_user_id, email, provider_data, created_at, updated_at
+        FROM user_oauth
+        WHERE user_id = ?
+        ORDER BY provider
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(identities)
+}
+
+pub async fn delete_oauth_identity(
+    pool: &DbPool,
+    user_id: &str,
+    provider: &str,
+) -> anyhow::Result<bool> {
+    let result = query(r"DELETE FROM user_oauth WHERE user_id = ? AND provider = ?")
+        .bind(user_id)
+        .bind(provider)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn create_audit_log_entry(pool: &DbPool, entry: &AuditLogEntry) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO audit_log (actor_id, action, target_type, target_id, ip_address,
+                               user_agent, diff, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&entry.actor_id)
+    .bind(&entry.action)
+    .bind(&entry.target_type)
+    .bind(&entry.target_id)
+    .bind(&entry.ip_address)
+    .bind(&entry.user_agent)
+    .bind(&entry.diff)
+    .bind(entry.created_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Filters for [`get_audit_log`], `None` matches everything.
+#[derive(Debug, Default)]
+pub struct AuditLogFilter<'a> {
+    pub actor_id: Option<&'a str>,
+    /// Exact action, or a prefix such as `track.` when it ends with a dot.
+    pub action: Option<&'a str>,
+    pub target_type: Option<&'a str>,
+    pub target_id: Option<&'a str>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only entries older than this id, for paging.
+    pub before_id: Option<i64>,
+}
+
+/// Newest entries first.
+pub async fn get_audit_log(
+    pool: &DbPool,
+    filter: &AuditLogFilter<'_>,
+    limit: i64,
+) -> anyhow::Result<Vec<AuditLogEntry>> {
+    let action_pattern = filter.action.map(|action| {
+        let escaped = action
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        if action.ends_with('.') {
+            format!("{escaped}%")
+        } else {
+            escaped
+        }
+    });
+
+    let entries = query_as::<_, AuditLogEntry>(
+        r"
+        SELECT id, actor_id, action, target_type, target_id, ip_address, user_agent,
+               diff, created_at
+        FROM audit_log
+        WHERE (?1 IS NULL OR actor_id = ?1)
+          AND (?2 IS NULL OR action LIKE ?2 ESCAPE '\')
+          AND (?3 IS NULL OR target_type = ?3)
+          AND (?4 IS NULL OR target_id = ?4)
+          AND (?5 IS NULL OR created_at >= ?5)
+          AND (?6 IS NULL OR created_at < ?6)
+          AND (?7 IS NULL OR id < ?7)
+        ORDER BY id DESC
+        LIMIT ?8
+        ",
+    )
+    .bind(filter.actor_id)
+    .bind(action_pattern)
+    .bind(filter.target_type)
+    .bind(filter.target_id)
+    .bind(filter.since)
+    .bind(filter.until)
+    .bind(filter.before_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(entries)
+}
+
+/// Creates the artist with `owner_id` as its first owner.
+pub async fn create_artist(
+    pool: &DbPool,
+    artist: Artist,
+    owner_id: &str,
+) -> anyhow::Result<Artist> {
+    let mut tx = pool.begin().await?;
+
+    query(
+        r"
+        INSERT INTO artists (id, name, normalized_name, bio, website, created_by,
+                             created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&artist.id)
+    .bind(&artist.name)
+    .bind(&artist.normalized_name)
+    .bind(&artist.bio)
+    .bind(&artist.website)
+    .bind(&artist.created_by)
+    .bind(artist.created_at)
+    .bind(artist.updated_at)
+    .execute(&mut *tx)
+    .await?;
+
+    query(
+        r"
+        INSERT INTO artist_permissions (artist_id, user_id, permission, granted_by, granted_at)
+        VALUES (?, ?, 'owner', ?, ?)
+        ",
+    )
+    .bind(&artist.id)
+    .bind(owner_id)
+    .bind(owner_id)
+    .bind(artist.created_at)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(artist)
+}
+
+pub async fn get_all_artists(pool: &DbPool) -> anyhow::Result<Vec<Artist>> {
+    let artists = query_as::<_, Artist>(
+        r"
+        SELECT id, name, normalized_name, bio, website, created_by, created_at, updated_at
+        FROM artists
+        ORDER BY normalized_name
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(artists)
+}
+
+pub async fn get_artist_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<Artist>> {
+    let artist = query_as::<_, Artist>(
+        r"
+        SELECT id, name, normalized_name, bio, website, created_by, created_at, updated_at
+        FROM artists
+        WHERE id = ?
+        ",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(artist)
+}
+
+pub async fn get_artist_by_name(pool: &DbPool, name: &str) -> anyhow::Result<Option<Artist>> {
+    let artist = query_as::<_, Artist>(
+        r"
+        SELECT id, name, normalized_name, bio, website, created_by, created_at, updated_at
+        FROM artists
+        WHERE normalized_name = ?
+        ",
+    )
+    .bind(name.trim().to_lowercase())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(artist)
+}
+
+/// Returns the artist's album with the same title (ignoring case), creating
+/// `album` with an empty first version if there is none.
+pub async fn get_or_create_album(pool: &DbPool, album: Album) -> anyhow::Result<Album> {
+    let mut tx = pool.begin().await?;
+    let result = query(
+        r"
+        INSERT INTO albums (id, artist_id, title, normalized_title, cover_art_path, year,
+                            genre, metadata_sources, created_by, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (artist_id, normalized_title) DO NOTHING
+        ",
+    )
+    .bind(&album.id)
+    .bind(&album.artist_id)
+    .bind(&album.title)
+    .bind(&album.normalized_title)
+    .bind(&album.cover_art_path)
+    .bind(album.year)
+    .bind(&album.genre)
+    .bind(&album.metadata_sources)
+    .bind(&album.created_by)
+    .bind(album.created_at)
+    .bind(album.updated_at)
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        let version_id = uuid::Uuid::new_v4().to_string();
+        query(
+            r"
+            INSERT INTO album_versions (id, album_id, version_number, release_status, created_by, created_at)
+            VALUES (?, ?, 1, 'released', ?, ?)
+            "
+        )
+        .bind(&version_id)
+        .bind(&album.id)
+        .bind(&album.created_by)
+        .bind(album.created_at)
+        .execute(&mut *tx)
+        .await?;
+
+        query(r"UPDATE albums SET current_version_id = ? WHERE id = ?")
+            .bind(&version_id)
+            .bind(&album.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    get_album_by_title(pool, &album.artist_id, &album.normalized_title)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Album {} disappeared after it was created", album.id))
+}
+
+pub async fn get_album_by_id(pool: &DbPool, id: &str) -> anyhow::Result<Option<Album>> {
+    let album = query_as::<_, Album>(
+        r"
+        SELECT id, artist_id, title, normalized_title, cover_art_path, year, genre, current_version_id,
+               metadata_sources, created_by, created_at, updated_at
+        FROM albums
+        WHERE id = ?
+        "
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(album)
+}
+
+pub async fn get_album_by_title(
+    pool: &DbPool,
+    artist_id: &str,
+    normalized_title: &str,
+) -> anyhow::Result<Option<Album>> {
+    let album = query_as::<_, Album>(
+        r"
+        SELECT id, artist_id, title, normalized_title, cover_art_path, year, genre, current_version_id,
+               metadata_sources, created_by, created_at, updated_at
+        FROM albums
+        WHERE artist_id = ? AND normalized_title = ?
+        "
+    )
+    .bind(artist_id)
+    .bind(normalized_title)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(album)
+}
+
+/// The album's tracks in its current version.
+pub async fn get_album_tracks(pool: &DbPool, album_id: &str) -> anyhow::Result<Vec<Track>> {
+    let tracks = query_as::<_, Track>(
+        r"
+        SELECT id, title, artist, artist_id, album, album_id, duration, file_path, original_filename,
+               original_format, stored_format, cover_art_path, genre, year, track_number, created_at, updated_at
+        FROM tracks
+        WHERE album_id = ? AND superseded_at IS NULL
+        ORDER BY track_number, title
+        "
+    )
+    .bind(album_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tracks)
+}
+
+/// `(track_number, title)` of the numbered current tracks in an artist's album, found
+/// by its normalized title. Empty if there is no such album yet.
+pub async fn get_album_track_numbers(
+    pool: &DbPool,
+    artist_id: &str,
+    normalized_title: &str,
+) -> anyhow::Result<Vec<(i32, String)>> {
+    let numbers = query_as::<_, (i32, String)>(
+        r"
+        SELECT t.track_number, t.title
+        FROM tracks t
+        JOIN albums a ON a.id = t.album_id
+        WHERE a.artist_id = ? AND a.normalized_title = ? AND t.track_number IS NOT NULL
+          AND t.superseded_at IS NULL
+        ORDER BY t.track_number
+        ",
+    )
+    .bind(artist_id)
+    .bind(normalized_title)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(numbers)
+}
+
+pub async fn set_album_cover(
+    pool: &DbPool,
+    album_id: &str,
+    cover_art_path: &str,
+) -> anyhow::Result<()> {
+    query(r"UPDATE albums SET cover_art_path = ?, updated_at = ? WHERE id = ?")
+        .bind(cover_art_path)
+        .bind(Utc::now())
+        .bind(album_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Deletes an album, and with it its versions, unless a track is in it.
+pub async fn delete_album_if_empty(pool: &DbPool, album_id: &str) -> anyhow::Result<bool> {
+    let result = query(
+        r"DELETE FROM albums WHERE id = ? AND NOT EXISTS (SELECT 1 FROM tracks WHERE album_id = ?)",
+    )
+    .bind(album_id)
+    .bind(album_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_artist_tracks(pool: &DbPool, artist_id: &str) -> anyhow::Result<Vec<Track>> {
+    let tracks = query_as::<_, Track>(
+        r"
+        SELECT id, title, artist, artist_id, album, album_id, duration, file_path, original_filename,
+               original_format, stored_format, cover_art_path, genre, year, track_number, created_at, updated_at
+        FROM tracks
+        WHERE artist_id = ? AND superseded_at IS NULL
+        ORDER BY album, track_number
+        "
+    )
+    .bind(artist_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tracks)
+}
+
+pub async fn get_artist_permissions_for_user(
+    pool: &DbPool,
+    artist_id: &str,
+    user_id: &str,
+) -> anyhow::Result<Vec<String>> {
+    let rows: Vec<(String,)> =
+        query_as(r"SELECT permission FROM artist_permissions WHERE artist_id = ? AND user_id = ?")
+            .bind(artist_id)
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+    Ok(rows.into_iter().map(|(permission,)| permission).collect())
+}
+
+pub async fn get_artist_members(
+    pool: &DbPool,
+    artist_id: &str,
+) -> anyhow::Result<Vec<ArtistMemberPermission>> {
+    let members = query_as::<_, ArtistMemberPermission>(
+        r"
+        SELECT p.user_id, u.username, p.permission, p.granted_at
+        FROM artist_permissions p
+        INNER JOIN users u ON u.id = p.user_id
+        WHERE p.artist_id = ?
+        ORDER BY u.username, p.permission
+        ",
+    )
+    .bind(artist_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(members)
+}
+
+/// Replaces the member's permissions with `permissions`.
+pub async fn set_artist_member_permissions(
+    pool: &DbPool,
+    artist_id: &str,
+    user_id: &str,
+    permissions: &[&str],
+    granted_by: &str,
+    granted_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+
+    query(r"DELETE FROM artist_permissions WHERE artist_id = ? AND user_id = ?")
+        .bind(artist_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for permission in permissions {
+        query(
+            r"
+            INSERT INTO artist_permissions (artist_id, user_id, permission, granted_by, granted_at)
+            VALUES (?, ?, ?, ?, ?)
+            ",
+        )
+        .bind(artist_id)
+        .bind(user_id)
+        .bind(permission)
+        .bind(granted_by)
+        .bind(granted_at)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn remove_artist_member(
+    pool: &DbPool,
+    artist_id: &str,
+    user_id: &str,
+) -> anyhow::Result<bool> {
+    let result = query(r"DELETE FROM artist_permissions WHERE artist_id = ? AND user_id = ?")
+        .bind(artist_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn count_artist_owners(pool: &DbPool, artist_id: &str) -> anyhow::Result<i64> {
+    let (count,): (i64,) = query_as(
+        r"SELECT COUNT(*) FROM artist_permissions WHERE artist_id = ? AND permission = 'owner'",
+    )
+    .bind(artist_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count)
+}
+
+pub async fn create_upload_job(pool: &DbPool, job: &UploadJob) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO upload_jobs (id, user_id, credential, ip_address, user_agent, status, stage,
+                                 zip_path, options, files, album_id, error, attempts,
+                                 created_at, updated_at, finished_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&job.id)
+    .bind(&job.user_id)
+    .bind(&job.credential)
+    .bind(&job.ip_address)
+    .bind(&job.user_agent)
+    .bind(&job.status)
+    .bind(&job.stage)
+    .bind(&job.zip_path)
+    .bind(&job.options)
+    .bind(&job.files)
+    .bind(&job.album_id)
+    .bind(&job.error)
+    .bind(job.attempts)
+    .bind(job.created_at)
+    .bind(job.updated_at)
+    .bind(job.finished_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_upload_job(pool: &DbPool, id: &str) -> anyhow::Result<Option<UploadJob>> {
+    let job = query_as::<_, UploadJob>(
+        r"
+        SELECT id, user_id, credential, ip_address, user_agent, status, stage, zip_path, options,
+               files, album_id, error, attempts, created_at, updated_at, finished_at
+        FROM upload_jobs
+        WHERE id = ?
+        ",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(job)
+}
+
+/// Marks the oldest queued job as processing and returns it. Workers can call
+/// this concurrently, each job is handed out once.
+pub async fn claim_upload_job(
+    pool: &DbPool,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<UploadJob>> {
+    let job = query_as::<_, UploadJob>(
+        r"
+        UPDATE upload_jobs
+        SET status = 'processing', attempts = attempts + 1, updated_at = ?
+        WHERE id = (
+            SELECT id FROM upload_jobs
+            WHERE status = 'queued'
+            ORDER BY created_at
+            LIMIT 1
+        )
+        RETURNING id, user_id, credential, ip_address, user_agent, status, stage, zip_path, options,
+                  files, album_id, error, attempts, created_at, updated_at, finished_at
+        ",
+    )
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(job)
+}
+
+/// Queues jobs that were being processed when the server stopped.
+pub async fn requeue_interrupted_upload_jobs(
+    pool: &DbPool,
+    now: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let result = query(
+        r"UPDATE upload_jobs SET status = 'queued', updated_at = ? WHERE status = 'processing'",
+    )
+    .bind(now)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn update_upload_job_progress(
+    pool: &DbPool,
+    id: &str,
+    stage: &str,
+    files: &str,
+    album_id: Option<&str>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        UPDATE upload_jobs
+        SET stage = ?, files = ?, album_id = COALESCE(?, album_id), updated_at = ?
+        WHERE id = ?
+        ",
+    )
+    .bind(stage)
+    .bind(files)
+    .bind(album_id)
+    .bind(now)
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Sets the final `status`, with the error response when the job failed.
+pub async fn finish_upload_job(
+    pool: &DbPool,
+    id: &str,
+    status: &str,
+    error: Option<&str>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        UPDATE upload_jobs
+        SET status = ?, error = ?, updated_at = ?, finished_at = ?
+        WHERE id = ?
+        ",
+    )
+    .bind(status)
+    .bind(error)
+    .bind(now)
+    .bind(now)
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn create_chunked_upload(pool: &DbPool, upload: &ChunkedUpload) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO chunked_uploads (id, user_id, kind, file_name, total_bytes, part_bytes, storage_key,
+                                     storage_upload_id, metadata, status, assembled_at, created_at,
+                                     updated_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "
+    )
+    .bind(&upload.id)
+    .bind(&upload.user_id)
+    .bind(&upload.kind)
+    .bind(&upload.file_name)
+    .bind(upload.total_bytes)
+    .bind(upload.part_bytes)
+    .bind(&upload.storage_key)
+    .bind(&upload.storage_upload_id)
+    .bind(&upload.metadata)
+    .bind(&upload.status)
+    .bind(upload.assembled_at)
+    .bind(upload.created_at)
+    .bind(upload.updated_at)
+    .bind(upload.expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_chunked_upload(pool: &DbPool, id: &str) -> anyhow::Result<Option<ChunkedUpload>> {
+    let upload = query_as::<_, ChunkedUpload>(
+        r"
+        SELECT id, user_id, kind, file_name, total_bytes, part_bytes, storage_key, storage_upload_id,
+               metadata, status, assembled_at, created_at, updated_at, expires_at
+        FROM chunked_uploads
+        WHERE id = ?
+        "
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(upload)
+}
+
+pub async fn get_chunked_upload_parts(
+    pool: &DbPool,
+    upload_id: &str,
+) -> anyhow::Result<Vec<ChunkedUploadPart>> {
+    let parts = query_as::<_, ChunkedUploadPart>(
+        r"
+        SELECT upload_id, part_number, size, checksum_sha256, etag, created_at
+        FROM chunked_upload_parts
+        WHERE upload_id = ?
+        ORDER BY part_number
+        ",
+    )
+    .bind(upload_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(parts)
+}
+
+/// Records a received part, replacing an earlier copy of it, and pushes the
+/// upload's expiry back to `expires_at`.
+pub async fn save_chunked_upload_part(
+    pool: &DbPool,
+    part: &ChunkedUploadPart,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    query(
+        r"
+        INSERT INTO chunked_upload_parts (upload_id, part_number, size, checksum_sha256, etag, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (upload_id, part_number) DO UPDATE
+        SET size = excluded.size, checksum_sha256 = excluded.checksum_sha256, etag = excluded.etag,
+            created_at = excluded.created_at
+        "
+    )
+    .bind(&part.upload_id)
+    .bind(part.part_number)
+    .bind(part.size)
+    .bind(&part.checksum_sha256)
+    .bind(&part.etag)
+    .bind(part.created_at)
+    .execute(&mut *tx)
+    .await?;
+
+    query(r"UPDATE chunked_uploads SET updated_at = ?, expires_at = ? WHERE id = ?")
+        .bind(part.created_at)
+        .bind(expires_at)
+        .bind(&part.upload_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Moves an upload from `from` to `to`, returning whether it was in `from`.
+/// Guards against two requests completing or aborting it at once.
+pub async fn transition_chunked_upload(
+    pool: &DbPool,
+    id: &str,
+    from: &str,
+    to: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<bool> {
+    let result =
+        query(r"UPDATE chunked_uploads SET status = ?, updated_at = ? WHERE id = ? AND status = ?")
+            .bind(to)
+            .bind(now)
+            .bind(id)
+            .bind(from)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_chunked_upload_assembled(
+    pool: &DbPool,
+    id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(r"UPDATE chunked_uploads SET assembled_at = ?, updated_at = ? WHERE id = ?")
+        .bind(now)
+        .bind(now)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Uploads nobody has touched since they expired, including ones a restart
+/// interrupted while completing.
+pub async fn get_expired_chunked_uploads(
+    pool: &DbPool,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Vec<ChunkedUpload>> {
+    let uploads = query_as::<_, ChunkedUpload>(
+        r"
+        SELECT id, user_id, kind, file_name, total_bytes, part_bytes, storage_key, storage_upload_id,
+               metadata, status, assembled_at, created_at, updated_at, expires_at
+        FROM chunked_uploads
+        WHERE status IN ('open', 'completing') AND expires_at < ?
+        "
+    )
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(uploads)
+}
+
+/// Whether a multipart upload in storage belongs to an upload still in use.
+pub async fn chunked_upload_in_use(pool: &DbPool, storage_upload_id: &str) -> anyhow::Result<bool> {
+    let row: Option<(i64,)> = query_as(
+        r"
+        SELECT 1 FROM chunked_uploads
+        WHERE storage_upload_id = ? AND status IN ('open', 'completing')
+        ",
+    )
+    .bind(storage_upload_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+pub async fn extend_chunked_upload(
+    pool: &DbPool,
+    id: &str,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(r"UPDATE chunked_uploads SET expires_at = ? WHERE id = ?")
+        .bind(expires_at)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_album_versions(
+    pool: &DbPool,
+    album_id: &str,
+) -> anyhow::Result<Vec<AlbumVersion>> {
+    let versions = query_as::<_, AlbumVersion>(
+        r"
+        SELECT id, album_id, version_number, label, release_status, upload_id, created_by, created_at
+        FROM album_versions
+        WHERE album_id = ?
+        ORDER BY version_number
+        "
+    )
+    .bind(album_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(versions)
+}
+
+pub async fn get_album_version(pool: &DbPool, id: &str) -> anyhow::Result<Option<AlbumVersion>> {
+    let version = query_as::<_, AlbumVersion>(
+        r"
+        SELECT id, album_id, version_number, label, release_status, upload_id, created_by, created_at
+        FROM album_versions
+        WHERE id = ?
+        "
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(version)
+}
+
+/// The version an album upload made, if it got that far.
+pub async fn get_album_version_by_upload(
+    pool: &DbPool,
+    upload_id: &str,
+) -> anyhow::Result<Option<AlbumVersion>> {
+    let version = query_as::<_, AlbumVersion>(
+        r"
+        SELECT id, album_id, version_number, label, release_status, upload_id, created_by, created_at
+        FROM album_versions
+        WHERE upload_id = ?
+        "
+    )
+    .bind(upload_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(version)
+}
+
+pub async fn get_album_version_tracks(
+    pool: &DbPool,
+    version_id: &str,
+) -> anyhow::Result<Vec<AlbumVersionTrack>> {
+    let tracks = query_as::<_, AlbumVersionTrack>(
+        r"
+        SELECT track_id, title, track_number, duration, file_path, original_filename
+        FROM album_version_tracks
+        WHERE version_id = ?
+        ORDER BY track_number, title
+        ",
+    )
+    .bind(version_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tracks)
+}
+
+/// `(version_id, tracks)` for each version of the album.
+pub async fn get_album_version_track_counts(
+    pool: &DbPool,
+    album_id: &str,
+) -> anyhow::Result<Vec<(String, i64)>> {
+    let counts = query_as::<_, (String, i64)>(
+        r"
+        SELECT v.id, COUNT(t.track_id)
+        FROM album_versions v
+        LEFT JOIN album_version_tracks t ON t.version_id = v.id
+        WHERE v.album_id = ?
+        GROUP BY v.id
+        ",
+    )
+    .bind(album_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(counts)
+}
+
+/// Makes `version` the album's current version, all or nothing: inserts
+/// `created`, overwrites `replaced` (same ids, new audio or metadata), saves
+/// `sources` as `(track_id, JSON)`, records `track_ids` as the version's
+/// tracks and supersedes the album's other tracks.
+///
+/// A `version` that already exists, i.e. an empty current version being
+/// filled, keeps its number; a new one is numbered after the last.
+pub async fn save_album_version(
+    pool: &DbPool,
+    version: &AlbumVersion,
+    created: &[Track],
+    replaced: &[Track],
+    sources: &[(String, String)],
+    track_ids: &[String],
+) -> anyhow::Result<AlbumVersion> {
+    let mut tx = pool.begin().await?;
+    query(
+        r"
+        INSERT INTO album_versions (id, album_id, version_number, label, release_status, upload_id,
+                                    created_by, created_at)
+        VALUES (?, ?, (SELECT COALESCE(MAX(version_number), 0) + 1 FROM album_versions WHERE album_id = ?),
+                ?, ?, ?, ?, ?)
+        ON CONFLICT (id) DO UPDATE
+        SET label = excluded.label, release_status = excluded.release_status, upload_id = excluded.upload_id
+        "
+    )
+    .bind(&version.id)
+    .bind(&version.album_id)
+    .bind(&version.album_id)
+    .bind(&version.label)
+    .bind(&version.release_status)
+    .bind(&version.upload_id)
+    .bind(&version.created_by)
+    .bind(version.created_at)
+    .execute(&mut *tx)
+    .await?;
+
+    for track in created {
+        insert_track(&mut *tx, track).await?;
+    }
+    for track in replaced {
+        query(
+            r"
+            UPDATE tracks
+            SET title = ?, duration = ?, file_path = ?, original_filename = ?, original_format = ?,
+                stored_format = ?, cover_art_path = ?, genre = ?, year = ?, track_number = ?, updated_at = ?
+            WHERE id = ?
+            "
+        )
+        .bind(&track.title)
+        .bind(track.duration)
+        .bind(&track.file_path)
+        .bind(&track.original_filename)
+        .bind(&track.original_format)
+        .bind(&track.stored_format)
+        .bind(&track.cover_art_path)
+        .bind(&track.genre)
+        .bind(track.year)
+        .bind(track.track_number)
+        .bind(track.updated_at)
+        .bind(&track.id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    for (track_id, track_sources) in sources {
+        set_track_sources(&mut tx, track_id, track_sources).await?;
+    }
+
+    query(r"DELETE FROM album_version_tracks WHERE version_id = ?")
+        .bind(&version.id)
+        .execute(&mut *tx)
+        .await?;
+    for track_id in track_ids {
+        query(
+            r"
+            INSERT INTO album_version_tracks (version_id, track_id, title, track_number, duration, file_path,
+                                              original_filename, original_format, stored_format, metadata_sources)
+            SELECT ?, id, title, track_number, duration, file_path, original_filename, original_format,
+                   stored_format, metadata_sources
+            FROM tracks
+            WHERE id = ?
+            "
+        )
+        .bind(&version.id)
+        .bind(track_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    make_version_current(&mut tx, &version.album_id, &version.id, version.created_at).await?;
+    tx.commit().await?;
+
+    get_album_version(pool, &version.id).await?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Album version {} disappeared after it was saved",
+            version.id
+        )
+    })
+}
+
+/// Makes an earlier version current again, putting each of its tracks back as
+/// it was then. Tracks deleted since are left out.
+pub async fn restore_album_version(
+    pool: &DbPool,
+    album_id: &str,
+    version_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    query(
+        r"
+        UPDATE tracks
+        SET title = v.title, track_number = v.track_number, duration = v.duration, file_path = v.file_path,
+            original_filename = v.original_filename, original_format = v.original_format,
+            stored_format = v.stored_format, metadata_sources = v.metadata_sources, updated_at = ?
+        FROM album_version_tracks v
+        WHERE v.version_id = ? AND v.track_id = tracks.id AND tracks.album_id = ?
+        "
+    )
+    .bind(now)
+    .bind(version_id)
+    .bind(album_id)
+    .execute(&mut *tx)
+    .await?;
+    make_version_current(&mut tx, album_id, version_id, now).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Points the album at `version_id` and supersedes its tracks that version
+/// leaves out.
+async fn make_version_current(
+    tx: &mut sqlx::SqliteConnection,
+    album_id: &str,
+    version_id: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        UPDATE tracks
+        SET superseded_at = CASE
+            WHEN id IN (SELECT track_id FROM album_version_tracks WHERE version_id = ?) THEN NULL
+            ELSE COALESCE(superseded_at, ?)
+        END
+        WHERE album_id = ?
+        ",
+    )
+    .bind(version_id)
+    .bind(now)
+    .bind(album_id)
+    .execute(&mut *tx)
+    .await?;
+
+    query(r"UPDATE albums SET current_version_id = ?, updated_at = ? WHERE id = ?")
+        .bind(version_id)
+        .bind(now)
+        .bind(album_id)
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+/// The CRC-32 of the stored file at `key`, if worked out before for this
+/// `ETag` of it.
+pub async fn get_file_checksum(
+    pool: &DbPool,
+    key: &str,
+    etag: &str,
+) -> anyhow::Result<Option<u32>> {
+    let row: Option<(i64,)> =
+        query_as(r"SELECT crc32 FROM file_checksums WHERE file_key = ? AND etag = ?")
+            .bind(key)
+            .bind(etag)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(row.and_then(|(crc32,)| u32::try_from(crc32).ok()))
+}
+
+pub async fn save_file_checksum(
+    pool: &DbPool,
+    key: &str,
+    etag: &str,
+    crc32: u32,
+    created_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO file_checksums (file_key, etag, crc32, created_at)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT (file_key, etag) DO NOTHING
+        ",
+    )
+    .bind(key)
+    .bind(etag)
+    .bind(i64::from(crc32))
+    .bind(created_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_library_files(pool: &DbPool) -> anyhow::Result<Vec<LibraryFile>> {
+    let files = query_as::<_, LibraryFile>(
+        r"
+        SELECT path, content_hash, size, modified_at, track_id, scanned_at
+        FROM library_files
+        ORDER BY path
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(files)
+}
+
+pub async fn save_library_file(pool: &DbPool, file: &LibraryFile) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO library_files (path, content_hash, size, modified_at, track_id, scanned_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (path) DO UPDATE SET
+            content_hash = excluded.content_hash,
+            size = excluded.size,
+            modified_at = excluded.modified_at,
+            track_id = excluded.track_id,
+            scanned_at = excluded.scanned_at
+        ",
+    )
+    .bind(&file.path)
+    .bind(&file.content_hash)
+    .bind(file.size)
+    .bind(file.modified_at)
+    .bind(&file.track_id)
+    .bind(file.scanned_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Records that the file at `from` is now `file.path`.
+pub async fn move_library_file(
+    pool: &DbPool,
+    from: &str,
+    file: &LibraryFile,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    query(r"DELETE FROM library_files WHERE path = ?")
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+    query(
+        r"
+        INSERT INTO library_files (path, content_hash, size, modified_at, track_id, scanned_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(&file.path)
+    .bind(&file.content_hash)
+    .bind(file.size)
+    .bind(file.modified_at)
+    .bind(&file.track_id)
+    .bind(file.scanned_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn delete_library_file(pool: &DbPool, path: &str) -> anyhow::Result<()> {
+    query(r"DELETE FROM library_files WHERE path = ?")
+        .bind(path)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn save_audio_fingerprint(
+    pool: &DbPool,
+    key: &str,
+    content_hash: &str,
+    fingerprint: Option<&[u8]>,
+    created_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    query(
+        r"
+        INSERT INTO audio_fingerprints (file_key, content_hash, fingerprint, created_at)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT (file_key) DO UPDATE SET
+            content_hash = excluded.content_hash,
+            fingerprint = excluded.fingerprint,
+            created_at = excluded.created_at
+        ",
+    )
+    .bind(key)
+    .bind(content_hash)
+    .bind(fingerprint)
+    .bind(created_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Storage keys of current tracks with nothing recorded in
+/// `audio_fingerprints`.
+pub async fn get_unfingerprinted_files(pool: &DbPool) -> anyhow::Result<Vec<String>> {
+    let rows: Vec<(String,)> = query_as(
+        r"
+        SELECT DISTINCT t.file_path
+        FROM tracks t
+        LEFT JOIN audio_fingerprints f ON f.file_key = t.file_path
+        WHERE t.superseded_at IS NULL AND f.file_key IS NULL
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|(key,)| key).collect())
+}
+
+/// `(track_id, content_hash, fingerprint)` of every current track whose
+/// file was recorded.
+pub async fn get_track_fingerprints(
+    pool: &DbPool,
+) -> anyhow::Result<Vec<(String, String, Option<Vec<u8>>)>> {
+    let rows = query_as(
+        r"
+        SELECT t.id, f.content_hash, f.fingerprint
+        FROM tracks t
+        JOIN audio_fingerprints f ON f.file_key = t.file_path
+        WHERE t.superseded_at IS NULL
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows)
+}
+
+/// Playlist entries and plays moved over by [`merge_tracks`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MergedCounts {
+    pub playlist_tracks: u64,
+    pub plays: u64,
+}
+
+/// Points the playlist entries, plays and library files of each track in
+/// `remove` at `keep`, then deletes them. A playlist that has both keeps the
+/// entry it already had for `keep`.
+pub async fn merge_tracks(
+    pool: &DbPool,
+    keep: &str,
+    remove: &[String],
+) -> anyhow::Result<MergedCounts> {
+    let mut tx = pool.begin().await?;
+    let mut counts = MergedCounts::default();
+    for track_id in remove {
+        query(
+            r"
+            DELETE FROM playlist_tracks
+            WHERE track_id = ?
+              AND playlist_id IN (SELECT playlist_id FROM playlist_tracks WHERE track_id = ?)
+            ",
+        )
+        .bind(track_id)
+        .bind(keep)
+        .execute(&mut *tx)
+        .await?;
+        counts.playlist_tracks +=
+            query(r"UPDATE playlist_tracks SET track_id = ? WHERE track_id = ?")
+                .bind(keep)
+                .bind(track_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        counts.plays += query(r"UPDATE play_history SET track_id = ? WHERE track_id = ?")
+            .bind(keep)
+            .bind(track_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        query(r"UPDATE library_files SET track_id = ? WHERE track_id = ?")
+            .bind(keep)
+            .bind(track_id)
+            .execute(&mut *tx)
+            .await?;
+        query(r"DELETE FROM tracks WHERE id = ?")
+            .bind(track_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(counts)
+}
+
+/// Whether a track or a saved album version still points to the stored
+/// object `key`.
+pub async fn is_file_referenced(pool: &DbPool, key: &str) -> anyhow::Result<bool> {
+    let (referenced,): (bool,) = query_as(
+        r"
+        SELECT EXISTS (SELECT 1 FROM tracks WHERE file_path = ?)
+            OR EXISTS (SELECT 1 FROM album_version_tracks WHERE file_path = ?)
+        ",
+    )
+    .bind(key)
+    .bind(key)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(referenced)
+}
+
+pub async fn delete_audio_fingerprint(pool: &DbPool, key: &str) -> anyhow::Result<()> {
+    query(r"DELETE FROM audio_fingerprints WHERE file_key = ?")
+        .bind(key)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn album_with_artist(pool: &DbPool, title: &str) -> Album {
+        let user = create_user(
+            pool,
+            CreateUser {
+                username: format!("{title}-owner"),
+                email: format!("{title}@example.com"),
+                password_hash: "not-a-real-hash".to_string(),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user");
+        let now = Utc::now();
+        let artist = Artist {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: format!("{title} Artist"),
+            normalized_name: format!("{title} artist"),
+            bio: None,
+            website: None,
+            created_by: Some(user.id.clone()),
+            created_at: now,
+            updated_at: now,
+        };
+        let artist = create_artist(pool, artist, &user.id).await.expect("artist");
+        get_or_create_album(pool, Album::new(&artist.id, title, &user.id))
+            .await
+            .expect("album")
+    }
+
+    /// Track `number` of `album`, stored as `file`.
+    fn song(album: &Album, number: i32, file: &str) -> Track {
+        let now = Utc::now();
+        Track {
+            id: uuid::Uuid::new_v4().to_string(),
+            title: format!("Song {number}"),
+            artist: "Artist".to_string(),
+            artist_id: Some(album.artist_id.clone()),
+            album: album.title.clone(),
+            album_id: Some(album.id.clone()),
+            duration: 180,
+            file_path: format!("music/{}/{}/{file}", album.artist_id, album.id),
+            original_filename: Some(file.to_string()),
+            original_format: None,
+            stored_format: None,
+            cover_art_path: None,
+            genre: None,
+            year: None,
+            track_number: Some(number),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// `(file_path, superseded)` of track `id`.
+    async fn track_state(pool: &DbPool, id: &str) -> (String, bool) {
+        let (file_path, superseded_at): (String, Option<DateTime<Utc>>) =
+            query_as(r"SELECT file_path, superseded_at FROM tracks WHERE id = ?")
+                .bind(id)
+                .fetch_one(pool)
+                .await
+                .expect("track");
+        (file_path, superseded_at.is_some())
+    }
+
+    #[tokio::test]
+    async fn deletes_albums_only_while_empty() {
+        let pool = crate::db::test_pool().await;
+        let empty = album_with_artist(&pool, "empty").await;
+        let used = album_with_artist(&pool, "used").await;
+        let track = song(&used, 1, "song.flac");
+        create_track(&pool, track, "{}").await.expect("track");
+
+        assert!(!delete_album_if_empty(&pool, &used.id)
+            .await
+            .expect("delete"));
+        assert!(get_album_by_id(&pool, &used.id)
+            .await
+            .expect("lookup")
+            .is_some());
+
+        assert!(delete_album_if_empty(&pool, &empty.id)
+            .await
+            .expect("delete"));
+        assert!(get_album_by_id(&pool, &empty.id)
+            .await
+            .expect("lookup")
+            .is_none());
+        assert!(get_album_versions(&pool, &empty.id)
+            .await
+            .expect("versions")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn restoring_a_version_brings_back_its_tracks_and_audio() {
+        let pool = crate::db::test_pool().await;
+        let album = album_with_artist(&pool, "versions").await;
+        let first_version = album.current_version_id.clone().expect("first version");
+        let one = create_track(&pool, song(&album, 1, "one.flac"), "{}")
+            .await
+            .expect("track");
+        let two = create_track(&pool, song(&album, 2, "two.flac"), "{}")
+            .await
+            .expect("track");
+
+        // A new version replaces the audio of track 1, adds track 3 and drops track 2.
+        let replaced = Track {
+            file_path: format!("music/{}/{}/one-remaster.flac", album.artist_id, album.id),
+            ..one.clone()
+        };
+        let three = song(&album, 3, "three.flac");
+        let now = Utc::now();
+        let version = AlbumVersion {
+            id: uuid::Uuid::new_v4().to_string(),
+            album_id: album.id.clone(),
+            version_number: 0,
+            label: Some("remaster".to_string()),
+            release_status: "released".to_string(),
+            upload_id: None,
+            created_by: album.created_by.clone(),
+            created_at: now,
+        };
+        let saved = save_album_version(
+            &pool,
+            &version,
+            std::slice::from_ref(&three),
+            std::slice::from_ref(&replaced),
+            &[],
+            &[one.id.clone(), three.id.clone()],
+        )
+        .await
+        .expect("save version");
+        assert_eq!(saved.version_number, 2);
+
+        let current = get_album_by_id(&pool, &album.id)
+            .await
+            .expect("album")
+            .expect("album");
+        assert_eq!(
+            current.current_version_id.as_deref(),
+            Some(version.id.as_str())
+        );
+        assert_eq!(
+            track_state(&pool, &one.id).await,
+            (replaced.file_path.clone(), false)
+        );
+        assert_eq!(
+            track_state(&pool, &two.id).await,
+            (two.file_path.clone(), true)
+        );
+        assert_eq!(
+            track_state(&pool, &three.id).await,
+            (three.file_path.clone(), false)
+        );
+
+        restore_album_version(&pool, &album.id, &first_version, Utc::now())
+            .await
+            .expect("restore");
+
+        let current = get_album_by_id(&pool, &album.id)
+            .await
+            .expect("album")
+            .expect("album");
+        assert_eq!(
+            current.current_version_id.as_deref(),
+            Some(first_version.as_str())
+        );
+        assert_eq!(
+            track_state(&pool, &one.id).await,
+            (one.file_path.clone(), false)
+        );
+        assert_eq!(
+            track_state(&pool, &two.id).await,
+            (two.file_path.clone(), false)
+        );
+        assert_eq!(
+            track_state(&pool, &three.id).await,
+            (three.file_path.clone(), true)
+        );
+
+        // The newer version still points at the audio it replaced the track with.
+        let tracks = get_album_version_tracks(&pool, &version.id)
+            .await
+            .expect("version tracks");
+        let paths: Vec<&str> = tracks
+            .iter()
+            .map(|track| track.file_path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            [replaced.file_path.as_str(), three.file_path.as_str()]
+        );
+    }
+
+    async fn playlist(pool: &DbPool, name: &str, tracks: &[&Track]) -> String {
+        let now = Utc::now();
+        let playlist = create_playlist(
+            pool,
+            Playlist {
+                id: uuid::Uuid::new_v4().to_string(),
+                user_id: None,
+                name: name.to_string(),
+                description: None,
+                created_at: now,
+                updated_at: now,
+            },
+        )
+        .await
+        .expect("playlist");
+        for (position, track) in (1..).zip(tracks) {
+            add_track_to_playlist(pool, &playlist.id, &track.id, position)
+                .await
+                .expect("playlist track");
+        }
+        playlist.id
+    }
+
+    async fn plays_of(pool: &DbPool, track_id: &str) -> i64 {
+        let (plays,): (i64,) = query_as(r"SELECT COUNT(*) FROM play_history WHERE track_id = ?")
+            .bind(track_id)
+            .fetch_one(pool)
+            .await
+            .expect("plays");
+        plays
+    }
+
+    #[tokio::test]
+    async fn merging_tracks_moves_playlist_entries_and_plays() {
+        let pool = crate::db::test_pool().await;
+        let album = album_with_artist(&pool, "merge").await;
+        let keep = create_track(&pool, song(&album, 1, "one.flac"), "{}")
+            .await
+            .expect("track");
+        let copy = create_track(&pool, song(&album, 1, "one.mp3"), "{}")
+            .await
+            .expect("track");
+        let other = create_track(&pool, song(&album, 2, "two.flac"), "{}")
+            .await
+            .expect("track");
+
+        let only_copy = playlist(&pool, "only copy", &[&copy, &other]).await;
+        let both = playlist(&pool, "both", &[&copy, &other, &keep]).await;
+        record_play(&pool, &copy.id, None, Some(180))
+            .await
+            .expect("play");
+        record_play(&pool, &copy.id, None, None)
+            .await
+            .expect("play");
+        record_play(&pool, &keep.id, None, None)
+            .await
+            .expect("play");
+
+        let counts = merge_tracks(&pool, &keep.id, std::slice::from_ref(&copy.id))
+            .await
+            .expect("merge");
+        assert_eq!((counts.playlist_tracks, counts.plays), (1, 2));
+
+        let ids = |tracks: Vec<Track>| -> Vec<String> {
+            tracks.into_iter().map(|track| track.id).collect()
+        };
+        assert_eq!(
+            ids(get_playlist_tracks(&pool, &only_copy)
+                .await
+                .expect("tracks")),
+            [keep.id.clone(), other.id.clone()]
+        );
+        // The playlist already had the kept track, so it is not listed twice.
+        assert_eq!(
+            ids(get_playlist_tracks(&pool, &both).await.expect("tracks")),
+            [other.id.clone(), keep.id.clone()]
+        );
+        assert_eq!(plays_of(&pool, &keep.id).await, 3);
+        assert_eq!(plays_of(&pool, &copy.id).await, 0);
+        assert!(get_track_by_id(&pool, &copy.id)
+            .await
+            .expect("lookup")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn merging_two_copies_in_one_playlist_keeps_a_single_entry() {
+        let pool = crate::db::test_pool().await;
+        let album = album_with_artist(&pool, "copies").await;
+        let keep = create_track(&pool, song(&album, 1, "one.flac"), "{}")
+            .await
+            .expect("track");
+        let mp3 = create_track(&pool, song(&album, 1, "one.mp3"), "{}")
+            .await
+            .expect("track");
+        let ogg = create_track(&pool, song(&album, 1, "one.ogg"), "{}")
+            .await
+            .expect("track");
+        let list = playlist(&pool, "copies", &[&mp3, &ogg]).await;
+
+        let counts = merge_tracks(&pool, &keep.id, &[mp3.id.clone(), ogg.id.clone()])
+            .await
+            .expect("merge");
+        assert_eq!(counts.playlist_tracks, 1);
+
+        let tracks = get_playlist_tracks(&pool, &list).await.expect("tracks");
+        let ids: Vec<&str> = tracks.iter().map(|track| track.id.as_str()).collect();
+        assert_eq!(ids, [keep.id.as_str()]);
+    }
+}
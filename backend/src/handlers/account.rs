@@ -0,0 +1,270 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{
+        email_token::{self, EmailTokenPurpose},
+        password,
+        rate_limit::{self, AuthAction},
+        AuthUser, ClientInfo,
+    },
+    db::queries,
+    AppState,
+};
+
+use super::{auth::validate_password, ApiError};
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmPasswordResetRequest {
+    pub token: String,
+    pub password: String,
+}
+
+/// Sends the logged-in user a new verification link.
+pub async fn request_email_verification(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    user.require_session()?;
+    let account = queries::get_user_by_id(&state.db, &user.user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+
+    if account.email_verified {
+        return Err(ApiError::conflict("Email is already verified"));
+    }
+
+    email_token::send_verification(&state.db, state.mailer.as_ref(), &state.config, &account)
+        .await?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "message": "Verification email sent" })),
+    ))
+}
+
+pub async fn confirm_email_verification(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<ConfirmEmailRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let token = email_token::redeem(
+        &state.db,
+        &state.config.auth,
+        &payload.token,
+        EmailTokenPurpose::VerifyEmail,
+    )
+    .await?;
+
+    // The address changed since the link was sent.
+    if !queries::set_email_verified(&state.db, &token.user_id, &token.email).await? {
+        return Err(ApiError::bad_request(
+            "This link was sent to a different email address",
+        ));
+    }
+
+    let event = AuditEvent::new(AuditAction::EmailVerified)
+        .actor(&token.user_id)
+        .target("user", &token.user_id)
+        .diff(json!({ "email": token.email }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(json!({ "message": "Email verified" })))
+}
+
+/// Always answers the same way, so it can't be used to find out which
+/// addresses have accounts.
+pub async fn request_password_reset(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<PasswordResetRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let email = payload.email.trim().to_lowercase();
+
+    // Sending happens in the background so response times don't reveal
+    // whether the account exists either.
+    tokio::spawn(async move {
+        let result = send_password_reset(&state, &email, &client).await;
+        if let Err(err) = result {
+            tracing::error!("Failed to send password reset email: {err:#}");
+        }
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "message": "If an account exists for that address, a password reset link has been sent"
+        })),
+    )
+}
+
+async fn send_password_reset(
+    state: &AppState,
+    email: &str,
+    client: &ClientInfo,
+) -> anyhow::Result<()> {
+    let Some(user) = queries::get_user_by_email(&state.db, email).await? else {
+        return Ok(());
+    };
+
+    if email_token::send_password_reset(&state.db, state.mailer.as_ref(), &state.config, &user)
+        .await?
+    {
+        let event = AuditEvent::new(AuditAction::PasswordResetRequested).target("user", &user.id);
+        audit::record(&state.db, event, client).await;
+    }
+
+    Ok(())
+}
+
+/// Sets a new password from a reset link and logs the account out everywhere.
+pub async fn confirm_password_reset(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<ConfirmPasswordResetRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    // Checked first so a rejected password doesn't use up the link.
+    validate_password(&payload.password)?;
+
+    let token = email_token::redeem(
+        &state.db,
+        &state.config.auth,
+        &payload.token,
+        EmailTokenPurpose::ResetPassword,
+    )
+    .await?;
+
+    let user = queries::get_user_by_id(&state.db, &token.user_id)
+        .await?
+        .filter(|user| user.email == token.email)
+        .ok_or_else(|| ApiError::bad_request("This link was sent to a different email address"))?;
+
+    let password_hash =
+        tokio::task::spawn_blocking(move || password::hash_password(&payload.password)).await??;
+    queries::set_user_password(&state.db, &user.id, &password_hash).await?;
+
+    let now = Utc::now();
+    let revoked = queries::revoke_all_sessions_for_user(&state.db, &user.id, now).await?;
+    // Following the link proves the user reads this mailbox.
+    queries::set_email_verified(&state.db, &user.id, &token.email).await?;
+    // Counts as a successful login, which lifts any lockout.
+    rate_limit::record_attempt(
+        &state.db,
+        AuthAction::Login,
+        Some(&user.username),
+        Some(&user.id),
+        client.ip_address.as_deref(),
+        true,
+    )
+    .await?;
+
+    tracing::info!(
+        "Reset password for {}, revoked {revoked} sessions",
+        user.username
+    );
+
+    let event = AuditEvent::new(AuditAction::PasswordReset)
+        .actor(&user.id)
+        .target("user", &user.id)
+        .diff(json!({ "sessions_revoked": revoked }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(
+        json!({ "message": "Password updated, please log in again" }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{auth::session, db::models::CreateUser, storage::mock::MockBucket};
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            ip_address: Some("127.0.0.1".to_string()),
+            user_agent: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resetting_a_password_uses_up_the_link_and_logs_out() {
+        let db = crate::db::test_pool().await;
+        let storage = MockBucket::default().serve().await;
+        let state = Arc::new(AppState::for_tests(db.clone(), storage));
+        let user = queries::create_user(
+            &db,
+            CreateUser {
+                username: "listener".to_string(),
+                email: "listener@example.com".to_string(),
+                password_hash: password::hash_password("old password").expect("hash"),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user");
+        let (old_session, _) = session::start_session(&db, &state.config.auth, &user.id, &client())
+            .await
+            .expect("session");
+        let token = email_token::issue(
+            &db,
+            &state.config.auth,
+            &user,
+            EmailTokenPurpose::ResetPassword,
+            Duration::minutes(30),
+        )
+        .await
+        .expect("issue")
+        .expect("token");
+        let confirm = |password: &str| {
+            confirm_password_reset(
+                State(state.clone()),
+                client(),
+                Json(ConfirmPasswordResetRequest {
+                    token: token.clone(),
+                    password: password.to_string(),
+                }),
+            )
+        };
+
+        let err = confirm("short").await.expect_err("too short");
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+        // The rejected password left the link usable.
+        let Json(body) = confirm("a much better password").await.expect("reset");
+        assert_eq!(body["message"], "Password updated, please log in again");
+        let updated = queries::get_user_by_id(&db, &user.id)
+            .await
+            .expect("lookup")
+            .expect("user");
+        assert!(password::verify_password(
+            "a much better password",
+            &updated.password_hash
+        ));
+        assert!(updated.email_verified);
+        assert!(session::validate_session(&db, &old_session.id, &user.id)
+            .await
+            .expect("validate")
+            .is_none());
+
+        let err = confirm("another password").await.expect_err("used");
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,348 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{AdminUser, ClientInfo},
+    db::{
+        models::{AuditLogEntry, Track},
+        queries,
+    },
+    ingest::{
+        duplicates::{self, DuplicateCluster, PrintedTrack},
+        fingerprint,
+        library::{self, ScanStatus},
+        store,
+    },
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Deserialize)]
+pub struct SetAdminRequest {
+    is_admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// User id of whoever performed the action.
+    actor: Option<String>,
+    /// Exact action, or a prefix ending in `.` such as `track.`.
+    action: Option<String>,
+    target_type: Option<String>,
+    target_id: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Return entries older than this id, from `next_before` of the last page.
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScanQuery {
+    /// Report what would change without changing it.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DuplicatesQuery {
+    /// How alike fingerprints must be, from 0.5 to 1.
+    min_similarity: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuplicatesResponse {
+    clusters: Vec<DuplicateCluster>,
+    /// Current tracks whose files haven't been fingerprinted yet.
+    unfingerprinted: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeRequest {
+    /// Track that stays.
+    keep: String,
+    /// Tracks folded into `keep` and deleted.
+    remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergeResponse {
+    track: Track,
+    removed: Vec<String>,
+    /// Playlist entries now pointing at `track`.
+    playlist_tracks: u64,
+    /// Plays now counted for `track`.
+    plays: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntryResponse {
+    id: i64,
+    actor_id: Option<String>,
+    action: String,
+    target_type: Option<String>,
+    target_id: Option<String>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    diff: Option<serde_json::Value>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<AuditLogEntry> for AuditEntryResponse {
+    fn from(entry: AuditLogEntry) -> Self {
+        Self {
+            id: entry.id,
+            actor_id: entry.actor_id,
+            action: entry.action,
+            target_type: entry.target_type,
+            target_id: entry.target_id,
+            ip_address: entry.ip_address,
+            user_agent: entry.user_agent,
+            diff: entry.diff.and_then(|diff| serde_json::from_str(&diff).ok()),
+            created_at: entry.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogResponse {
+    entries: Vec<AuditEntryResponse>,
+    /// Pass as `before` to fetch the next page, absent on the last page.
+    next_before: Option<i64>,
+}
+
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 500;
+
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<navicore_music_shared::User>>, ApiError> {
+    let users = queries::get_all_users(&state.db)
+        .await?
+        .into_iter()
+        .map(navicore_music_shared::User::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Json(users))
+}
+
+pub async fn set_user_admin(
+    State(state): State<Arc<AppState>>,
+    AdminUser(admin): AdminUser,
+    client: ClientInfo,
+    Path(user_id): Path<String>,
+    Json(payload): Json<SetAdminRequest>,
+) -> Result<Json<navicore_music_shared::User>, ApiError> {
+    let user = queries::get_user_by_id(&state.db, &user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+
+    if user.is_admin && !payload.is_admin && queries::count_admins(&state.db).await? <= 1 {
+        return Err(ApiError::conflict("Cannot remove the last admin"));
+    }
+
+    queries::set_user_admin(&state.db, &user.id, payload.is_admin).await?;
+    tracing::info!(
+        "{} set is_admin={} on {}",
+        admin.username,
+        payload.is_admin,
+        user.username
+    );
+
+    let event = AuditEvent::new(AuditAction::AdminChanged)
+        .actor(&admin.id)
+        .target("user", &user.id)
+        .diff(json!({
+            "before": { "is_admin": user.is_admin },
+            "after": { "is_admin": payload.is_admin },
+        }));
+    audit::record(&state.db, event, &client).await;
+
+    let mut user = user;
+    user.is_admin = payload.is_admin;
+    Ok(Json(user.try_into()?))
+}
+
+pub async fn list_audit_log(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuditQuery>,
+) -> Result<Json<AuditLogResponse>, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    if !(1..=MAX_AUDIT_LIMIT).contains(&limit) {
+        return Err(ApiError::bad_request(format!(
+            "limit must be between 1 and {MAX_AUDIT_LIMIT}"
+        )));
+    }
+
+    let filter = queries::AuditLogFilter {
+        actor_id: params.actor.as_deref(),
+        action: params.action.as_deref(),
+        target_type: params.target_type.as_deref(),
+        target_id: params.target_id.as_deref(),
+        since: params.since,
+        until: params.until,
+        before_id: params.before,
+    };
+    let entries = queries::get_audit_log(&state.db, &filter, limit).await?;
+
+    let next_before = entries
+        .last()
+        .filter(|_| i64::try_from(entries.len()).is_ok_and(|count| count == limit))
+        .map(|entry| entry.id);
+
+    Ok(Json(AuditLogResponse {
+        entries: entries.into_iter().map(AuditEntryResponse::from).collect(),
+        next_before,
+    }))
+}
+
+/// `POST /api/v1/admin/library/scan`: starts bringing the catalog in line
+/// with `LIBRARY_DIR`, see [`library`]. `GET` on the same path says how it
+/// went.
+pub async fn scan_library(
+    State(state): State<Arc<AppState>>,
+    AdminUser(admin): AdminUser,
+    client: ClientInfo,
+    Query(params): Query<ScanQuery>,
+) -> Result<(StatusCode, Json<ScanStatus>), ApiError> {
+    let root = state.config.library.root.as_deref().ok_or_else(|| {
+        ApiError::not_found("No library directory is configured, set LIBRARY_DIR")
+    })?;
+    let Some(status) = library::start_scan(&state, root.into(), &admin.id, client, params.dry_run)
+    else {
+        return Err(
+            ApiError::conflict("A library scan is already running").with_code("scan_running")
+        );
+    };
+    tracing::info!(
+        "{} started a library scan{}",
+        admin.username,
+        if params.dry_run { " (dry run)" } else { "" }
+    );
+
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+/// `GET /api/v1/admin/library/scan`: the last scan started through the API.
+pub async fn library_scan_status(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ScanStatus>, ApiError> {
+    state
+        .library_scans
+        .last()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No library scan has been started"))
+}
+
+/// `GET /api/v1/admin/duplicates`: current tracks that are the same file, or
+/// sound the same, see [`duplicates`].
+pub async fn list_duplicates(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DuplicatesQuery>,
+) -> Result<Json<DuplicatesResponse>, ApiError> {
+    let min_similarity = params
+        .min_similarity
+        .unwrap_or(duplicates::DEFAULT_MIN_SIMILARITY);
+    if !(0.5..=1.0).contains(&min_similarity) {
+        return Err(ApiError::bad_request(
+            "min_similarity must be between 0.5 and 1",
+        ));
+    }
+
+    let mut tracks: HashMap<String, Track> = queries::get_all_tracks(&state.db)
+        .await?
+        .into_iter()
+        .map(|track| (track.id.clone(), track))
+        .collect();
+    let printed: Vec<PrintedTrack> = queries::get_track_fingerprints(&state.db)
+        .await?
+        .into_iter()
+        .filter_map(|(track_id, content_hash, print)| {
+            Some(PrintedTrack {
+                track: tracks.remove(&track_id)?,
+                content_hash,
+                fingerprint: print.as_deref().map(fingerprint::from_bytes),
+            })
+        })
+        .collect();
+    let unfingerprinted = tracks.len();
+
+    let clusters =
+        tokio::task::spawn_blocking(move || duplicates::find(printed, min_similarity)).await?;
+
+    Ok(Json(DuplicatesResponse {
+        clusters,
+        unfingerprinted,
+    }))
+}
+
+/// `POST /api/v1/admin/duplicates/merge`: folds duplicates into the track
+/// that stays, moving their playlist entries and plays over to it.
+pub async fn merge_duplicates(
+    State(state): State<Arc<AppState>>,
+    AdminUser(admin): AdminUser,
+    client: ClientInfo,
+    Json(payload): Json<MergeRequest>,
+) -> Result<Json<MergeResponse>, ApiError> {
+    let mut remove = payload.remove;
+    remove.sort();
+    remove.dedup();
+    if remove.is_empty() {
+        return Err(ApiError::bad_request("Name at least one track to remove"));
+    }
+    if remove.contains(&payload.keep) {
+        return Err(ApiError::bad_request(
+            "The track to keep can't also be removed",
+        ));
+    }
+
+    let track = queries::get_track_by_id(&state.db, &payload.keep)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Track to keep not found"))?;
+    let mut removed = Vec::with_capacity(remove.len());
+    for track_id in &remove {
+        let duplicate = queries::get_track_by_id(&state.db, track_id)
+            .await?
+            .ok_or_else(|| ApiError::not_found(format!("Track {track_id} not found")))?;
+        removed.push(duplicate);
+    }
+
+    let counts = queries::merge_tracks(&state.db, &track.id, &remove).await?;
+    tracing::info!(
+        "{} merged {} tracks into {}",
+        admin.username,
+        remove.len(),
+        track.id
+    );
+
+    let event = AuditEvent::new(AuditAction::TracksMerged)
+        .actor(&admin.id)
+        .target("track", &track.id)
+        .diff(json!({
+            "removed": removed,
+            "playlist_tracks": counts.playlist_tracks,
+            "plays": counts.plays,
+        }));
+    audit::record(&state.db, event, &client).await;
+
+    // Earlier versions of an album may still play a removed track's file.
+    for duplicate in &removed {
+        store::remove_unreferenced(&state, &duplicate.file_path).await;
+    }
+
+    Ok(Json(MergeResponse {
+        track,
+        removed: remove,
+        playlist_tracks: counts.playlist_tracks,
+        plays: counts.plays,
+    }))
+}
@@ -0,0 +1,328 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use std::{fmt::Write as _, ops::Range, sync::Arc};
+use tokio::io::DuplexStream;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::AuthUser,
+    db::queries,
+    ingest::{
+        export::{self, AlbumExport, ExportFormat},
+        manifest::base_name,
+    },
+    storage::zip_stream::{ZipEntry, ZipStream},
+    AppState,
+};
+
+use super::{albums::find_album, ApiError};
+
+/// Bytes buffered between the archive writer and the response.
+const STREAM_BUFFER: usize = 256 * 1024;
+
+/// What a request's `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum Requested {
+    Whole,
+    Part(Range<u64>),
+    Unsatisfiable,
+}
+
+/// `GET /api/v1/albums/{id}/download`: the album's current tracks and cover
+/// as a ZIP, with a freshly generated `album.yaml` so it can be edited and
+/// uploaded again.
+///
+/// The archive is written while it is sent, reading each file from storage
+/// in turn. The same album always gives the same bytes, so `Range` requests
+/// can resume an interrupted download; `If-Range` with the `ETag` makes sure
+/// the album hasn't changed in between.
+pub async fn download_album(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let album = find_album(&state, &user, &id).await?;
+    let artist = queries::get_artist_by_id(&state.db, &album.artist_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Artist not found"))?;
+    let version = match &album.current_version_id {
+        Some(version_id) => queries::get_album_version(&state.db, version_id).await?,
+        None => None,
+    };
+    let tracks = queries::get_album_tracks(&state.db, &album.id).await?;
+    let sources = queries::get_album_track_sources(&state.db, &album.id).await?;
+
+    // Dated by the last change rather than today, so a download resumed the
+    // next day still gets the same archive.
+    let changed = tracks
+        .iter()
+        .map(|track| track.updated_at)
+        .chain([album.updated_at])
+        .max()
+        .unwrap_or(album.updated_at);
+    let manifest = AlbumExport::new(&album, &artist.name, version.as_ref(), &tracks, &sources)
+        .render(ExportFormat::Yaml, changed.date_naive())?;
+
+    let mut entries = vec![ZipEntry::inline(
+        ExportFormat::Yaml.file_name(),
+        changed,
+        manifest.into_bytes(),
+    )];
+    if let Some(cover) = &album.cover_art_path {
+        entries.push(stored_entry(&state, base_name(cover), cover, album.updated_at).await?);
+    }
+    for (track, file) in tracks.iter().zip(export::track_files(&tracks)) {
+        entries.push(stored_entry(&state, &file, &track.file_path, track.updated_at).await?);
+    }
+
+    let zip = ZipStream::new(entries);
+    let etag = zip.etag();
+    let len = zip.len();
+    let (status, range) = match requested_range(&headers, &etag, len) {
+        Requested::Whole => (StatusCode::OK, 0..len),
+        Requested::Part(range) => (StatusCode::PARTIAL_CONTENT, range),
+        Requested::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+            )
+                .into_response());
+        }
+    };
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    response_headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&attachment(&format!(
+            "{} - {}.zip",
+            artist.name, album.title
+        )))?,
+    );
+    response_headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(range.end - range.start),
+    );
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::ETAG, HeaderValue::from_str(&etag)?);
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+        response_headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&content_range)?,
+        );
+    }
+
+    let (writer, reader) = tokio::io::duplex(STREAM_BUFFER);
+    tokio::spawn(write_archive(Arc::clone(&state), zip, range, writer));
+
+    Ok((
+        status,
+        response_headers,
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+/// An archive entry for a stored file, with its checksum if already known.
+async fn stored_entry(
+    state: &AppState,
+    name: &str,
+    key: &str,
+    modified: DateTime<Utc>,
+) -> anyhow::Result<ZipEntry> {
+    let (size, etag) = state.storage.head(key).await?;
+    let crc32 = match &etag {
+        Some(etag) => queries::get_file_checksum(&state.db, key, etag).await?,
+        None => None,
+    };
+
+    Ok(ZipEntry::stored(name, modified, key, size, etag, crc32))
+}
+
+/// Writes the archive into the response, then keeps the checksums it had to
+/// work out. A download cut short just ends the response early.
+async fn write_archive(
+    state: Arc<AppState>,
+    mut zip: ZipStream,
+    range: Range<u64>,
+    mut out: DuplexStream,
+) {
+    let written = zip.write_range(&state.storage, range, &mut out).await;
+    if let Err(err) = written {
+        debug!("Album download ended early: {err:#}");
+    }
+    drop(out);
+
+    let now = Utc::now();
+    for (key, etag, crc32) in zip.learned() {
+        let saved = queries::save_file_checksum(&state.db, key, etag, crc32, now).await;
+        if let Err(err) = saved {
+            warn!("Failed to save the checksum of {key}: {err:#}");
+        }
+    }
+}
+
+/// The single byte range a request asks for. Anything else, including a
+/// range for an archive whose `ETag` no longer matches `If-Range`, gets the
+/// whole archive.
+fn requested_range(headers: &HeaderMap, etag: &str, len: u64) -> Requested {
+    let Some(spec) = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return Requested::Whole;
+    };
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        if if_range.to_str().ok().map(str::trim) != Some(etag) {
+            return Requested::Whole;
+        }
+    }
+    if spec.contains(',') {
+        return Requested::Whole;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Requested::Whole;
+    };
+
+    let range = if start.is_empty() {
+        // `bytes=-500` is the last 500 bytes.
+        match end.parse::<u64>() {
+            Ok(0) => return Requested::Unsatisfiable,
+            Ok(suffix) => len.saturating_sub(suffix)..len,
+            Err(_) => return Requested::Whole,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return Requested::Whole;
+        };
+        let end = if end.is_empty() {
+            len
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(len),
+                Ok(_) | Err(_) => return Requested::Whole,
+            }
+        };
+        start..end
+    };
+
+    if range.start >= len {
+        Requested::Unsatisfiable
+    } else {
+        Requested::Part(range)
+    }
+}
+
+/// `Content-Disposition` for a download named `file_name`, with an ASCII
+/// fallback for clients that don't read `filename*`.
+fn attachment(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && !matches!(c, '"' | '\\' | '/') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"abc\"";
+    const LEN: u64 = 1000;
+
+    fn requested(range: &str, if_range: Option<&str>) -> Requested {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).expect("range"));
+        if let Some(if_range) = if_range {
+            headers.insert(
+                header::IF_RANGE,
+                HeaderValue::from_str(if_range).expect("if-range"),
+            );
+        }
+        requested_range(&headers, ETAG, LEN)
+    }
+
+    #[test]
+    fn reads_single_ranges() {
+        assert_eq!(requested("bytes=0-99", None), Requested::Part(0..100));
+        assert_eq!(
+            requested("bytes=900-2000", None),
+            Requested::Part(900..1000)
+        );
+        assert_eq!(requested(" bytes= 10-10 ", None), Requested::Part(10..11));
+    }
+
+    #[test]
+    fn reads_open_ended_and_suffix_ranges() {
+        assert_eq!(requested("bytes=500-", None), Requested::Part(500..1000));
+        assert_eq!(requested("bytes=-100", None), Requested::Part(900..1000));
+        // A suffix longer than the archive is the whole of it.
+        assert_eq!(requested("bytes=-5000", None), Requested::Part(0..1000));
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        assert_eq!(requested("bytes=1000-", None), Requested::Unsatisfiable);
+        assert_eq!(requested("bytes=5000-6000", None), Requested::Unsatisfiable);
+        assert_eq!(requested("bytes=-0", None), Requested::Unsatisfiable);
+    }
+
+    #[test]
+    fn sends_the_whole_archive_for_anything_else() {
+        assert_eq!(
+            requested_range(&HeaderMap::new(), ETAG, LEN),
+            Requested::Whole
+        );
+        for range in [
+            "bytes=0-1,5-6",
+            "bytes=9-5",
+            "bytes=a-b",
+            "bytes=5",
+            "items=0-5",
+        ] {
+            assert_eq!(requested(range, None), Requested::Whole, "{range}");
+        }
+    }
+
+    #[test]
+    fn if_range_must_match_the_etag() {
+        assert_eq!(
+            requested("bytes=500-", Some(ETAG)),
+            Requested::Part(500..1000)
+        );
+        assert_eq!(requested("bytes=500-", Some("\"old\"")), Requested::Whole);
+        // Dates are never a match, the archive is only known by its ETag.
+        assert_eq!(
+            requested("bytes=500-", Some("Wed, 01 May 2024 12:00:00 GMT")),
+            Requested::Whole
+        );
+        assert_eq!(requested("bytes=5000-", Some("\"old\"")), Requested::Whole);
+    }
+}
@@ -0,0 +1,262 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{artist, ApiScope, ArtistPermission, AuthUser, ClientInfo},
+    db::{
+        models::{Album, AlbumVersion, AlbumVersionTrack},
+        queries,
+    },
+    ingest::{
+        export::{AlbumExport, ExportFormat},
+        versions::{self, VersionDiff},
+    },
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Serialize)]
+pub struct VersionSummary {
+    #[serde(flatten)]
+    version: AlbumVersion,
+    track_count: i64,
+    is_current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionListResponse {
+    album_id: String,
+    current_version_id: Option<String>,
+    versions: Vec<VersionSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionResponse {
+    #[serde(flatten)]
+    version: AlbumVersion,
+    is_current: bool,
+    tracks: Vec<AlbumVersionTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    /// Defaults to the version before `to`.
+    from: Option<String>,
+    /// Defaults to the current version.
+    to: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffResponse {
+    from: AlbumVersion,
+    to: AlbumVersion,
+    #[serde(flatten)]
+    diff: VersionDiff,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetCurrentVersion {
+    version_id: String,
+}
+
+/// `GET /api/v1/albums/{id}/versions`: every version of the album, oldest
+/// first.
+pub async fn list_versions(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<VersionListResponse>, ApiError> {
+    let album = find_album(&state, &user, &id).await?;
+    let counts = queries::get_album_version_track_counts(&state.db, &album.id).await?;
+    let versions = queries::get_album_versions(&state.db, &album.id)
+        .await?
+        .into_iter()
+        .map(|version| VersionSummary {
+            track_count: counts
+                .iter()
+                .find(|(version_id, _)| *version_id == version.id)
+                .map_or(0, |(_, count)| *count),
+            is_current: album.current_version_id.as_ref() == Some(&version.id),
+            version,
+        })
+        .collect();
+
+    Ok(Json(VersionListResponse {
+        album_id: album.id,
+        current_version_id: album.current_version_id,
+        versions,
+    }))
+}
+
+/// `GET /api/v1/albums/{id}/versions/{version_id}`: a version and its tracks
+/// as they were then.
+pub async fn get_version(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, version_id)): Path<(String, String)>,
+) -> Result<Json<VersionResponse>, ApiError> {
+    let album = find_album(&state, &user, &id).await?;
+    let version = find_version(&state, &album, &version_id).await?;
+    let tracks = queries::get_album_version_tracks(&state.db, &version.id).await?;
+
+    Ok(Json(VersionResponse {
+        is_current: album.current_version_id.as_ref() == Some(&version.id),
+        version,
+        tracks,
+    }))
+}
+
+/// `GET /api/v1/albums/{id}/diff?from=&to=`: the tracks added, removed and
+/// changed between two versions, by default the current one and the one
+/// before it.
+pub async fn diff_versions(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Query(params): Query<DiffQuery>,
+) -> Result<Json<DiffResponse>, ApiError> {
+    let album = find_album(&state, &user, &id).await?;
+    let to_id = params
+        .to
+        .or_else(|| album.current_version_id.clone())
+        .ok_or_else(|| ApiError::not_found("Album has no versions"))?;
+    let to = find_version(&state, &album, &to_id).await?;
+
+    let from = match params.from {
+        Some(from_id) => find_version(&state, &album, &from_id).await?,
+        None => queries::get_album_versions(&state.db, &album.id)
+            .await?
+            .into_iter()
+            .rev()
+            .find(|version| version.version_number < to.version_number)
+            .ok_or_else(|| {
+                ApiError::bad_request(format!(
+                    "Version {} is the first, give from to compare it",
+                    to.version_number
+                ))
+            })?,
+    };
+
+    let from_tracks = queries::get_album_version_tracks(&state.db, &from.id).await?;
+    let to_tracks = queries::get_album_version_tracks(&state.db, &to.id).await?;
+
+    Ok(Json(DiffResponse {
+        diff: versions::diff(from_tracks, to_tracks),
+        from,
+        to,
+    }))
+}
+
+/// `PUT /api/v1/albums/{id}/current-version`: rolls the album back (or
+/// forward) to another version, putting its tracks back as they were then.
+pub async fn set_current_version(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path(id): Path<String>,
+    Json(payload): Json<SetCurrentVersion>,
+) -> Result<Json<VersionResponse>, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let album = find_album(&state, &user, &id).await?;
+    let version = find_version(&state, &album, &payload.version_id).await?;
+    if album.current_version_id.as_ref() != Some(&version.id) {
+        queries::restore_album_version(&state.db, &album.id, &version.id, chrono::Utc::now())
+            .await?;
+
+        let event = AuditEvent::new(AuditAction::AlbumVersionRestored)
+            .actor(&user.user_id)
+            .target("album", &album.id)
+            .diff(serde_json::json!({
+                "before": album.current_version_id,
+                "after": version.id,
+            }));
+        audit::record(&state.db, event, &client).await;
+    }
+    let tracks = queries::get_album_version_tracks(&state.db, &version.id).await?;
+
+    Ok(Json(VersionResponse {
+        version,
+        is_current: true,
+        tracks,
+    }))
+}
+
+/// `GET /api/v1/albums/{id}/metadata?format=yaml|json`: the album's current
+/// metadata as an editable manifest, noting where each value came from.
+pub async fn export_metadata(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Query(params): Query<MetadataQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let album = find_album(&state, &user, &id).await?;
+    let artist = queries::get_artist_by_id(&state.db, &album.artist_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Artist not found"))?;
+    let version = match &album.current_version_id {
+        Some(version_id) => queries::get_album_version(&state.db, version_id).await?,
+        None => None,
+    };
+    let tracks = queries::get_album_tracks(&state.db, &album.id).await?;
+    let sources = queries::get_album_track_sources(&state.db, &album.id).await?;
+
+    let export = AlbumExport::new(&album, &artist.name, version.as_ref(), &tracks, &sources);
+    let body = export.render(params.format, chrono::Utc::now().date_naive())?;
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                params.format.content_type().to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", params.format.file_name()),
+            ),
+        ],
+        body,
+    ))
+}
+
+/// The album, if the caller may edit its artist's music.
+pub async fn find_album(state: &AppState, user: &AuthUser, id: &str) -> Result<Album, ApiError> {
+    let album = queries::get_album_by_id(&state.db, id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Album not found"))?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        user,
+        Some(&album.artist_id),
+        ArtistPermission::Edit,
+    )
+    .await?;
+
+    Ok(album)
+}
+
+async fn find_version(
+    state: &AppState,
+    album: &Album,
+    version_id: &str,
+) -> Result<AlbumVersion, ApiError> {
+    queries::get_album_version(&state.db, version_id)
+        .await?
+        .filter(|version| version.album_id == album.id)
+        .ok_or_else(|| ApiError::not_found("Album version not found"))
+}
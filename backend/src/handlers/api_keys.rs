@@ -0,0 +1,153 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{api_key, artist, ApiScope, AuthUser, ClientInfo},
+    db::{models::ApiKey, queries},
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<ApiScope>,
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyResponse {
+    id: String,
+    name: String,
+    prefix: String,
+    scopes: Vec<ApiScope>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            scopes: ApiScope::parse_list(&key.scopes),
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+            expires_at: key.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKeyResponse {
+    #[serde(flatten)]
+    api_key: ApiKeyResponse,
+    /// The full key. It is not stored and cannot be retrieved again.
+    key: String,
+}
+
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> Result<Json<CreatedApiKeyResponse>, ApiError> {
+    user.require_session()?;
+
+    let name = payload.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(ApiError::bad_request("Key name must be 1-100 characters"));
+    }
+
+    let mut scopes: Vec<ApiScope> = Vec::new();
+    for scope in payload.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        return Err(ApiError::bad_request("At least one scope is required"));
+    }
+
+    if scopes.contains(&ApiScope::Admin)
+        && !artist::is_acting_admin(&state.db, &state.config.auth, &user).await?
+    {
+        return Err(ApiError::forbidden(
+            "admin_required",
+            "Only admins can create keys with the 'admin' scope",
+        ));
+    }
+
+    let expires_at = match payload.expires_in_days {
+        Some(days) if days > 0 => Some(
+            Utc::now()
+                + Duration::try_days(days)
+                    .ok_or_else(|| ApiError::bad_request("expires_in_days is too large"))?,
+        ),
+        Some(_) => return Err(ApiError::bad_request("expires_in_days must be positive")),
+        None => None,
+    };
+
+    let generated = api_key::generate_key(&user.user_id, name, &scopes, expires_at);
+    let key = queries::create_api_key(&state.db, generated.key).await?;
+
+    let event = AuditEvent::new(AuditAction::ApiKeyCreated)
+        .actor(&user.user_id)
+        .target("api_key", &key.id)
+        .diff(serde_json::json!({
+            "after": { "name": key.name, "prefix": key.prefix, "scopes": key.scopes, "expires_at": key.expires_at }
+        }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(CreatedApiKeyResponse {
+        api_key: key.into(),
+        key: generated.secret,
+    }))
+}
+
+pub async fn list_api_keys(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<ApiKeyResponse>>, ApiError> {
+    user.require_session()?;
+
+    let keys = queries::get_api_keys_for_user(&state.db, &user.user_id)
+        .await?
+        .into_iter()
+        .map(ApiKeyResponse::from)
+        .collect();
+
+    Ok(Json(keys))
+}
+
+pub async fn revoke_api_key(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path(key_id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_session()?;
+
+    let revoked = queries::revoke_api_key(&state.db, &key_id, &user.user_id, Utc::now()).await?;
+    if !revoked {
+        return Err(ApiError::not_found("API key not found"));
+    }
+
+    let event = AuditEvent::new(AuditAction::ApiKeyRevoked)
+        .actor(&user.user_id)
+        .target("api_key", &key_id);
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(serde_json::json!({
+        "message": "API key revoked"
+    })))
+}
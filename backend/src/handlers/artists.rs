@@ -0,0 +1,297 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{
+        artist::{self, is_acting_admin},
+        ApiScope, ArtistPermission, AuthUser, ClientInfo,
+    },
+    db::{
+        self,
+        models::{Artist, CreateArtist, Track},
+        queries,
+    },
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Serialize)]
+pub struct ArtistResponse {
+    #[serde(flatten)]
+    artist: Artist,
+    tracks: Vec<Track>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistMember {
+    user_id: String,
+    username: String,
+    permissions: Vec<ArtistPermission>,
+    granted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetMemberRequest {
+    /// Username or email of the member to invite or update.
+    user: String,
+    permissions: Vec<ArtistPermission>,
+}
+
+pub async fn list_artists(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Artist>>, ApiError> {
+    let artists = queries::get_all_artists(&state.db).await?;
+    Ok(Json(artists))
+}
+
+pub async fn get_artist(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<ArtistResponse>, ApiError> {
+    let artist = find_artist(&state, &id).await?;
+    let tracks = queries::get_artist_tracks(&state.db, &id).await?;
+
+    Ok(Json(ArtistResponse { artist, tracks }))
+}
+
+pub async fn create_artist(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<CreateArtist>,
+) -> Result<Json<Artist>, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let name_length = payload.name.trim().chars().count();
+    if !(1..=200).contains(&name_length) {
+        return Err(ApiError::bad_request(
+            "Artist name must be 1-200 characters",
+        ));
+    }
+
+    let artist = payload.into_artist(&user.user_id);
+    let artist = match queries::create_artist(&state.db, artist, &user.user_id).await {
+        Ok(artist) => artist,
+        Err(err) if db::is_unique_violation(&err) => {
+            return Err(ApiError::conflict(
+                "An artist with this name already exists",
+            ));
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let event = AuditEvent::new(AuditAction::ArtistCreated)
+        .actor(&user.user_id)
+        .target("artist", &artist.id)
+        .diff(json!({ "after": artist }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(artist))
+}
+
+pub async fn list_members(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(artist_id): Path<String>,
+) -> Result<Json<Vec<ArtistMember>>, ApiError> {
+    find_artist(&state, &artist_id).await?;
+
+    let is_member = !artist::member_permissions(&state.db, &artist_id, &user.user_id)
+        .await?
+        .is_empty();
+    if !is_member && !is_acting_admin(&state.db, &state.config.auth, &user).await? {
+        return Err(ApiError::forbidden(
+            "artist_permission_required",
+            "Only members of this artist can see its members",
+        ));
+    }
+
+    Ok(Json(load_members(&state, &artist_id).await?))
+}
+
+/// Invites a user or replaces their permissions.
+pub async fn set_member(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path(artist_id): Path<String>,
+    Json(payload): Json<SetMemberRequest>,
+) -> Result<Json<ArtistMember>, ApiError> {
+    user.require_session()?;
+    find_artist(&state, &artist_id).await?;
+
+    let mut permissions = payload.permissions;
+    permissions.sort_unstable();
+    permissions.dedup();
+    if permissions.is_empty() {
+        return Err(ApiError::bad_request(
+            "At least one permission is required, remove the member instead",
+        ));
+    }
+
+    let member = queries::get_user_by_username_or_email(&state.db, payload.user.trim())
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    let before = artist::member_permissions(&state.db, &artist_id, &member.id).await?;
+
+    // Owners are the only ones who can hand out or take away control.
+    let touches_control = [&permissions, &before]
+        .into_iter()
+        .flatten()
+        .any(|permission| {
+            matches!(
+                permission,
+                ArtistPermission::Owner | ArtistPermission::ManageMembers
+            )
+        });
+    let required = if touches_control {
+        ArtistPermission::Owner
+    } else {
+        ArtistPermission::ManageMembers
+    };
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        &user,
+        Some(&artist_id),
+        required,
+    )
+    .await?;
+
+    if before.contains(&ArtistPermission::Owner)
+        && !permissions.contains(&ArtistPermission::Owner)
+        && queries::count_artist_owners(&state.db, &artist_id).await? <= 1
+    {
+        return Err(ApiError::conflict(
+            "Cannot remove the last owner of an artist",
+        ));
+    }
+
+    let stored: Vec<&str> = permissions
+        .iter()
+        .map(|permission| permission.as_str())
+        .collect();
+    queries::set_artist_member_permissions(
+        &state.db,
+        &artist_id,
+        &member.id,
+        &stored,
+        &user.user_id,
+        Utc::now(),
+    )
+    .await?;
+
+    let event = AuditEvent::new(AuditAction::ArtistMemberSet)
+        .actor(&user.user_id)
+        .target("artist", &artist_id)
+        .diff(json!({
+            "user_id": member.id,
+            "before": { "permissions": before },
+            "after": { "permissions": permissions },
+        }));
+    audit::record(&state.db, event, &client).await;
+
+    load_members(&state, &artist_id)
+        .await?
+        .into_iter()
+        .find(|entry| entry.user_id == member.id)
+        .map(Json)
+        .ok_or_else(|| anyhow::anyhow!("Member disappeared after update").into())
+}
+
+/// Removes a member. Members can always remove themselves.
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path((artist_id, member_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_session()?;
+    find_artist(&state, &artist_id).await?;
+
+    let before = artist::member_permissions(&state.db, &artist_id, &member_id).await?;
+    if before.is_empty() {
+        return Err(ApiError::not_found("Member not found"));
+    }
+
+    let is_owner = before.contains(&ArtistPermission::Owner);
+    if member_id != user.user_id {
+        let required = if is_owner {
+            ArtistPermission::Owner
+        } else {
+            ArtistPermission::ManageMembers
+        };
+        artist::require_permission(
+            &state.db,
+            &state.config.auth,
+            &user,
+            Some(&artist_id),
+            required,
+        )
+        .await?;
+    }
+
+    if is_owner && queries::count_artist_owners(&state.db, &artist_id).await? <= 1 {
+        return Err(ApiError::conflict(
+            "Cannot remove the last owner of an artist",
+        ));
+    }
+
+    queries::remove_artist_member(&state.db, &artist_id, &member_id).await?;
+
+    let event = AuditEvent::new(AuditAction::ArtistMemberRemoved)
+        .actor(&user.user_id)
+        .target("artist", &artist_id)
+        .diff(json!({
+            "user_id": member_id,
+            "before": { "permissions": before },
+        }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(json!({
+        "message": "Member removed"
+    })))
+}
+
+async fn find_artist(state: &AppState, artist_id: &str) -> Result<Artist, ApiError> {
+    queries::get_artist_by_id(&state.db, artist_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Artist not found"))
+}
+
+/// Groups the per-permission rows into one entry per member.
+async fn load_members(state: &AppState, artist_id: &str) -> Result<Vec<ArtistMember>, ApiError> {
+    let mut members: Vec<ArtistMember> = Vec::new();
+
+    for row in queries::get_artist_members(&state.db, artist_id).await? {
+        let Ok(permission) = row.permission.parse() else {
+            continue;
+        };
+        match members.last_mut() {
+            Some(member) if member.user_id == row.user_id => {
+                member.permissions.push(permission);
+                member.granted_at = member.granted_at.max(row.granted_at);
+            }
+            _ => members.push(ArtistMember {
+                user_id: row.user_id,
+                username: row.username,
+                permissions: vec![permission],
+                granted_at: row.granted_at,
+            }),
+        }
+    }
+
+    for member in &mut members {
+        member.permissions.sort_unstable();
+    }
+
+    Ok(members)
+}
@@ -1,16 +1,30 @@
-use axum::{Json};
-use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use axum::{
+    extract::State,
+    http::{header, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
 
-use super::ApiError;
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{
+        email_token, mfa, password,
+        rate_limit::{self, AuthAction},
+        session, AuthUser, ClientInfo,
+    },
+    db::{
+        self,
+        models::{CreateUser, User},
+        queries,
+    },
+    AppState,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String,
-    pub exp: i64,
-    pub iat: i64,
-}
+use super::ApiError;
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
@@ -22,32 +36,464 @@ pub struct LoginRequest {
 pub struct LoginResponse {
     pub token: String,
     pub expires_in: i64,
+    /// Set when finishing an external login that was interrupted for 2FA.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to: Option<String>,
+}
+
+/// Returned instead of a token when the account has 2FA enabled.
+#[derive(Debug, Serialize)]
+pub struct MfaRequiredResponse {
+    pub mfa_required: bool,
+    /// Exchanged for a session at `POST /api/v1/auth/login/mfa`.
+    pub mfa_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    /// A TOTP code or a recovery code.
+    pub code: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+}
+
+const INVALID_CREDENTIALS: &str = "Invalid username or password";
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+type SetCookie = [(HeaderName, HeaderValue); 1];
+
 pub async fn login(
-    Json(_payload): Json<LoginRequest>,
-) -> Result<Json<LoginResponse>, ApiError> {
-    // TODO: Implement actual authentication
-    // For now, return a dummy token for development
-    
-    let now = Utc::now();
-    let expires_in = Duration::try_hours(24).unwrap();
-    let exp = (now + expires_in).timestamp();
-    
-    let claims = Claims {
-        sub: "dev-user".to_string(),
-        exp,
-        iat: now.timestamp(),
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Response, ApiError> {
+    let limits = &state.config.rate_limit;
+    let ip_address = client.ip_address.as_deref();
+    let user_result = queries::get_user_by_username_or_email(&state.db, &payload.username).await?;
+    let user_id = user_result.as_ref().map(|user| user.id.as_str());
+    rate_limit::check_login(&state.db, limits, ip_address, &payload.username, user_id).await?;
+
+    let Some(user) = user_result else {
+        let attempted = payload.password;
+        tokio::task::spawn_blocking(move || password::verify_dummy(&attempted)).await?;
+        rate_limit::record_attempt(
+            &state.db,
+            AuthAction::Login,
+            Some(&payload.username),
+            None,
+            ip_address,
+            false,
+        )
+        .await?;
+        let event = AuditEvent::new(AuditAction::LoginFailed)
+            .diff(json!({ "username": payload.username, "reason": "unknown_user" }));
+        audit::record(&state.db, event, &client).await;
+        return Err(ApiError::unauthorized(INVALID_CREDENTIALS));
     };
-    
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret("dev-jwt-secret".as_ref()),
-    )?;
-    
-    Ok(Json(LoginResponse {
-        token,
-        expires_in: expires_in.num_seconds(),
-    }))
-}
\ No newline at end of file
+
+    // Argon2 verification is deliberately slow, keep it off the async workers.
+    let password_hash = user.password_hash.clone();
+    let attempted = payload.password;
+    let verified =
+        tokio::task::spawn_blocking(move || password::verify_password(&attempted, &password_hash))
+            .await?;
+
+    if !verified {
+        rate_limit::record_attempt(
+            &state.db,
+            AuthAction::Login,
+            Some(&payload.username),
+            Some(&user.id),
+            ip_address,
+            false,
+        )
+        .await?;
+        let event = AuditEvent::new(AuditAction::LoginFailed)
+            .target("user", &user.id)
+            .diff(json!({ "username": payload.username, "reason": "wrong_password" }));
+        audit::record(&state.db, event, &client).await;
+        return Err(ApiError::unauthorized(INVALID_CREDENTIALS));
+    }
+
+    // Not recorded as a successful login yet, that would reset the failure
+    // count between guesses at the code.
+    if mfa::is_enabled(&state.db, &user.id).await? {
+        let challenge = mfa::issue_challenge(&state.config.auth, &user.id, "password", None)?;
+        return Ok(Json(MfaRequiredResponse {
+            mfa_required: true,
+            mfa_token: challenge.token,
+            expires_in: challenge.expires_in,
+        })
+        .into_response());
+    }
+
+    rate_limit::record_attempt(
+        &state.db,
+        AuthAction::Login,
+        Some(&payload.username),
+        Some(&user.id),
+        ip_address,
+        true,
+    )
+    .await?;
+
+    finish_login(
+        &state,
+        &user,
+        &client,
+        json!({ "method": "password" }),
+        None,
+    )
+    .await
+}
+
+/// Second login step for accounts with 2FA, taking a TOTP or recovery code.
+pub async fn login_mfa(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<MfaLoginRequest>,
+) -> Result<Response, ApiError> {
+    let challenge = mfa::decode_challenge(&state.config.auth, &payload.mfa_token)?;
+    let user = queries::get_user_by_id(&state.db, &challenge.sub)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("Account no longer exists"))?;
+
+    // Wrong codes count as failed logins, so guessing runs into the lockout.
+    let ip_address = client.ip_address.as_deref();
+    rate_limit::check_login(
+        &state.db,
+        &state.config.rate_limit,
+        ip_address,
+        &user.username,
+        Some(&user.id),
+    )
+    .await?;
+
+    let Some(method) = mfa::verify(&state.db, &user, &payload.code).await? else {
+        rate_limit::record_attempt(
+            &state.db,
+            AuthAction::Login,
+            Some(&user.username),
+            Some(&user.id),
+            ip_address,
+            false,
+        )
+        .await?;
+        let event = AuditEvent::new(AuditAction::LoginFailed)
+            .target("user", &user.id)
+            .diff(json!({ "username": user.username, "reason": "wrong_mfa_code" }));
+        audit::record(&state.db, event, &client).await;
+        return Err(ApiError::unauthorized("Invalid authentication code"));
+    };
+
+    rate_limit::record_attempt(
+        &state.db,
+        AuthAction::Login,
+        Some(&user.username),
+        Some(&user.id),
+        ip_address,
+        true,
+    )
+    .await?;
+
+    let details = json!({ "method": challenge.method, "mfa": method.as_str() });
+    finish_login(&state, &user, &client, details, challenge.redirect_to).await
+}
+
+/// Starts a session for a fully authenticated user and sets the cookie.
+async fn finish_login(
+    state: &AppState,
+    user: &User,
+    client: &ClientInfo,
+    details: serde_json::Value,
+    redirect_to: Option<String>,
+) -> Result<Response, ApiError> {
+    queries::update_last_login(&state.db, &user.id, Utc::now()).await?;
+
+    let (_, issued) =
+        session::start_session(&state.db, &state.config.auth, &user.id, client).await?;
+    let cookie = session::session_cookie(&state.config.auth, &issued.token, issued.expires_in)?;
+
+    let event = AuditEvent::new(AuditAction::Login)
+        .actor(&user.id)
+        .target("user", &user.id)
+        .diff(details);
+    audit::record(&state.db, event, client).await;
+
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Json(LoginResponse {
+            token: issued.token,
+            expires_in: issued.expires_in,
+            redirect_to,
+        }),
+    )
+        .into_response())
+}
+
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+) -> Result<(SetCookie, Json<serde_json::Value>), ApiError> {
+    let session_id = user.require_session()?;
+    queries::revoke_session(&state.db, session_id, &user.user_id, Utc::now()).await?;
+    let cookie = session::clear_session_cookie(&state.config.auth)?;
+
+    let event = AuditEvent::new(AuditAction::Logout)
+        .actor(&user.user_id)
+        .target("session", session_id);
+    audit::record(&state.db, event, &client).await;
+
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Json(json!({
+            "message": "Logged out"
+        })),
+    ))
+}
+
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<Json<navicore_music_shared::User>, ApiError> {
+    let ip_address = client.ip_address.as_deref();
+    rate_limit::check_register(&state.db, &state.config.rate_limit, ip_address).await?;
+
+    // Rejected requests count towards the limit too, so probing for taken
+    // names is throttled as well.
+    let result = create_account(&state, payload).await;
+    rate_limit::record_attempt(
+        &state.db,
+        AuthAction::Register,
+        None,
+        None,
+        ip_address,
+        result.is_ok(),
+    )
+    .await?;
+
+    if let Ok(user) = &result {
+        let event = AuditEvent::new(AuditAction::Register)
+            .actor(user.id.to_string())
+            .target("user", user.id.to_string())
+            .diff(json!({ "after": { "username": user.username, "email": user.email, "is_admin": user.is_admin } }));
+        audit::record(&state.db, event, &client).await;
+    }
+
+    result.map(Json)
+}
+
+async fn create_account(
+    state: &AppState,
+    payload: RegisterRequest,
+) -> Result<navicore_music_shared::User, ApiError> {
+    let username = payload.username.trim().to_string();
+    let email = payload.email.trim().to_lowercase();
+
+    validate_username(&username)?;
+    validate_email(&email)?;
+    validate_password(&payload.password)?;
+
+    if queries::username_exists(&state.db, &username).await? {
+        return Err(ApiError::conflict("Username is already taken"));
+    }
+    if queries::email_exists(&state.db, &email).await? {
+        return Err(ApiError::conflict("Email is already registered"));
+    }
+
+    let password_hash =
+        tokio::task::spawn_blocking(move || password::hash_password(&payload.password)).await??;
+
+    let user = CreateUser {
+        username,
+        email,
+        password_hash,
+    }
+    .into_user();
+
+    // The existence checks above race with concurrent registrations, the
+    // UNIQUE constraints are the real guard.
+    let user =
+        match queries::create_user(&state.db, user, state.config.auth.first_user_is_admin).await {
+            Ok(user) => user,
+            Err(err) if db::is_unique_violation(&err) => {
+                return Err(ApiError::conflict(
+                    "Username or email is already registered",
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+    tracing::info!(
+        "Registered user {}{}",
+        user.username,
+        if user.is_admin {
+            " as the first admin"
+        } else {
+            ""
+        }
+    );
+
+    // The account is usable either way, the link can be requested again.
+    let sent =
+        email_token::send_verification(&state.db, state.mailer.as_ref(), &state.config, &user)
+            .await;
+    if let Err(err) = sent {
+        tracing::error!(
+            "Failed to send verification email to {}: {err:#}",
+            user.username
+        );
+    }
+
+    Ok(user.try_into()?)
+}
+
+fn validate_username(username: &str) -> Result<(), ApiError> {
+    let valid_length = (3..=32).contains(&username.chars().count());
+    let valid_chars = username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    let starts_alphanumeric = username
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric());
+
+    if valid_length && valid_chars && starts_alphanumeric {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(
+            "Username must be 3-32 characters of letters, digits, '_', '-' or '.', starting with a letter or digit",
+        ))
+    }
+}
+
+fn validate_email(email: &str) -> Result<(), ApiError> {
+    let valid = email.len() <= 254
+        && !email.chars().any(char::is_whitespace)
+        && email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request("Email address is not valid"))
+    }
+}
+
+pub fn validate_password(password: &str) -> Result<(), ApiError> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "Password must be at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "Password must be at most {MAX_PASSWORD_LENGTH} characters"
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use super::*;
+    use crate::storage::mock::MockBucket;
+
+    async fn register_as(
+        state: &Arc<AppState>,
+        username: &str,
+        email: &str,
+    ) -> Result<navicore_music_shared::User, StatusCode> {
+        let client = ClientInfo {
+            ip_address: None,
+            user_agent: None,
+        };
+        let payload = RegisterRequest {
+            username: username.to_string(),
+            email: email.to_string(),
+            password: "correct horse battery".to_string(),
+        };
+        let result = register(State(state.clone()), client, Json(payload)).await;
+        result
+            .map(|Json(user)| user)
+            .map_err(|err| err.into_response().status())
+    }
+
+    #[tokio::test]
+    async fn registers_unique_accounts_with_hashed_passwords() {
+        let db = crate::db::test_pool().await;
+        let storage = MockBucket::default().serve().await;
+        let state = Arc::new(AppState::for_tests(db.clone(), storage));
+
+        let user = register_as(&state, " listener ", "Listener@Example.com")
+            .await
+            .expect("registered");
+        assert_eq!(
+            (user.username.as_str(), user.email.as_str()),
+            ("listener", "listener@example.com")
+        );
+        let stored = queries::get_user_by_username_or_email(&db, "listener")
+            .await
+            .expect("lookup")
+            .expect("stored");
+        assert_ne!(stored.password_hash, "correct horse battery");
+        assert!(password::verify_password(
+            "correct horse battery",
+            &stored.password_hash
+        ));
+
+        assert_eq!(
+            register_as(&state, "listener", "other@example.com")
+                .await
+                .err(),
+            Some(StatusCode::CONFLICT)
+        );
+        // Emails are compared in any case.
+        assert_eq!(
+            register_as(&state, "other", "LISTENER@example.com")
+                .await
+                .err(),
+            Some(StatusCode::CONFLICT)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_accounts() {
+        let db = crate::db::test_pool().await;
+        let storage = MockBucket::default().serve().await;
+        let state = Arc::new(AppState::for_tests(db, storage));
+
+        for (username, email) in [
+            ("ab", "ab@example.com"),
+            ("-dash", "dash@example.com"),
+            ("has space", "space@example.com"),
+            ("nodomain", "nodomain@localhost"),
+            ("twoats", "two@ats@example.com"),
+        ] {
+            assert_eq!(
+                register_as(&state, username, email).await.err(),
+                Some(StatusCode::BAD_REQUEST),
+                "{username} <{email}>"
+            );
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use axum::{
+    body::Bytes,
+    extract::{Path as AxumPath, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{path::Path, sync::Arc};
+use uuid::Uuid;
+
+use crate::{
+    auth::{artist, ApiScope, AuthUser, ClientInfo},
+    db::{
+        models::{ChunkedUpload, ChunkedUploadPart},
+        queries,
+    },
+    ingest::{
+        album::AlbumUploadMetadata,
+        chunked::{self, ChunkedStatus, UploadKind},
+        jobs,
+        spool::SpooledFile,
+    },
+    AppState,
+};
+
+use super::{
+    upload::{self, ReceivedFile, UploadMetadata, UploadResponse, UploadStatusResponse},
+    ApiError,
+};
+
+/// Header carrying the base64 SHA-256 of a part.
+const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+
+#[derive(Debug, Deserialize)]
+pub struct StartChunkedUpload {
+    kind: UploadKind,
+    file_name: String,
+    total_bytes: u64,
+    /// Defaults to 8 MB, kept between 5 and 64 MB.
+    part_bytes: Option<u64>,
+    /// What the `metadata` form field of the matching upload endpoint holds.
+    metadata: Option<serde_json::Value>,
+}
+
+/// A chunked upload and which of its parts have arrived.
+#[derive(Debug, Serialize)]
+pub struct ChunkedUploadResponse {
+    upload_id: String,
+    kind: UploadKind,
+    file_name: String,
+    total_bytes: i64,
+    part_bytes: i64,
+    parts_total: i64,
+    /// `open`, `completing`, `completed`, `aborted` or `expired`.
+    status: String,
+    parts: Vec<ChunkedUploadPart>,
+    received_bytes: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl ChunkedUploadResponse {
+    fn new(upload: ChunkedUpload, parts: Vec<ChunkedUploadPart>) -> anyhow::Result<Self> {
+        let kind = UploadKind::parse(&upload.kind)
+            .ok_or_else(|| anyhow::anyhow!("Unknown chunked upload kind '{}'", upload.kind))?;
+        Ok(Self {
+            parts_total: chunked::parts_total(&upload),
+            received_bytes: parts.iter().map(|part| part.size).sum(),
+            upload_id: upload.id,
+            kind,
+            file_name: upload.file_name,
+            total_bytes: upload.total_bytes,
+            part_bytes: upload.part_bytes,
+            status: upload.status,
+            parts,
+            created_at: upload.created_at,
+            updated_at: upload.updated_at,
+            expires_at: upload.expires_at,
+        })
+    }
+}
+
+/// `POST /api/v1/upload/chunked`: starts an upload to be sent in parts.
+///
+/// The size limits of the matching upload endpoint apply, and `metadata` is
+/// checked now so a mistake doesn't surface only after the last part.
+pub async fn start_upload(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(payload): Json<StartChunkedUpload>,
+) -> Result<(StatusCode, Json<ChunkedUploadResponse>), ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let file_name = payload.file_name.trim();
+    if file_name.is_empty() {
+        return Err(ApiError::bad_request("file_name is required"));
+    }
+    if payload.total_bytes == 0 {
+        return Err(ApiError::bad_request("Uploaded file is empty"));
+    }
+
+    let config = &state.config.upload;
+    let max_bytes = match payload.kind {
+        UploadKind::File => config.max_file_bytes,
+        UploadKind::Album => config.max_zip_bytes,
+    };
+    if payload.total_bytes > max_bytes {
+        return Err(upload::file_too_large(max_bytes));
+    }
+
+    let metadata = payload
+        .metadata
+        .filter(|metadata| !metadata.is_null())
+        .map(|metadata| {
+            let checked = match payload.kind {
+                UploadKind::File => {
+                    serde_json::from_value::<UploadMetadata>(metadata.clone()).map(drop)
+                }
+                UploadKind::Album => {
+                    serde_json::from_value::<AlbumUploadMetadata>(metadata.clone()).map(drop)
+                }
+            };
+            checked
+                .map_err(|err| ApiError::bad_request(format!("Invalid metadata JSON: {err}")))?;
+            Ok::<_, ApiError>(metadata.to_string())
+        })
+        .transpose()?;
+
+    let id = Uuid::new_v4().to_string();
+    let storage_key = format!("{}{id}", chunked::STORAGE_PREFIX);
+    let storage_upload_id = state
+        .storage
+        .create_multipart_upload(&storage_key, None)
+        .await
+        .map_err(|err| storage_error("start", &err))?;
+
+    let now = Utc::now();
+    let upload = ChunkedUpload {
+        id,
+        user_id: user.user_id.clone(),
+        kind: payload.kind.as_str().to_string(),
+        file_name: file_name.to_string(),
+        total_bytes: i64::try_from(payload.total_bytes)?,
+        part_bytes: i64::try_from(chunked::part_size(payload.total_bytes, payload.part_bytes))?,
+        storage_key,
+        storage_upload_id,
+        metadata,
+        status: ChunkedStatus::Open.as_str().to_string(),
+        assembled_at: None,
+        created_at: now,
+        updated_at: now,
+        expires_at: chunked::expires_at(&state, now),
+    };
+    if let Err(err) = queries::create_chunked_upload(&state.db, &upload).await {
+        chunked::discard(&state, &upload).await;
+        return Err(err.into());
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ChunkedUploadResponse::new(upload, Vec::new())?),
+    ))
+}
+
+/// `GET /api/v1/upload/chunked/{upload_id}`: the upload and the parts
+/// received so far, for resuming it.
+pub async fn get_upload(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    AxumPath(upload_id): AxumPath<String>,
+) -> Result<Json<ChunkedUploadResponse>, ApiError> {
+    let upload = find_upload(&state, &user, &upload_id).await?;
+    let parts = queries::get_chunked_upload_parts(&state.db, &upload.id).await?;
+
+    Ok(Json(ChunkedUploadResponse::new(upload, parts)?))
+}
+
+/// `PUT /api/v1/upload/chunked/{upload_id}/parts/{part_number}`: stores one
+/// part, sent as the raw request body with its base64 SHA-256 in the
+/// `X-Checksum-SHA256` header. Sending a part again replaces it.
+pub async fn upload_part(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    AxumPath((upload_id, part_number)): AxumPath<(String, i64)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<ChunkedUploadPart>, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let upload = find_upload(&state, &user, &upload_id).await?;
+    require_open(&upload)?;
+
+    let expected_len = chunked::part_len(&upload, part_number).ok_or_else(|| {
+        ApiError::bad_request(format!(
+            "Part number must be between 1 and {}",
+            chunked::parts_total(&upload)
+        ))
+    })?;
+    if i64::try_from(body.len())? != expected_len {
+        return Err(ApiError::bad_request(format!(
+            "Part {part_number} must be {expected_len} bytes, got {}",
+            body.len()
+        ))
+        .with_code("part_size_mismatch"));
+    }
+
+    let checksum = headers
+        .get(CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .ok_or_else(|| ApiError::bad_request("Missing X-Checksum-SHA256 header"))?;
+    let actual = STANDARD.encode(Sha256::digest(&body));
+    if checksum != actual {
+        return Err(ApiError::bad_request(format!(
+            "Checksum mismatch for part {part_number}: expected {checksum}, got {actual}"
+        ))
+        .with_code("checksum_mismatch"));
+    }
+
+    let etag = state
+        .storage
+        .upload_part(
+            &upload.storage_key,
+            &upload.storage_upload_id,
+            i32::try_from(part_number)?,
+            body.to_vec(),
+            &actual,
+        )
+        .await
+        .map_err(|err| storage_error("store a part of", &err))?;
+
+    let now = Utc::now();
+    let part = ChunkedUploadPart {
+        upload_id: upload.id,
+        part_number,
+        size: expected_len,
+        checksum_sha256: actual,
+        etag,
+        created_at: now,
+    };
+    queries::save_chunked_upload_part(&state.db, &part, chunked::expires_at(&state, now)).await?;
+
+    Ok(Json(part))
+}
+
+/// `POST /api/v1/upload/chunked/{upload_id}/complete`: joins the parts and
+/// processes the file like the matching upload endpoint, answering as it
+/// would. A failed attempt leaves the upload open to be completed again.
+pub async fn complete_upload(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    AxumPath(upload_id): AxumPath<String>,
+) -> Result<Response, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let mut upload = find_upload(&state, &user, &upload_id).await?;
+    if upload.status != ChunkedStatus::Open.as_str() {
+        return Err(not_open(&upload));
+    }
+    let parts = queries::get_chunked_upload_parts(&state.db, &upload.id).await?;
+    if upload.assembled_at.is_none() {
+        let total = chunked::parts_total(&upload);
+        if i64::try_from(parts.len())? != total {
+            let missing: Vec<i64> = (1..=total)
+                .filter(|number| !parts.iter().any(|part| part.part_number == *number))
+                .collect();
+            return Err(
+                ApiError::conflict(format!("Parts still missing: {missing:?}"))
+                    .with_code("parts_missing"),
+            );
+        }
+    }
+
+    let now = Utc::now();
+    let (open, completing) = (
+        ChunkedStatus::Open.as_str(),
+        ChunkedStatus::Completing.as_str(),
+    );
+    if !queries::transition_chunked_upload(&state.db, &upload.id, open, completing, now).await? {
+        return Err(ApiError::conflict("Upload is already being completed"));
+    }
+    queries::extend_chunked_upload(&state.db, &upload.id, chunked::expires_at(&state, now)).await?;
+
+    let result = assemble_and_process(&state, &user, &client, &mut upload, &parts).await;
+    let status = if result.is_ok() {
+        ChunkedStatus::Completed
+    } else {
+        ChunkedStatus::Open
+    };
+    let moved = queries::transition_chunked_upload(
+        &state.db,
+        &upload.id,
+        completing,
+        status.as_str(),
+        Utc::now(),
+    )
+    .await;
+    if let Err(err) = moved {
+        tracing::warn!(
+            "Failed to record the outcome of chunked upload {}: {err:#}",
+            upload.id
+        );
+    }
+    if result.is_ok() {
+        // Processing made its own copy, as a track or a queued ZIP.
+        chunked::discard(&state, &upload).await;
+    }
+
+    result
+}
+
+/// `DELETE /api/v1/upload/chunked/{upload_id}`: abandons an upload and
+/// releases the parts stored for it.
+pub async fn abort_upload(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    AxumPath(upload_id): AxumPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let upload = find_upload(&state, &user, &upload_id).await?;
+    let aborted = ChunkedStatus::Aborted.as_str();
+    if upload.status != ChunkedStatus::Open.as_str()
+        || !queries::transition_chunked_upload(
+            &state.db,
+            &upload.id,
+            &upload.status,
+            aborted,
+            Utc::now(),
+        )
+        .await?
+    {
+        return Err(not_open(&upload));
+    }
+    chunked::discard(&state, &upload).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn assemble_and_process(
+    state: &AppState,
+    user: &AuthUser,
+    client: &ClientInfo,
+    upload: &mut ChunkedUpload,
+    parts: &[ChunkedUploadPart],
+) -> Result<Response, ApiError> {
+    if upload.assembled_at.is_none() {
+        let completed = parts
+            .iter()
+            .map(|part| {
+                Ok((
+                    i32::try_from(part.part_number)?,
+                    part.etag.clone(),
+                    part.checksum_sha256.clone(),
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        state
+            .storage
+            .complete_multipart_upload(&upload.storage_key, &upload.storage_upload_id, &completed)
+            .await
+            .map_err(|err| storage_error("assemble", &err))?;
+
+        let now = Utc::now();
+        queries::set_chunked_upload_assembled(&state.db, &upload.id, now).await?;
+        upload.assembled_at = Some(now);
+    }
+
+    let (spooled, mut file) =
+        SpooledFile::create(Path::new(&state.config.upload.spool_dir)).await?;
+    state
+        .storage
+        .download_to(&upload.storage_key, &mut file)
+        .await
+        .map_err(|err| storage_error("fetch", &err))?;
+    drop(file);
+
+    let metadata = upload.metadata.as_deref().unwrap_or("{}");
+    match UploadKind::parse(&upload.kind) {
+        Some(UploadKind::File) => {
+            let received = ReceivedFile {
+                spooled,
+                file_name: Some(upload.file_name.clone()),
+            };
+            let metadata: UploadMetadata = serde_json::from_str(metadata)?;
+            let track =
+                upload::create_uploaded_track(state, user, client, received, metadata).await?;
+
+            Ok((StatusCode::CREATED, Json(UploadResponse { track })).into_response())
+        }
+        Some(UploadKind::Album) => {
+            let form: AlbumUploadMetadata = serde_json::from_str(metadata)?;
+            let job = jobs::new_job(user, client, spooled.path(), &form)?;
+            state.upload_queue.enqueue(&state.db, &job).await?;
+            // The worker removes it once the job is finished.
+            spooled.keep();
+
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(UploadStatusResponse::from_job(job)?),
+            )
+                .into_response())
+        }
+        None => Err(anyhow::anyhow!("Unknown chunked upload kind '{}'", upload.kind).into()),
+    }
+}
+
+/// The upload, if the caller started it or is an admin.
+async fn find_upload(
+    state: &AppState,
+    user: &AuthUser,
+    upload_id: &str,
+) -> Result<ChunkedUpload, ApiError> {
+    let upload = queries::get_chunked_upload(&state.db, upload_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Upload not found"))?;
+    if upload.user_id != user.user_id
+        && !artist::is_acting_admin(&state.db, &state.config.auth, user).await?
+    {
+        return Err(ApiError::not_found("Upload not found"));
+    }
+
+    Ok(upload)
+}
+
+/// Parts are only accepted until the upload is assembled.
+fn require_open(upload: &ChunkedUpload) -> Result<(), ApiError> {
+    if upload.status != ChunkedStatus::Open.as_str() {
+        return Err(not_open(upload));
+    }
+    if upload.assembled_at.is_some() {
+        return Err(
+            ApiError::conflict("Upload is already assembled, complete it again")
+                .with_code("upload_assembled"),
+        );
+    }
+
+    Ok(())
+}
+
+fn not_open(upload: &ChunkedUpload) -> ApiError {
+    ApiError::conflict(format!("Upload is {}", upload.status)).with_code("upload_not_open")
+}
+
+fn storage_error(action: &str, err: &anyhow::Error) -> ApiError {
+    tracing::error!("Failed to {action} a chunked upload: {err:#}");
+    ApiError::new(
+        StatusCode::BAD_GATEWAY,
+        format!("Failed to {action} the upload in storage"),
+    )
+}
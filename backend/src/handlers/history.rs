@@ -0,0 +1,32 @@
+use axum::{extract::State, Json};
+use std::sync::Arc;
+
+use crate::{
+    auth::{ApiScope, AuthUser},
+    db::{models::PlayHistory, queries},
+    AppState,
+};
+
+use super::ApiError;
+
+pub async fn list_history(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<PlayHistory>>, ApiError> {
+    user.require_scope(ApiScope::Read)?;
+    let history = queries::get_play_history_for_user(&state.db, &user.user_id).await?;
+    Ok(Json(history))
+}
+
+pub async fn clear_history(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_scope(ApiScope::Read)?;
+    let removed = queries::clear_play_history_for_user(&state.db, &user.user_id).await?;
+
+    Ok(Json(serde_json::json!({
+        "message": "Play history cleared",
+        "removed": removed
+    })))
+}
@@ -0,0 +1,190 @@
+use axum::{extract::State, Json};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{
+        mfa::{self, Enrollment, MfaMethod},
+        rate_limit::{self, AuthAction},
+        AuthUser, ClientInfo,
+    },
+    db::{models::User, queries},
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaStatusResponse {
+    enabled: bool,
+    /// An enrolment was started but not confirmed.
+    pending: bool,
+    recovery_codes_remaining: i64,
+    /// Whether `REQUIRE_ADMIN_2FA` applies to this account.
+    required: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    /// Shown once, only hashes are stored.
+    recovery_codes: Vec<String>,
+}
+
+pub async fn get_status(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<MfaStatusResponse>, ApiError> {
+    user.require_session()?;
+    let account = load_account(&state, &user).await?;
+    let totp = queries::get_user_totp(&state.db, &account.id).await?;
+
+    Ok(Json(MfaStatusResponse {
+        enabled: totp.as_ref().is_some_and(|totp| totp.enabled_at.is_some()),
+        pending: totp.as_ref().is_some_and(|totp| totp.enabled_at.is_none()),
+        recovery_codes_remaining: queries::count_unused_recovery_codes(&state.db, &account.id)
+            .await?,
+        required: state.config.auth.require_admin_2fa && account.is_admin,
+    }))
+}
+
+/// Starts enrolment and returns the secret to add to an authenticator app.
+pub async fn enroll(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Enrollment>, ApiError> {
+    user.require_session()?;
+    let account = load_account(&state, &user).await?;
+
+    let enrollment = mfa::begin_enrollment(&state.db, &account)
+        .await?
+        .ok_or_else(|| ApiError::conflict("Two-factor authentication is already enabled"))?;
+
+    Ok(Json(enrollment))
+}
+
+/// Confirms enrolment with a first code. Every other session is logged out,
+/// since they were started without the second factor.
+pub async fn activate(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<CodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, ApiError> {
+    let session_id = user.require_session()?;
+    let account = load_account(&state, &user).await?;
+
+    let recovery_codes = mfa::activate(&state.db, &account, &payload.code).await?;
+    let revoked =
+        queries::revoke_other_sessions_for_user(&state.db, &account.id, session_id, Utc::now())
+            .await?;
+
+    let event = AuditEvent::new(AuditAction::MfaEnabled)
+        .actor(&account.id)
+        .target("user", &account.id)
+        .diff(json!({ "sessions_revoked": revoked }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Replaces the recovery codes, after checking a current code.
+pub async fn regenerate_recovery_codes(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<CodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, ApiError> {
+    user.require_session()?;
+    let account = load_account(&state, &user).await?;
+    verify_code(&state, &account, &client, &payload.code).await?;
+
+    let recovery_codes = mfa::regenerate_recovery_codes(&state.db, &account.id).await?;
+
+    let event = AuditEvent::new(AuditAction::RecoveryCodesRegenerated)
+        .actor(&account.id)
+        .target("user", &account.id);
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn disable(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<CodeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_session()?;
+    let account = load_account(&state, &user).await?;
+
+    if state.config.auth.require_admin_2fa && account.is_admin {
+        return Err(ApiError::conflict(
+            "Admin accounts must keep two-factor authentication enabled",
+        ));
+    }
+    let method = verify_code(&state, &account, &client, &payload.code).await?;
+
+    queries::delete_user_totp(&state.db, &account.id).await?;
+
+    let event = AuditEvent::new(AuditAction::MfaDisabled)
+        .actor(&account.id)
+        .target("user", &account.id)
+        .diff(json!({ "mfa": method.as_str() }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(json!({
+        "message": "Two-factor authentication disabled"
+    })))
+}
+
+async fn load_account(state: &AppState, user: &AuthUser) -> Result<User, ApiError> {
+    queries::get_user_by_id(&state.db, &user.user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))
+}
+
+/// Checks a code for a sensitive change. Failures count towards the login
+/// lockout, so a stolen session can't be used to guess codes either.
+async fn verify_code(
+    state: &AppState,
+    account: &User,
+    client: &ClientInfo,
+    code: &str,
+) -> Result<MfaMethod, ApiError> {
+    if !mfa::is_enabled(&state.db, &account.id).await? {
+        return Err(ApiError::conflict(
+            "Two-factor authentication is not enabled",
+        ));
+    }
+
+    let ip_address = client.ip_address.as_deref();
+    rate_limit::check_login(
+        &state.db,
+        &state.config.rate_limit,
+        ip_address,
+        &account.username,
+        Some(&account.id),
+    )
+    .await?;
+
+    let method = mfa::verify(&state.db, account, code).await?;
+    rate_limit::record_attempt(
+        &state.db,
+        AuthAction::Login,
+        Some(&account.username),
+        Some(&account.id),
+        ip_address,
+        method.is_some(),
+    )
+    .await?;
+
+    method.ok_or_else(|| ApiError::bad_request("Invalid authentication code"))
+}
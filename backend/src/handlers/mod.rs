@@ -2,22 +2,124 @@ pub mod tracks;
 pub mod playlists;
 pub mod auth;
 pub mod stream;
+pub mod history;
+pub mod sessions;
+pub mod api_keys;
+pub mod admin;
+pub mod artists;
+pub mod albums;
+pub mod oauth;
+pub mod account;
+pub mod mfa;
+pub mod upload;
+pub mod chunked_upload;
+pub mod album_download;
 
-use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}, Json};
 use serde_json::json;
 
-pub struct ApiError(anyhow::Error);
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    error: anyhow::Error,
+    code: Option<&'static str>,
+    retry_after: Option<i64>,
+    issues: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: anyhow::anyhow!(message.into()),
+            code: None,
+            retry_after: None,
+            issues: None,
+        }
+    }
+
+    /// Adds a machine-readable `code` to the response body.
+    #[must_use]
+    pub const fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message).with_code(code)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    /// A `429` telling the client to wait `retry_after` seconds.
+    pub fn too_many_requests(message: impl Into<String>, retry_after: i64) -> Self {
+        let mut err = Self::new(StatusCode::TOO_MANY_REQUESTS, message).with_code("rate_limited");
+        err.retry_after = Some(retry_after.max(1));
+        err
+    }
+
+    /// A `422` listing each way the upload's metadata doesn't match its files.
+    pub fn validation_failed(issues: &impl serde::Serialize) -> Self {
+        let mut err = Self::new(StatusCode::UNPROCESSABLE_ENTITY, "Metadata validation failed");
+        err.issues = Some(json!(issues));
+        err
+    }
+}
+
+impl ApiError {
+    pub const fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The JSON body sent for this error.
+    pub fn body(&self) -> serde_json::Value {
+        let mut body = json!({
+            "error": self.error.to_string(),
+        });
+        if let Some(code) = self.code {
+            body["code"] = json!(code);
+        }
+
+        if let Some(retry_after) = self.retry_after {
+            body["retry_after"] = json!(retry_after);
+        }
+
+        if let Some(issues) = &self.issues {
+            body["issues"] = issues.clone();
+        }
+
+        body
+    }
+}
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        let error_message = self.0.to_string();
-        let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+        if self.status.is_server_error() {
+            tracing::error!("Request failed: {:#}", self.error);
+        }
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
+        let mut response = (self.status, Json(self.body())).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
 
-        (status_code, body).into_response()
+        response
     }
 }
 
@@ -26,6 +128,12 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: err.into(),
+            code: None,
+            retry_after: None,
+            issues: None,
+        }
     }
-}
\ No newline at end of file
+}
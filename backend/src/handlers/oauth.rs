@@ -0,0 +1,219 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+    Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{extractor, mfa, oauth, session, AuthUser, ClientInfo},
+    db::{models::OAuthIdentity, queries},
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeQuery {
+    /// Path to return to after logging in, defaults to `/`.
+    pub redirect_to: Option<String>,
+    /// Link the provider to the logged-in account instead of logging in.
+    #[serde(default)]
+    pub link: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+pub async fn list_providers(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(json!({
+        "providers": state.oauth.names().collect::<Vec<_>>()
+    }))
+}
+
+pub async fn authorize(
+    State(state): State<Arc<AppState>>,
+    Path(provider_name): Path<String>,
+    user: Option<AuthUser>,
+    Query(params): Query<AuthorizeQuery>,
+) -> Result<Response, ApiError> {
+    let provider = state
+        .oauth
+        .get(&provider_name)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown login provider '{provider_name}'")))?;
+
+    let link_user_id = if params.link {
+        let user = user.ok_or_else(|| ApiError::unauthorized("Log in to link a provider"))?;
+        user.require_session()?;
+        Some(user.user_id)
+    } else {
+        None
+    };
+
+    let started = oauth::begin_authorization(
+        &state.db,
+        &state.config.oauth,
+        &provider_name,
+        provider.as_ref(),
+        oauth::sanitize_redirect(params.redirect_to.as_deref()),
+        link_user_id,
+    )
+    .await
+    .map_err(|err| {
+        tracing::warn!("Could not start {provider_name} login: {err:#}");
+        ApiError::new(
+            axum::http::StatusCode::BAD_GATEWAY,
+            format!("Login provider '{provider_name}' is unavailable"),
+        )
+    })?;
+
+    let cookie = oauth::start_browser_cookie(&state.config.auth, &started.browser_token)?;
+
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(&started.url)).into_response())
+}
+
+pub async fn callback(
+    State(state): State<Arc<AppState>>,
+    Path(provider_name): Path<String>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Query(params): Query<CallbackQuery>,
+) -> Result<Response, ApiError> {
+    let provider = state
+        .oauth
+        .get(&provider_name)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown login provider '{provider_name}'")))?;
+
+    if let Some(error) = params.error {
+        return Err(ApiError::bad_request(format!(
+            "{provider_name} login was not completed: {}",
+            params.error_description.unwrap_or(error)
+        )));
+    }
+    let (Some(code), Some(oauth_state)) = (params.code, params.state) else {
+        return Err(ApiError::bad_request("Missing code or state"));
+    };
+    let browser_token =
+        extractor::cookie_value(&headers, oauth::BROWSER_COOKIE).ok_or_else(|| {
+            ApiError::bad_request("Login request was started in another browser, please try again")
+        })?;
+
+    let (user, redirect_to) = oauth::complete_authorization(
+        &state.db,
+        &state.config.oauth,
+        state.config.auth.first_user_is_admin,
+        &provider_name,
+        provider.as_ref(),
+        &oauth::Callback {
+            state: &oauth_state,
+            code: &code,
+            browser_token: &browser_token,
+        },
+    )
+    .await?;
+    let clear_browser = oauth::clear_browser_cookie(&state.config.auth)?;
+
+    let frontend_url = state
+        .config
+        .oauth
+        .frontend_url
+        .as_deref()
+        .unwrap_or_default();
+
+    // The provider only stands in for the password. The challenge goes in the
+    // fragment so it never reaches server logs.
+    if mfa::is_enabled(&state.db, &user.id).await? {
+        let challenge = mfa::issue_challenge(
+            &state.config.auth,
+            &user.id,
+            &format!("oauth:{provider_name}"),
+            Some(redirect_to),
+        )?;
+        let location = format!("{frontend_url}/login/mfa#mfa_token={}", challenge.token);
+        return Ok((
+            [(header::SET_COOKIE, clear_browser)],
+            Redirect::to(&location),
+        )
+            .into_response());
+    }
+
+    queries::update_last_login(&state.db, &user.id, Utc::now()).await?;
+
+    let (_, issued) =
+        session::start_session(&state.db, &state.config.auth, &user.id, &client).await?;
+    let cookie = session::session_cookie(&state.config.auth, &issued.token, issued.expires_in)?;
+
+    let event = AuditEvent::new(AuditAction::Login)
+        .actor(&user.id)
+        .target("user", &user.id)
+        .diff(json!({ "method": "oauth", "provider": provider_name }));
+    audit::record(&state.db, event, &client).await;
+
+    let location = format!("{frontend_url}{redirect_to}");
+
+    let cookies = AppendHeaders([
+        (header::SET_COOKIE, cookie),
+        (header::SET_COOKIE, clear_browser),
+    ]);
+
+    Ok((cookies, Redirect::to(&location)).into_response())
+}
+
+pub async fn list_identities(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<OAuthIdentity>>, ApiError> {
+    let identities = queries::get_oauth_identities_for_user(&state.db, &user.user_id).await?;
+    Ok(Json(identities))
+}
+
+pub async fn unlink_identity(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path(provider_name): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_session()?;
+
+    let account = queries::get_user_by_id(&state.db, &user.user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    let identities = queries::get_oauth_identities_for_user(&state.db, &user.user_id).await?;
+
+    if !identities
+        .iter()
+        .any(|identity| identity.provider == provider_name)
+    {
+        return Err(ApiError::not_found(format!(
+            "No {provider_name} account is linked"
+        )));
+    }
+    // Accounts created through a provider have no password to fall back on.
+    if account.password_hash.is_empty() && identities.len() == 1 {
+        return Err(ApiError::conflict(
+            "Cannot unlink the only way to log in to this account",
+        ));
+    }
+
+    queries::delete_oauth_identity(&state.db, &user.user_id, &provider_name).await?;
+
+    let event = AuditEvent::new(AuditAction::OAuthUnlinked)
+        .actor(&user.user_id)
+        .target("user", &user.user_id)
+        .diff(json!({ "provider": provider_name }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(json!({
+        "message": format!("Unlinked {provider_name}")
+    })))
+}
@@ -3,10 +3,13 @@ use axum::{
     Json,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::sync::Arc;
 
 use crate::{
-    db::{models::*, queries},
+    audit::{self, AuditAction, AuditEvent},
+    auth::{artist, ApiScope, AuthUser, ClientInfo},
+    db::{models::{CreatePlaylist, Playlist, Track}, queries},
     AppState,
 };
 
@@ -37,7 +40,7 @@ pub async fn get_playlist(
 ) -> Result<Json<PlaylistResponse>, ApiError> {
     let playlist = queries::get_playlist_by_id(&state.db, &id)
         .await?
-        .ok_or_else(|| anyhow::anyhow!("Playlist not found"))?;
+        .ok_or_else(|| ApiError::not_found("Playlist not found"))?;
 
     let tracks = queries::get_playlist_tracks(&state.db, &id).await?;
 
@@ -46,20 +49,37 @@ pub async fn get_playlist(
 
 pub async fn create_playlist(
     State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
     Json(payload): Json<CreatePlaylist>,
 ) -> Result<Json<Playlist>, ApiError> {
-    let playlist = payload.into_playlist();
+    user.require_scope(ApiScope::Upload)?;
+    let playlist = payload.into_playlist(&user.user_id);
     let playlist = queries::create_playlist(&state.db, playlist).await?;
+
+    let event = AuditEvent::new(AuditAction::PlaylistCreated)
+        .actor(&user.user_id)
+        .target("playlist", &playlist.id)
+        .diff(json!({ "after": playlist }));
+    audit::record(&state.db, event, &client).await;
+
     Ok(Json(playlist))
 }
 
 pub async fn add_track_to_playlist(
     State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
     Path(playlist_id): Path<String>,
     Json(payload): Json<AddTrackRequest>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
+    ensure_can_modify(&state, &user, &playlist_id).await?;
+
     let tracks = queries::get_playlist_tracks(&state.db, &playlist_id).await?;
-    let position = payload.position.unwrap_or(tracks.len() as i32);
+    let position = match payload.position {
+        Some(position) => position,
+        None => i32::try_from(tracks.len())?,
+    };
 
     queries::add_track_to_playlist(
         &state.db,
@@ -69,22 +89,64 @@ pub async fn add_track_to_playlist(
     )
     .await?;
 
-    Ok(Json(serde_json::json!({
+    let event = AuditEvent::new(AuditAction::PlaylistTrackAdded)
+        .actor(&user.user_id)
+        .target("playlist", &playlist_id)
+        .diff(json!({ "after": { "track_id": payload.track_id, "position": position } }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(json!({
         "message": "Track added to playlist"
     })))
 }
 
 pub async fn remove_track_from_playlist(
     State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
     Path((playlist_id, track_id)): Path<(String, String)>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
+    ensure_can_modify(&state, &user, &playlist_id).await?;
+
     let removed = queries::remove_track_from_playlist(&state.db, &playlist_id, &track_id).await?;
 
     if !removed {
-        return Err(anyhow::anyhow!("Track not found in playlist").into());
+        return Err(ApiError::not_found("Track not found in playlist"));
     }
 
-    Ok(Json(serde_json::json!({
+    let event = AuditEvent::new(AuditAction::PlaylistTrackRemoved)
+        .actor(&user.user_id)
+        .target("playlist", &playlist_id)
+        .diff(json!({ "before": { "track_id": track_id } }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(json!({
         "message": "Track removed from playlist"
     })))
-}
\ No newline at end of file
+}
+
+/// Only the owner of a playlist, or an acting admin, may change it.
+async fn ensure_can_modify(
+    state: &AppState,
+    user: &AuthUser,
+    playlist_id: &str,
+) -> Result<Playlist, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let playlist = queries::get_playlist_by_id(&state.db, playlist_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Playlist not found"))?;
+
+    if playlist.user_id.as_deref() == Some(user.user_id.as_str()) {
+        return Ok(playlist);
+    }
+
+    if artist::is_acting_admin(&state.db, &state.config.auth, user).await? {
+        Ok(playlist)
+    } else {
+        Err(ApiError::forbidden(
+            "not_playlist_owner",
+            "Only the owner of this playlist can change it",
+        ))
+    }
+}
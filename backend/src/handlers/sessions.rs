@@ -0,0 +1,80 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{AuthUser, ClientInfo},
+    db::{models::Session, queries},
+    AppState,
+};
+
+use super::ApiError;
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    id: String,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    current: bool,
+}
+
+impl SessionResponse {
+    fn new(session: Session, current_session_id: &str) -> Self {
+        Self {
+            current: session.id == current_session_id,
+            id: session.id,
+            ip_address: session.ip_address,
+            user_agent: session.user_agent,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<SessionResponse>>, ApiError> {
+    let current_session_id = user.require_session()?;
+    let sessions = queries::get_active_sessions_for_user(&state.db, &user.user_id, Utc::now())
+        .await?
+        .into_iter()
+        .map(|session| SessionResponse::new(session, current_session_id))
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path(session_id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_session()?;
+
+    let revoked =
+        queries::revoke_session(&state.db, &session_id, &user.user_id, Utc::now()).await?;
+
+    if !revoked {
+        return Err(ApiError::not_found("Session not found"));
+    }
+
+    let event = AuditEvent::new(AuditAction::SessionRevoked)
+        .actor(&user.user_id)
+        .target("session", &session_id);
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(serde_json::json!({
+        "message": "Session revoked"
+    })))
+}
@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use crate::{
+    auth::{ApiScope, AuthUser},
     db::queries,
     AppState,
 };
@@ -27,11 +28,16 @@ const PRESIGNED_URL_EXPIRY_SECONDS: u64 = 3600; // 1 hour
 
 pub async fn get_stream_url(
     State(state): State<Arc<AppState>>,
+    user: Option<AuthUser>,
     Path(track_id): Path<String>,
 ) -> Result<Json<StreamUrlResponse>, ApiError> {
+    if let Some(user) = &user {
+        user.require_scope(ApiScope::Stream)?;
+    }
+
     let track = queries::get_track_by_id(&state.db, &track_id)
         .await?
-        .ok_or_else(|| anyhow::anyhow!("Track not found"))?;
+        .ok_or_else(|| ApiError::not_found("Track not found"))?;
 
     let url = state
         .storage
@@ -46,11 +52,16 @@ pub async fn get_stream_url(
 
 pub async fn record_play(
     State(state): State<Arc<AppState>>,
+    user: Option<AuthUser>,
     Path(track_id): Path<String>,
     Json(payload): Json<RecordPlayRequest>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
-    // TODO: Get user_id from auth context when implemented
-    let user_id = None;
+    if let Some(user) = &user {
+        user.require_scope(ApiScope::Stream)?;
+    }
+
+    // Anonymous listeners are still counted, just without a user.
+    let user_id = user.as_ref().map(|user| user.user_id.as_str());
 
     queries::record_play(&state.db, &track_id, user_id, payload.duration).await?;
 
@@ -3,14 +3,26 @@ use axum::{
     Json,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{path::Path as FsPath, sync::Arc};
 
 use crate::{
-    db::{models::*, queries},
+    audit::{self, AuditAction, AuditEvent},
+    auth::{artist, ApiScope, ArtistPermission, AuthUser, ClientInfo},
+    db::{
+        models::{CreateTrack, Track, UpdateTrack},
+        queries,
+    },
+    ingest::{
+        chunked,
+        metadata::{self, AudioMetadata},
+        provenance::{FieldSources, Source},
+        spool::SpooledFile,
+        DEFAULT_ALBUM_TITLE,
+    },
     AppState,
 };
 
-use super::ApiError;
+use super::{upload::unreadable_audio, ApiError};
 
 #[derive(Debug, Deserialize)]
 pub struct SearchQuery {
@@ -42,31 +54,314 @@ pub async fn get_track(
 ) -> Result<Json<Track>, ApiError> {
     let track = queries::get_track_by_id(&state.db, &id)
         .await?
-        .ok_or_else(|| anyhow::anyhow!("Track not found"))?;
+        .ok_or_else(|| ApiError::not_found("Track not found"))?;
 
     Ok(Json(track))
 }
 
 pub async fn create_track(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateTrack>,
+    user: AuthUser,
+    client: ClientInfo,
+    Json(mut payload): Json<CreateTrack>,
 ) -> Result<Json<Track>, ApiError> {
-    let track = payload.into_track();
-    let track = queries::create_track(&state.db, track).await?;
+    user.require_scope(ApiScope::Upload)?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        &user,
+        payload.artist_id.as_deref(),
+        ArtistPermission::Upload,
+    )
+    .await?;
+    check_file_path(payload.artist_id.as_deref(), &payload.file_path)?;
+    if !state.storage.file_exists(&payload.file_path).await? {
+        return Err(ApiError::bad_request(format!(
+            "{} is not in storage",
+            payload.file_path
+        )));
+    }
+
+    let mut sources = FieldSources::default();
+    for field in present_fields(&payload) {
+        sources.set(field, Source::Form);
+    }
+    if payload.is_incomplete() {
+        let embedded = read_stored_metadata(&state, &payload.file_path).await?;
+        fill_missing(&mut payload, embedded);
+        for field in present_fields(&payload) {
+            if sources.get(field).is_none() {
+                sources.set(
+                    field,
+                    if field == "duration" {
+                        Source::Measured
+                    } else {
+                        Source::Tags
+                    },
+                );
+            }
+        }
+    }
+
+    let mut track = payload.into_track();
+    if track.title.trim().is_empty() {
+        return Err(ApiError::bad_request(
+            "Title is required, the file has no title tag",
+        ));
+    }
+    if track.album.trim().is_empty() {
+        DEFAULT_ALBUM_TITLE.clone_into(&mut track.album);
+    }
+    if let Some(artist_id) = &track.artist_id {
+        let artist = queries::get_artist_by_id(&state.db, artist_id)
+            .await?
+            .ok_or_else(|| ApiError::not_found("Artist not found"))?;
+        if track.artist.trim().is_empty() {
+            track.artist = artist.name;
+        }
+    }
+
+    let track = queries::create_track(&state.db, track, &sources.to_json()).await?;
+
+    let event = AuditEvent::new(AuditAction::TrackCreated)
+        .actor(&user.user_id)
+        .target("track", &track.id)
+        .diff(serde_json::json!({ "after": track }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(track))
+}
+
+/// A track of an artist can only use a file stored under that artist, so a
+/// member can't point it at another artist's audio. Tracks without an artist
+/// are admin-only and may use any stored file except unfinished chunked
+/// uploads.
+fn check_file_path(artist_id: Option<&str>, key: &str) -> Result<(), ApiError> {
+    if key
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(ApiError::bad_request(
+            "file_path must be a plain storage key",
+        ));
+    }
+    let Some(artist_id) = artist_id else {
+        if key.starts_with(chunked::STORAGE_PREFIX) {
+            return Err(ApiError::bad_request(format!(
+                "file_path can't be under {}",
+                chunked::STORAGE_PREFIX
+            )));
+        }
+        return Ok(());
+    };
+    let own = key
+        .strip_prefix("music/")
+        .and_then(|rest| rest.strip_prefix(artist_id))
+        .is_some_and(|rest| rest.starts_with('/'));
+    if !own {
+        return Err(ApiError::bad_request(format!(
+            "file_path must be a file under music/{artist_id}/"
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the tags of a file that is already in storage.
+async fn read_stored_metadata(state: &AppState, key: &str) -> Result<AudioMetadata, ApiError> {
+    let (spooled, mut file) =
+        SpooledFile::create(FsPath::new(&state.config.upload.spool_dir)).await?;
+    if let Err(err) = state.storage.download_to(key, &mut file).await {
+        tracing::warn!("Failed to fetch {key} to read its tags: {err:#}");
+        return Err(ApiError::bad_request(format!(
+            "Could not read {key} from storage to fill in the missing fields"
+        )));
+    }
+    drop(file);
+
+    let embedded = metadata::extract(spooled.path(), None)
+        .await
+        .map_err(|err| unreadable_audio(&err))?;
+    Ok(embedded)
+}
+
+/// The manifest fields `payload` has a value for.
+fn present_fields(payload: &CreateTrack) -> Vec<&'static str> {
+    let given = |value: &Option<String>| {
+        value
+            .as_deref()
+            .is_some_and(|value| !value.trim().is_empty())
+    };
+    [
+        ("title", given(&payload.title)),
+        ("duration", payload.duration.is_some()),
+        ("genre", given(&payload.genre)),
+        ("year", payload.year.is_some()),
+        ("track_number", payload.track_number.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(field, present)| present.then_some(field))
+    .collect()
+}
+
+/// Fills fields the client left out or blank from the file's tags.
+fn fill_missing(payload: &mut CreateTrack, embedded: AudioMetadata) {
+    fn fill(field: &mut Option<String>, value: Option<String>) {
+        if field
+            .as_deref()
+            .is_none_or(|current| current.trim().is_empty())
+            && value.is_some()
+        {
+            *field = value;
+        }
+    }
+
+    fill(&mut payload.title, embedded.title);
+    fill(&mut payload.album, embedded.album);
+    // The artist profile's name is used instead when there is one.
+    if payload.artist_id.is_none() {
+        fill(&mut payload.artist, embedded.artist);
+    }
+    fill(&mut payload.genre, embedded.genre);
+    payload.duration = payload.duration.or(embedded.duration);
+    payload.year = payload.year.or(embedded.year);
+    payload.track_number = payload.track_number.or(embedded.track_number);
+}
+
+pub async fn update_track(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateTrack>,
+) -> Result<Json<Track>, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let before = queries::get_track_by_id(&state.db, &id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Track not found"))?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        &user,
+        before.artist_id.as_deref(),
+        ArtistPermission::Edit,
+    )
+    .await?;
+
+    // Moving a track is an upload to the new artist, and detaching it hands it
+    // to the admins.
+    match &payload.artist_id {
+        Some(Some(artist_id)) if before.artist_id.as_ref() != Some(artist_id) => {
+            queries::get_artist_by_id(&state.db, artist_id)
+                .await?
+                .ok_or_else(|| ApiError::not_found("Artist not found"))?;
+            artist::require_permission(
+                &state.db,
+                &state.config.auth,
+                &user,
+                Some(artist_id),
+                ArtistPermission::Upload,
+            )
+            .await?;
+        }
+        Some(None)
+            if before.artist_id.is_some()
+                && !artist::is_acting_admin(&state.db, &state.config.auth, &user).await? =>
+        {
+            return Err(ApiError::forbidden(
+                "admin_required",
+                "Only admins can detach a track from its artist",
+            ));
+        }
+        _ => {}
+    }
+
+    let mut track = before.clone();
+    payload.apply(&mut track);
+    let mut sources =
+        FieldSources::parse(queries::get_track_sources(&state.db, &id).await?.as_deref());
+    for (field, changed) in [
+        ("title", track.title != before.title),
+        ("genre", track.genre != before.genre),
+        ("year", track.year != before.year),
+        ("track_number", track.track_number != before.track_number),
+    ] {
+        if changed {
+            sources.set(field, Source::Edited);
+        }
+    }
+    if !queries::update_track(&state.db, &track, &sources.to_json()).await? {
+        return Err(ApiError::not_found("Track not found"));
+    }
+
+    let event = AuditEvent::new(AuditAction::TrackUpdated)
+        .actor(&user.user_id)
+        .target("track", &id)
+        .diff(serde_json::json!({ "before": before, "after": track }));
+    audit::record(&state.db, event, &client).await;
+
     Ok(Json(track))
 }
 
 pub async fn delete_track(
     State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
     Path(id): Path<String>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let track = queries::get_track_by_id(&state.db, &id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Track not found"))?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        &user,
+        track.artist_id.as_deref(),
+        ArtistPermission::Delete,
+    )
+    .await?;
+
     let deleted = queries::delete_track(&state.db, &id).await?;
-    
     if !deleted {
-        return Err(anyhow::anyhow!("Track not found").into());
+        return Err(ApiError::not_found("Track not found"));
     }
 
+    let event = AuditEvent::new(AuditAction::TrackDeleted)
+        .actor(&user.user_id)
+        .target("track", &id)
+        .diff(serde_json::json!({ "before": track }));
+    audit::record(&state.db, event, &client).await;
+
     Ok(Json(serde_json::json!({
         "message": "Track deleted successfully"
     })))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::check_file_path;
+
+    #[test]
+    fn keeps_artist_tracks_to_their_own_files() {
+        assert!(check_file_path(Some("a1"), "music/a1/album/track.flac").is_ok());
+        for key in [
+            "music/a2/album/track.flac",
+            "music/a1x/album/track.flac",
+            "music/a1",
+            "uploads/chunk",
+            "music/a1/../a2/track.flac",
+            "music/a1//track.flac",
+            "songs/a1/track.flac",
+        ] {
+            assert!(check_file_path(Some("a1"), key).is_err(), "{key}");
+        }
+    }
+
+    #[test]
+    fn keeps_admin_tracks_out_of_chunked_uploads() {
+        assert!(check_file_path(None, "songs/artist/album/song.mp3").is_ok());
+        assert!(check_file_path(None, "uploads/abc/part").is_err());
+        assert!(check_file_path(None, "songs/../uploads/abc").is_err());
+    }
+}
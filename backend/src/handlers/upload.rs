@@ -0,0 +1,698 @@
+use axum::{
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, Path as AxumPath, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{artist, ApiScope, ArtistPermission, AuthUser, ClientInfo},
+    db::{
+        models::{Album, AlbumVersion, Track, UploadJob},
+        queries,
+    },
+    ingest::{
+        album::AlbumUploadMetadata,
+        filename, fingerprint,
+        format::{AudioFormat, SNIFF_LEN},
+        jobs::{self, FileProgress},
+        metadata, non_empty,
+        provenance::{FieldSources, Source},
+        spool::SpooledFile,
+        store, transcode,
+        validation::{self, TrackCheck},
+        versions::ReleaseStatus,
+        DEFAULT_ALBUM_TITLE,
+    },
+    AppState,
+};
+
+use super::ApiError;
+
+/// Largest `metadata` form field accepted.
+const MAX_METADATA_BYTES: usize = 64 * 1024;
+
+/// The `metadata` form field. Anything missing is filled in from the file's
+/// own tags, then from the file name, or left empty.
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadMetadata {
+    title: Option<String>,
+    /// Name of an existing artist, used when `artist_id` is not given.
+    artist: Option<String>,
+    artist_id: Option<String>,
+    album: Option<String>,
+    duration: Option<i32>,
+    genre: Option<String>,
+    year: Option<i32>,
+    track_number: Option<i32>,
+    /// Store a WAV or AIFF file as FLAC.
+    #[serde(default)]
+    convert_to_flac: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub track: Track,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplaceAudioResponse {
+    track: Track,
+    /// The album version the new audio is in.
+    version: AlbumVersion,
+}
+
+/// An album upload and how far it has got.
+#[derive(Debug, Serialize)]
+pub struct UploadStatusResponse {
+    upload_id: String,
+    /// `queued`, `processing`, `completed` or `failed`.
+    status: String,
+    /// `extract`, `probe`, `transcode`, `store` or `index`; the last one
+    /// reached once finished.
+    stage: Option<String>,
+    files: Vec<FileProgress>,
+    storage: StorageSummary,
+    album_id: Option<String>,
+    /// The error response the upload failed with, e.g. validation issues.
+    error: Option<serde_json::Value>,
+    attempts: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+/// Bytes uploaded against bytes stored, which is less when WAV and AIFF
+/// files were converted to FLAC.
+#[derive(Debug, Serialize)]
+pub struct StorageSummary {
+    uploaded: u64,
+    stored: u64,
+    saved: u64,
+}
+
+impl StorageSummary {
+    fn of(files: &[FileProgress]) -> Self {
+        let uploaded = files.iter().map(|file| file.bytes).sum();
+        let stored = files
+            .iter()
+            .map(|file| file.stored_bytes.unwrap_or(file.bytes))
+            .sum();
+
+        Self {
+            uploaded,
+            stored,
+            saved: uploaded.saturating_sub(stored),
+        }
+    }
+}
+
+impl UploadStatusResponse {
+    pub fn from_job(job: UploadJob) -> anyhow::Result<Self> {
+        let files: Vec<FileProgress> = serde_json::from_str(&job.files)?;
+        Ok(Self {
+            storage: StorageSummary::of(&files),
+            files,
+            error: job.error.as_deref().map(serde_json::from_str).transpose()?,
+            upload_id: job.id,
+            status: job.status,
+            stage: job.stage,
+            album_id: job.album_id,
+            attempts: job.attempts,
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+            finished_at: job.finished_at,
+        })
+    }
+}
+
+/// An uploaded file written to the spool directory.
+pub struct ReceivedFile {
+    pub spooled: SpooledFile,
+    pub file_name: Option<String>,
+}
+
+/// `POST /api/v1/upload/file`: stores one audio file and creates its track.
+///
+/// Expects a `file` field and an optional JSON `metadata` field. The file is
+/// spooled to disk rather than held in memory, checked, then stored under
+/// `music/{artist_id}/{album_id}/{track_id}.{ext}`.
+pub async fn upload_file(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<UploadResponse>), ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let spool_dir = Path::new(&state.config.upload.spool_dir);
+    let mut received = None;
+    let mut metadata = UploadMetadata::default();
+    loop {
+        let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|err| multipart_error(&err))?
+        else {
+            break;
+        };
+        match field.name() {
+            Some("file") => {
+                let file_name = field.file_name().map(str::to_string);
+                let spooled =
+                    spool_field(field, spool_dir, state.config.upload.max_file_bytes).await?;
+                received = Some(ReceivedFile { spooled, file_name });
+            }
+            Some("metadata") => metadata = read_metadata_field(field).await?,
+            _ => {}
+        }
+    }
+    let received = received.ok_or_else(|| ApiError::bad_request("No file provided"))?;
+
+    let track = create_uploaded_track(&state, &user, &client, received, metadata).await?;
+
+    Ok((StatusCode::CREATED, Json(UploadResponse { track })))
+}
+
+/// Checks a received audio file, stores it and creates its track, filling in
+/// what `metadata` leaves out from the file's tags and name.
+pub async fn create_uploaded_track(
+    state: &AppState,
+    user: &AuthUser,
+    client: &ClientInfo,
+    received: ReceivedFile,
+    metadata: UploadMetadata,
+) -> Result<Track, ApiError> {
+    let (format, embedded) = read_audio(&received).await?;
+    let from_name = received
+        .file_name
+        .as_deref()
+        .map(|name| state.filename_parser.parse(name))
+        .unwrap_or_default();
+
+    // The file's artist tag is only used when the form names no artist.
+    let artist_name = non_empty(metadata.artist.as_deref()).or(embedded.artist.as_deref());
+    let artist = store::resolve_artist(state, metadata.artist_id.as_deref(), artist_name).await?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        user,
+        Some(&artist.id),
+        ArtistPermission::Upload,
+    )
+    .await?;
+
+    let album = uploaded_album(&metadata, &embedded, &artist.id, &user.user_id);
+    let track_number = metadata
+        .track_number
+        .or(embedded.track_number)
+        .or(from_name.track_number);
+    let check = TrackCheck {
+        file_name: received.file_name.as_deref().unwrap_or("file"),
+        format,
+        duration: metadata.duration,
+        measured_duration: embedded.duration,
+        track_number,
+    };
+    let existing =
+        queries::get_album_track_numbers(&state.db, &artist.id, &album.normalized_title).await?;
+    validation::check(&[check], &existing)?;
+    let new_album_id = album.id.clone();
+    let album = queries::get_or_create_album(&state.db, album).await?;
+    let created_album = (album.id == new_album_id).then_some(new_album_id);
+
+    let sources = uploaded_track_sources(&metadata, &embedded, &from_name, &album);
+    let content_hash = fingerprint::hash_file(received.spooled.path()).await?;
+    let (spooled, stored_format, _) = if metadata.convert_to_flac {
+        transcode::convert_upload(received.spooled, format, &embedded).await
+    } else {
+        (received.spooled, format, None)
+    };
+
+    let title = non_empty(metadata.title.as_deref())
+        .map(str::to_string)
+        .or(embedded.title)
+        .or(from_name.title)
+        .unwrap_or_else(|| "Untitled".to_string());
+
+    let track_id = Uuid::new_v4().to_string();
+    let key = store::track_key(&artist.id, &album.id, &track_id, stored_format);
+    if let Err(err) = store::store(state, &key, spooled.path(), stored_format.content_type()).await
+    {
+        if let Some(album_id) = &created_album {
+            store::remove_empty_album(state, album_id).await;
+        }
+        return Err(err);
+    }
+
+    let now = chrono::Utc::now();
+    let track = Track {
+        id: track_id,
+        title,
+        artist: artist.name,
+        artist_id: Some(artist.id),
+        album: album.title,
+        album_id: Some(album.id),
+        duration: metadata.duration.or(embedded.duration).unwrap_or(0),
+        file_path: key,
+        original_filename: received.file_name,
+        original_format: Some(format.extension().to_string()),
+        stored_format: Some(stored_format.extension().to_string()),
+        cover_art_path: album.cover_art_path,
+        genre: metadata.genre.or(embedded.genre).or(album.genre),
+        year: metadata.year.or(embedded.year).or(album.year),
+        track_number,
+        created_at: now,
+        updated_at: now,
+    };
+
+    if let Err(err) = queries::create_track(&state.db, track.clone(), &sources.to_json()).await {
+        store::remove_stored(state, std::slice::from_ref(&track)).await;
+        if let Some(album_id) = &created_album {
+            store::remove_empty_album(state, album_id).await;
+        }
+        return Err(err.into());
+    }
+    // Only once the track exists, so a failed upload leaves no fingerprint.
+    fingerprint::record(state, &track.file_path, spooled.path(), &content_hash).await;
+
+    let event = AuditEvent::new(AuditAction::TrackCreated)
+        .actor(&user.user_id)
+        .target("track", &track.id)
+        .diff(serde_json::json!({ "after": track }));
+    audit::record(&state.db, event, client).await;
+
+    Ok(track)
+}
+
+/// The album a single uploaded track goes in, named by the form or the file's
+/// tags.
+fn uploaded_album(
+    metadata: &UploadMetadata,
+    embedded: &metadata::AudioMetadata,
+    artist_id: &str,
+    user_id: &str,
+) -> Album {
+    let album_title = non_empty(metadata.album.as_deref())
+        .or(embedded.album.as_deref())
+        .unwrap_or(DEFAULT_ALBUM_TITLE);
+    let mut album = Album::new(artist_id, album_title, user_id);
+    let form_artist =
+        metadata.artist_id.is_some() || non_empty(metadata.artist.as_deref()).is_some();
+    let mut album_sources = FieldSources::default();
+    album_sources.set_first(
+        "title",
+        &[
+            (non_empty(metadata.album.as_deref()).is_some(), Source::Form),
+            (embedded.album.is_some(), Source::Tags),
+            (true, Source::Default),
+        ],
+    );
+    album_sources.set_first(
+        "artist",
+        &[(form_artist, Source::Form), (true, Source::Tags)],
+    );
+    album.metadata_sources = Some(album_sources.to_json());
+    album
+}
+
+/// Where each field of a single uploaded track comes from, in the order
+/// [`create_uploaded_track`] fills them.
+fn uploaded_track_sources(
+    metadata: &UploadMetadata,
+    embedded: &metadata::AudioMetadata,
+    from_name: &filename::ParsedFilename,
+    album: &Album,
+) -> FieldSources {
+    let mut sources = FieldSources::default();
+    sources.set_first(
+        "title",
+        &[
+            (non_empty(metadata.title.as_deref()).is_some(), Source::Form),
+            (embedded.title.is_some(), Source::Tags),
+            (from_name.title.is_some(), Source::Filename),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "track_number",
+        &[
+            (metadata.track_number.is_some(), Source::Form),
+            (embedded.track_number.is_some(), Source::Tags),
+            (from_name.track_number.is_some(), Source::Filename),
+        ],
+    );
+    sources.set_first(
+        "duration",
+        &[
+            (metadata.duration.is_some(), Source::Form),
+            (embedded.duration.is_some(), Source::Measured),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "genre",
+        &[
+            (metadata.genre.is_some(), Source::Form),
+            (embedded.genre.is_some(), Source::Tags),
+            (album.genre.is_some(), Source::Album),
+        ],
+    );
+    sources.set_first(
+        "year",
+        &[
+            (metadata.year.is_some(), Source::Form),
+            (embedded.year.is_some(), Source::Tags),
+            (album.year.is_some(), Source::Album),
+        ],
+    );
+
+    sources
+}
+
+/// `PUT /api/v1/tracks/{id}/audio`: replaces one track's audio without
+/// touching the rest of its album.
+///
+/// Expects a `file` field. The track keeps its id and metadata, apart from
+/// the measured duration, and the change is saved as a new version of the
+/// album, so the old audio stays in the version before.
+pub async fn replace_track_audio(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    AxumPath(id): AxumPath<String>,
+    mut multipart: Multipart,
+) -> Result<Json<ReplaceAudioResponse>, ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let before = queries::get_track_by_id(&state.db, &id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Track not found"))?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        &user,
+        before.artist_id.as_deref(),
+        ArtistPermission::Edit,
+    )
+    .await?;
+    let (album, current) = current_album(&state, &before).await?;
+
+    let spool_dir = Path::new(&state.config.upload.spool_dir);
+    let mut received = None;
+    loop {
+        let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|err| multipart_error(&err))?
+        else {
+            break;
+        };
+        if field.name() == Some("file") {
+            let file_name = field.file_name().map(str::to_string);
+            let spooled = spool_field(field, spool_dir, state.config.upload.max_file_bytes).await?;
+            received = Some(ReceivedFile { spooled, file_name });
+        }
+    }
+    let received = received.ok_or_else(|| ApiError::bad_request("No file provided"))?;
+
+    let (format, embedded) = read_audio(&received).await?;
+    let check = TrackCheck {
+        file_name: received.file_name.as_deref().unwrap_or("file"),
+        format,
+        duration: None,
+        measured_duration: embedded.duration,
+        track_number: None,
+    };
+    validation::check(&[check], &[])?;
+
+    // A new key, so the previous version keeps its audio.
+    let key = store::track_key(
+        &album.artist_id,
+        &album.id,
+        &Uuid::new_v4().to_string(),
+        format,
+    );
+    let content_hash = fingerprint::hash_file(received.spooled.path()).await?;
+    store::store(&state, &key, received.spooled.path(), format.content_type()).await?;
+
+    let mut track = before.clone();
+    track.file_path.clone_from(&key);
+    track.duration = embedded.duration.unwrap_or(before.duration);
+    track.original_filename = received
+        .file_name
+        .or_else(|| before.original_filename.clone());
+    track.original_format = Some(format.extension().to_string());
+    track.stored_format = Some(format.extension().to_string());
+    track.updated_at = chrono::Utc::now();
+
+    let version = next_version(&state, &album, &user.user_id, track.updated_at).await?;
+    let mut sources = FieldSources::parse(
+        queries::get_track_sources(&state.db, &track.id)
+            .await?
+            .as_deref(),
+    );
+    if embedded.duration.is_some() {
+        sources.set("duration", Source::Measured);
+    }
+    let sources = [(track.id.clone(), sources.to_json())];
+    let track_ids: Vec<String> = current.into_iter().map(|track| track.id).collect();
+    let saved = queries::save_album_version(
+        &state.db,
+        &version,
+        &[],
+        std::slice::from_ref(&track),
+        &sources,
+        &track_ids,
+    )
+    .await;
+    let version = match saved {
+        Ok(version) => version,
+        Err(err) => {
+            store::remove_key(&state, &key).await;
+            return Err(err.into());
+        }
+    };
+    fingerprint::record(&state, &key, received.spooled.path(), &content_hash).await;
+
+    let event = AuditEvent::new(AuditAction::TrackUpdated)
+        .actor(&user.user_id)
+        .target("track", &track.id)
+        .diff(serde_json::json!({ "before": before, "after": track, "version_id": version.id }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(Json(ReplaceAudioResponse { track, version }))
+}
+
+/// Detects the format of a received file and reads its tags.
+async fn read_audio(
+    received: &ReceivedFile,
+) -> Result<(AudioFormat, metadata::AudioMetadata), ApiError> {
+    let header = received.spooled.read_header(SNIFF_LEN).await?;
+    let format = AudioFormat::sniff(&header).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported audio format, expected FLAC, MP3, OGG, M4A, WAV or AIFF",
+        )
+    })?;
+    let embedded = metadata::extract(received.spooled.path(), Some(format))
+        .await
+        .map_err(|err| unreadable_audio(&err))?;
+    Ok((format, embedded))
+}
+
+/// The album a track is on and the tracks of its current version, which must
+/// include the track.
+async fn current_album(state: &AppState, track: &Track) -> Result<(Album, Vec<Track>), ApiError> {
+    let album = match track.album_id.as_deref() {
+        Some(album_id) => queries::get_album_by_id(&state.db, album_id).await?,
+        None => None,
+    }
+    .ok_or_else(|| {
+        ApiError::bad_request("Only tracks on an album can have their audio replaced")
+    })?;
+    let current = queries::get_album_tracks(&state.db, &album.id).await?;
+    if !current.iter().any(|current| current.id == track.id) {
+        return Err(
+            ApiError::conflict("Track is not in the album's current version")
+                .with_code("track_superseded"),
+        );
+    }
+    Ok((album, current))
+}
+
+/// A version to follow the album's current one, released or not as that one
+/// is. `save_album_version` numbers it.
+async fn next_version(
+    state: &AppState,
+    album: &Album,
+    user_id: &str,
+    created_at: DateTime<Utc>,
+) -> Result<AlbumVersion, ApiError> {
+    let release_status = match &album.current_version_id {
+        Some(current) => queries::get_album_version(&state.db, current).await?,
+        None => None,
+    }
+    .map_or_else(
+        || ReleaseStatus::default().as_str().to_string(),
+        |version| version.release_status,
+    );
+    Ok(AlbumVersion {
+        id: Uuid::new_v4().to_string(),
+        album_id: album.id.clone(),
+        version_number: 0,
+        label: None,
+        release_status,
+        upload_id: None,
+        created_by: Some(user_id.to_string()),
+        created_at,
+    })
+}
+
+/// `POST /api/v1/upload/album`: queues a ZIP of audio files to be made into
+/// an album in the background.
+///
+/// Expects a `file` field with the ZIP and an optional JSON `metadata` field.
+/// Returns `202` with the upload's status, which can be followed at
+/// `GET /api/v1/upload/status/{upload_id}`.
+pub async fn upload_album(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    client: ClientInfo,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<UploadStatusResponse>), ApiError> {
+    user.require_scope(ApiScope::Upload)?;
+
+    let config = &state.config.upload;
+    let spool_dir = PathBuf::from(&config.spool_dir);
+    let mut zip = None;
+    let mut form = AlbumUploadMetadata::default();
+    loop {
+        let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|err| multipart_error(&err))?
+        else {
+            break;
+        };
+        match field.name() {
+            Some("file") => zip = Some(spool_field(field, &spool_dir, config.max_zip_bytes).await?),
+            Some("metadata") => form = read_metadata_field(field).await?,
+            _ => {}
+        }
+    }
+    let zip = zip.ok_or_else(|| ApiError::bad_request("No file provided"))?;
+
+    let job = jobs::new_job(&user, &client, zip.path(), &form)?;
+    state.upload_queue.enqueue(&state.db, &job).await?;
+    // The worker removes it once the job is finished.
+    zip.keep();
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(UploadStatusResponse::from_job(job)?),
+    ))
+}
+
+/// `GET /api/v1/upload/status/{upload_id}`: how far an album upload has got.
+/// Only the uploader and admins can see it.
+pub async fn upload_status(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    AxumPath(upload_id): AxumPath<String>,
+) -> Result<Json<UploadStatusResponse>, ApiError> {
+    let job = queries::get_upload_job(&state.db, &upload_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Upload not found"))?;
+    if job.user_id != user.user_id
+        && !artist::is_acting_admin(&state.db, &state.config.auth, &user).await?
+    {
+        return Err(ApiError::not_found("Upload not found"));
+    }
+
+    Ok(Json(UploadStatusResponse::from_job(job)?))
+}
+
+/// Writes a multipart field to a spool file, stopping at `max_bytes`.
+async fn spool_field(
+    mut field: Field<'_>,
+    dir: &Path,
+    max_bytes: u64,
+) -> Result<SpooledFile, ApiError> {
+    let (spooled, mut file) = SpooledFile::create(dir).await?;
+
+    let mut written: u64 = 0;
+    loop {
+        let Some(chunk) = field.chunk().await.map_err(|err| multipart_error(&err))? else {
+            break;
+        };
+        written += chunk.len() as u64;
+        if written > max_bytes {
+            return Err(file_too_large(max_bytes));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    if written == 0 {
+        return Err(ApiError::bad_request("Uploaded file is empty"));
+    }
+
+    Ok(spooled)
+}
+
+async fn read_metadata_field<T: serde::de::DeserializeOwned>(
+    mut field: Field<'_>,
+) -> Result<T, ApiError> {
+    let mut bytes = Vec::new();
+    loop {
+        let Some(chunk) = field.chunk().await.map_err(|err| multipart_error(&err))? else {
+            break;
+        };
+        if bytes.len() + chunk.len() > MAX_METADATA_BYTES {
+            return Err(ApiError::bad_request("Metadata is too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&bytes)
+        .map_err(|err| ApiError::bad_request(format!("Invalid metadata JSON: {err}")))
+}
+
+/// Keeps the status axum picked, e.g. `413` when the body limit is hit.
+fn multipart_error(err: &MultipartError) -> ApiError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "Upload is too large");
+    }
+
+    ApiError::new(err.status(), err.body_text())
+}
+
+/// The contents looked like audio but couldn't be parsed.
+pub fn unreadable_audio(err: &anyhow::Error) -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Could not read the audio file: {err}"),
+    )
+}
+
+pub fn file_too_large(max_bytes: u64) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!(
+            "File too large, the limit is {} MB",
+            max_bytes / (1024 * 1024)
+        ),
+    )
+}
@@ -0,0 +1,874 @@
+//! Turning an uploaded album ZIP into stored tracks, run by the upload
+//! workers in [`super::jobs`].
+//!
+//! For each track the manifest wins over embedded tags, which win over what
+//! the file name says. Either all tracks are created or none are, as a new
+//! version of the album (see [`super::versions`]).
+
+use std::path::PathBuf;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{artist, extractor::Credential, ArtistPermission, AuthUser, ClientInfo},
+    db::{
+        models::{Album, AlbumVersion, Artist, Track, UploadJob},
+        queries,
+    },
+    handlers::ApiError,
+    AppState,
+};
+
+use super::{
+    archive::{self, ExtractLimits, ExtractedAlbum, ExtractedFile},
+    filename::{self, FilenameParser, ParsedFilename},
+    fingerprint,
+    format::{AudioFormat, ImageFormat, SNIFF_LEN},
+    jobs::{FileProgress, FileStatus, JobProgress, JobStage},
+    manifest::{names_file, AlbumManifest, ManifestTrack},
+    metadata::{self, AudioMetadata},
+    non_empty,
+    provenance::{FieldSources, Source},
+    spool::SpooledFile,
+    store, transcode,
+    validation::{self, TrackCheck},
+    versions::{self, ReleaseStatus, UploadMode},
+    DEFAULT_ALBUM_TITLE,
+};
+
+/// The `metadata` form field of an album upload, for what the ZIP's manifest
+/// doesn't say.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AlbumUploadMetadata {
+    pub artist: Option<String>,
+    /// Takes precedence over any artist name, including the manifest's.
+    pub artist_id: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    /// Required when the album already has tracks.
+    pub mode: Option<UploadMode>,
+    /// Used when the manifest gives no version label or release status.
+    pub version: Option<String>,
+    pub release_status: Option<ReleaseStatus>,
+    /// Store WAV and AIFF files as FLAC, see [`super::transcode`].
+    #[serde(default)]
+    pub convert_to_flac: bool,
+}
+
+/// An audio file from an album ZIP, checked and read.
+struct AlbumFile {
+    name: String,
+    /// Where it was in the ZIP, which is what the manifest and the job's
+    /// progress know it by.
+    path: String,
+    spooled: SpooledFile,
+    /// What gets stored, FLAC once a WAV or AIFF is converted.
+    format: AudioFormat,
+    /// What was in the ZIP.
+    uploaded_format: AudioFormat,
+    /// Hex SHA-256 of the file as it was in the ZIP, before any conversion.
+    content_hash: String,
+    embedded: AudioMetadata,
+    from_name: ParsedFilename,
+}
+
+impl AlbumFile {
+    /// The manifest's number first, then the file's tags, then its name.
+    fn track_number(&self, listed: Option<&ManifestTrack>) -> Option<i32> {
+        listed
+            .and_then(|listed| listed.track_number)
+            .or(self.embedded.track_number)
+            .or(self.from_name.track_number)
+    }
+}
+
+/// An album that passed validation, ready to be stored.
+struct CheckedAlbum {
+    artist: Artist,
+    album: Album,
+    manifest: AlbumManifest,
+    /// Each with the current track it replaces, if any.
+    files: Vec<(AlbumFile, Option<Track>)>,
+    /// Ids of current tracks the new version carries over unchanged.
+    kept: Vec<String>,
+    label: Option<String>,
+    release_status: ReleaseStatus,
+    cover: Option<ExtractedFile>,
+}
+
+/// The stored files of a checked album, ready to be made its new version.
+struct StoredAlbum {
+    album: Album,
+    created: Vec<Track>,
+    replaced: Vec<Track>,
+    /// `(track_id, JSON)` for the created and replaced tracks.
+    sources: Vec<(String, String)>,
+    kept: Vec<String>,
+    label: Option<String>,
+    release_status: ReleaseStatus,
+}
+
+/// Processes the upload `job`, recording how far it got in `progress`.
+pub async fn process(
+    state: &AppState,
+    job: &UploadJob,
+    progress: &mut JobProgress,
+) -> Result<(), ApiError> {
+    let user = AuthUser {
+        user_id: job.user_id.clone(),
+        credential: serde_json::from_str::<Credential>(&job.credential)?,
+    };
+    let options: AlbumUploadMetadata = serde_json::from_str(&job.options)?;
+    let convert_to_flac = options.convert_to_flac;
+
+    // An earlier attempt got as far as saving the version.
+    if queries::get_album_version_by_upload(&state.db, &job.id)
+        .await?
+        .is_some()
+    {
+        for file in &mut progress.files {
+            file.status = FileStatus::Indexed;
+        }
+        progress.save().await?;
+        return Ok(());
+    }
+
+    progress.set_stage(JobStage::Extract).await?;
+    let extracted = extract(state, PathBuf::from(&job.zip_path)).await?;
+    // Keeps what earlier attempts stored, to be reused.
+    let mut files = Vec::with_capacity(extracted.audio.len());
+    for file in &extracted.audio {
+        let bytes = tokio::fs::metadata(file.spooled.path()).await?.len();
+        let previous = progress
+            .files
+            .iter()
+            .find(|progress| progress.file == file.path);
+        files.push(previous.map_or_else(
+            || FileProgress::new(&file.path, bytes),
+            |previous| FileProgress {
+                error: None,
+                ..previous.clone()
+            },
+        ));
+    }
+    progress.files = files;
+
+    progress.set_stage(JobStage::Probe).await?;
+    let mut checked = check(state, &user, options, extracted, progress).await?;
+
+    progress.set_stage(JobStage::Transcode).await?;
+    checked.files = transcode_files(checked.files, convert_to_flac, progress).await?;
+
+    progress.set_stage(JobStage::Store).await?;
+    let stored = store_album(state, checked, progress).await?;
+
+    progress.set_stage(JobStage::Index).await?;
+    let album = &stored.album;
+    let version = save_version(state, job, &stored).await?;
+    for track in stored.created.iter().chain(&stored.replaced) {
+        let entry = track
+            .original_filename
+            .as_deref()
+            .and_then(|name| progress.file_mut(name));
+        if let Some(entry) = entry {
+            entry.track_id = Some(track.id.clone());
+        }
+    }
+    for file in &mut progress.files {
+        file.status = FileStatus::Indexed;
+    }
+    progress.save().await?;
+    if let Some(cover) = &album.cover_art_path {
+        queries::set_album_cover(&state.db, &album.id, cover).await?;
+    }
+
+    let client = ClientInfo {
+        ip_address: job.ip_address.clone(),
+        user_agent: job.user_agent.clone(),
+    };
+    let event = AuditEvent::new(AuditAction::AlbumUploaded)
+        .actor(&user.user_id)
+        .target("album", &album.id)
+        .diff(serde_json::json!({
+            "upload_id": job.id,
+            "version_id": version.id,
+            "version_number": version.version_number,
+            "tracks": stored.created.iter().map(|track| &track.id).collect::<Vec<_>>(),
+            "replaced": stored.replaced.iter().map(|track| &track.id).collect::<Vec<_>>(),
+            "cover_art_path": album.cover_art_path,
+        }));
+    audit::record(&state.db, event, &client).await;
+
+    Ok(())
+}
+
+/// Records the stored tracks as the album's new current version. An album
+/// whose current version has no tracks yet, e.g. one just created, has that
+/// version filled in instead.
+async fn save_version(
+    state: &AppState,
+    job: &UploadJob,
+    stored: &StoredAlbum,
+) -> Result<AlbumVersion, ApiError> {
+    let album = &stored.album;
+    let mut version_id = None;
+    if let Some(current) = &album.current_version_id {
+        if queries::get_album_version_tracks(&state.db, current)
+            .await?
+            .is_empty()
+        {
+            version_id = Some(current.clone());
+        }
+    }
+
+    let version = AlbumVersion {
+        id: version_id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        album_id: album.id.clone(),
+        version_number: 0,
+        label: stored.label.clone(),
+        release_status: stored.release_status.as_str().to_string(),
+        upload_id: Some(job.id.clone()),
+        created_by: Some(job.user_id.clone()),
+        created_at: chrono::Utc::now(),
+    };
+    let track_ids: Vec<String> = stored
+        .created
+        .iter()
+        .chain(&stored.replaced)
+        .map(|track| track.id.clone())
+        .chain(stored.kept.iter().cloned())
+        .collect();
+
+    Ok(queries::save_album_version(
+        &state.db,
+        &version,
+        &stored.created,
+        &stored.replaced,
+        &stored.sources,
+        &track_ids,
+    )
+    .await?)
+}
+
+async fn extract(state: &AppState, zip_path: PathBuf) -> Result<ExtractedAlbum, ApiError> {
+    if !tokio::fs::try_exists(&zip_path).await? {
+        return Err(ApiError::new(
+            StatusCode::GONE,
+            "The uploaded ZIP is no longer available",
+        ));
+    }
+
+    // Audio barely compresses, so this is plenty for a real album while
+    // stopping archives that expand without end.
+    let config = &state.config.upload;
+    let limits = ExtractLimits {
+        max_entry_bytes: config.max_file_bytes,
+        max_total_bytes: config.max_zip_bytes.saturating_mul(2),
+    };
+    let spool_dir = PathBuf::from(&config.spool_dir);
+    tokio::task::spawn_blocking(move || archive::extract(&zip_path, &spool_dir, limits)).await?
+}
+
+/// Reads every file, works out the artist and album, and checks the uploader
+/// may add to it and that the metadata matches the audio.
+async fn check(
+    state: &AppState,
+    user: &AuthUser,
+    options: AlbumUploadMetadata,
+    extracted: ExtractedAlbum,
+    progress: &mut JobProgress,
+) -> Result<CheckedAlbum, ApiError> {
+    let manifest = extracted.manifest.unwrap_or_default();
+    check_listed(&manifest, &extracted.audio)?;
+
+    let files = read_album_files(&state.filename_parser, extracted.audio, progress).await?;
+
+    let first_tag =
+        |tag: fn(&AudioMetadata) -> Option<&str>| files.iter().find_map(|file| tag(&file.embedded));
+    let from_folders = folder_names(&files);
+    let artist_name = non_empty(manifest.album.artist.as_deref())
+        .or_else(|| non_empty(options.artist.as_deref()))
+        .or_else(|| first_tag(|tags| tags.artist.as_deref()))
+        .or_else(|| from_folders.and_then(|name| name.artist.as_deref()));
+    let artist = store::resolve_artist(state, options.artist_id.as_deref(), artist_name).await?;
+    artist::require_permission(
+        &state.db,
+        &state.config.auth,
+        user,
+        Some(&artist.id),
+        ArtistPermission::Upload,
+    )
+    .await?;
+
+    let album_title = non_empty(manifest.album.title.as_deref())
+        .or_else(|| non_empty(options.album.as_deref()))
+        .or_else(|| first_tag(|tags| tags.album.as_deref()))
+        .or_else(|| from_folders.and_then(|name| name.album.as_deref()))
+        .unwrap_or(DEFAULT_ALBUM_TITLE);
+    let mut album = Album::new(&artist.id, album_title, &user.user_id);
+    album.year = manifest
+        .album
+        .year
+        .or(options.year)
+        .or_else(|| files.iter().find_map(|file| file.embedded.year))
+        .or_else(|| from_folders.and_then(|name| name.year));
+    album.genre = manifest
+        .album
+        .genre
+        .clone()
+        .or_else(|| options.genre.clone())
+        .or_else(|| first_tag(|tags| tags.genre.as_deref()).map(str::to_string));
+    album.metadata_sources = Some(
+        album_sources(
+            &manifest,
+            &options,
+            &files,
+            from_folders,
+            extracted.cover.is_some(),
+        )
+        .to_json(),
+    );
+
+    let (label, release_status) = version_label(&manifest, &options)?;
+
+    let current =
+        match queries::get_album_by_title(&state.db, &artist.id, &album.normalized_title).await? {
+            Some(existing) => queries::get_album_tracks(&state.db, &existing.id).await?,
+            None => Vec::new(),
+        };
+    let mode = match options.mode {
+        Some(mode) => mode,
+        None if current.is_empty() => UploadMode::NewVersion,
+        None => {
+            return Err(ApiError::conflict(format!(
+                "Album '{}' already has tracks, set mode to \"update\" or \"new_version\"",
+                album.title
+            ))
+            .with_code("album_exists"));
+        }
+    };
+
+    let numbered: Vec<(&str, Option<i32>)> = files
+        .iter()
+        .map(|file| {
+            (
+                file.name.as_str(),
+                file.track_number(manifest.track(&file.path)),
+            )
+        })
+        .collect();
+    let (replaced, kept) = replaced_and_kept(mode, &current, &numbered);
+    let existing: Vec<(i32, String)> = kept
+        .iter()
+        .filter_map(|track| Some((track.track_number?, track.title.clone())))
+        .collect();
+    validate_album(&manifest, &files, &existing)?;
+
+    Ok(CheckedAlbum {
+        artist,
+        album,
+        manifest,
+        files: files.into_iter().zip(replaced).collect(),
+        kept: kept.into_iter().map(|track| track.id.clone()).collect(),
+        label,
+        release_status,
+        cover: extracted.cover,
+    })
+}
+
+/// The version label and release status the manifest, or else the form,
+/// gives the upload.
+fn version_label(
+    manifest: &AlbumManifest,
+    options: &AlbumUploadMetadata,
+) -> Result<(Option<String>, ReleaseStatus), ApiError> {
+    let release_status = match non_empty(manifest.album.release_status.as_deref()) {
+        Some(status) => ReleaseStatus::parse(status).ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "Unknown release_status '{status}', expected draft or released"
+            ))
+        })?,
+        None => options.release_status.unwrap_or_default(),
+    };
+    let label = non_empty(manifest.album.version.as_deref())
+        .or_else(|| non_empty(options.version.as_deref()))
+        .map(str::to_string);
+    Ok((label, release_status))
+}
+
+/// The current track each file replaces, if any, and the current tracks the
+/// new version keeps alongside the files.
+fn replaced_and_kept<'a>(
+    mode: UploadMode,
+    current: &'a [Track],
+    numbered: &[(&str, Option<i32>)],
+) -> (Vec<Option<Track>>, Vec<&'a Track>) {
+    let replaced: Vec<Option<Track>> = versions::match_replaced(current, numbered)
+        .into_iter()
+        .map(Option::<&Track>::cloned)
+        .collect();
+    let kept: Vec<&Track> = match mode {
+        UploadMode::Update => current
+            .iter()
+            .filter(|track| {
+                !replaced
+                    .iter()
+                    .flatten()
+                    .any(|replaced| replaced.id == track.id)
+            })
+            .collect(),
+        UploadMode::NewVersion => Vec::new(),
+    };
+    (replaced, kept)
+}
+
+/// What the folders the files are in say, from the file whose folders say
+/// it most surely.
+fn folder_names(files: &[AlbumFile]) -> Option<&ParsedFilename> {
+    files
+        .iter()
+        .map(|file| &file.from_name)
+        .filter(|name| name.folder_confidence > 0.0)
+        .max_by(|a, b| a.folder_confidence.total_cmp(&b.folder_confidence))
+}
+
+/// Where the album fields worked out in `check` came from, in the same order.
+fn album_sources(
+    manifest: &AlbumManifest,
+    options: &AlbumUploadMetadata,
+    files: &[AlbumFile],
+    from_folders: Option<&ParsedFilename>,
+    has_cover: bool,
+) -> FieldSources {
+    let album = &manifest.album;
+    let tagged = |tag: fn(&AudioMetadata) -> bool| files.iter().any(|file| tag(&file.embedded));
+    let named = |name: fn(&ParsedFilename) -> bool| from_folders.is_some_and(name);
+    let mut sources = FieldSources::default();
+    sources.set_first(
+        "title",
+        &[
+            (
+                non_empty(album.title.as_deref()).is_some(),
+                Source::Manifest,
+            ),
+            (non_empty(options.album.as_deref()).is_some(), Source::Form),
+            (
+                tagged(|tags| non_empty(tags.album.as_deref()).is_some()),
+                Source::Tags,
+            ),
+            (named(|name| name.album.is_some()), Source::Filename),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "artist",
+        &[
+            (
+                non_empty(options.artist_id.as_deref()).is_some(),
+                Source::Form,
+            ),
+            (
+                non_empty(album.artist.as_deref()).is_some(),
+                Source::Manifest,
+            ),
+            (non_empty(options.artist.as_deref()).is_some(), Source::Form),
+            (
+                tagged(|tags| non_empty(tags.artist.as_deref()).is_some()),
+                Source::Tags,
+            ),
+            (true, Source::Filename),
+        ],
+    );
+    sources.set_first(
+        "year",
+        &[
+            (album.year.is_some(), Source::Manifest),
+            (options.year.is_some(), Source::Form),
+            (tagged(|tags| tags.year.is_some()), Source::Tags),
+            (named(|name| name.year.is_some()), Source::Filename),
+        ],
+    );
+    sources.set_first(
+        "genre",
+        &[
+            (album.genre.is_some(), Source::Manifest),
+            (options.genre.is_some(), Source::Form),
+            (true, Source::Tags),
+        ],
+    );
+    sources.set_first(
+        "cover",
+        &[
+            (has_cover && album.cover.is_some(), Source::Manifest),
+            (has_cover, Source::Archive),
+        ],
+    );
+
+    sources
+}
+
+/// Checks that each file the manifest lists is exactly one file in the ZIP.
+fn check_listed(manifest: &AlbumManifest, audio: &[ExtractedFile]) -> Result<(), ApiError> {
+    for track in &manifest.tracks {
+        match audio.iter().filter(|file| names_file(&track.file, &file.path)).count() {
+            0 => {
+                return Err(ApiError::bad_request(format!(
+                    "The manifest lists {}, which is not in the ZIP",
+                    track.file
+                )))
+            }
+            1 => {}
+            _ => {
+                return Err(ApiError::bad_request(format!(
+                    "The manifest lists {}, which could be any of several files in the ZIP, give its folder too",
+                    track.file
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the format of every audio file from an album ZIP, reads its tags
+/// and what its path says, and hashes it before it can be converted.
+async fn read_album_files(
+    parser: &FilenameParser,
+    audio: Vec<ExtractedFile>,
+    progress: &mut JobProgress,
+) -> Result<Vec<AlbumFile>, ApiError> {
+    let mut from_names: Vec<ParsedFilename> =
+        audio.iter().map(|file| parser.parse(&file.path)).collect();
+    filename::number_across_discs(&mut from_names);
+
+    let mut files = Vec::with_capacity(audio.len());
+    for (file, from_name) in audio.into_iter().zip(from_names) {
+        let read = read_album_file(&file).await;
+        let entry = progress.file_mut(&file.path);
+        let (format, embedded, content_hash) = match read {
+            Ok(read) => {
+                if let Some(entry) = entry.filter(|entry| entry.status == FileStatus::Pending) {
+                    entry.status = FileStatus::Probed;
+                }
+                read
+            }
+            Err(err) => {
+                if let Some(entry) = entry {
+                    entry.error = err.body()["error"].as_str().map(str::to_string);
+                }
+                progress.save().await?;
+                return Err(err);
+            }
+        };
+        progress.save().await?;
+
+        files.push(AlbumFile {
+            from_name,
+            name: file.name,
+            path: file.path,
+            spooled: file.spooled,
+            format,
+            uploaded_format: format,
+            content_hash,
+            embedded,
+        });
+    }
+
+    Ok(files)
+}
+
+async fn read_album_file(
+    file: &ExtractedFile,
+) -> Result<(AudioFormat, AudioMetadata, String), ApiError> {
+    let header = file.spooled.read_header(SNIFF_LEN).await?;
+    let format = AudioFormat::sniff(&header).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "{} is not FLAC, MP3, OGG, M4A, WAV or AIFF audio",
+                file.path
+            ),
+        )
+    })?;
+    let embedded = metadata::extract(file.spooled.path(), Some(format))
+        .await
+        .map_err(|err| {
+            ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Could not read {}: {err}", file.path),
+            )
+        })?;
+    let content_hash = fingerprint::hash_file(file.spooled.path()).await?;
+
+    Ok((format, embedded, content_hash))
+}
+
+/// Checks every file against the manifest and the tracks the new version
+/// keeps before anything is stored.
+fn validate_album(
+    manifest: &AlbumManifest,
+    files: &[AlbumFile],
+    existing: &[(i32, String)],
+) -> Result<(), ApiError> {
+    let checks: Vec<TrackCheck<'_>> = files
+        .iter()
+        .map(|file| {
+            let listed = manifest.track(&file.path);
+            TrackCheck {
+                file_name: &file.path,
+                format: file.format,
+                duration: listed.and_then(|listed| listed.duration),
+                measured_duration: file.embedded.duration,
+                track_number: file.track_number(listed),
+            }
+        })
+        .collect();
+
+    validation::check(&checks, existing)
+}
+
+/// Converts WAV and AIFF files to FLAC when the uploader asked for it, and
+/// records what each file is stored as. Files an earlier attempt already
+/// stored converted are not converted again.
+async fn transcode_files(
+    files: Vec<(AlbumFile, Option<Track>)>,
+    convert_to_flac: bool,
+    progress: &mut JobProgress,
+) -> Result<Vec<(AlbumFile, Option<Track>)>, ApiError> {
+    let mut transcoded = Vec::with_capacity(files.len());
+    for (mut file, previous) in files {
+        let entry = progress
+            .file_mut(&file.path)
+            .ok_or_else(|| anyhow::anyhow!("No progress for {}", file.path))?;
+        let stored_converted = file.format.is_uncompressed()
+            && entry.status == FileStatus::Stored
+            && entry.stored_format.as_deref() == Some(AudioFormat::Flac.extension());
+
+        if stored_converted {
+            file.format = AudioFormat::Flac;
+        } else {
+            entry.stored_bytes = Some(entry.bytes);
+            if convert_to_flac {
+                let (spooled, format, converted) =
+                    transcode::convert_upload(file.spooled, file.format, &file.embedded).await;
+                file.spooled = spooled;
+                file.format = format;
+                if let Some(converted) = converted {
+                    entry.stored_bytes = Some(converted.stored_bytes);
+                }
+            }
+            entry.stored_format = Some(file.format.extension().to_string());
+        }
+        progress.save().await?;
+        transcoded.push((file, previous));
+    }
+
+    Ok(transcoded)
+}
+
+/// Creates the album if needed and stores its cover and every file, reusing
+/// files an earlier attempt already stored.
+///
+/// Files are stored under new keys even when they replace a track, so the
+/// versions before keep their audio.
+async fn store_album(
+    state: &AppState,
+    checked: CheckedAlbum,
+    progress: &mut JobProgress,
+) -> Result<StoredAlbum, ApiError> {
+    let CheckedAlbum {
+        artist,
+        album,
+        manifest,
+        files,
+        kept,
+        label,
+        release_status,
+        cover,
+    } = checked;
+    let mut album = queries::get_or_create_album(&state.db, album).await?;
+    progress.set_album(&album.id);
+    if let Some(cover) = &cover {
+        album.cover_art_path = Some(store_cover(state, cover, &album).await?);
+    }
+
+    let mut created: Vec<Track> = Vec::with_capacity(files.len());
+    let mut replaced: Vec<Track> = Vec::new();
+    let mut sources = Vec::with_capacity(files.len());
+    for (file, previous) in files {
+        let entry = progress
+            .file_mut(&file.path)
+            .ok_or_else(|| anyhow::anyhow!("No progress for {}", file.path))?;
+        let stored =
+            entry
+                .track_id
+                .clone()
+                .zip(entry.file_path.clone())
+                .filter(|(file_id, key)| {
+                    *key == store::track_key(&artist.id, &album.id, file_id, file.format)
+                });
+
+        let (file_id, key) = if let Some(stored) = stored {
+            stored
+        } else {
+            let file_id = Uuid::new_v4().to_string();
+            let key = store::track_key(&artist.id, &album.id, &file_id, file.format);
+            if let Err(err) =
+                store::store(state, &key, file.spooled.path(), file.format.content_type()).await
+            {
+                entry.error = Some("Failed to store the file".to_string());
+                return Err(err);
+            }
+            fingerprint::record(state, &key, file.spooled.path(), &file.content_hash).await;
+            entry.status = FileStatus::Stored;
+            entry.track_id = Some(file_id.clone());
+            entry.file_path = Some(key.clone());
+            (file_id, key)
+        };
+        progress.save().await?;
+
+        let listed = manifest.track(&file.path).cloned();
+        let (mut track, track_sources) = album_track(file, listed, &artist, &album, file_id, key);
+        let is_replacement = previous.is_some();
+        if let Some(previous) = previous {
+            track.id = previous.id;
+            track.created_at = previous.created_at;
+        }
+        sources.push((track.id.clone(), track_sources.to_json()));
+        if is_replacement {
+            replaced.push(track);
+        } else {
+            created.push(track);
+        }
+    }
+
+    Ok(StoredAlbum {
+        album,
+        created,
+        replaced,
+        sources,
+        kept,
+        label,
+        release_status,
+    })
+}
+
+/// Stores the album's cover next to its tracks and returns the key.
+async fn store_cover(
+    state: &AppState,
+    cover: &ExtractedFile,
+    album: &Album,
+) -> Result<String, ApiError> {
+    let header = cover.spooled.read_header(SNIFF_LEN).await?;
+    let format = ImageFormat::sniff(&header).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("{} is not a JPEG or PNG image", cover.name),
+        )
+    })?;
+
+    let key = format!(
+        "music/{}/{}/cover.{}",
+        album.artist_id,
+        album.id,
+        format.extension()
+    );
+    store::store(state, &key, cover.spooled.path(), format.content_type()).await?;
+
+    Ok(key)
+}
+
+/// The track for a stored album file, and where its metadata came from: the
+/// manifest's entry first, then the file's tags, then its name.
+fn album_track(
+    file: AlbumFile,
+    listed: Option<ManifestTrack>,
+    artist: &Artist,
+    album: &Album,
+    track_id: String,
+    key: String,
+) -> (Track, FieldSources) {
+    let track_number = file.track_number(listed.as_ref());
+    let listed = listed.unwrap_or_default();
+    let embedded = file.embedded;
+    let now = chrono::Utc::now();
+
+    let mut sources = FieldSources::default();
+    sources.set_first(
+        "title",
+        &[
+            (
+                non_empty(listed.title.as_deref()).is_some(),
+                Source::Manifest,
+            ),
+            (embedded.title.is_some(), Source::Tags),
+            (file.from_name.title.is_some(), Source::Filename),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "track_number",
+        &[
+            (listed.track_number.is_some(), Source::Manifest),
+            (embedded.track_number.is_some(), Source::Tags),
+            (file.from_name.track_number.is_some(), Source::Filename),
+        ],
+    );
+    sources.set_first(
+        "duration",
+        &[
+            (listed.duration.is_some(), Source::Manifest),
+            (embedded.duration.is_some(), Source::Measured),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "genre",
+        &[
+            (listed.genre.is_some(), Source::Manifest),
+            (embedded.genre.is_some(), Source::Tags),
+            (album.genre.is_some(), Source::Album),
+        ],
+    );
+    sources.set_first(
+        "year",
+        &[
+            (listed.year.is_some(), Source::Manifest),
+            (embedded.year.is_some(), Source::Tags),
+            (album.year.is_some(), Source::Album),
+        ],
+    );
+
+    let track = Track {
+        id: track_id,
+        title: non_empty(listed.title.as_deref())
+            .map(str::to_string)
+            .or(embedded.title)
+            .or(file.from_name.title)
+            .unwrap_or_else(|| "Untitled".to_string()),
+        artist: artist.name.clone(),
+        artist_id: Some(artist.id.clone()),
+        album: album.title.clone(),
+        album_id: Some(album.id.clone()),
+        duration: listed.duration.or(embedded.duration).unwrap_or(0),
+        file_path: key,
+        original_filename: Some(file.name),
+        original_format: Some(file.uploaded_format.extension().to_string()),
+        stored_format: Some(file.format.extension().to_string()),
+        cover_art_path: album.cover_art_path.clone(),
+        genre: listed
+            .genre
+            .or(embedded.genre)
+            .or_else(|| album.genre.clone()),
+        year: listed.year.or(embedded.year).or(album.year),
+        track_number,
+        created_at: now,
+        updated_at: now,
+    };
+
+    (track, sources)
+}
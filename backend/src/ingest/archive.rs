@@ -0,0 +1,401 @@
+//! Unpacking album ZIPs into spool files.
+//!
+//! Only audio files, the manifest and the cover are extracted; anything else
+//! in the archive (liner notes, cue sheets, `__MACOSX` folders) is skipped.
+//! Files are found wherever they are in the ZIP, so zipping the album's
+//! folder works too; the folders are only read for names, see
+//! [`super::filename`].
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use axum::http::StatusCode;
+use zip::ZipArchive;
+
+use crate::handlers::ApiError;
+
+use super::{
+    format::AudioFormat,
+    manifest::{base_name, names_file, AlbumManifest, MANIFEST_NAMES},
+    spool::SpooledFile,
+};
+
+/// Covers used when the manifest doesn't name one, in order of preference.
+const COVER_NAMES: &[&str] = &[
+    "cover.jpg",
+    "cover.jpeg",
+    "cover.png",
+    "folder.jpg",
+    "album.jpg",
+    "front.jpg",
+];
+const MAX_MANIFEST_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    /// Largest single file once uncompressed.
+    pub max_entry_bytes: u64,
+    /// Largest total once uncompressed, against ZIP bombs.
+    pub max_total_bytes: u64,
+}
+
+/// A file taken out of the archive.
+#[derive(Debug)]
+pub struct ExtractedFile {
+    /// Name without any folders, e.g. `01-song.flac`.
+    pub name: String,
+    /// Where it was in the ZIP, e.g. `Artist - Album/CD1/01-song.flac`.
+    pub path: String,
+    pub spooled: SpooledFile,
+}
+
+#[derive(Debug)]
+pub struct ExtractedAlbum {
+    pub manifest: Option<AlbumManifest>,
+    /// Sorted by path.
+    pub audio: Vec<ExtractedFile>,
+    pub cover: Option<ExtractedFile>,
+}
+
+enum EntryKind {
+    Manifest,
+    Audio,
+    Image,
+}
+
+struct Entry {
+    index: usize,
+    name: String,
+    path: String,
+    kind: EntryKind,
+}
+
+/// Extracts the album in the ZIP at `zip_path` into `spool_dir`. Blocking.
+pub fn extract(
+    zip_path: &Path,
+    spool_dir: &Path,
+    limits: ExtractLimits,
+) -> Result<ExtractedAlbum, ApiError> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?).map_err(|err| invalid_zip(&err))?;
+    let entries = list_entries(&mut archive)?;
+    std::fs::create_dir_all(spool_dir)?;
+
+    let manifest = match entries
+        .iter()
+        .find(|entry| matches!(entry.kind, EntryKind::Manifest))
+    {
+        Some(entry) => {
+            let mut contents = Vec::new();
+            let mut file = archive
+                .by_index(entry.index)
+                .map_err(|err| invalid_zip(&err))?;
+            (&mut file)
+                .take(MAX_MANIFEST_BYTES + 1)
+                .read_to_end(&mut contents)?;
+            if contents.len() as u64 > MAX_MANIFEST_BYTES {
+                return Err(ApiError::bad_request(format!(
+                    "{} is too large",
+                    entry.name
+                )));
+            }
+
+            let manifest = AlbumManifest::parse(&entry.name, &contents)
+                .map_err(|err| ApiError::bad_request(format!("Invalid {}: {err}", entry.name)))?;
+            Some(manifest)
+        }
+        None => None,
+    };
+
+    let cover_entry = pick_cover(&entries, manifest.as_ref())?;
+    let mut total: u64 = 0;
+    let mut audio = Vec::new();
+    for entry in entries
+        .iter()
+        .filter(|entry| matches!(entry.kind, EntryKind::Audio))
+    {
+        audio.push(extract_entry(
+            &mut archive,
+            entry,
+            spool_dir,
+            limits,
+            &mut total,
+        )?);
+    }
+    let cover = cover_entry
+        .map(|entry| extract_entry(&mut archive, entry, spool_dir, limits, &mut total))
+        .transpose()?;
+
+    if audio.is_empty() {
+        return Err(ApiError::bad_request("The ZIP contains no audio files"));
+    }
+    audio.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(ExtractedAlbum {
+        manifest,
+        audio,
+        cover,
+    })
+}
+
+fn list_entries(archive: &mut ZipArchive<File>) -> Result<Vec<Entry>, ApiError> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+
+    for index in 0..archive.len() {
+        let file = archive.by_index(index).map_err(|err| invalid_zip(&err))?;
+        // Skips directories and paths that would escape the archive.
+        let Some(path) = file.enclosed_name().filter(|_| file.is_file()) else {
+            continue;
+        };
+        let path = path.to_string_lossy().replace('\\', "/");
+        if path
+            .split('/')
+            .any(|part| part == "__MACOSX" || part.starts_with('.'))
+        {
+            continue;
+        }
+
+        let name = base_name(&path).to_string();
+        let lower = name.to_ascii_lowercase();
+        let extension = lower
+            .rsplit_once('.')
+            .map(|(_, ext)| ext)
+            .unwrap_or_default();
+        let kind = if MANIFEST_NAMES.contains(&lower.as_str()) {
+            EntryKind::Manifest
+        } else if AudioFormat::from_extension(extension).is_some() {
+            EntryKind::Audio
+        } else if matches!(extension, "jpg" | "jpeg" | "png") {
+            EntryKind::Image
+        } else {
+            continue;
+        };
+
+        // Discs may reuse names (`CD1/01.flac`, `CD2/01.flac`), paths are
+        // what tells files apart. Only one manifest of each name is read.
+        let key = if matches!(kind, EntryKind::Manifest) {
+            lower
+        } else {
+            path.to_lowercase()
+        };
+        if !seen.insert(key) {
+            return Err(ApiError::bad_request(format!(
+                "The ZIP contains more than one {path}"
+            )));
+        }
+        entries.push(Entry {
+            index,
+            name,
+            path,
+            kind,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// The manifest's `cover` if given, else a conventionally named image
+/// nearest the top of the archive, else the only image in the archive.
+fn pick_cover<'a>(
+    entries: &'a [Entry],
+    manifest: Option<&AlbumManifest>,
+) -> Result<Option<&'a Entry>, ApiError> {
+    let images: Vec<&Entry> = entries
+        .iter()
+        .filter(|entry| matches!(entry.kind, EntryKind::Image))
+        .collect();
+
+    if let Some(cover) = manifest.and_then(|manifest| manifest.album.cover.as_deref()) {
+        let named: Vec<&Entry> = images
+            .iter()
+            .copied()
+            .filter(|entry| names_file(cover, &entry.path))
+            .collect();
+        return match named.as_slice() {
+            [] => Err(ApiError::bad_request(format!("The manifest's cover {cover} is not in the ZIP"))),
+            [entry] => Ok(Some(entry)),
+            _ => Err(ApiError::bad_request(format!(
+                "The manifest's cover {cover} could be any of several images in the ZIP, give its folder too"
+            ))),
+        };
+    }
+
+    Ok(COVER_NAMES
+        .iter()
+        .find_map(|name| {
+            images
+                .iter()
+                .copied()
+                .filter(|entry| entry.name.eq_ignore_ascii_case(name))
+                .min_by_key(|entry| (entry.path.matches('/').count(), &entry.path))
+        })
+        .or_else(|| (images.len() == 1).then(|| images[0])))
+}
+
+fn extract_entry(
+    archive: &mut ZipArchive<File>,
+    entry: &Entry,
+    spool_dir: &Path,
+    limits: ExtractLimits,
+    total: &mut u64,
+) -> Result<ExtractedFile, ApiError> {
+    let mut file = archive
+        .by_index(entry.index)
+        .map_err(|err| invalid_zip(&err))?;
+    let spooled = SpooledFile::reserve(spool_dir);
+    let mut out = File::create(spooled.path())?;
+
+    // The sizes in the ZIP can't be trusted, so count what comes out.
+    let written = std::io::copy(&mut (&mut file).take(limits.max_entry_bytes + 1), &mut out)?;
+    out.flush()?;
+    if written > limits.max_entry_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "{} is larger than the {} MB limit",
+                entry.name,
+                limits.max_entry_bytes / (1024 * 1024)
+            ),
+        ));
+    }
+
+    *total += written;
+    if *total > limits.max_total_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The ZIP's contents are too large",
+        ));
+    }
+
+    Ok(ExtractedFile {
+        name: entry.name.clone(),
+        path: entry.path.clone(),
+        spooled,
+    })
+}
+
+fn invalid_zip(err: &zip::result::ZipError) -> ApiError {
+    ApiError::bad_request(format!("Not a valid ZIP file: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use axum::response::IntoResponse;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    const LIMITS: ExtractLimits = ExtractLimits {
+        max_entry_bytes: 1024,
+        max_total_bytes: 4096,
+    };
+
+    /// A ZIP of `(path, contents)` in a directory of its own, removed on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn zip(files: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("navicore-archive-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).expect("scratch directory");
+            let mut zip = ZipWriter::new(File::create(dir.join("album.zip")).expect("zip file"));
+            for (path, contents) in files {
+                zip.start_file(*path, SimpleFileOptions::default())
+                    .expect("entry");
+                zip.write_all(contents.as_bytes()).expect("contents");
+            }
+            zip.finish().expect("zip written");
+            Self(dir)
+        }
+
+        fn extract(&self) -> Result<ExtractedAlbum, ApiError> {
+            extract(&self.0.join("album.zip"), &self.0.join("spool"), LIMITS)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn contents(file: &ExtractedFile) -> String {
+        std::fs::read_to_string(file.spooled.path()).expect("extracted file")
+    }
+
+    const MANIFEST: &str = r"
+album:
+  cover: CD2/cover.jpg
+tracks:
+  - file: CD1/01.flac
+    title: First disc
+  - file: Album/CD2/01.flac
+    title: Second disc
+";
+
+    #[test]
+    fn tells_files_apart_by_path() {
+        let zip = Scratch::zip(&[
+            ("Album/album.yaml", MANIFEST),
+            ("Album/CD2/01.flac", "disc 2"),
+            ("Album/CD1/01.flac", "disc 1"),
+            ("Album/CD1/cover.jpg", "cover 1"),
+            ("Album/CD2/cover.jpg", "cover 2"),
+        ]);
+        let album = zip.extract().expect("extracted");
+
+        let paths: Vec<&str> = album.audio.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["Album/CD1/01.flac", "Album/CD2/01.flac"]);
+        assert_eq!(contents(&album.audio[0]), "disc 1");
+
+        let manifest = album.manifest.expect("manifest");
+        let titles: Vec<_> = album
+            .audio
+            .iter()
+            .map(|file| {
+                manifest
+                    .track(&file.path)
+                    .and_then(|track| track.title.as_deref())
+            })
+            .collect();
+        assert_eq!(titles, [Some("First disc"), Some("Second disc")]);
+        assert_eq!(contents(&album.cover.expect("cover")), "cover 2");
+    }
+
+    #[test]
+    fn rejects_a_path_given_twice() {
+        let zip = Scratch::zip(&[("CD1/01.flac", "one"), ("cd1/01.FLAC", "two")]);
+        let err = zip.extract().expect_err("duplicate path");
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_a_cover_name_that_fits_several_images() {
+        let zip = Scratch::zip(&[
+            ("album.yaml", "album:\n  cover: cover.jpg\n"),
+            ("CD1/01.flac", "disc 1"),
+            ("CD1/cover.jpg", "cover 1"),
+            ("CD2/cover.jpg", "cover 2"),
+        ]);
+        let err = zip.extract().expect_err("ambiguous cover");
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn prefers_the_cover_nearest_the_top() {
+        let zip = Scratch::zip(&[
+            ("Album/CD1/01.flac", "disc 1"),
+            ("Album/CD1/cover.jpg", "cover 1"),
+            ("Album/cover.jpg", "album cover"),
+            ("Album/CD2/cover.jpg", "cover 2"),
+        ]);
+        let album = zip.extract().expect("extracted");
+        assert_eq!(contents(&album.cover.expect("cover")), "album cover");
+    }
+}
@@ -0,0 +1,272 @@
+//! Uploads sent in parts over several requests, for files too large to get
+//! through in one go over a flaky connection.
+//!
+//! Each part is checked against its SHA-256 and passed straight on to a
+//! multipart upload in storage under `uploads/{id}`, so nothing piles up on
+//! this server while the upload is open. Clients resume by asking which parts
+//! arrived. A cleanup task aborts uploads left alone past their expiry, and
+//! multipart uploads storage still holds that no upload refers to.
+
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    db::{models::ChunkedUpload, queries},
+    AppState,
+};
+
+/// Storage refuses smaller parts, except for the last one.
+pub const MIN_PART_BYTES: u64 = 5 * 1024 * 1024;
+pub const MAX_PART_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_PART_BYTES: u64 = 8 * 1024 * 1024;
+/// The most parts storage accepts for one upload.
+const MAX_PARTS: u64 = 10_000;
+/// Where parts are assembled before the file is processed.
+pub const STORAGE_PREFIX: &str = "uploads/";
+
+/// What the file becomes once complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadKind {
+    /// One audio file, like `POST /api/v1/upload/file`.
+    File,
+    /// An album ZIP, like `POST /api/v1/upload/album`.
+    Album,
+}
+
+impl UploadKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Album => "album",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "file" => Some(Self::File),
+            "album" => Some(Self::Album),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkedStatus {
+    /// Accepting parts.
+    Open,
+    /// Being assembled and processed by a complete request.
+    Completing,
+    Completed,
+    Aborted,
+    Expired,
+}
+
+impl ChunkedStatus {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Completing => "completing",
+            Self::Completed => "completed",
+            Self::Aborted => "aborted",
+            Self::Expired => "expired",
+        }
+    }
+}
+
+/// Part size for a `total`-byte upload: what the client asked for within the
+/// limits storage sets, grown if needed to stay under its part count.
+pub fn part_size(total: u64, requested: Option<u64>) -> u64 {
+    let part = requested
+        .unwrap_or(DEFAULT_PART_BYTES)
+        .clamp(MIN_PART_BYTES, MAX_PART_BYTES);
+    part.max(total.div_ceil(MAX_PARTS))
+}
+
+pub const fn parts_total(upload: &ChunkedUpload) -> i64 {
+    (upload.total_bytes + upload.part_bytes - 1) / upload.part_bytes
+}
+
+/// The size part `part_number` must have, or `None` if there is no such part.
+pub fn part_len(upload: &ChunkedUpload, part_number: i64) -> Option<i64> {
+    let total = parts_total(upload);
+    if !(1..=total).contains(&part_number) {
+        return None;
+    }
+
+    if part_number == total {
+        Some(upload.total_bytes - (total - 1) * upload.part_bytes)
+    } else {
+        Some(upload.part_bytes)
+    }
+}
+
+/// When an upload last touched at `now` is given up on.
+pub fn expires_at(state: &AppState, now: DateTime<Utc>) -> DateTime<Utc> {
+    now + Duration::hours(state.config.upload.chunked_ttl_hours)
+}
+
+/// Releases what storage holds for `upload`: the unfinished multipart upload,
+/// or the assembled file.
+pub async fn discard(state: &AppState, upload: &ChunkedUpload) {
+    let result = if upload.assembled_at.is_some() {
+        state.storage.delete_file(&upload.storage_key).await
+    } else {
+        state
+            .storage
+            .abort_multipart_upload(&upload.storage_key, &upload.storage_upload_id)
+            .await
+    };
+    if let Err(err) = result {
+        warn!("Failed to discard chunked upload {}: {err:#}", upload.id);
+    }
+}
+
+/// Periodically expires uploads that stopped receiving parts and aborts
+/// multipart uploads nothing refers to, e.g. after a crash.
+pub fn spawn_cleanup_task(state: &Arc<AppState>, interval_secs: u64) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(StdDuration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let result = expire_uploads(&state).await;
+            match result {
+                Ok(0) => {}
+                Ok(expired) => info!("Expired {expired} abandoned chunked uploads"),
+                Err(err) => warn!("Chunked upload cleanup failed: {err:#}"),
+            }
+
+            let result = abort_orphans(&state).await;
+            match result {
+                Ok(0) => {}
+                Ok(aborted) => info!("Aborted {aborted} orphaned multipart uploads"),
+                Err(err) => warn!("Multipart upload cleanup failed: {err:#}"),
+            }
+        }
+    });
+}
+
+async fn expire_uploads(state: &AppState) -> anyhow::Result<usize> {
+    let now = Utc::now();
+    let mut expired = 0;
+    for upload in queries::get_expired_chunked_uploads(&state.db, now).await? {
+        let status = ChunkedStatus::Expired.as_str();
+        if queries::transition_chunked_upload(&state.db, &upload.id, &upload.status, status, now)
+            .await?
+        {
+            discard(state, &upload).await;
+            expired += 1;
+        }
+    }
+
+    Ok(expired)
+}
+
+async fn abort_orphans(state: &AppState) -> anyhow::Result<usize> {
+    let cutoff = Utc::now() - Duration::hours(state.config.upload.chunked_ttl_hours);
+    let mut aborted = 0;
+    for (key, upload_id, initiated) in state.storage.list_multipart_uploads(STORAGE_PREFIX).await? {
+        if initiated.is_none_or(|initiated| initiated > cutoff)
+            || queries::chunked_upload_in_use(&state.db, &upload_id).await?
+        {
+            continue;
+        }
+
+        let result = state.storage.abort_multipart_upload(&key, &upload_id).await;
+        match result {
+            Ok(()) => aborted += 1,
+            Err(err) => warn!("Failed to abort multipart upload {upload_id} of {key}: {err:#}"),
+        }
+    }
+
+    Ok(aborted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn upload(total_bytes: u64, part_bytes: u64) -> ChunkedUpload {
+        let now = Utc::now();
+        ChunkedUpload {
+            id: "upload".to_string(),
+            user_id: "user".to_string(),
+            kind: UploadKind::File.as_str().to_string(),
+            file_name: "song.flac".to_string(),
+            total_bytes: i64::try_from(total_bytes).unwrap(),
+            part_bytes: i64::try_from(part_bytes).unwrap(),
+            storage_key: "uploads/upload".to_string(),
+            storage_upload_id: "multipart".to_string(),
+            metadata: None,
+            status: ChunkedStatus::Open.as_str().to_string(),
+            assembled_at: None,
+            created_at: now,
+            updated_at: now,
+            expires_at: now,
+        }
+    }
+
+    #[test]
+    fn part_size_stays_within_storage_limits() {
+        assert_eq!(part_size(100 * MIB, None), DEFAULT_PART_BYTES);
+        assert_eq!(part_size(100 * MIB, Some(16 * MIB)), 16 * MIB);
+        assert_eq!(part_size(100 * MIB, Some(1)), MIN_PART_BYTES);
+        assert_eq!(part_size(100 * MIB, Some(u64::MAX)), MAX_PART_BYTES);
+        // A file smaller than a part is sent as one part.
+        assert_eq!(part_size(1, None), DEFAULT_PART_BYTES);
+    }
+
+    #[test]
+    fn part_size_grows_to_stay_under_max_parts() {
+        let limit = MAX_PARTS * DEFAULT_PART_BYTES;
+        assert_eq!(part_size(limit, None), DEFAULT_PART_BYTES);
+
+        let part = part_size(limit + 1, None);
+        assert_eq!(part, DEFAULT_PART_BYTES + 1);
+        assert_eq!(
+            parts_total(&upload(limit + 1, part)),
+            i64::try_from(MAX_PARTS).unwrap()
+        );
+
+        // Past what the largest parts can carry, the size wins over the cap.
+        let huge = MAX_PARTS * MAX_PART_BYTES * 2;
+        let part = part_size(huge, Some(MIN_PART_BYTES));
+        assert_eq!(part, MAX_PART_BYTES * 2);
+        assert!(parts_total(&upload(huge, part)) <= i64::try_from(MAX_PARTS).unwrap());
+    }
+
+    #[test]
+    fn last_part_carries_the_remainder() {
+        let uneven = upload(10 * MIB + 3, 4 * MIB);
+        assert_eq!(parts_total(&uneven), 3);
+        let four = i64::try_from(4 * MIB).unwrap();
+        assert_eq!(part_len(&uneven, 1), Some(four));
+        assert_eq!(part_len(&uneven, 2), Some(four));
+        assert_eq!(
+            part_len(&uneven, 3),
+            Some(i64::try_from(2 * MIB + 3).unwrap())
+        );
+
+        let even = upload(8 * MIB, 4 * MIB);
+        assert_eq!(parts_total(&even), 2);
+        assert_eq!(part_len(&even, 2), Some(four));
+
+        let tiny = upload(10, 4 * MIB);
+        assert_eq!(parts_total(&tiny), 1);
+        assert_eq!(part_len(&tiny, 1), Some(10));
+    }
+
+    #[test]
+    fn part_numbers_outside_the_upload_have_no_length() {
+        let uneven = upload(10 * MIB + 3, 4 * MIB);
+        assert_eq!(part_len(&uneven, 0), None);
+        assert_eq!(part_len(&uneven, -1), None);
+        assert_eq!(part_len(&uneven, 4), None);
+    }
+}
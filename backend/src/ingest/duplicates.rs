@@ -0,0 +1,315 @@
+//! Grouping tracks that are the same recording, from what
+//! [`super::fingerprint`] recorded for their files.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::db::models::Track;
+
+use super::fingerprint;
+
+/// Fingerprints this alike are near duplicates, unless the request says
+/// otherwise. Re-encodes score about 0.9, unrelated audio about 0.6.
+pub const DEFAULT_MIN_SIMILARITY: f32 = 0.85;
+/// Seconds two tracks' durations may differ by and still be compared.
+const MAX_DURATION_DIFFERENCE: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKind {
+    /// Every file is byte for byte the same.
+    Exact,
+    /// The files differ but sound the same, e.g. one re-encoded.
+    Near,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuplicateCluster {
+    pub kind: DuplicateKind,
+    /// Lowest similarity of the fingerprints that put the tracks together,
+    /// `1.0` for exact duplicates.
+    pub similarity: f32,
+    /// Oldest first, the one usually kept.
+    pub tracks: Vec<Track>,
+}
+
+/// A current track with what was recorded for its file.
+#[derive(Debug)]
+pub struct PrintedTrack {
+    pub track: Track,
+    pub content_hash: String,
+    pub fingerprint: Option<Vec<u32>>,
+}
+
+/// Groups `tracks` with the same contents, or fingerprints at least
+/// `min_similarity` alike, leaving out tracks with no duplicate. Largest
+/// groups first.
+pub fn find(tracks: Vec<PrintedTrack>, min_similarity: f32) -> Vec<DuplicateCluster> {
+    let mut sets = DisjointSets::new(tracks.len());
+
+    let mut by_hash: HashMap<&str, usize> = HashMap::new();
+    for (i, printed) in tracks.iter().enumerate() {
+        if let Some(first) = by_hash.insert(&printed.content_hash, i) {
+            sets.union(first, i, 1.0);
+        }
+    }
+
+    // Only tracks of about the same length can be the same recording, so
+    // each is compared with its neighbours by duration.
+    let mut by_duration: Vec<(i32, &[u32], usize)> = tracks
+        .iter()
+        .enumerate()
+        .filter(|(_, printed)| printed.track.duration > 0)
+        .filter_map(|(i, printed)| {
+            Some((printed.track.duration, printed.fingerprint.as_deref()?, i))
+        })
+        .collect();
+    by_duration.sort_by_key(|(duration, _, _)| *duration);
+    for (n, (duration, print, i)) in by_duration.iter().enumerate() {
+        for (other_duration, other_print, j) in &by_duration[n + 1..] {
+            if other_duration - duration > MAX_DURATION_DIFFERENCE {
+                break;
+            }
+            if sets.find(*i) == sets.find(*j) || tracks[*i].content_hash == tracks[*j].content_hash
+            {
+                continue;
+            }
+            let similar = fingerprint::similarity(print, other_print)
+                .filter(|similarity| *similarity >= min_similarity);
+            if let Some(similarity) = similar {
+                sets.union(*i, *j, similarity);
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..tracks.len() {
+        groups.entry(sets.find(i)).or_default().push(i);
+    }
+    let mut tracks: Vec<Option<PrintedTrack>> = tracks.into_iter().map(Some).collect();
+    let mut clusters: Vec<DuplicateCluster> = groups
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(root, members)| {
+            let mut members: Vec<PrintedTrack> = members
+                .into_iter()
+                .filter_map(|i| tracks[i].take())
+                .collect();
+            let exact = members
+                .iter()
+                .all(|printed| printed.content_hash == members[0].content_hash);
+            members.sort_by_key(|printed| printed.track.created_at);
+            DuplicateCluster {
+                kind: if exact {
+                    DuplicateKind::Exact
+                } else {
+                    DuplicateKind::Near
+                },
+                similarity: sets.weakest[root],
+                tracks: members.into_iter().map(|printed| printed.track).collect(),
+            }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.tracks
+            .len()
+            .cmp(&a.tracks.len())
+            .then_with(|| a.tracks[0].id.cmp(&b.tracks[0].id))
+    });
+
+    clusters
+}
+
+/// Union-find over track indexes, remembering the weakest link of each set.
+struct DisjointSets {
+    parent: Vec<usize>,
+    weakest: Vec<f32>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            weakest: vec![1.0; len],
+        }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut node = i;
+        while self.parent[node] != root {
+            node = std::mem::replace(&mut self.parent[node], root);
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize, similarity: f32) {
+        let (a, b) = (self.find(a), self.find(b));
+        let weakest = self.weakest[a].min(self.weakest[b]).min(similarity);
+        self.parent[b] = a;
+        self.weakest[a] = weakest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+
+    /// Frames of reproducible noise, unrelated for each seed.
+    fn print(seed: u32) -> Vec<u32> {
+        let mut state = seed | 1;
+        (0..200)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    /// Track `id`, created `age` minutes ago.
+    fn printed(
+        id: &str,
+        age: i64,
+        duration: i32,
+        content_hash: &str,
+        fingerprint: Option<Vec<u32>>,
+    ) -> PrintedTrack {
+        let created_at = Utc::now() - Duration::minutes(age);
+        PrintedTrack {
+            track: Track {
+                id: id.to_string(),
+                title: id.to_string(),
+                artist: "Artist".to_string(),
+                artist_id: None,
+                album: "Album".to_string(),
+                album_id: None,
+                duration,
+                file_path: format!("music/{id}.flac"),
+                original_filename: None,
+                original_format: None,
+                stored_format: None,
+                cover_art_path: None,
+                genre: None,
+                year: None,
+                track_number: None,
+                created_at,
+                updated_at: created_at,
+            },
+            content_hash: content_hash.to_string(),
+            fingerprint,
+        }
+    }
+
+    fn ids(cluster: &DuplicateCluster) -> Vec<&str> {
+        cluster
+            .tracks
+            .iter()
+            .map(|track| track.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn groups_identical_files_as_exact() {
+        let clusters = find(
+            vec![
+                printed("new", 1, 180, "same", None),
+                printed("other", 3, 180, "different", None),
+                printed("old", 2, 0, "same", None),
+            ],
+            DEFAULT_MIN_SIMILARITY,
+        );
+
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].kind, DuplicateKind::Exact);
+        assert!((clusters[0].similarity - 1.0).abs() < f32::EPSILON);
+        assert_eq!(ids(&clusters[0]), ["old", "new"]);
+    }
+
+    #[test]
+    fn groups_alike_fingerprints_as_near() {
+        let flac = print(1);
+        let mp3: Vec<u32> = flac.iter().map(|frame| frame ^ 0x0101_0101).collect();
+        let clusters = find(
+            vec![
+                printed("flac", 2, 180, "a", Some(flac.clone())),
+                printed("mp3", 1, 181, "b", Some(mp3)),
+                printed("copy", 3, 180, "a", Some(flac)),
+                printed("unrelated", 4, 180, "c", Some(print(2))),
+            ],
+            DEFAULT_MIN_SIMILARITY,
+        );
+
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].kind, DuplicateKind::Near);
+        assert!((clusters[0].similarity - 0.875).abs() < f32::EPSILON);
+        assert_eq!(ids(&clusters[0]), ["copy", "flac", "mp3"]);
+
+        let stricter = find(
+            vec![
+                printed("flac", 2, 180, "a", Some(print(1))),
+                printed(
+                    "mp3",
+                    1,
+                    181,
+                    "b",
+                    Some(print(1).iter().map(|frame| frame ^ 0x0101_0101).collect()),
+                ),
+            ],
+            0.9,
+        );
+        assert!(stricter.is_empty());
+    }
+
+    #[test]
+    fn compares_only_tracks_of_about_the_same_length() {
+        let tracks = |other_duration| {
+            vec![
+                printed("short", 2, 180, "a", Some(print(1))),
+                printed("long", 1, other_duration, "b", Some(print(1))),
+            ]
+        };
+
+        let clusters = find(
+            tracks(180 + MAX_DURATION_DIFFERENCE),
+            DEFAULT_MIN_SIMILARITY,
+        );
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(ids(&clusters[0]), ["short", "long"]);
+
+        assert!(find(
+            tracks(181 + MAX_DURATION_DIFFERENCE),
+            DEFAULT_MIN_SIMILARITY
+        )
+        .is_empty());
+        // An unknown length says nothing about the recording.
+        assert!(find(tracks(0), DEFAULT_MIN_SIMILARITY).is_empty());
+    }
+
+    #[test]
+    fn lists_larger_groups_first() {
+        let clusters = find(
+            vec![
+                printed("pair-1", 1, 200, "pair", None),
+                printed("trio-1", 1, 180, "trio", None),
+                printed("pair-2", 2, 200, "pair", None),
+                printed("trio-2", 2, 180, "trio", None),
+                printed("trio-3", 3, 180, "trio", None),
+            ],
+            DEFAULT_MIN_SIMILARITY,
+        );
+
+        let groups: Vec<Vec<&str>> = clusters.iter().map(ids).collect();
+        assert_eq!(
+            groups,
+            [vec!["trio-3", "trio-2", "trio-1"], vec!["pair-2", "pair-1"]]
+        );
+    }
+}
@@ -0,0 +1,556 @@
+//! Editable `album.yaml` / `album.json` generated from an album's current
+//! tracks, noting where each value came from.
+//!
+//! The manifest is parsed back before it is handed out, so what an artist
+//! downloads can go straight into a ZIP and be uploaded again.
+
+use std::fmt::Write as _;
+
+use anyhow::bail;
+use chrono::NaiveDate;
+use serde::Deserialize;
+
+use crate::db::models::{Album, AlbumVersion, Track};
+
+use super::{
+    manifest::{base_name, AlbumManifest, ManifestAlbum, ManifestTrack},
+    provenance::{FieldSources, Source},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+impl ExportFormat {
+    pub const fn file_name(self) -> &'static str {
+        match self {
+            Self::Yaml => "album.yaml",
+            Self::Json => "album.json",
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Yaml => "application/yaml; charset=utf-8",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+/// An album's manifest together with the sources of its values.
+#[derive(Debug)]
+pub struct AlbumExport {
+    manifest: AlbumManifest,
+    album_sources: FieldSources,
+    track_sources: Vec<FieldSources>,
+}
+
+impl AlbumExport {
+    /// `tracks` are the album's current tracks, `sources` their stored
+    /// `(track_id, sources)`.
+    pub fn new(
+        album: &Album,
+        artist: &str,
+        version: Option<&AlbumVersion>,
+        tracks: &[Track],
+        sources: &[(String, Option<String>)],
+    ) -> Self {
+        let album_sources = FieldSources::parse(album.metadata_sources.as_deref());
+        let files = track_files(tracks);
+
+        let mut track_sources = Vec::with_capacity(tracks.len());
+        let mut entries = Vec::with_capacity(tracks.len());
+        for (track, file) in tracks.iter().zip(files) {
+            let stored = sources
+                .iter()
+                .find(|(track_id, _)| *track_id == track.id)
+                .and_then(|(_, sources)| sources.as_deref());
+            let sources = FieldSources::parse(stored);
+            // Values the track only has because its album does are left to be
+            // inherited again, so editing the album's changes them too.
+            let inherited = |field: &str, same_as_album: bool| {
+                sources
+                    .get(field)
+                    .map_or(same_as_album, |source| source == Source::Album)
+            };
+
+            entries.push(ManifestTrack {
+                file,
+                title: Some(track.title.clone()),
+                track_number: track.track_number,
+                duration: (track.duration > 0).then_some(track.duration),
+                genre: track
+                    .genre
+                    .clone()
+                    .filter(|genre| !inherited("genre", album.genre.as_ref() == Some(genre))),
+                year: track
+                    .year
+                    .filter(|year| !inherited("year", album.year == Some(*year))),
+            });
+            track_sources.push(sources);
+        }
+
+        let manifest = AlbumManifest {
+            album: ManifestAlbum {
+                title: Some(album.title.clone()),
+                artist: Some(artist.to_string()),
+                year: album.year,
+                genre: album.genre.clone(),
+                cover: album
+                    .cover_art_path
+                    .as_deref()
+                    .map(|cover| base_name(cover).to_string()),
+                version: None,
+                release_status: version.map(|version| version.release_status.clone()),
+            },
+            tracks: entries,
+        };
+
+        Self {
+            manifest,
+            album_sources,
+            track_sources,
+        }
+    }
+
+    /// The manifest as the importer reads it, failing if reading it back
+    /// would give anything other than what was written.
+    pub fn render(&self, format: ExportFormat, generated: NaiveDate) -> anyhow::Result<String> {
+        let rendered = match format {
+            ExportFormat::Yaml => self.render_yaml(generated),
+            ExportFormat::Json => self.render_json(generated)?,
+        };
+
+        let parsed = AlbumManifest::parse(format.file_name(), rendered.as_bytes())?;
+        if parsed != self.manifest {
+            bail!(
+                "Exported {} does not read back as written",
+                format.file_name()
+            );
+        }
+
+        Ok(rendered)
+    }
+
+    fn render_yaml(&self, generated: NaiveDate) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# Generated by Navicore Music on {}",
+            generated.format("%Y-%m-%d")
+        );
+        out.push_str("# Edit this file and include it in your ZIP for better metadata\n\n");
+
+        yaml_album(&mut out, &self.manifest.album, &self.album_sources);
+
+        out.push_str("\ntracks:");
+        if self.manifest.tracks.is_empty() {
+            out.push_str(" []");
+        }
+        out.push('\n');
+        for (index, (track, sources)) in self
+            .manifest
+            .tracks
+            .iter()
+            .zip(&self.track_sources)
+            .enumerate()
+        {
+            if index > 0 {
+                out.push('\n');
+            }
+            yaml_track(&mut out, track, sources);
+        }
+
+        out
+    }
+
+    /// JSON has no comments, so the notes go in `sources` objects the
+    /// importer ignores.
+    fn render_json(&self, generated: NaiveDate) -> anyhow::Result<String> {
+        let mut value = serde_json::to_value(&self.manifest)?;
+        value["comment"] = format!(
+            "Generated by Navicore Music on {}. Edit this file and include it in your ZIP for better metadata.",
+            generated.format("%Y-%m-%d")
+        )
+        .into();
+        value["album"]["sources"] = notes(
+            &self.album_sources,
+            &["title", "artist", "year", "genre", "cover"],
+            None,
+        );
+        if let Some(tracks) = value["tracks"].as_array_mut() {
+            for ((entry, track), sources) in tracks
+                .iter_mut()
+                .zip(&self.manifest.tracks)
+                .zip(&self.track_sources)
+            {
+                entry["sources"] = notes(
+                    sources,
+                    &["title", "track_number", "duration", "genre", "year"],
+                    track.duration,
+                );
+            }
+        }
+
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+}
+
+/// Names for the album's tracks inside a ZIP: the names they were uploaded
+/// under, else `01-title.ext`, made unique ignoring case.
+pub fn track_files(tracks: &[Track]) -> Vec<String> {
+    let mut files: Vec<String> = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        let name = track
+            .original_filename
+            .as_deref()
+            .map(base_name)
+            .filter(|name| !name.trim().is_empty())
+            .map_or_else(|| generated_file(track), str::to_string);
+        let (stem, extension) = name.rsplit_once('.').unwrap_or((name.as_str(), ""));
+
+        let mut unique = name.clone();
+        let mut copy = 2;
+        while files.iter().any(|file| file.eq_ignore_ascii_case(&unique)) {
+            unique = if extension.is_empty() {
+                format!("{stem}-{copy}")
+            } else {
+                format!("{stem}-{copy}.{extension}")
+            };
+            copy += 1;
+        }
+        files.push(unique);
+    }
+
+    files
+}
+
+fn generated_file(track: &Track) -> String {
+    let mut slug = String::new();
+    for c in track.title.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    let slug = if slug.is_empty() { "track" } else { slug };
+    let extension = base_name(&track.file_path)
+        .rsplit_once('.')
+        .map_or("", |(_, extension)| extension);
+
+    let mut name = track
+        .track_number
+        .map_or_else(|| slug.to_string(), |number| format!("{number:02}-{slug}"));
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(extension);
+    }
+
+    name
+}
+
+/// The `album:` section.
+fn yaml_album(out: &mut String, album: &ManifestAlbum, sources: &FieldSources) {
+    out.push_str("album:\n");
+    yaml_field(
+        out,
+        "  ",
+        "title",
+        album.title.as_deref().map(quoted),
+        note(sources, "title", None),
+    );
+    yaml_field(
+        out,
+        "  ",
+        "artist",
+        album.artist.as_deref().map(quoted),
+        note(sources, "artist", None),
+    );
+    yaml_field(
+        out,
+        "  ",
+        "year",
+        album.year.map(|year| year.to_string()),
+        note(sources, "year", None),
+    );
+    yaml_field(
+        out,
+        "  ",
+        "genre",
+        album.genre.as_deref().map(quoted),
+        note(sources, "genre", None),
+    );
+    if let Some(cover) = &album.cover {
+        yaml_field(
+            out,
+            "  ",
+            "cover",
+            Some(quoted(cover)),
+            note(sources, "cover", None),
+        );
+    }
+    if let Some(status) = &album.release_status {
+        yaml_field(
+            out,
+            "  ",
+            "release_status",
+            Some(quoted(status)),
+            Some("draft or released".to_string()),
+        );
+    }
+}
+
+/// One entry of the `tracks:` list.
+fn yaml_track(out: &mut String, track: &ManifestTrack, sources: &FieldSources) {
+    let _ = writeln!(out, "  - file: {}", quoted(&track.file));
+    let indent = "    ";
+    yaml_field(
+        out,
+        indent,
+        "title",
+        track.title.as_deref().map(quoted),
+        note(sources, "title", None),
+    );
+    if let Some(number) = track.track_number {
+        yaml_field(
+            out,
+            indent,
+            "track_number",
+            Some(number.to_string()),
+            note(sources, "track_number", None),
+        );
+    }
+    yaml_field(
+        out,
+        indent,
+        "duration",
+        track.duration.map(|duration| duration.to_string()),
+        note(sources, "duration", track.duration),
+    );
+    if let Some(genre) = &track.genre {
+        yaml_field(
+            out,
+            indent,
+            "genre",
+            Some(quoted(genre)),
+            note(sources, "genre", None),
+        );
+    }
+    if let Some(year) = track.year {
+        yaml_field(
+            out,
+            indent,
+            "year",
+            Some(year.to_string()),
+            note(sources, "year", None),
+        );
+    }
+}
+
+/// A field and its note; fields without a value are left commented out for
+/// the artist to fill in.
+fn yaml_field(
+    out: &mut String,
+    indent: &str,
+    key: &str,
+    value: Option<String>,
+    note: Option<String>,
+) {
+    let _ = match (value, note) {
+        (Some(value), Some(note)) => writeln!(out, "{indent}{key}: {value}  # {note}"),
+        (Some(value), None) => writeln!(out, "{indent}{key}: {value}"),
+        (None, _) => writeln!(out, "{indent}# {key}:  # {}", Source::Default.comment()),
+    };
+}
+
+/// What to say about `field`; a measured duration also reads as `m:ss`.
+fn note(sources: &FieldSources, field: &str, duration: Option<i32>) -> Option<String> {
+    let source = sources.get(field)?;
+    Some(match (source, duration) {
+        (Source::Measured, Some(duration)) => {
+            format!(
+                "{} ({}:{:02})",
+                source.comment(),
+                duration / 60,
+                duration % 60
+            )
+        }
+        _ => source.comment().to_string(),
+    })
+}
+
+fn notes(sources: &FieldSources, fields: &[&str], duration: Option<i32>) -> serde_json::Value {
+    fields
+        .iter()
+        .filter_map(|field| Some(((*field).to_string(), note(sources, field, duration)?.into())))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// A YAML double-quoted string, which JSON's string syntax is a subset of.
+fn quoted(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write as _};
+
+    use chrono::Utc;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+    use crate::ingest::archive::{self, ExtractLimits};
+
+    const ODD_TITLES: &[&str] = &[
+        "He said \"hi\"",
+        "Line one\nline two",
+        "Ünïcödé – 日本語 🎵",
+        "# not a comment: really",
+        "back\\slash 'single' @ & * ! | >",
+    ];
+
+    fn album() -> Album {
+        let mut album = Album::new("artist", "Odd \"Album\": #1\nreissue", "user");
+        album.year = Some(2024);
+        album.genre = Some("Post-rock: live".to_string());
+        album.cover_art_path = Some("covers/artist/Cover \"Art\" ü.jpg".to_string());
+        album
+    }
+
+    fn tracks(album: &Album) -> Vec<Track> {
+        let now = Utc::now();
+        ODD_TITLES
+            .iter()
+            .zip(1..)
+            .map(|(title, number)| Track {
+                id: format!("track-{number}"),
+                title: (*title).to_string(),
+                artist: "Artist".to_string(),
+                artist_id: Some(album.artist_id.clone()),
+                album: album.title.clone(),
+                album_id: Some(album.id.clone()),
+                duration: 180 + number,
+                file_path: format!("music/artist/{}/{number}.flac", album.id),
+                // Two tracks uploaded under the same name, one without a name.
+                original_filename: match number {
+                    1 => Some("dir/Song.flac".to_string()),
+                    2 => Some("song.FLAC".to_string()),
+                    3 => None,
+                    _ => Some(format!("{number} \"{title}\".flac").replace('\n', " ")),
+                },
+                original_format: None,
+                stored_format: None,
+                cover_art_path: None,
+                genre: (number == 2).then(|| "Genre: \"quoted\"".to_string()),
+                year: None,
+                track_number: Some(number),
+                created_at: now,
+                updated_at: now,
+            })
+            .collect()
+    }
+
+    fn export() -> AlbumExport {
+        let album = album();
+        let tracks = tracks(&album);
+        let sources: Vec<_> = tracks
+            .iter()
+            .map(|track| {
+                let sources = r#"{"title":"tags","duration":"measured","track_number":"filename"}"#;
+                (track.id.clone(), Some(sources.to_string()))
+            })
+            .collect();
+        AlbumExport::new(&album, "Artist \"AKA\" Ü", None, &tracks, &sources)
+    }
+
+    /// Zips `manifest` with an audio file for each of its tracks and the
+    /// cover, and extracts it again the way an upload does.
+    fn reupload(format: ExportFormat, manifest: &AlbumManifest, rendered: &str) -> AlbumManifest {
+        let dir = std::env::temp_dir().join(format!("navicore-export-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).expect("scratch directory");
+        let zip_path = dir.join("album.zip");
+        let mut zip = ZipWriter::new(File::create(&zip_path).expect("zip file"));
+        let mut add = |path: &str, contents: &str| {
+            zip.start_file(path, SimpleFileOptions::default())
+                .expect("entry");
+            zip.write_all(contents.as_bytes()).expect("contents");
+        };
+        add(&format!("Album/{}", format.file_name()), rendered);
+        for track in &manifest.tracks {
+            add(&format!("Album/{}", track.file), &track.file);
+        }
+        add(
+            &format!("Album/{}", manifest.album.cover.as_deref().expect("cover")),
+            "cover",
+        );
+        zip.finish().expect("zip written");
+
+        let limits = ExtractLimits {
+            max_entry_bytes: 1024 * 1024,
+            max_total_bytes: 1024 * 1024,
+        };
+        let extracted = archive::extract(&zip_path, &dir.join("spool"), limits);
+        let _ = std::fs::remove_dir_all(&dir);
+        let extracted = extracted.expect("extracted");
+
+        let parsed = extracted.manifest.expect("manifest");
+        assert_eq!(extracted.audio.len(), manifest.tracks.len());
+        for file in &extracted.audio {
+            let track = parsed.track(&file.path).expect("track for file");
+            assert_eq!(track.file, file.name);
+        }
+        assert!(extracted.cover.is_some());
+        parsed
+    }
+
+    #[test]
+    fn names_duplicate_files_apart() {
+        let files: Vec<String> = export()
+            .manifest
+            .tracks
+            .iter()
+            .map(|track| track.file.clone())
+            .collect();
+        assert_eq!(files[0], "Song.flac");
+        assert_eq!(files[1], "song-2.FLAC");
+        assert_eq!(files[2], "03-ünïcödé-日本語.flac");
+        assert_eq!(files[3], "4 \"# not a comment: really\".flac");
+    }
+
+    #[test]
+    fn odd_values_read_back_as_written() {
+        let export = export();
+        let generated = NaiveDate::from_ymd_opt(2024, 5, 1).expect("date");
+        for format in [ExportFormat::Yaml, ExportFormat::Json] {
+            let rendered = export.render(format, generated).expect("rendered");
+            let parsed =
+                AlbumManifest::parse(format.file_name(), rendered.as_bytes()).expect("parsed");
+            assert_eq!(parsed, export.manifest, "{}", format.file_name());
+
+            let titles: Vec<_> = parsed
+                .tracks
+                .iter()
+                .map(|track| track.title.as_deref().expect("title"))
+                .collect();
+            assert_eq!(titles, ODD_TITLES);
+            assert_eq!(
+                parsed.album.title.as_deref(),
+                Some("Odd \"Album\": #1\nreissue")
+            );
+
+            assert_eq!(
+                reupload(format, &export.manifest, &rendered),
+                export.manifest,
+                "{}",
+                format.file_name()
+            );
+        }
+    }
+}
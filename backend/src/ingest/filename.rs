@@ -0,0 +1,776 @@
+//! Metadata guessed from file and folder names, the last resort before
+//! giving up on it.
+//!
+//! A [`FilenameParser`] matches the end of a file's path against patterns
+//! like `{artist} - {album}/{track} {title}`: one segment per folder, the last
+//! one for the file name without its extension. Deployments can add their
+//! own patterns (`FILENAME_PATTERNS`), which are tried before the built-in
+//! ones for the file name, disc folders (`CD1/`) and album folders.
+//!
+//! In patterns, `{track}`, `{disc}` and `{year}` match numbers, `{title}`,
+//! `{artist}` and `{album}` match any text, a space matches any run of spaces
+//! and underscores, and anything else matches itself, ignoring ASCII case.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+/// How much a match of a deployment's own pattern is trusted.
+const CUSTOM_CONFIDENCE: f32 = 0.95;
+
+/// File names, most specific first. A bare number like `2024` is a title,
+/// not a track number.
+const FILE_PATTERNS: &[(&str, f32)] = &[
+    ("{track} - {title}", 0.9),
+    ("{track}. {title}", 0.9),
+    ("{disc}-{track} {title}", 0.75),
+    ("{disc}.{track} {title}", 0.75),
+    ("{track}.{title}", 0.8),
+    ("{track}-{title}", 0.85),
+    ("{track}) {title}", 0.85),
+    ("{track}){title}", 0.8),
+    ("{track} {title}", 0.8),
+    ("Track{track}", 0.6),
+    ("Track {track}", 0.6),
+    ("{track}{title}", 0.5),
+    ("{title}", 0.3),
+];
+
+/// Folders a multi-disc album keeps each disc in.
+const DISC_PATTERNS: &[&str] = &[
+    "CD{disc}",
+    "CD {disc}",
+    "Disc{disc}",
+    "Disc {disc}",
+    "Disk{disc}",
+    "Disk {disc}",
+];
+
+/// The folder holding the album's files.
+const ALBUM_PATTERNS: &[(&str, f32)] = &[
+    ("{artist} - {year} - {album}", 0.85),
+    ("{artist} - {album} ({year})", 0.85),
+    ("{artist} - {album} [{year}]", 0.85),
+    ("{year} - {album}", 0.7),
+    ("{artist} - {album}", 0.75),
+    ("{album} ({year})", 0.6),
+    ("{album}", 0.5),
+];
+
+/// What a file's path says. Values that weren't found are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedFilename {
+    pub track_number: Option<i32>,
+    pub disc_number: Option<i32>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<i32>,
+    /// How far the track number and title can be trusted, from 0 to 1.
+    pub confidence: f32,
+    /// How far the artist, album and year from the folders can be trusted,
+    /// 0 when there were none.
+    pub folder_confidence: f32,
+}
+
+impl ParsedFilename {
+    fn set(&mut self, field: Field, value: &str) {
+        match field {
+            Field::Track => self.track_number = self.track_number.or_else(|| number(value)),
+            Field::Disc => self.disc_number = self.disc_number.or_else(|| number(value)),
+            Field::Year => self.year = self.year.or_else(|| number(value)),
+            Field::Title => self.title = self.title.take().or_else(|| clean(value)),
+            Field::Artist => self.artist = self.artist.take().or_else(|| clean(value)),
+            Field::Album => self.album = self.album.take().or_else(|| clean(value)),
+        }
+    }
+
+    const fn has_folder_values(&self) -> bool {
+        self.artist.is_some() || self.album.is_some() || self.year.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Track,
+    Disc,
+    Year,
+    Title,
+    Artist,
+    Album,
+}
+
+impl Field {
+    /// Digits a number field takes, `None` for text.
+    const fn digits(self) -> Option<(usize, usize)> {
+        match self {
+            Self::Track => Some((1, 3)),
+            Self::Disc => Some((1, 2)),
+            Self::Year => Some((4, 4)),
+            Self::Title | Self::Artist | Self::Album => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Space,
+    Field(Field),
+}
+
+/// One path segment of a pattern.
+#[derive(Debug, Clone)]
+struct Segment {
+    tokens: Vec<Token>,
+}
+
+impl Segment {
+    fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let mut tokens = Vec::new();
+        let mut rest = pattern;
+        while let Some(c) = rest.chars().next() {
+            if c == '{' {
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| anyhow!("Unclosed {{ in '{pattern}'"))?;
+                let field = match &rest[1..end] {
+                    "track" => Field::Track,
+                    "disc" => Field::Disc,
+                    "year" => Field::Year,
+                    "title" => Field::Title,
+                    "artist" => Field::Artist,
+                    "album" => Field::Album,
+                    other => bail!("Unknown field {{{other}}} in '{pattern}'"),
+                };
+                tokens.push(Token::Field(field));
+                rest = &rest[end + 1..];
+            } else if c == ' ' {
+                if tokens.last() != Some(&Token::Space) {
+                    tokens.push(Token::Space);
+                }
+                rest = &rest[1..];
+            } else {
+                let len = rest.find(['{', ' ']).unwrap_or(rest.len());
+                tokens.push(Token::Literal(rest[..len].to_string()));
+                rest = &rest[len..];
+            }
+        }
+        if tokens.is_empty() {
+            bail!("Empty segment in '{pattern}'");
+        }
+
+        Ok(Self { tokens })
+    }
+
+    /// The fields `text` gives, if it matches.
+    fn captures<'t>(&self, text: &'t str) -> Option<Vec<(Field, &'t str)>> {
+        let mut captures = Vec::new();
+        match_tokens(&self.tokens, text, &mut captures).then_some(captures)
+    }
+}
+
+/// Matches `tokens` against the whole of `text`, trying the shortest text
+/// for each text field first.
+fn match_tokens<'t>(tokens: &[Token], text: &'t str, captures: &mut Vec<(Field, &'t str)>) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+
+    match token {
+        Token::Literal(literal) => text
+            .get(..literal.len())
+            .filter(|start| start.eq_ignore_ascii_case(literal))
+            .is_some_and(|_| match_tokens(rest, &text[literal.len()..], captures)),
+        Token::Space => {
+            let after = text.trim_start_matches([' ', '_']);
+            after.len() < text.len() && match_tokens(rest, after, captures)
+        }
+        Token::Field(field) => {
+            if let Some((min, max)) = field.digits() {
+                let len = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                if !(min..=max).contains(&len) {
+                    return false;
+                }
+                captures.push((*field, &text[..len]));
+                if match_tokens(rest, &text[len..], captures) {
+                    return true;
+                }
+                captures.pop();
+                return false;
+            }
+
+            let ends: Vec<usize> = if rest.is_empty() {
+                vec![text.len()]
+            } else {
+                text.char_indices().map(|(i, c)| i + c.len_utf8()).collect()
+            };
+            for end in ends {
+                let value = &text[..end];
+                if value.trim().is_empty() {
+                    continue;
+                }
+                captures.push((*field, value));
+                if match_tokens(rest, &text[end..], captures) {
+                    return true;
+                }
+                captures.pop();
+            }
+            false
+        }
+    }
+}
+
+/// A pattern over the last few segments of a path.
+#[derive(Debug, Clone)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let segments = pattern
+            .split('/')
+            .map(Segment::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { segments })
+    }
+
+    /// Matches the pattern's segments against the last of `components`.
+    fn captures<'t>(&self, components: &[&'t str]) -> Option<Vec<(Field, &'t str)>> {
+        let start = components.len().checked_sub(self.segments.len())?;
+        let mut captures = Vec::new();
+        for (segment, component) in self.segments.iter().zip(&components[start..]) {
+            captures.extend(segment.captures(component)?);
+        }
+        Some(captures)
+    }
+}
+
+/// Parses paths with a deployment's own patterns, then the built-in ones.
+#[derive(Debug, Clone)]
+pub struct FilenameParser {
+    custom: Vec<Pattern>,
+    files: Vec<(Segment, f32)>,
+    discs: Vec<Segment>,
+    albums: Vec<(Segment, f32)>,
+}
+
+impl FilenameParser {
+    /// A parser trying `patterns` first, e.g. `{artist}/{album}/{track} {title}`.
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let custom = patterns
+            .iter()
+            .map(|pattern| Pattern::parse(pattern))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let weighted = |patterns: &[(&str, f32)]| {
+            patterns
+                .iter()
+                .map(|(pattern, confidence)| Ok((Segment::parse(pattern)?, *confidence)))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            custom,
+            files: weighted(FILE_PATTERNS)?,
+            discs: DISC_PATTERNS
+                .iter()
+                .map(|pattern| Segment::parse(pattern))
+                .collect::<anyhow::Result<_>>()?,
+            albums: weighted(ALBUM_PATTERNS)?,
+        })
+    }
+
+    /// Reads what it can from `path`, e.g. `Artist - Album (2024)/CD2/03 -
+    /// Song_Title.flac` gives track 3 of disc 2, "Song Title", by "Artist" on
+    /// "Album" from 2024. `01-song-title.flac` gives track 1, "Song Title".
+    /// Names that aren't all lowercase keep their case.
+    pub fn parse(&self, path: &str) -> ParsedFilename {
+        let mut components: Vec<&str> = path
+            .split(['/', '\\'])
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .collect();
+        if let Some(last) = components.last_mut() {
+            let name: &str = last;
+            *last = Path::new(name)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(name);
+        }
+
+        let mut parsed = ParsedFilename::default();
+        if let Some(captures) = self
+            .custom
+            .iter()
+            .find_map(|pattern| pattern.captures(&components))
+        {
+            for (field, value) in captures {
+                parsed.set(field, value);
+            }
+            parsed.confidence = CUSTOM_CONFIDENCE;
+            if parsed.has_folder_values() {
+                parsed.folder_confidence = CUSTOM_CONFIDENCE;
+            }
+        }
+
+        let Some((file, mut folders)) = components.split_last() else {
+            return parsed;
+        };
+        if let Some((captures, confidence)) = self
+            .files
+            .iter()
+            .find_map(|(segment, confidence)| Some((segment.captures(file)?, *confidence)))
+        {
+            for (field, value) in captures {
+                parsed.set(field, value);
+            }
+            parsed.confidence = parsed.confidence.max(confidence);
+        }
+
+        if let Some((disc, rest)) = folders.split_last() {
+            if let Some(captures) = self.discs.iter().find_map(|segment| segment.captures(disc)) {
+                for (field, value) in captures {
+                    parsed.set(field, value);
+                }
+                folders = rest;
+            }
+        }
+        if let Some((album, rest)) = folders.split_last() {
+            let guessed = parsed.has_folder_values();
+            if let Some((captures, confidence)) = self
+                .albums
+                .iter()
+                .find_map(|(segment, confidence)| Some((segment.captures(album)?, *confidence)))
+            {
+                let plain = captures.iter().all(|(field, _)| *field == Field::Album);
+                for (field, value) in captures {
+                    parsed.set(field, value);
+                }
+                // `Artist/Album/`
+                if plain {
+                    if let Some(artist) = rest.last() {
+                        parsed.set(Field::Artist, artist);
+                    }
+                }
+                if !guessed {
+                    parsed.folder_confidence = confidence;
+                }
+            }
+        }
+
+        parsed
+    }
+}
+
+/// Numbers tracks on from one disc to the next, so disc 2's first track
+/// follows the last of disc 1. Files without a disc number are left as they
+/// are, as are albums with a single disc.
+pub fn number_across_discs(files: &mut [ParsedFilename]) {
+    let mut discs: Vec<i32> = files.iter().filter_map(|file| file.disc_number).collect();
+    discs.sort_unstable();
+    discs.dedup();
+    if discs.len() < 2 {
+        return;
+    }
+
+    let last_track = |disc: i32| {
+        files
+            .iter()
+            .filter(|file| file.disc_number == Some(disc))
+            .filter_map(|file| file.track_number)
+            .max()
+            .unwrap_or_default()
+    };
+    let offsets: Vec<(i32, i32)> = discs
+        .iter()
+        .map(|disc| {
+            (
+                *disc,
+                discs
+                    .iter()
+                    .filter(|earlier| *earlier < disc)
+                    .map(|earlier| last_track(*earlier))
+                    .sum(),
+            )
+        })
+        .collect();
+
+    for file in files {
+        let offset = offsets
+            .iter()
+            .find(|(disc, _)| Some(*disc) == file.disc_number)
+            .map_or(0, |(_, offset)| *offset);
+        file.track_number = file.track_number.map(|number| number + offset);
+    }
+}
+
+fn number(value: &str) -> Option<i32> {
+    value.parse().ok().filter(|number| *number > 0)
+}
+
+/// Underscores become spaces; names in lowercase with dashes for spaces, like
+/// `song-title`, become `Song Title`.
+fn clean(value: &str) -> Option<String> {
+    let value = value.replace('_', " ");
+    let slug = !value.contains(' ') && !value.chars().any(char::is_uppercase);
+    let words: Vec<&str> = value
+        .split(|c: char| c.is_whitespace() || (slug && c == '-'))
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+
+    Some(if value.chars().any(char::is_uppercase) {
+        words.join(" ")
+    } else {
+        words
+            .iter()
+            .map(|word| capitalize(word))
+            .collect::<Vec<_>>()
+            .join(" ")
+    })
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A [`ParsedFilename`] that can live in a const table.
+    #[derive(Debug, Clone, Copy)]
+    struct Expected {
+        track: Option<i32>,
+        disc: Option<i32>,
+        title: Option<&'static str>,
+        artist: Option<&'static str>,
+        album: Option<&'static str>,
+        year: Option<i32>,
+        confidence: f32,
+        folder_confidence: f32,
+    }
+
+    const NOTHING: Expected = Expected {
+        track: None,
+        disc: None,
+        title: None,
+        artist: None,
+        album: None,
+        year: None,
+        confidence: 0.0,
+        folder_confidence: 0.0,
+    };
+
+    impl From<Expected> for ParsedFilename {
+        fn from(expected: Expected) -> Self {
+            Self {
+                track_number: expected.track,
+                disc_number: expected.disc,
+                title: expected.title.map(str::to_string),
+                artist: expected.artist.map(str::to_string),
+                album: expected.album.map(str::to_string),
+                year: expected.year,
+                confidence: expected.confidence,
+                folder_confidence: expected.folder_confidence,
+            }
+        }
+    }
+
+    const BUILT_IN: &[(&str, Expected)] = &[
+        (
+            "01 - Song Title.flac",
+            Expected {
+                track: Some(1),
+                title: Some("Song Title"),
+                confidence: 0.9,
+                ..NOTHING
+            },
+        ),
+        (
+            "07. Another Song.mp3",
+            Expected {
+                track: Some(7),
+                title: Some("Another Song"),
+                confidence: 0.9,
+                ..NOTHING
+            },
+        ),
+        (
+            "01-song-title.flac",
+            Expected {
+                track: Some(1),
+                title: Some("Song Title"),
+                confidence: 0.85,
+                ..NOTHING
+            },
+        ),
+        (
+            "03_-_Song_Title.flac",
+            Expected {
+                track: Some(3),
+                title: Some("Song Title"),
+                confidence: 0.9,
+                ..NOTHING
+            },
+        ),
+        (
+            "4) Closing Time.ogg",
+            Expected {
+                track: Some(4),
+                title: Some("Closing Time"),
+                confidence: 0.85,
+                ..NOTHING
+            },
+        ),
+        (
+            "1-02 Second Disc Opener.flac",
+            Expected {
+                track: Some(2),
+                disc: Some(1),
+                title: Some("Second Disc Opener"),
+                confidence: 0.75,
+                ..NOTHING
+            },
+        ),
+        (
+            "Track05.wav",
+            Expected {
+                track: Some(5),
+                confidence: 0.6,
+                ..NOTHING
+            },
+        ),
+        // Too long for a track number.
+        (
+            "2024.flac",
+            Expected {
+                title: Some("2024"),
+                confidence: 0.3,
+                ..NOTHING
+            },
+        ),
+        (
+            "Untitled Demo.mp3",
+            Expected {
+                title: Some("Untitled Demo"),
+                confidence: 0.3,
+                ..NOTHING
+            },
+        ),
+        (
+            "Boards of Canada - Geogaddi/03 Music Is Math.flac",
+            Expected {
+                track: Some(3),
+                title: Some("Music Is Math"),
+                artist: Some("Boards of Canada"),
+                album: Some("Geogaddi"),
+                confidence: 0.8,
+                folder_confidence: 0.75,
+                ..NOTHING
+            },
+        ),
+        (
+            "Radiohead - OK Computer (1997)/02 - Paranoid Android.flac",
+            Expected {
+                track: Some(2),
+                title: Some("Paranoid Android"),
+                artist: Some("Radiohead"),
+                album: Some("OK Computer"),
+                year: Some(1997),
+                confidence: 0.9,
+                folder_confidence: 0.85,
+                ..NOTHING
+            },
+        ),
+        (
+            "Can - 1972 - Ege Bamyasi/01. Pinch.flac",
+            Expected {
+                track: Some(1),
+                title: Some("Pinch"),
+                artist: Some("Can"),
+                album: Some("Ege Bamyasi"),
+                year: Some(1972),
+                confidence: 0.9,
+                folder_confidence: 0.85,
+                ..NOTHING
+            },
+        ),
+        (
+            "Music/Stereolab/Dots and Loops/01 Brakhage.mp3",
+            Expected {
+                track: Some(1),
+                title: Some("Brakhage"),
+                artist: Some("Stereolab"),
+                album: Some("Dots and Loops"),
+                confidence: 0.8,
+                folder_confidence: 0.5,
+                ..NOTHING
+            },
+        ),
+        (
+            "The Beatles - The Beatles (1968)/CD1/01 - Back in the U.S.S.R..flac",
+            Expected {
+                track: Some(1),
+                disc: Some(1),
+                title: Some("Back in the U.S.S.R."),
+                artist: Some("The Beatles"),
+                album: Some("The Beatles"),
+                year: Some(1968),
+                confidence: 0.9,
+                folder_confidence: 0.85,
+            },
+        ),
+        (
+            "Sandinista! (1980)\\Disc 2\\05 Lose This Skin.flac",
+            Expected {
+                track: Some(5),
+                disc: Some(2),
+                title: Some("Lose This Skin"),
+                album: Some("Sandinista!"),
+                year: Some(1980),
+                confidence: 0.8,
+                folder_confidence: 0.6,
+                ..NOTHING
+            },
+        ),
+        (
+            "Artist - Album/disk3/10 - Last.flac",
+            Expected {
+                track: Some(10),
+                disc: Some(3),
+                title: Some("Last"),
+                artist: Some("Artist"),
+                album: Some("Album"),
+                confidence: 0.9,
+                folder_confidence: 0.75,
+                ..NOTHING
+            },
+        ),
+    ];
+
+    #[test]
+    fn parses_the_built_in_layouts() {
+        let parser = FilenameParser::new(&[]).expect("built-in patterns");
+        for (path, expected) in BUILT_IN {
+            assert_eq!(
+                parser.parse(path),
+                ParsedFilename::from(*expected),
+                "{path}"
+            );
+        }
+    }
+
+    const CUSTOM: &[(&str, Expected)] = &[
+        (
+            "Mixtape/DJ Shadow -- Midnight in a Perfect World.mp3",
+            Expected {
+                title: Some("Midnight in a Perfect World"),
+                artist: Some("DJ Shadow"),
+                album: Some("Mixtape"),
+                confidence: 0.95,
+                folder_confidence: 0.95,
+                ..NOTHING
+            },
+        ),
+        (
+            "Live/Set 3 - 07.flac",
+            Expected {
+                track: Some(7),
+                disc: Some(3),
+                title: Some("Set 3 - 07"),
+                album: Some("Live"),
+                confidence: 0.95,
+                folder_confidence: 0.5,
+                ..NOTHING
+            },
+        ),
+        // Neither custom pattern matches, the built-in ones still apply.
+        (
+            "Artist - Album/01 - Song.flac",
+            Expected {
+                track: Some(1),
+                title: Some("Song"),
+                artist: Some("Artist"),
+                album: Some("Album"),
+                confidence: 0.9,
+                folder_confidence: 0.75,
+                ..NOTHING
+            },
+        ),
+    ];
+
+    #[test]
+    fn tries_custom_patterns_first() {
+        let patterns = [
+            "{album}/{artist} -- {title}".to_string(),
+            "Set {disc} - {track}".to_string(),
+        ];
+        let parser = FilenameParser::new(&patterns).expect("custom patterns");
+        for (path, expected) in CUSTOM {
+            assert_eq!(
+                parser.parse(path),
+                ParsedFilename::from(*expected),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for (pattern, error) in [
+            ("{track} - {name}", "Unknown field {name}"),
+            ("{track - {title}", "Unknown field {track - {title}"),
+            ("{artist}/{title", "Unclosed {"),
+            ("{artist}//{title}", "Empty segment"),
+            ("", "Empty segment"),
+        ] {
+            let err = FilenameParser::new(&[pattern.to_string()]).expect_err(pattern);
+            assert!(err.to_string().starts_with(error), "{pattern}: {err}");
+        }
+    }
+
+    fn disc_track(disc: Option<i32>, track: i32) -> ParsedFilename {
+        ParsedFilename {
+            disc_number: disc,
+            track_number: Some(track),
+            ..ParsedFilename::default()
+        }
+    }
+
+    #[test]
+    fn numbers_tracks_across_discs() {
+        let mut files = vec![
+            disc_track(Some(2), 1),
+            disc_track(Some(1), 1),
+            disc_track(Some(1), 2),
+            disc_track(Some(3), 1),
+            disc_track(Some(1), 3),
+            disc_track(Some(2), 2),
+            disc_track(None, 9),
+        ];
+        number_across_discs(&mut files);
+
+        let numbers: Vec<_> = files.iter().filter_map(|file| file.track_number).collect();
+        assert_eq!(numbers, [4, 1, 2, 6, 3, 5, 9]);
+    }
+
+    #[test]
+    fn leaves_single_discs_alone() {
+        let mut files = vec![
+            disc_track(Some(2), 1),
+            disc_track(Some(2), 2),
+            disc_track(None, 3),
+        ];
+        let before = files.clone();
+        number_across_discs(&mut files);
+        assert_eq!(files, before);
+    }
+}
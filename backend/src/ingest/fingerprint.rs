@@ -0,0 +1,430 @@
+//! Content hashes and acoustic fingerprints of stored audio, for finding the
+//! same recording uploaded twice.
+//!
+//! Files with the same SHA-256 are exact duplicates. The hash is of the file
+//! as uploaded, before a WAV or AIFF is converted to FLAC, so an upload
+//! matches the same file uploaded again whether or not either was converted.
+//! A FLAC and an MP3 of the same master are not, so the audio is
+//! fingerprinted too, after Haitsma and Kalker's robust audio hashing: the
+//! first two minutes are decoded, mixed to mono at 11025 Hz and cut into
+//! overlapping frames, and each frame gives 32 bits saying whether the energy
+//! difference between neighbouring frequency bands grew since the frame
+//! before. Re-encoding flips a few of those bits, a different recording about
+//! half of them.
+//!
+//! Both are recorded by storage key as soon as a file is stored, and
+//! [`spawn_backfill_task`] catches up on files stored any other way, hashing
+//! them as stored.
+
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration as StdDuration,
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::DecoderOptions,
+    dsp::{complex::Complex, fft::Fft},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::{MediaSourceStream, MediaSourceStreamOptions},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+use tracing::{debug, info, warn};
+
+use crate::{db::queries, AppState};
+
+use super::spool::SpooledFile;
+
+/// Rate the audio is resampled to before fingerprinting.
+const SAMPLE_RATE: u32 = 11025;
+/// Seconds of audio fingerprinted, from the start.
+const MAX_SECONDS: u32 = 120;
+/// Samples per frame, 186 ms.
+const FRAME_LEN: usize = 2048;
+/// Samples between frames, 46 ms.
+const HOP_LEN: usize = 512;
+/// Frequency bands, one more than the bits per frame.
+const BANDS: usize = 33;
+const LOW_HZ: f32 = 300.0;
+const HIGH_HZ: f32 = 3000.0;
+/// Frames two fingerprints are shifted against each other by at most, for
+/// encoders that pad the start of the audio.
+const MAX_OFFSET: usize = 8;
+/// Frames two fingerprints must overlap by to be compared, about 3 seconds.
+const MIN_OVERLAP: usize = 64;
+
+/// Hex SHA-256 of the file at `path`, on the blocking pool. Uploads are
+/// hashed before they are converted, see [`record`].
+pub async fn hash_file(path: &Path) -> io::Result<String> {
+    let local: PathBuf = path.to_path_buf();
+    tokio::task::spawn_blocking(move || content_hash(&local))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Fingerprints the file stored at `key` from its local copy at `path`, and
+/// records it with `content_hash`, the [`hash_file`] of what was uploaded.
+/// Failures are logged and left for the backfill task.
+pub async fn record(state: &AppState, key: &str, path: &Path, content_hash: &str) {
+    let local: PathBuf = path.to_path_buf();
+    let computed = tokio::task::spawn_blocking(move || fingerprint(&local)).await;
+    let fingerprint = match computed {
+        Ok(Ok(fingerprint)) => Some(to_bytes(&fingerprint)),
+        Ok(Err(err)) => {
+            debug!("No fingerprint for {key}: {err:#}");
+            None
+        }
+        Err(err) => {
+            warn!("Failed to fingerprint {key}: {err}");
+            return;
+        }
+    };
+
+    let saved = queries::save_audio_fingerprint(
+        &state.db,
+        key,
+        content_hash,
+        fingerprint.as_deref(),
+        Utc::now(),
+    )
+    .await;
+    if let Err(err) = saved {
+        warn!("Failed to save the fingerprint of {key}: {err:#}");
+    }
+}
+
+/// Hex SHA-256 of the file at `path`.
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// How alike two fingerprints are at their best alignment, about `0.6` for
+/// unrelated audio up to `1.0` for the same. `None` when they're too short to
+/// tell.
+pub fn similarity(a: &[u32], b: &[u32]) -> Option<f32> {
+    (0..=2 * MAX_OFFSET)
+        .filter_map(|shift| {
+            let (a, b) = if shift < MAX_OFFSET {
+                (a.get(MAX_OFFSET - shift..)?, b)
+            } else {
+                (a, b.get(shift - MAX_OFFSET..)?)
+            };
+            let overlap = a.len().min(b.len());
+            if overlap < MIN_OVERLAP {
+                return None;
+            }
+            let differing: u32 = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum();
+            #[allow(clippy::cast_precision_loss)]
+            let error_rate = differing as f32 / (overlap * 32) as f32;
+            Some(1.0 - error_rate)
+        })
+        .max_by(f32::total_cmp)
+}
+
+pub fn to_bytes(fingerprint: &[u32]) -> Vec<u8> {
+    fingerprint
+        .iter()
+        .flat_map(|frame| frame.to_le_bytes())
+        .collect()
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|frame| u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))
+        .collect()
+}
+
+fn fingerprint(path: &Path) -> anyhow::Result<Vec<u32>> {
+    let samples = decode_mono(path)?;
+    let fft = Fft::new(FRAME_LEN);
+    #[allow(clippy::cast_precision_loss)]
+    let window: Vec<f32> = (0..FRAME_LEN)
+        .map(|i| {
+            0.5f32.mul_add(
+                -(2.0 * std::f32::consts::PI * i as f32 / FRAME_LEN as f32).cos(),
+                0.5,
+            )
+        })
+        .collect();
+    let edges = band_edges();
+
+    let mut buffer = vec![Complex::default(); FRAME_LEN];
+    let mut previous: Option<[f32; BANDS]> = None;
+    let mut fingerprint = Vec::new();
+    for frame in samples.windows(FRAME_LEN).step_by(HOP_LEN) {
+        for ((value, sample), weight) in buffer.iter_mut().zip(frame).zip(&window) {
+            *value = Complex::new(sample * weight, 0.0);
+        }
+        fft.fft_inplace(&mut buffer);
+
+        let mut energy = [0.0f32; BANDS];
+        for (band, bins) in energy.iter_mut().zip(edges.windows(2)) {
+            *band = buffer[bins[0]..bins[1]]
+                .iter()
+                .map(|bin| bin.re.mul_add(bin.re, bin.im * bin.im))
+                .sum();
+        }
+        if let Some(previous) = previous {
+            let bits = (0..BANDS - 1).fold(0u32, |bits, band| {
+                let change =
+                    (energy[band] - energy[band + 1]) - (previous[band] - previous[band + 1]);
+                bits | u32::from(change > 0.0) << band
+            });
+            fingerprint.push(bits);
+        }
+        previous = Some(energy);
+    }
+
+    if fingerprint.len() < MIN_OVERLAP {
+        return Err(anyhow!("Too short to fingerprint"));
+    }
+    Ok(fingerprint)
+}
+
+/// FFT bins where each band starts, the last one where the last band ends.
+/// Spaced evenly on a log scale, as hearing is.
+fn band_edges() -> Vec<usize> {
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    let bin = |hz: f32| (hz * FRAME_LEN as f32 / SAMPLE_RATE as f32).round() as usize;
+    let mut edges: Vec<usize> = (0..=BANDS)
+        .map(|band| {
+            #[allow(clippy::cast_precision_loss)]
+            let step = band as f32 / BANDS as f32;
+            bin(LOW_HZ * (HIGH_HZ / LOW_HZ).powf(step))
+        })
+        .collect();
+    // Every band gets at least one bin.
+    for i in 1..edges.len() {
+        edges[i] = edges[i].max(edges[i - 1] + 1);
+    }
+    edges
+}
+
+/// The first [`MAX_SECONDS`] of the file's audio, mixed to mono at
+/// [`SAMPLE_RATE`].
+fn decode_mono(path: &Path) -> anyhow::Result<Vec<f32>> {
+    let source = MediaSourceStream::new(
+        Box::new(File::open(path)?),
+        MediaSourceStreamOptions::default(),
+    );
+    let mut reader = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|err| anyhow!("Unrecognised audio file: {err}"))?
+        .format;
+    let track = reader
+        .default_track()
+        .ok_or_else(|| anyhow!("Audio file has no audio track"))?;
+    let track_id = track.id;
+    let rate = track
+        .codec_params
+        .sample_rate
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| anyhow!("Audio file has no sample rate"))?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|err| anyhow!("Could not decode the audio: {err}"))?;
+
+    let max_samples = (SAMPLE_RATE * MAX_SECONDS) as usize;
+    let mut resampler = Resampler::new(rate);
+    let mut samples = Vec::with_capacity(max_samples);
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    while samples.len() < max_samples {
+        let packet = match reader.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => packet,
+            Ok(_) => continue,
+            Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(err) => return Err(anyhow!("Failed to read audio file: {err}")),
+        };
+        let audio = match decoder.decode(&packet) {
+            Ok(audio) => audio,
+            // A damaged packet, the rest may still decode.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(err) => return Err(anyhow!("Could not decode the audio: {err}")),
+        };
+
+        let channels = audio.spec().channels.count().max(1);
+        let buffer =
+            buffer.get_or_insert_with(|| SampleBuffer::new(audio.capacity() as u64, *audio.spec()));
+        if buffer.capacity() < audio.capacity() * channels {
+            *buffer = SampleBuffer::new(audio.capacity() as u64, *audio.spec());
+        }
+        buffer.copy_interleaved_ref(audio);
+        for frame in buffer.samples().chunks_exact(channels) {
+            #[allow(clippy::cast_precision_loss)]
+            let mono = frame.iter().sum::<f32>() / channels as f32;
+            if let Some(sample) = resampler.push(mono) {
+                samples.push(sample);
+            }
+        }
+    }
+    samples.truncate(max_samples);
+
+    Ok(samples)
+}
+
+/// Averages runs of input samples down to [`SAMPLE_RATE`], which is all the
+/// filtering the bands up to [`HIGH_HZ`] need.
+struct Resampler {
+    rate: u64,
+    input: u64,
+    output: u64,
+    sum: f32,
+    count: u32,
+}
+
+impl Resampler {
+    const fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as u64,
+            input: 0,
+            output: 0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Takes the next input sample, returning an output sample once one is
+    /// complete.
+    fn push(&mut self, sample: f32) -> Option<f32> {
+        let output = self.input * u64::from(SAMPLE_RATE) / self.rate;
+        self.input += 1;
+        let mut done = None;
+        if output != self.output && self.count > 0 {
+            #[allow(clippy::cast_precision_loss)]
+            let average = self.sum / self.count as f32;
+            done = Some(average);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+        self.output = output;
+        self.sum += sample;
+        self.count += 1;
+        done
+    }
+}
+
+/// Records what's missing for the current tracks' files every
+/// `interval_secs`, starting now, fetching each file from storage.
+pub fn spawn_backfill_task(state: &Arc<AppState>, interval_secs: u64) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(StdDuration::from_secs(interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let result = backfill(&state).await;
+            match result {
+                Ok(0) => {}
+                Ok(recorded) => info!("Fingerprinted {recorded} stored files"),
+                Err(err) => warn!("Fingerprint backfill failed: {err:#}"),
+            }
+        }
+    });
+}
+
+async fn backfill(state: &AppState) -> anyhow::Result<usize> {
+    let spool_dir = Path::new(&state.config.upload.spool_dir);
+    let mut recorded = 0;
+    for key in queries::get_unfingerprinted_files(&state.db).await? {
+        let (spooled, mut file) = SpooledFile::create(spool_dir).await?;
+        let downloaded = state.storage.download_to(&key, &mut file).await;
+        drop(file);
+        if let Err(err) = downloaded {
+            warn!("Failed to fetch {key} to fingerprint it: {err:#}");
+            continue;
+        }
+
+        // The upload is long gone, so the file is hashed as stored.
+        let hashed = hash_file(spooled.path()).await;
+        match hashed {
+            Ok(content_hash) => record(state, &key, spooled.path(), &content_hash).await,
+            Err(err) => {
+                warn!("Failed to hash {key}: {err}");
+                continue;
+            }
+        }
+        recorded += 1;
+    }
+
+    Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` frames of reproducible noise.
+    fn print(seed: u32, len: usize) -> Vec<u32> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn needs_enough_frames_to_compare() {
+        let a = print(1, MIN_OVERLAP + MAX_OFFSET);
+        assert_eq!(similarity(&a, &a), Some(1.0));
+        assert_eq!(
+            similarity(&a[..MIN_OVERLAP - 1], &a[..MIN_OVERLAP - 1]),
+            None
+        );
+        assert_eq!(similarity(&a, &a[..MIN_OVERLAP - 1]), None);
+    }
+
+    #[test]
+    fn lines_up_prints_that_start_a_few_frames_apart() {
+        let a = print(2, 200);
+        assert_eq!(similarity(&a, &a[MAX_OFFSET..]), Some(1.0));
+        assert_eq!(similarity(&a[3..], &a), Some(1.0));
+
+        let too_late = similarity(&a, &a[MAX_OFFSET + 1..]).expect("long enough");
+        assert!(too_late < 0.6, "{too_late}");
+    }
+
+    #[test]
+    fn scores_flipped_bits_and_unrelated_audio() {
+        let a = print(3, 200);
+        // One bit in eight differs, as a lossy re-encode might.
+        let reencoded: Vec<u32> = a.iter().map(|frame| frame ^ 0x0101_0101).collect();
+        assert_eq!(similarity(&a, &reencoded), Some(0.875));
+
+        let unrelated = similarity(&a, &print(4, 200)).expect("long enough");
+        assert!((0.4..0.6).contains(&unrelated), "{unrelated}");
+    }
+
+    #[test]
+    fn stores_frames_as_little_endian_bytes() {
+        let a = print(5, 3);
+        let bytes = to_bytes(&a);
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(bytes[..4], a[0].to_le_bytes());
+        assert_eq!(from_bytes(&bytes), a);
+    }
+}
@@ -0,0 +1,829 @@
+//! A small FLAC encoder for converting uncompressed uploads.
+//!
+//! Uses fixed blocks of [`BLOCK_SIZE`] samples, the fixed polynomial
+//! predictors (orders 0 to 4) with partitioned Rice coding of the residual,
+//! and picks the best of the four stereo decorrelation modes per frame. That
+//! gets most of what `flac -5` saves on typical music, without LPC.
+//!
+//! Samples are given per channel as signed integers at the stream's bit
+//! depth. The MD5 of the audio is written to `STREAMINFO`, as decoders use it
+//! to check their output.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use md5::{Digest, Md5};
+
+/// Samples per channel in every frame but the last.
+pub const BLOCK_SIZE: usize = 4096;
+/// The deepest samples FLAC decoders can be relied on for.
+pub const MAX_BITS_PER_SAMPLE: u32 = 24;
+const MAX_PARTITION_ORDER: u32 = 8;
+const MAX_FIXED_ORDER: usize = 4;
+const STREAMINFO_LEN: u32 = 34;
+const VENDOR: &str = "Navicore Music";
+
+/// How the two channels of a stereo frame are coded.
+#[derive(Debug, Clone, Copy)]
+enum ChannelMode {
+    Independent,
+    LeftSide,
+    RightSide,
+    MidSide,
+}
+
+pub struct FlacEncoder<W: Write + Seek> {
+    out: W,
+    channels: usize,
+    bits_per_sample: u32,
+    sample_rate: u32,
+    pending: Vec<Vec<i32>>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame: usize,
+    max_frame: usize,
+    md5: Md5,
+    /// Where `STREAMINFO` starts, to fill it in once the stream is done.
+    streaminfo_at: u64,
+}
+
+impl<W: Write + Seek> FlacEncoder<W> {
+    /// Writes the stream header to `out`, with `tags` as Vorbis comments,
+    /// e.g. `("TITLE", "Orbit")`.
+    pub fn new(
+        mut out: W,
+        channels: usize,
+        bits_per_sample: u32,
+        sample_rate: u32,
+        tags: &[(&str, String)],
+    ) -> io::Result<Self> {
+        if !(1..=8).contains(&channels) || !(4..=MAX_BITS_PER_SAMPLE).contains(&bits_per_sample) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FLAC can't hold {channels} channels of {bits_per_sample}-bit audio"),
+            ));
+        }
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported sample rate {sample_rate}"),
+            ));
+        }
+
+        out.write_all(b"fLaC")?;
+        let streaminfo_at = out.stream_position()?;
+        let mut encoder = Self {
+            out,
+            channels,
+            bits_per_sample,
+            sample_rate,
+            pending: vec![Vec::with_capacity(BLOCK_SIZE); channels],
+            frame_number: 0,
+            total_samples: 0,
+            min_frame: usize::MAX,
+            max_frame: 0,
+            md5: Md5::new(),
+            streaminfo_at,
+        };
+        let streaminfo = encoder.streaminfo();
+        encoder.out.write_all(&streaminfo)?;
+        encoder.out.write_all(&vorbis_comment(tags))?;
+
+        Ok(encoder)
+    }
+
+    /// Adds samples, one slice per channel, all the same length.
+    pub fn write(&mut self, channels: &[&[i32]]) -> io::Result<()> {
+        let len = channels.first().map_or(0, |channel| channel.len());
+        if channels.len() != self.channels || channels.iter().any(|channel| channel.len() != len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Channels of different lengths",
+            ));
+        }
+
+        let mut start = 0;
+        while start < len {
+            let take = (BLOCK_SIZE - self.pending[0].len()).min(len - start);
+            for (pending, channel) in self.pending.iter_mut().zip(channels) {
+                pending.extend_from_slice(&channel[start..start + take]);
+            }
+            start += take;
+            if self.pending[0].len() == BLOCK_SIZE {
+                self.flush_block()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encodes what is left and fills in `STREAMINFO`, returning the writer
+    /// and the MD5 of the audio.
+    pub fn finish(mut self) -> io::Result<(W, [u8; 16])> {
+        if !self.pending[0].is_empty() {
+            self.flush_block()?;
+        }
+        let md5: [u8; 16] = self.md5.clone().finalize().into();
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(self.streaminfo_at))?;
+        let mut streaminfo = self.streaminfo();
+        streaminfo[22..38].copy_from_slice(&md5);
+        self.out.write_all(&streaminfo)?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+
+        Ok((self.out, md5))
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        let block = std::mem::take(&mut self.pending);
+        self.pending = vec![Vec::with_capacity(BLOCK_SIZE); self.channels];
+
+        update_md5(&mut self.md5, &block, self.bits_per_sample);
+        let frame = self.encode_frame(&block);
+        self.min_frame = self.min_frame.min(frame.len());
+        self.max_frame = self.max_frame.max(frame.len());
+        self.out.write_all(&frame)?;
+        self.frame_number += 1;
+        self.total_samples += block[0].len() as u64;
+
+        Ok(())
+    }
+
+    fn encode_frame(&self, block: &[Vec<i32>]) -> Vec<u8> {
+        let bits = self.bits_per_sample;
+        let (mode, subframes) = if block.len() == 2 {
+            stereo_subframes(&block[0], &block[1], bits)
+        } else {
+            let subframes = block
+                .iter()
+                .map(|channel| Subframe::best(channel, bits))
+                .collect();
+            (ChannelMode::Independent, subframes)
+        };
+
+        let mut frame = BitWriter::default();
+        frame.write(0xFFF8, 16); // sync, fixed block size
+        let size_code = if block[0].len() == BLOCK_SIZE {
+            0b1100
+        } else {
+            0b0111
+        };
+        frame.write(size_code, 4);
+        frame.write(0, 4); // sample rate from STREAMINFO
+        let assignment = match mode {
+            ChannelMode::Independent => block.len() as u64 - 1,
+            ChannelMode::LeftSide => 0b1000,
+            ChannelMode::RightSide => 0b1001,
+            ChannelMode::MidSide => 0b1010,
+        };
+        frame.write(assignment, 4);
+        frame.write(sample_size_code(bits), 3);
+        frame.write(0, 1);
+        write_utf8_number(&mut frame, self.frame_number);
+        if size_code == 0b0111 {
+            frame.write(block[0].len() as u64 - 1, 16);
+        }
+        let crc = crc8(frame.bytes());
+        frame.write(u64::from(crc), 8);
+
+        for subframe in &subframes {
+            subframe.write(&mut frame);
+        }
+        frame.align();
+        let crc = crc16(frame.bytes());
+        frame.write(u64::from(crc), 16);
+
+        frame.into_bytes()
+    }
+
+    fn streaminfo(&self) -> Vec<u8> {
+        let mut info = BitWriter::default();
+        info.write(0, 1); // not the last metadata block
+        info.write(0, 7); // STREAMINFO
+        info.write(u64::from(STREAMINFO_LEN), 24);
+        info.write(BLOCK_SIZE as u64, 16);
+        info.write(BLOCK_SIZE as u64, 16);
+        let (min_frame, max_frame) = if self.max_frame == 0 {
+            (0, 0)
+        } else {
+            (self.min_frame, self.max_frame)
+        };
+        info.write(min_frame as u64, 24);
+        info.write(max_frame as u64, 24);
+        info.write(u64::from(self.sample_rate), 20);
+        info.write(self.channels as u64 - 1, 3);
+        info.write(u64::from(self.bits_per_sample) - 1, 5);
+        info.write(self.total_samples, 36);
+        // MD5, filled in by `finish`.
+        info.write(0, 64);
+        info.write(0, 64);
+
+        info.into_bytes()
+    }
+}
+
+/// The last metadata block: Vorbis comments with the given tags.
+fn vorbis_comment(tags: &[(&str, String)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let put = |body: &mut Vec<u8>, text: &[u8]| {
+        body.extend_from_slice(&u32::try_from(text.len()).unwrap_or_default().to_le_bytes());
+        body.extend_from_slice(text);
+    };
+    put(&mut body, VENDOR.as_bytes());
+    body.extend_from_slice(&u32::try_from(tags.len()).unwrap_or_default().to_le_bytes());
+    for (name, value) in tags {
+        put(&mut body, format!("{name}={value}").as_bytes());
+    }
+
+    let mut block = vec![0x80 | 4]; // last block, VORBIS_COMMENT
+    let len = u32::try_from(body.len()).unwrap_or_default();
+    block.extend_from_slice(&len.to_be_bytes()[1..]);
+    block.extend_from_slice(&body);
+
+    block
+}
+
+/// FLAC's MD5 covers the samples interleaved, little-endian, in whole bytes.
+fn update_md5(md5: &mut Md5, block: &[Vec<i32>], bits_per_sample: u32) {
+    let width = bits_per_sample.div_ceil(8) as usize;
+    let mut bytes = Vec::with_capacity(block[0].len() * block.len() * width);
+    for i in 0..block[0].len() {
+        for channel in block {
+            bytes.extend_from_slice(&channel[i].to_le_bytes()[..width]);
+        }
+    }
+    md5.update(&bytes);
+}
+
+/// Codes both channels each way and keeps the smallest pair.
+fn stereo_subframes(left: &[i32], right: &[i32], bits: u32) -> (ChannelMode, Vec<Subframe>) {
+    let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+    let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+
+    let left = Subframe::best(left, bits);
+    let right = Subframe::best(right, bits);
+    let side = Subframe::best(&side, bits + 1);
+    let mid = Subframe::best(&mid, bits);
+
+    let options = [
+        (ChannelMode::Independent, left.bits + right.bits),
+        (ChannelMode::LeftSide, left.bits + side.bits),
+        (ChannelMode::RightSide, side.bits + right.bits),
+        (ChannelMode::MidSide, mid.bits + side.bits),
+    ];
+    let (mode, _) = options
+        .into_iter()
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap_or((ChannelMode::Independent, 0));
+
+    let subframes = match mode {
+        ChannelMode::Independent => vec![left, right],
+        ChannelMode::LeftSide => vec![left, side],
+        ChannelMode::RightSide => vec![side, right],
+        ChannelMode::MidSide => vec![mid, side],
+    };
+
+    (mode, subframes)
+}
+
+enum SubframeKind {
+    Constant(i32),
+    Verbatim(Vec<i32>),
+    Fixed {
+        warmup: Vec<i32>,
+        residual: Residual,
+    },
+}
+
+struct Subframe {
+    kind: SubframeKind,
+    sample_bits: u32,
+    /// Coded size, header included.
+    bits: u64,
+}
+
+impl Subframe {
+    /// The smallest of the constant, verbatim and fixed codings.
+    fn best(samples: &[i32], sample_bits: u32) -> Self {
+        let header = 8;
+        if samples.iter().all(|sample| *sample == samples[0]) {
+            return Self {
+                kind: SubframeKind::Constant(samples[0]),
+                sample_bits,
+                bits: header + u64::from(sample_bits),
+            };
+        }
+
+        let verbatim_bits = header + samples.len() as u64 * u64::from(sample_bits);
+        let fixed = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+            .map(|order| (order, fixed_residual(samples, order)))
+            .min_by_key(|(_, residual)| {
+                residual
+                    .iter()
+                    .map(|value| value.unsigned_abs())
+                    .sum::<u64>()
+            })
+            .map(|(order, residual)| {
+                let residual = Residual::code(&residual, samples.len(), order);
+                let bits = header + order as u64 * u64::from(sample_bits) + residual.bits;
+                (order, residual, bits)
+            });
+
+        match fixed {
+            Some((order, residual, bits)) if bits < verbatim_bits => Self {
+                kind: SubframeKind::Fixed {
+                    warmup: samples[..order].to_vec(),
+                    residual,
+                },
+                sample_bits,
+                bits,
+            },
+            _ => Self {
+                kind: SubframeKind::Verbatim(samples.to_vec()),
+                sample_bits,
+                bits: verbatim_bits,
+            },
+        }
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        match &self.kind {
+            SubframeKind::Constant(value) => {
+                out.write(0, 8);
+                out.write_signed(i64::from(*value), self.sample_bits);
+            }
+            SubframeKind::Verbatim(samples) => {
+                out.write(0b10, 8);
+                for sample in samples {
+                    out.write_signed(i64::from(*sample), self.sample_bits);
+                }
+            }
+            SubframeKind::Fixed { warmup, residual } => {
+                out.write((0b1000 | warmup.len() as u64) << 1, 8);
+                for sample in warmup {
+                    out.write_signed(i64::from(*sample), self.sample_bits);
+                }
+                residual.write(out);
+            }
+        }
+    }
+}
+
+/// What the fixed predictor of `order` misses, from sample `order` on.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i64> {
+    let s = |i: usize| i64::from(samples[i]);
+    (order..samples.len())
+        .map(|i| match order {
+            0 => s(i),
+            1 => s(i) - s(i - 1),
+            2 => s(i) - 2 * s(i - 1) + s(i - 2),
+            3 => s(i) - 3 * s(i - 1) + 3 * s(i - 2) - s(i - 3),
+            _ => s(i) - 4 * s(i - 1) + 6 * s(i - 2) - 4 * s(i - 3) + s(i - 4),
+        })
+        .collect()
+}
+
+/// A residual in Rice-coded partitions.
+struct Residual {
+    values: Vec<u64>,
+    block_len: usize,
+    predictor_order: usize,
+    partition_order: u32,
+    parameters: Vec<u32>,
+    /// Whether parameters need five bits rather than four.
+    wide: bool,
+    bits: u64,
+}
+
+impl Residual {
+    /// Picks the partition order and parameters that code `residual` of a
+    /// block of `block_len` samples in the fewest bits.
+    fn code(residual: &[i64], block_len: usize, predictor_order: usize) -> Self {
+        let values: Vec<u64> = residual.iter().map(|value| zigzag(*value)).collect();
+
+        let mut best: Option<(u32, Vec<u32>, u64)> = None;
+        for order in 0..=MAX_PARTITION_ORDER {
+            let partitions = 1usize << order;
+            let partition_len = block_len >> order;
+            if block_len % partitions != 0 || partition_len <= predictor_order {
+                break;
+            }
+
+            let mut parameters = Vec::with_capacity(partitions);
+            let mut bits = 0;
+            let mut start = 0;
+            for partition in 0..partitions {
+                let len = if partition == 0 {
+                    partition_len - predictor_order
+                } else {
+                    partition_len
+                };
+                let (parameter, partition_bits) = rice_parameter(&values[start..start + len]);
+                parameters.push(parameter);
+                bits += partition_bits;
+                start += len;
+            }
+            let wide = parameters.iter().any(|parameter| *parameter > 14);
+            bits += 2 + 4 + partitions as u64 * if wide { 5 } else { 4 };
+
+            if best
+                .as_ref()
+                .is_none_or(|(_, _, best_bits)| bits < *best_bits)
+            {
+                best = Some((order, parameters, bits));
+            }
+        }
+
+        let (partition_order, parameters, bits) = best.unwrap_or_else(|| {
+            let (parameter, bits) = rice_parameter(&values);
+            (0, vec![parameter], bits + 2 + 4 + 5)
+        });
+        Self {
+            wide: parameters.iter().any(|parameter| *parameter > 14),
+            values,
+            block_len,
+            predictor_order,
+            partition_order,
+            parameters,
+            bits,
+        }
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        out.write(u64::from(self.wide), 2);
+        out.write(u64::from(self.partition_order), 4);
+        let partition_len = self.block_len >> self.partition_order;
+        let mut start = 0;
+        for (partition, parameter) in self.parameters.iter().enumerate() {
+            // The first partition leaves out the warm-up samples.
+            let len = if partition == 0 {
+                partition_len - self.predictor_order
+            } else {
+                partition_len
+            };
+            out.write(u64::from(*parameter), if self.wide { 5 } else { 4 });
+            for value in &self.values[start..start + len] {
+                out.write_rice(*value, *parameter);
+            }
+            start += len;
+        }
+    }
+}
+
+/// The Rice parameter coding `values` in the fewest bits, and that size.
+fn rice_parameter(values: &[u64]) -> (u32, u64) {
+    let sum: u64 = values.iter().sum();
+    let len = values.len() as u64;
+    let estimate = if len == 0 || sum < len {
+        0
+    } else {
+        (sum / len).ilog2()
+    };
+    let size = |parameter: u32| {
+        len * (u64::from(parameter) + 1)
+            + values.iter().map(|value| value >> parameter).sum::<u64>()
+    };
+
+    (estimate.saturating_sub(1)..=(estimate + 1).min(30))
+        .map(|parameter| (parameter, size(parameter)))
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap_or_else(|| (0, size(0)))
+}
+
+#[allow(clippy::cast_sign_loss)]
+const fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+const fn sample_size_code(bits: u32) -> u64 {
+    match bits {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    }
+}
+
+/// Frame numbers are coded like UTF-8 characters, up to 36 bits.
+fn write_utf8_number(out: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        out.write(value, 8);
+        return;
+    }
+    let continuation = match value {
+        0..0x800 => 1,
+        0x800..0x1_0000 => 2,
+        0x1_0000..0x20_0000 => 3,
+        0x20_0000..0x400_0000 => 4,
+        0x400_0000..0x8000_0000 => 5,
+        _ => 6,
+    };
+    let lead_marker = (0xFF00u64 >> (continuation + 1)) & 0xFF;
+    out.write(lead_marker | (value >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x07
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, byte| {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x8005
+            };
+        }
+        crc
+    })
+}
+
+/// Packs values most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet making up a whole byte, in the low end.
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `bits` bits of `value`, at most 56 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 56 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        self.pending = (self.pending << bits) | (value & ((1 << bits) - 1));
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            // Only the top byte of what's pending.
+            #[allow(clippy::cast_possible_truncation)]
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    /// Writes `value` in two's complement in `bits` bits.
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        #[allow(clippy::cast_sign_loss)]
+        self.write(value as u64, bits);
+    }
+
+    fn write_rice(&mut self, value: u64, parameter: u32) {
+        let mut quotient = value >> parameter;
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, u32::try_from(quotient).unwrap_or_default() + 1);
+        if parameter > 0 {
+            self.write(value, parameter);
+        }
+    }
+
+    /// Pads with zero bits to a whole byte.
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+
+    /// The whole bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+/// Round trips through the encoder, checked with symphonia's decoder. The
+/// signals and decoding are shared with [`super::transcode`]'s tests.
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+
+    use symphonia::core::{
+        audio::SampleBuffer,
+        codecs::{DecoderOptions, VerificationCheck},
+        errors::Error as SymphoniaError,
+        formats::FormatOptions,
+        io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions},
+        meta::MetadataOptions,
+        probe::Hint,
+    };
+
+    use super::*;
+
+    /// Test audio, generated sample by sample.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Signal {
+        Silence,
+        /// A triangle wave at half scale, a different pitch per channel.
+        Triangle,
+        /// A triangle wave clipped at the top and bottom of the range.
+        FullScale,
+        Noise,
+    }
+
+    impl Signal {
+        pub fn sample(self, frame: i32, channel: i32, bits: u32) -> i32 {
+            let max = (1 << (bits - 1)) - 1;
+            let min = -(1 << (bits - 1));
+            match self {
+                Self::Silence => 0,
+                Self::Triangle => triangle(frame, channel, max) / 2,
+                Self::FullScale => (triangle(frame, channel, max) * 2).clamp(min, max),
+                Self::Noise => {
+                    let mut hash = frame.unsigned_abs().wrapping_mul(0x9E37_79B9)
+                        ^ channel.unsigned_abs() << 24;
+                    for multiplier in [0x7FEB_352D, 0x846C_A68B] {
+                        hash = (hash ^ hash >> 16).wrapping_mul(multiplier);
+                    }
+                    i32::try_from((hash ^ hash >> 16) >> (32 - bits)).expect("fits") + min
+                }
+            }
+        }
+
+        /// `frames` samples for each of `channels`.
+        pub fn generate(self, channels: i32, bits: u32, frames: i32) -> Vec<Vec<i32>> {
+            (0..channels)
+                .map(|channel| {
+                    (0..frames)
+                        .map(|frame| self.sample(frame, channel, bits))
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    /// From `-peak` to `peak` and back, at a different pitch per channel.
+    fn triangle(frame: i32, channel: i32, peak: i32) -> i32 {
+        let period = 64 + 16 * channel;
+        ((frame % period - period / 2).abs() - period / 4) * peak / (period / 4)
+    }
+
+    /// What symphonia makes of a FLAC stream.
+    pub struct Decoded {
+        /// Per channel, at the stream's bit depth.
+        pub channels: Vec<Vec<i32>>,
+        pub streaminfo_md5: Option<[u8; 16]>,
+        pub streaminfo_frames: Option<u64>,
+        /// Whether symphonia's own check of the MD5 passed.
+        pub verified: Option<bool>,
+    }
+
+    pub fn decode(source: Box<dyn MediaSource>, bits: u32) -> Decoded {
+        let stream = MediaSourceStream::new(source, MediaSourceStreamOptions::default());
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
+        let mut reader = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .expect("a FLAC stream")
+            .format;
+        let track = reader.default_track().expect("a track").clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: true })
+            .expect("a FLAC decoder");
+
+        let count = track.codec_params.channels.expect("channels").count();
+        let mut channels = vec![Vec::new(); count];
+        loop {
+            let packet = match reader.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(err) => panic!("Unreadable FLAC: {err}"),
+            };
+            let audio = decoder.decode(&packet).expect("a decodable frame");
+            let mut samples = SampleBuffer::<i32>::new(audio.capacity() as u64, *audio.spec());
+            samples.copy_interleaved_ref(audio);
+            for frame in samples.samples().chunks_exact(count) {
+                for (channel, sample) in channels.iter_mut().zip(frame) {
+                    // Decoded samples are scaled up to 32 bits.
+                    channel.push(sample >> (32 - bits));
+                }
+            }
+        }
+
+        let streaminfo_md5 = match track.codec_params.verification_check {
+            Some(VerificationCheck::Md5(md5)) => Some(md5),
+            _ => None,
+        };
+        Decoded {
+            channels,
+            streaminfo_md5,
+            streaminfo_frames: track.codec_params.n_frames,
+            verified: decoder.finalize().verify_ok,
+        }
+    }
+
+    /// The MD5 FLAC defines: interleaved little-endian samples, in whole
+    /// bytes.
+    fn audio_md5(channels: &[Vec<i32>], bits: u32) -> [u8; 16] {
+        let width = bits.div_ceil(8) as usize;
+        let mut md5 = Md5::new();
+        for frame in 0..channels[0].len() {
+            for channel in channels {
+                md5.update(&channel[frame].to_le_bytes()[..width]);
+            }
+        }
+        md5.finalize().into()
+    }
+
+    fn round_trip(signal: Signal, channels: i32, bits: u32, frames: i32) {
+        let case = format!("{signal:?}, {channels} channels of {bits} bits, {frames} frames");
+        let input = signal.generate(channels, bits, frames);
+
+        let count = usize::try_from(channels).expect("channels");
+        let mut encoder =
+            FlacEncoder::new(Cursor::new(Vec::new()), count, bits, 44_100, &[]).expect(&case);
+        // Uneven writes, so blocks are put together from several.
+        let mut start = 0;
+        while start < input[0].len() {
+            let end = (start + 1000).min(input[0].len());
+            let chunk: Vec<&[i32]> = input.iter().map(|channel| &channel[start..end]).collect();
+            encoder.write(&chunk).expect(&case);
+            start = end;
+        }
+        let (out, md5) = encoder.finish().expect(&case);
+
+        let decoded = decode(Box::new(Cursor::new(out.into_inner())), bits);
+        assert!(decoded.channels == input, "{case}: decoded samples differ");
+        assert_eq!(md5, audio_md5(&input, bits), "{case}");
+        assert_eq!(decoded.streaminfo_md5, Some(md5), "{case}");
+        assert_eq!(decoded.verified, Some(true), "{case}");
+        assert_eq!(
+            decoded.streaminfo_frames,
+            Some(u64::from(frames.unsigned_abs())),
+            "{case}"
+        );
+    }
+
+    #[test]
+    fn decodes_to_what_went_in() {
+        // Two whole blocks and a partial one.
+        let frames = i32::try_from(2 * BLOCK_SIZE).expect("frames") + 1234;
+        for bits in [8, 16, 24] {
+            for channels in [1, 2] {
+                for signal in [
+                    Signal::Silence,
+                    Signal::Triangle,
+                    Signal::FullScale,
+                    Signal::Noise,
+                ] {
+                    round_trip(signal, channels, bits, frames);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_short_and_block_sized_streams() {
+        let block = i32::try_from(BLOCK_SIZE).expect("frames");
+        for frames in [1, 5, 31, block - 1, block, block + 1] {
+            round_trip(Signal::Triangle, 2, 16, frames);
+        }
+        round_trip(Signal::Noise, 6, 12, 3000);
+    }
+
+    #[test]
+    fn rejects_what_flac_cannot_hold() {
+        let out = || Cursor::new(Vec::new());
+        assert!(FlacEncoder::new(out(), 0, 16, 44_100, &[]).is_err());
+        assert!(FlacEncoder::new(out(), 9, 16, 44_100, &[]).is_err());
+        assert!(FlacEncoder::new(out(), 2, 32, 44_100, &[]).is_err());
+        assert!(FlacEncoder::new(out(), 2, 16, 0, &[]).is_err());
+
+        let mut encoder = FlacEncoder::new(out(), 2, 16, 44_100, &[]).expect("encoder");
+        assert!(encoder.write(&[&[1, 2], &[3]]).is_err());
+    }
+}
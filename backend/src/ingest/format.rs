@@ -0,0 +1,120 @@
+//! Audio and cover image format detection from file contents.
+//!
+//! The client's content type and file extension are only hints, so the stored
+//! object's extension and content type come from the bytes themselves.
+
+/// Bytes needed to recognise every supported format.
+pub const SNIFF_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Flac,
+    Mp3,
+    Ogg,
+    M4a,
+    Wav,
+    Aiff,
+}
+
+impl AudioFormat {
+    /// Detects the format from the first [`SNIFF_LEN`] bytes of a file.
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        match header {
+            [b'f', b'L', b'a', b'C', ..] => Some(Self::Flac),
+            [b'O', b'g', b'g', b'S', ..] => Some(Self::Ogg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Self::Wav),
+            [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => {
+                Some(Self::Aiff)
+            }
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(Self::M4a),
+            [b'I', b'D', b'3', ..] => Some(Self::Mp3),
+            // MPEG frame sync; layer bits of 00 would be ADTS AAC, not MP3.
+            [0xFF, second, ..] if second & 0xE0 == 0xE0 && second & 0x06 != 0 => Some(Self::Mp3),
+            _ => None,
+        }
+    }
+
+    /// The format a file name's extension claims, if it names an audio format.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "flac" => Some(Self::Flac),
+            "mp3" => Some(Self::Mp3),
+            "ogg" | "oga" => Some(Self::Ogg),
+            "m4a" | "mp4" => Some(Self::M4a),
+            "wav" => Some(Self::Wav),
+            "aif" | "aiff" | "aifc" => Some(Self::Aiff),
+            _ => None,
+        }
+    }
+
+    /// Name shown to users, e.g. `FLAC`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Flac => "FLAC",
+            Self::Mp3 => "MP3",
+            Self::Ogg => "OGG",
+            Self::M4a => "M4A",
+            Self::Wav => "WAV",
+            Self::Aiff => "AIFF",
+        }
+    }
+
+    /// Uncompressed PCM, which can be converted to FLAC without losing
+    /// anything.
+    pub const fn is_uncompressed(self) -> bool {
+        matches!(self, Self::Wav | Self::Aiff)
+    }
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Flac => "flac",
+            Self::Mp3 => "mp3",
+            Self::Ogg => "ogg",
+            Self::M4a => "m4a",
+            Self::Wav => "wav",
+            Self::Aiff => "aiff",
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Flac => "audio/flac",
+            Self::Mp3 => "audio/mpeg",
+            Self::Ogg => "audio/ogg",
+            Self::M4a => "audio/mp4",
+            Self::Wav => "audio/wav",
+            Self::Aiff => "audio/aiff",
+        }
+    }
+}
+
+/// Cover art formats accepted alongside audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        match header {
+            [0xFF, 0xD8, 0xFF, ..] => Some(Self::Jpeg),
+            [0x89, b'P', b'N', b'G', ..] => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Change notifications for directories from Linux's inotify.
+//!
+//! Only says that something changed, not what: the library scanner works out
+//! the changes itself, the same way as for a scan nothing prompted. Elsewhere
+//! [`Inotify::new`] fails, leaving scans on an interval.
+
+use std::{io, path::Path};
+
+#[cfg(target_os = "linux")]
+use std::{
+    ffi::CString,
+    fs::File,
+    io::Read,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+};
+
+#[cfg(target_os = "linux")]
+/// Files written, created, deleted or moved, and the directory itself going.
+const EVENTS: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+
+#[cfg(target_os = "linux")]
+/// Room for a few hundred events per read.
+const BUFFER_LEN: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+pub struct Inotify {
+    file: File,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: takes no pointers, the result is checked before use.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        Ok(Self {
+            file: File::from(fd),
+        })
+    }
+
+    /// Watches the entries of `dir`, not those of its subdirectories.
+    /// Watching a directory again changes nothing, and a watch goes away by
+    /// itself when its directory is deleted.
+    pub fn watch(&self, dir: &Path) -> io::Result<()> {
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains a NUL byte"))?;
+        // SAFETY: `path` is NUL-terminated and outlives the call.
+        let watch =
+            unsafe { libc::inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), EVENTS) };
+        if watch < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until something changes under a watched directory, discarding
+    /// the events that say what.
+    pub fn wait(&self) -> io::Result<()> {
+        let mut buffer = vec![0; BUFFER_LEN];
+        let read = (&self.file).read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Inotify;
+
+#[cfg(not(target_os = "linux"))]
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "inotify is only available on Linux",
+        ))
+    }
+
+    pub fn watch(&self, _dir: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn wait(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
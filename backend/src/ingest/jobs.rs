@@ -0,0 +1,358 @@
+//! Background processing of album uploads.
+//!
+//! An upload is saved as a row in `upload_jobs`, with its ZIP kept in the
+//! spool directory, and picked up by one of `UPLOAD_WORKERS` workers. Jobs cut
+//! off by a restart are queued again when the server starts; files they had
+//! already stored are reused rather than stored twice.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::{AuthUser, ClientInfo},
+    db::{models::UploadJob, queries, DbPool},
+    handlers::ApiError,
+    AppState,
+};
+
+use super::{
+    album::{self, AlbumUploadMetadata},
+    store,
+};
+
+/// How often idle workers look for jobs nobody woke them for.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Jobs interrupted this many times are failed rather than tried again.
+const MAX_ATTEMPTS: i64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Processing,
+    Completed,
+    Failed,
+}
+
+impl JobStatus {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Processing => "processing",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Steps of processing an album, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStage {
+    /// Unpacking the ZIP.
+    Extract,
+    /// Checking each file's format and reading its tags.
+    Probe,
+    /// Converting files before they are stored.
+    Transcode,
+    /// Uploading files to storage.
+    Store,
+    /// Creating the tracks.
+    Index,
+}
+
+impl JobStage {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Extract => "extract",
+            Self::Probe => "probe",
+            Self::Transcode => "transcode",
+            Self::Store => "store",
+            Self::Index => "index",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Pending,
+    Probed,
+    Stored,
+    Indexed,
+}
+
+/// Where one audio file from the ZIP has got to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileProgress {
+    /// Path in the ZIP, e.g. `CD1/01-song.flac`.
+    pub file: String,
+    pub bytes: u64,
+    pub status: FileStatus,
+    /// Set once the file is stored.
+    pub track_id: Option<String>,
+    pub file_path: Option<String>,
+    /// Format the file is stored in, `flac` for a converted WAV or AIFF. Set
+    /// by the transcode stage.
+    #[serde(default)]
+    pub stored_format: Option<String>,
+    /// Size of the file stored, less than `bytes` when it was converted.
+    #[serde(default)]
+    pub stored_bytes: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl FileProgress {
+    pub fn new(file: &str, bytes: u64) -> Self {
+        Self {
+            file: file.to_string(),
+            bytes,
+            status: FileStatus::Pending,
+            track_id: None,
+            file_path: None,
+            stored_format: None,
+            stored_bytes: None,
+            error: None,
+        }
+    }
+}
+
+/// The progress of the job being processed, saved as it changes so the
+/// status endpoint can show it.
+pub struct JobProgress {
+    db: DbPool,
+    job_id: String,
+    stage: JobStage,
+    album_id: Option<String>,
+    pub files: Vec<FileProgress>,
+}
+
+impl JobProgress {
+    fn resume(db: DbPool, job: &UploadJob) -> anyhow::Result<Self> {
+        Ok(Self {
+            db,
+            job_id: job.id.clone(),
+            stage: JobStage::Extract,
+            album_id: job.album_id.clone(),
+            files: serde_json::from_str(&job.files)?,
+        })
+    }
+
+    pub async fn set_stage(&mut self, stage: JobStage) -> anyhow::Result<()> {
+        self.stage = stage;
+        self.save().await
+    }
+
+    pub fn set_album(&mut self, album_id: &str) {
+        self.album_id = Some(album_id.to_string());
+    }
+
+    pub fn file_mut(&mut self, file: &str) -> Option<&mut FileProgress> {
+        self.files.iter_mut().find(|progress| progress.file == file)
+    }
+
+    pub async fn save(&self) -> anyhow::Result<()> {
+        queries::update_upload_job_progress(
+            &self.db,
+            &self.job_id,
+            self.stage.as_str(),
+            &serde_json::to_string(&self.files)?,
+            self.album_id.as_deref(),
+            Utc::now(),
+        )
+        .await
+    }
+
+    /// Whether an earlier attempt created the tracks but stopped before
+    /// recording it. Tracks are created together, so one is enough to check.
+    async fn already_indexed(&mut self) -> anyhow::Result<bool> {
+        let Some(track_id) = self
+            .files
+            .iter()
+            .filter(|progress| progress.status == FileStatus::Stored)
+            .find_map(|progress| progress.track_id.as_deref())
+        else {
+            return Ok(false);
+        };
+        if queries::get_track_by_id(&self.db, track_id)
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+
+        for file in &mut self.files {
+            file.status = FileStatus::Indexed;
+        }
+        self.save().await?;
+
+        Ok(true)
+    }
+
+    /// Forgets the stored files that have no track yet and returns their
+    /// paths, for removing them from storage.
+    fn take_unindexed(&mut self) -> Vec<String> {
+        self.files
+            .iter_mut()
+            .filter(|progress| progress.status == FileStatus::Stored)
+            .filter_map(|progress| {
+                progress.status = FileStatus::Probed;
+                progress.track_id = None;
+                progress.file_path.take()
+            })
+            .collect()
+    }
+}
+
+/// Wakes idle workers when an upload is queued.
+#[derive(Debug, Default)]
+pub struct UploadQueue {
+    wake: Notify,
+}
+
+impl UploadQueue {
+    pub async fn enqueue(&self, db: &DbPool, job: &UploadJob) -> anyhow::Result<()> {
+        queries::create_upload_job(db, job).await?;
+        self.wake.notify_one();
+
+        Ok(())
+    }
+}
+
+/// A queued job for the album ZIP at `zip_path`, to be processed with `user`'s
+/// rights.
+pub fn new_job(
+    user: &AuthUser,
+    client: &ClientInfo,
+    zip_path: &Path,
+    options: &AlbumUploadMetadata,
+) -> anyhow::Result<UploadJob> {
+    let now = Utc::now();
+    Ok(UploadJob {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.user_id.clone(),
+        credential: serde_json::to_string(&user.credential)?,
+        ip_address: client.ip_address.clone(),
+        user_agent: client.user_agent.clone(),
+        status: JobStatus::Queued.as_str().to_string(),
+        stage: None,
+        zip_path: zip_path.to_string_lossy().into_owned(),
+        options: serde_json::to_string(options)?,
+        files: "[]".to_string(),
+        album_id: None,
+        error: None,
+        attempts: 0,
+        created_at: now,
+        updated_at: now,
+        finished_at: None,
+    })
+}
+
+/// Queues jobs interrupted by the last shutdown again, then starts `workers`
+/// workers.
+pub fn spawn_workers(state: &Arc<AppState>, workers: usize) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let result = queries::requeue_interrupted_upload_jobs(&state.db, Utc::now()).await;
+        match result {
+            Ok(0) => {}
+            Ok(requeued) => info!("Resuming {requeued} interrupted uploads"),
+            Err(err) => warn!("Failed to requeue interrupted uploads: {err:#}"),
+        }
+
+        for _ in 0..workers.max(1) {
+            tokio::spawn(run_worker(Arc::clone(&state)));
+        }
+    });
+}
+
+async fn run_worker(state: Arc<AppState>) {
+    loop {
+        let claimed = queries::claim_upload_job(&state.db, Utc::now()).await;
+        match claimed {
+            Ok(Some(job)) => Box::pin(process(&state, job)).await,
+            Ok(None) => {
+                let woken =
+                    tokio::time::timeout(POLL_INTERVAL, state.upload_queue.wake.notified()).await;
+                drop(woken);
+            }
+            Err(err) => {
+                warn!("Failed to claim an upload job: {err:#}");
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn process(state: &AppState, job: UploadJob) {
+    info!("Processing upload {} (attempt {})", job.id, job.attempts);
+    let result = run(state, &job).await;
+    let (status, error) = match &result {
+        Ok(()) => (JobStatus::Completed, None),
+        Err(err) => {
+            if err.status().is_server_error() {
+                error!("Upload {} failed: {}", job.id, err.body());
+            }
+            (JobStatus::Failed, Some(err.body().to_string()))
+        }
+    };
+
+    let finished = queries::finish_upload_job(
+        &state.db,
+        &job.id,
+        status.as_str(),
+        error.as_deref(),
+        Utc::now(),
+    )
+    .await;
+    if let Err(err) = finished {
+        // Left as processing, so it is tried again after a restart.
+        warn!("Failed to record the outcome of upload {}: {err:#}", job.id);
+        return;
+    }
+
+    let removed = tokio::fs::remove_file(&job.zip_path).await;
+    if let Err(err) = removed {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove the ZIP of upload {}: {err}", job.id);
+        }
+    }
+}
+
+async fn run(state: &AppState, job: &UploadJob) -> Result<(), ApiError> {
+    let mut progress = JobProgress::resume(state.db.clone(), job)?;
+    if progress.already_indexed().await? {
+        return Ok(());
+    }
+
+    let result = if job.attempts > MAX_ATTEMPTS {
+        Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Processing was interrupted too many times",
+        ))
+    } else {
+        album::process(state, job, &mut progress).await
+    };
+
+    if result.is_err() {
+        // No track points to these, including ones stored by earlier attempts.
+        let paths = progress.take_unindexed();
+        if !paths.is_empty() {
+            for path in &paths {
+                store::remove_key(state, path).await;
+            }
+            let saved = progress.save().await;
+            if let Err(err) = saved {
+                warn!("Failed to save the progress of upload {}: {err:#}", job.id);
+            }
+        }
+    }
+
+    result
+}
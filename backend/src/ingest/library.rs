@@ -0,0 +1,1360 @@
+//! Keeping the catalog in step with a directory of audio files, such as a
+//! team's masters on a NAS.
+//!
+//! A scan walks `LIBRARY_DIR` and compares it with the `library_files` rows
+//! from the last one. Files whose size and modification time haven't changed
+//! are skipped, the rest are hashed:
+//!
+//! - a new hash at a new path is a new track, read from its tags and path the
+//!   way an upload is,
+//! - a new hash at a known path replaces its track's audio, keeping metadata
+//!   that was edited through the API,
+//! - a known hash at a new path is the same track moved if its old path is
+//!   gone, and another copy of it otherwise,
+//! - a known path that is gone deletes its track, unless a copy is left.
+//!
+//! Tracks go to existing artists, as uploads do. A dry run works all of this
+//! out, tags and artists included, without changing anything.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration as StdDuration,
+};
+
+use anyhow::bail;
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::ClientInfo,
+    db::{
+        models::{Album, LibraryFile, Track},
+        queries,
+    },
+    handlers::{upload::unreadable_audio, ApiError},
+    AppState,
+};
+
+use super::{
+    filename::ParsedFilename,
+    fingerprint,
+    format::{AudioFormat, SNIFF_LEN},
+    inotify::Inotify,
+    metadata::{self, AudioMetadata},
+    provenance::{FieldSources, Source},
+    store,
+    validation::{self, TrackCheck},
+    DEFAULT_ALBUM_TITLE,
+};
+
+/// What a scan changed, or would change on a dry run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanReport {
+    pub dry_run: bool,
+    pub added: Vec<ScannedFile>,
+    /// Files whose contents changed, their tracks got the new audio.
+    pub updated: Vec<ScannedFile>,
+    pub moved: Vec<ScannedFile>,
+    /// Copies of a file already in the library, recorded against its track.
+    pub linked: Vec<ScannedFile>,
+    pub removed: Vec<ScannedFile>,
+    pub unchanged: usize,
+    /// Modified too recently to be scanned, see `LIBRARY_SETTLE_SECS`.
+    pub pending: Vec<String>,
+    pub failed: Vec<FailedFile>,
+    /// Every directory scanned, for the watcher.
+    #[serde(skip)]
+    pub directories: Vec<PathBuf>,
+}
+
+impl ScanReport {
+    pub fn changed(&self) -> usize {
+        self.added.len()
+            + self.updated.len()
+            + self.moved.len()
+            + self.linked.len()
+            + self.removed.len()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedFile {
+    /// Relative to the library directory.
+    pub path: String,
+    /// Where a moved file used to be.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// `None` for tracks a dry run would create.
+    pub track_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedFile {
+    pub path: String,
+    pub error: String,
+}
+
+impl FailedFile {
+    fn new(path: impl Into<String>, err: &ApiError) -> Self {
+        Self {
+            path: path.into(),
+            error: err.body()["error"].as_str().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// A scan started through the API, and how it went.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanStatus {
+    pub status: ScanState,
+    pub dry_run: bool,
+    /// User id of the admin who started it.
+    pub started_by: String,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<ScanReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Held while a scan runs, so only one runs at a time, together with the
+/// last scan started through the API. Scans the watcher starts aren't kept,
+/// so they don't replace an admin's dry run.
+#[derive(Debug, Default)]
+pub struct LibraryScans {
+    running: Arc<tokio::sync::Mutex<()>>,
+    last: Mutex<Option<ScanStatus>>,
+}
+
+impl LibraryScans {
+    /// The last scan started through the API, running or not.
+    pub fn last(&self) -> Option<ScanStatus> {
+        self.last
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_last(&self, status: ScanStatus) {
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) = Some(status);
+    }
+}
+
+/// An audio file found under the library directory.
+#[derive(Debug)]
+struct DiskFile {
+    /// Relative to the library directory, with `/` separators.
+    path: String,
+    full_path: PathBuf,
+    size: i64,
+    modified_at: DateTime<Utc>,
+}
+
+impl DiskFile {
+    fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    fn row(
+        &self,
+        content_hash: &str,
+        track_id: Option<String>,
+        scanned_at: DateTime<Utc>,
+    ) -> LibraryFile {
+        LibraryFile {
+            path: self.path.clone(),
+            content_hash: content_hash.to_string(),
+            size: self.size,
+            modified_at: self.modified_at,
+            track_id,
+            scanned_at,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tree {
+    files: Vec<DiskFile>,
+    directories: Vec<PathBuf>,
+    /// Directories that couldn't be listed, relative like `files`. What was
+    /// in them last time is left alone rather than taken as deleted.
+    unreadable: Vec<String>,
+    failed: Vec<FailedFile>,
+}
+
+/// Starts scanning the library at `root` for the admin `actor` in the
+/// background, see [`scan`]. `None` while another scan runs.
+pub fn start_scan(
+    state: &Arc<AppState>,
+    root: PathBuf,
+    actor: &str,
+    client: ClientInfo,
+    dry_run: bool,
+) -> Option<ScanStatus> {
+    let running = Arc::clone(&state.library_scans.running)
+        .try_lock_owned()
+        .ok()?;
+    let status = ScanStatus {
+        status: ScanState::Running,
+        dry_run,
+        started_by: actor.to_string(),
+        started_at: Utc::now(),
+        finished_at: None,
+        report: None,
+        error: None,
+    };
+    state.library_scans.set_last(status.clone());
+
+    let state = Arc::clone(state);
+    let mut finished = status.clone();
+    tokio::spawn(async move {
+        let scanned = scan(
+            &state,
+            &root,
+            Some(finished.started_by.as_str()),
+            &client,
+            dry_run,
+        )
+        .await;
+        finished.finished_at = Some(Utc::now());
+        match scanned {
+            Ok(report) => {
+                info!(
+                    "Library scan{}: {} changes",
+                    if dry_run { " (dry run)" } else { "" },
+                    report.changed()
+                );
+                finished.status = ScanState::Completed;
+                finished.report = Some(report);
+            }
+            Err(err) => {
+                warn!("Library scan failed: {err:#}");
+                finished.status = ScanState::Failed;
+                finished.error = Some(format!("{err:#}"));
+            }
+        }
+        // Before letting the next scan start, so it isn't overwritten.
+        state.library_scans.set_last(finished);
+        drop(running);
+    });
+
+    Some(status)
+}
+
+/// Scans the library at `root`, see the module docs. `actor` is whoever
+/// asked for the scan, recorded on the tracks' audit entries.
+///
+/// Callers hold the lock in `AppState::library_scans`, so one scan runs at a
+/// time.
+pub async fn scan(
+    state: &AppState,
+    root: &Path,
+    actor: Option<&str>,
+    client: &ClientInfo,
+    dry_run: bool,
+) -> anyhow::Result<ScanReport> {
+    let walked_root = root.to_path_buf();
+    let tree = tokio::task::spawn_blocking(move || walk(&walked_root)).await??;
+    let known: HashMap<String, LibraryFile> = queries::get_library_files(&state.db)
+        .await?
+        .into_iter()
+        .map(|file| (file.path.clone(), file))
+        .collect();
+    // An unmounted share looks like an empty directory.
+    if tree.files.is_empty() && !known.is_empty() {
+        bail!(
+            "Found no audio files under {}, is it mounted?",
+            root.display()
+        );
+    }
+
+    let now = Utc::now();
+    let settle =
+        Duration::seconds(i64::try_from(state.config.library.settle_secs).unwrap_or(i64::MAX));
+    let mut scanner = Scanner {
+        state,
+        actor,
+        client,
+        dry_run,
+        now,
+        report: ScanReport {
+            dry_run,
+            failed: tree.failed,
+            directories: tree.directories,
+            ..ScanReport::default()
+        },
+        vanished: HashMap::new(),
+        tracks_by_hash: HashMap::new(),
+        kept: HashSet::new(),
+    };
+
+    let on_disk: HashSet<&str> = tree.files.iter().map(|file| file.path.as_str()).collect();
+    for file in known.values() {
+        let unreadable = tree
+            .unreadable
+            .iter()
+            .any(|dir| file.path.starts_with(&format!("{dir}/")));
+        if on_disk.contains(file.path.as_str()) || unreadable {
+            scanner.keep(&file.content_hash, file.track_id.as_ref());
+        } else {
+            scanner
+                .vanished
+                .entry(file.content_hash.clone())
+                .or_default()
+                .push(file.clone());
+        }
+    }
+
+    for file in &tree.files {
+        let previous = known.get(&file.path);
+        if now - file.modified_at < settle {
+            scanner.report.pending.push(file.path.clone());
+            continue;
+        }
+        if previous.is_some_and(|previous| {
+            previous.size == file.size && previous.modified_at == file.modified_at
+        }) {
+            scanner.report.unchanged += 1;
+            continue;
+        }
+
+        let full_path = file.full_path.clone();
+        let hashed =
+            tokio::task::spawn_blocking(move || fingerprint::content_hash(&full_path)).await?;
+        let result = match hashed {
+            Ok(hash) => scanner.scan_file(file, &hash, previous).await,
+            Err(err) => Err(ApiError::from(err)),
+        };
+        if let Err(err) = result {
+            scanner
+                .report
+                .failed
+                .push(FailedFile::new(&file.path, &err));
+        }
+    }
+
+    for file in std::mem::take(&mut scanner.vanished)
+        .into_values()
+        .flatten()
+    {
+        let result = scanner.remove(&file).await;
+        if let Err(err) = result {
+            scanner
+                .report
+                .failed
+                .push(FailedFile::new(&file.path, &err));
+        }
+    }
+
+    Ok(scanner.report)
+}
+
+struct Scanner<'a> {
+    state: &'a AppState,
+    actor: Option<&'a str>,
+    client: &'a ClientInfo,
+    dry_run: bool,
+    now: DateTime<Utc>,
+    report: ScanReport,
+    /// Files from the last scan that are gone, by hash, until they turn out
+    /// to have moved.
+    vanished: HashMap<String, Vec<LibraryFile>>,
+    /// Track of each hash still in the library.
+    tracks_by_hash: HashMap<String, Option<String>>,
+    /// Tracks with a file still in the library.
+    kept: HashSet<String>,
+}
+
+impl Scanner<'_> {
+    fn keep(&mut self, hash: &str, track_id: Option<&String>) {
+        if let Some(track_id) = track_id {
+            self.kept.insert(track_id.clone());
+        }
+        let tracked = self.tracks_by_hash.entry(hash.to_string()).or_default();
+        if tracked.is_none() {
+            tracked.clone_from(&track_id.cloned());
+        }
+    }
+
+    async fn scan_file(
+        &mut self,
+        file: &DiskFile,
+        hash: &str,
+        previous: Option<&LibraryFile>,
+    ) -> Result<(), ApiError> {
+        if let Some(previous) = previous {
+            if previous.content_hash == hash {
+                // Touched, not changed.
+                self.save(&file.row(hash, previous.track_id.clone(), self.now))
+                    .await?;
+                self.report.unchanged += 1;
+                return Ok(());
+            }
+            // Tracks deleted through the API come back once their file changes.
+            let track = match &previous.track_id {
+                Some(track_id) => queries::get_track_by_id(&self.state.db, track_id).await?,
+                None => None,
+            };
+            if let Some(track) = track {
+                return self.update(file, hash, track).await;
+            }
+            return self.add(file, hash).await;
+        }
+
+        if let Some(from) = self.vanished.get_mut(hash).and_then(Vec::pop) {
+            let moved = file.row(hash, from.track_id.clone(), self.now);
+            if !self.dry_run {
+                queries::move_library_file(&self.state.db, &from.path, &moved).await?;
+            }
+            self.keep(hash, from.track_id.as_ref());
+            self.report.moved.push(ScannedFile {
+                path: file.path.clone(),
+                from: Some(from.path),
+                track_id: from.track_id,
+            });
+            return Ok(());
+        }
+
+        if let Some(track_id) = self.tracks_by_hash.get(hash).cloned() {
+            self.save(&file.row(hash, track_id.clone(), self.now))
+                .await?;
+            self.report.linked.push(ScannedFile {
+                path: file.path.clone(),
+                from: None,
+                track_id,
+            });
+            return Ok(());
+        }
+
+        self.add(file, hash).await
+    }
+
+    /// Creates a track for a file that's new to the library.
+    async fn add(&mut self, file: &DiskFile, hash: &str) -> Result<(), ApiError> {
+        let (format, embedded) = read_audio(file).await?;
+        let from_name = self.state.filename_parser.parse(&file.path);
+        let artist_name = embedded
+            .artist
+            .as_deref()
+            .or(from_name.artist.as_deref())
+            .ok_or_else(|| ApiError::bad_request("No artist in the file's tags or path"))?;
+        let artist = store::resolve_artist(self.state, None, Some(artist_name)).await?;
+        if self.dry_run {
+            self.keep(hash, None);
+            self.report.added.push(ScannedFile {
+                path: file.path.clone(),
+                from: None,
+                track_id: None,
+            });
+            return Ok(());
+        }
+
+        let album_title = embedded
+            .album
+            .as_deref()
+            .or(from_name.album.as_deref())
+            .unwrap_or(DEFAULT_ALBUM_TITLE);
+        let mut album = Album::new(&artist.id, album_title, self.actor.unwrap_or_default());
+        // Scans the watcher starts have no one behind them.
+        album.created_by = self.actor.map(str::to_string);
+        let mut album_sources = FieldSources::default();
+        album_sources.set_first(
+            "title",
+            &[
+                (embedded.album.is_some(), Source::Tags),
+                (from_name.album.is_some(), Source::Filename),
+                (true, Source::Default),
+            ],
+        );
+        album_sources.set_first(
+            "artist",
+            &[
+                (embedded.artist.is_some(), Source::Tags),
+                (true, Source::Filename),
+            ],
+        );
+        album.metadata_sources = Some(album_sources.to_json());
+        let album = queries::get_or_create_album(&self.state.db, album).await?;
+
+        let sources = track_sources(&embedded, &from_name, &album);
+        let track_id = Uuid::new_v4().to_string();
+        let key = store::track_key(&artist.id, &album.id, &track_id, format);
+        store::store(self.state, &key, &file.full_path, format.content_type()).await?;
+
+        let track = Track {
+            id: track_id,
+            title: embedded
+                .title
+                .or(from_name.title)
+                .unwrap_or_else(|| "Untitled".to_string()),
+            artist: artist.name,
+            artist_id: Some(artist.id),
+            album: album.title,
+            album_id: Some(album.id),
+            duration: embedded.duration.unwrap_or(0),
+            file_path: key,
+            original_filename: Some(file.file_name().to_string()),
+            original_format: Some(format.extension().to_string()),
+            stored_format: Some(format.extension().to_string()),
+            cover_art_path: album.cover_art_path,
+            genre: embedded.genre.or(album.genre),
+            year: embedded.year.or(from_name.year).or(album.year),
+            track_number: embedded.track_number.or(from_name.track_number),
+            created_at: self.now,
+            updated_at: self.now,
+        };
+        if let Err(err) =
+            queries::create_track(&self.state.db, track.clone(), &sources.to_json()).await
+        {
+            store::remove_key(self.state, &track.file_path).await;
+            return Err(err.into());
+        }
+        fingerprint::record(self.state, &track.file_path, &file.full_path, hash).await;
+        self.save(&file.row(hash, Some(track.id.clone()), self.now))
+            .await?;
+        self.keep(hash, Some(&track.id));
+
+        self.record(
+            AuditEvent::new(AuditAction::TrackCreated)
+                .target("track", &track.id)
+                .diff(serde_json::json!({ "after": track, "library_path": file.path })),
+        )
+        .await;
+        self.report.added.push(ScannedFile {
+            path: file.path.clone(),
+            from: None,
+            track_id: Some(track.id),
+        });
+
+        Ok(())
+    }
+
+    /// Gives `before` the new contents of its file, and the metadata in them
+    /// for fields that weren't edited through the API.
+    async fn update(&mut self, file: &DiskFile, hash: &str, before: Track) -> Result<(), ApiError> {
+        let (format, embedded) = read_audio(file).await?;
+        let from_name = self.state.filename_parser.parse(&file.path);
+        let (Some(artist_id), Some(album_id)) =
+            (before.artist_id.as_deref(), before.album_id.as_deref())
+        else {
+            return Err(ApiError::bad_request(
+                "Only tracks on an album can have their audio replaced",
+            ));
+        };
+        if self.dry_run {
+            self.keep(hash, Some(&before.id));
+            self.report.updated.push(ScannedFile {
+                path: file.path.clone(),
+                from: None,
+                track_id: Some(before.id),
+            });
+            return Ok(());
+        }
+
+        // A new key, so earlier versions of the album keep their audio.
+        let key = store::track_key(artist_id, album_id, &Uuid::new_v4().to_string(), format);
+        store::store(self.state, &key, &file.full_path, format.content_type()).await?;
+
+        let mut track = before.clone();
+        let mut sources = FieldSources::parse(
+            queries::get_track_sources(&self.state.db, &track.id)
+                .await?
+                .as_deref(),
+        );
+        refresh(
+            &mut sources,
+            "title",
+            &mut track.title,
+            [
+                (embedded.title, Source::Tags),
+                (from_name.title, Source::Filename),
+            ],
+        );
+        refresh(
+            &mut sources,
+            "track_number",
+            &mut track.track_number,
+            [
+                (embedded.track_number.map(Some), Source::Tags),
+                (from_name.track_number.map(Some), Source::Filename),
+            ],
+        );
+        refresh(
+            &mut sources,
+            "genre",
+            &mut track.genre,
+            [(embedded.genre.map(Some), Source::Tags)],
+        );
+        refresh(
+            &mut sources,
+            "year",
+            &mut track.year,
+            [
+                (embedded.year.map(Some), Source::Tags),
+                (from_name.year.map(Some), Source::Filename),
+            ],
+        );
+        if let Some(duration) = embedded.duration {
+            track.duration = duration;
+            sources.set("duration", Source::Measured);
+        }
+        track.file_path.clone_from(&key);
+        track.original_filename = Some(file.file_name().to_string());
+        track.original_format = Some(format.extension().to_string());
+        track.stored_format = Some(format.extension().to_string());
+        track.updated_at = self.now;
+
+        if let Err(err) =
+            queries::update_track_file(&self.state.db, &track, &sources.to_json()).await
+        {
+            store::remove_key(self.state, &key).await;
+            return Err(err.into());
+        }
+        fingerprint::record(self.state, &key, &file.full_path, hash).await;
+        self.save(&file.row(hash, Some(track.id.clone()), self.now))
+            .await?;
+        self.keep(hash, Some(&track.id));
+
+        self.record(AuditEvent::new(AuditAction::TrackUpdated)
+            .target("track", &track.id)
+            .diff(serde_json::json!({ "before": before, "after": track, "library_path": file.path })))
+        .await;
+        self.report.updated.push(ScannedFile {
+            path: file.path.clone(),
+            from: None,
+            track_id: Some(track.id),
+        });
+
+        Ok(())
+    }
+
+    /// Forgets a file that's gone, and its track unless a copy is left.
+    async fn remove(&mut self, file: &LibraryFile) -> Result<(), ApiError> {
+        let orphaned = file
+            .track_id
+            .as_ref()
+            .filter(|track_id| !self.kept.contains(*track_id));
+        if !self.dry_run {
+            queries::delete_library_file(&self.state.db, &file.path).await?;
+            if let Some(track_id) = orphaned {
+                let before = queries::get_track_by_id(&self.state.db, track_id).await?;
+                // Another file of the same track may already have deleted it.
+                if let Some(before) = before {
+                    if queries::delete_track(&self.state.db, track_id).await? {
+                        self.record(AuditEvent::new(AuditAction::TrackDeleted)
+                            .target("track", track_id)
+                            .diff(serde_json::json!({ "before": before, "library_path": file.path })))
+                        .await;
+                    }
+                }
+            }
+        }
+
+        self.report.removed.push(ScannedFile {
+            path: file.path.clone(),
+            from: None,
+            track_id: file.track_id.clone(),
+        });
+
+        Ok(())
+    }
+
+    async fn save(&self, file: &LibraryFile) -> Result<(), ApiError> {
+        if !self.dry_run {
+            queries::save_library_file(&self.state.db, file).await?;
+        }
+        Ok(())
+    }
+
+    async fn record(&self, event: AuditEvent) {
+        let event = match self.actor {
+            Some(actor) => event.actor(actor),
+            None => event,
+        };
+        audit::record(&self.state.db, event, self.client).await;
+    }
+}
+
+/// Sets `value` from the first of `candidates` there is, unless `field` was
+/// edited through the API.
+fn refresh<T, const N: usize>(
+    sources: &mut FieldSources,
+    field: &str,
+    value: &mut T,
+    candidates: [(Option<T>, Source); N],
+) {
+    if sources.get(field) == Some(Source::Edited) {
+        return;
+    }
+    if let Some((found, source)) = candidates
+        .into_iter()
+        .find_map(|(found, source)| Some((found?, source)))
+    {
+        *value = found;
+        sources.set(field, source);
+    }
+}
+
+fn track_sources(
+    embedded: &AudioMetadata,
+    from_name: &ParsedFilename,
+    album: &Album,
+) -> FieldSources {
+    let mut sources = FieldSources::default();
+    sources.set_first(
+        "title",
+        &[
+            (embedded.title.is_some(), Source::Tags),
+            (from_name.title.is_some(), Source::Filename),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "track_number",
+        &[
+            (embedded.track_number.is_some(), Source::Tags),
+            (from_name.track_number.is_some(), Source::Filename),
+        ],
+    );
+    sources.set_first(
+        "duration",
+        &[
+            (embedded.duration.is_some(), Source::Measured),
+            (true, Source::Default),
+        ],
+    );
+    sources.set_first(
+        "genre",
+        &[
+            (embedded.genre.is_some(), Source::Tags),
+            (album.genre.is_some(), Source::Album),
+        ],
+    );
+    sources.set_first(
+        "year",
+        &[
+            (embedded.year.is_some(), Source::Tags),
+            (from_name.year.is_some(), Source::Filename),
+            (album.year.is_some(), Source::Album),
+        ],
+    );
+
+    sources
+}
+
+/// Detects the file's format from its contents and reads its tags, checking
+/// them the way an upload's are.
+async fn read_audio(file: &DiskFile) -> Result<(AudioFormat, AudioMetadata), ApiError> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    tokio::fs::File::open(&file.full_path)
+        .await?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)
+        .await?;
+    let format = AudioFormat::sniff(&header).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported audio format, expected FLAC, MP3, OGG, M4A, WAV or AIFF",
+        )
+    })?;
+    let embedded = metadata::extract(&file.full_path, Some(format))
+        .await
+        .map_err(|err| unreadable_audio(&err))?;
+    let check = TrackCheck {
+        file_name: file.file_name(),
+        format,
+        duration: None,
+        measured_duration: embedded.duration,
+        track_number: None,
+    };
+    validation::check(&[check], &[])?;
+
+    Ok((format, embedded))
+}
+
+/// Lists the audio files under `root`, skipping hidden entries. Symlinked
+/// files are followed, symlinked directories are not, so nothing is visited
+/// twice.
+fn walk(root: &Path) -> io::Result<Tree> {
+    let mut tree = Tree::default();
+    let mut directories = vec![(root.to_path_buf(), fs::read_dir(root)?)];
+    loop {
+        let Some((dir, entries)) = directories.pop() else {
+            break;
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    tree.unreadable.push(relative(root, &dir));
+                    warn!("Failed to list {}: {err}", dir.display());
+                    break;
+                }
+            };
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    tree.failed
+                        .push(FailedFile::new(relative(root, &path), &err.into()));
+                    continue;
+                }
+            };
+            if file_type.is_dir() {
+                match fs::read_dir(&path) {
+                    Ok(entries) => directories.push((path, entries)),
+                    Err(err) => {
+                        warn!("Failed to list {}: {err}", path.display());
+                        tree.unreadable.push(relative(root, &path));
+                    }
+                }
+                continue;
+            }
+
+            let is_audio = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(AudioFormat::from_extension)
+                .is_some();
+            if !is_audio {
+                continue;
+            }
+            let relative_path = relative(root, &path);
+            let file = disk_file(&relative_path, path);
+            match file {
+                Ok(file) => tree.files.push(file),
+                Err(err) => tree.failed.push(FailedFile::new(relative_path, &err)),
+            }
+        }
+        tree.directories.push(dir);
+    }
+    tree.files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(tree)
+}
+
+fn disk_file(relative_path: &str, full_path: PathBuf) -> Result<DiskFile, ApiError> {
+    if relative_path.contains(char::REPLACEMENT_CHARACTER) {
+        return Err(ApiError::bad_request("File name is not valid UTF-8"));
+    }
+    let metadata = fs::metadata(&full_path)?;
+
+    Ok(DiskFile {
+        path: relative_path.to_string(),
+        full_path,
+        size: i64::try_from(metadata.len()).unwrap_or(i64::MAX),
+        modified_at: metadata.modified()?.into(),
+    })
+}
+
+/// `path` relative to `root`, with `/` separators.
+fn relative(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Scans the library at startup, then whenever inotify reports a change or
+/// `LIBRARY_SCAN_INTERVAL_SECS` passes, as configured.
+pub fn spawn_watcher(state: &Arc<AppState>) {
+    let config = &state.config.library;
+    let Some(root) = config.root.clone() else {
+        return;
+    };
+    if !config.watch && config.scan_interval_secs == 0 {
+        return;
+    }
+
+    let state = Arc::clone(state);
+    tokio::spawn(async move { watch(&state, Path::new(&root)).await });
+}
+
+async fn watch(state: &AppState, root: &Path) {
+    let config = &state.config.library;
+    let settle = StdDuration::from_secs(config.settle_secs.max(1));
+    let interval = StdDuration::from_secs(config.scan_interval_secs);
+    let (inotify, mut changes) = (if config.watch { watch_changes() } else { None }).unzip();
+
+    loop {
+        let report = scan_in_background(state, root).await;
+        if let (Some(report), Some(inotify)) = (&report, &inotify) {
+            for dir in &report.directories {
+                if let Err(err) = inotify.watch(dir) {
+                    warn!("Failed to watch {}: {err}", dir.display());
+                }
+            }
+        }
+        let retry = report.is_some_and(|report| !report.pending.is_empty());
+
+        tokio::select! {
+            () = tokio::time::sleep(interval), if !interval.is_zero() => {}
+            () = tokio::time::sleep(settle), if retry => {}
+            Some(()) = recv(changes.as_mut()), if changes.is_some() => {
+                // Wait for a burst of changes, such as an album being copied
+                // in, to finish.
+                loop {
+                    let next = tokio::time::timeout(settle, recv(changes.as_mut())).await;
+                    if !matches!(next, Ok(Some(()))) {
+                        break;
+                    }
+                }
+            }
+            else => {
+                warn!("Stopped watching the library, nothing left to wait for");
+                return;
+            }
+        }
+    }
+}
+
+async fn recv(changes: Option<&mut mpsc::Receiver<()>>) -> Option<()> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => None,
+    }
+}
+
+async fn scan_in_background(state: &AppState, root: &Path) -> Option<ScanReport> {
+    let _running = state.library_scans.running.lock().await;
+    let scanned = scan(state, root, None, &ClientInfo::default(), false).await;
+    match scanned {
+        Ok(report) => {
+            if report.changed() > 0 || !report.failed.is_empty() {
+                info!(
+                    "Library scan: {} added, {} updated, {} moved, {} linked, {} removed, {} failed",
+                    report.added.len(),
+                    report.updated.len(),
+                    report.moved.len(),
+                    report.linked.len(),
+                    report.removed.len(),
+                    report.failed.len()
+                );
+            }
+            Some(report)
+        }
+        Err(err) => {
+            warn!("Library scan failed: {err:#}");
+            None
+        }
+    }
+}
+
+/// Starts reading inotify events on a thread of its own, returning it to add
+/// watches to and a channel that says when something changed.
+fn watch_changes() -> Option<(Arc<Inotify>, mpsc::Receiver<()>)> {
+    let inotify = match Inotify::new() {
+        Ok(inotify) => Arc::new(inotify),
+        Err(err) => {
+            warn!("Can't watch the library for changes: {err}");
+            return None;
+        }
+    };
+
+    // One pending change is enough to rescan.
+    let (sender, receiver) = mpsc::channel(1);
+    let reader = Arc::clone(&inotify);
+    std::thread::spawn(move || loop {
+        if let Err(err) = reader.wait() {
+            warn!("Stopped watching the library for changes: {err}");
+            return;
+        }
+        // A full channel already has a rescan coming.
+        if matches!(
+            sender.try_send(()),
+            Err(mpsc::error::TrySendError::Closed(()))
+        ) {
+            return;
+        }
+    });
+
+    Some((inotify, receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{
+        db::models::{Artist, CreateUser},
+        storage::mock::MockBucket,
+    };
+
+    /// A library directory of its own, removed on drop.
+    struct Library(PathBuf);
+
+    impl Library {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("navicore-library-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).expect("library directory");
+            Self(dir)
+        }
+
+        /// Writes a second of audio whose samples depend on `tone`, dated
+        /// `age_secs` ago so the scan doesn't wait for it to settle.
+        fn write(&self, path: &str, tone: u8, age_secs: u64) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().expect("folder")).expect("folder");
+            fs::write(&path, wav(tone)).expect("audio file");
+            date(&path, age_secs);
+        }
+
+        fn rename(&self, from: &str, to: &str) {
+            let to = self.0.join(to);
+            fs::create_dir_all(to.parent().expect("folder")).expect("folder");
+            fs::rename(self.0.join(from), to).expect("moved");
+        }
+
+        /// Copies a file, dated like the original was.
+        fn copy(&self, from: &str, to: &str, age_secs: u64) {
+            fs::copy(self.0.join(from), self.0.join(to)).expect("copied");
+            date(&self.0.join(to), age_secs);
+        }
+
+        fn remove(&self, path: &str) {
+            fs::remove_file(self.0.join(path)).expect("removed");
+        }
+    }
+
+    impl Drop for Library {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn date(path: &Path, age_secs: u64) {
+        let modified = SystemTime::now() - StdDuration::from_secs(age_secs);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(modified))
+            .expect("modification time");
+    }
+
+    /// One second of 8 kHz, 8-bit mono PCM.
+    fn wav(tone: u8) -> Vec<u8> {
+        let samples: Vec<u8> = (0..8000u32)
+            .map(|i| u8::try_from(i * u32::from(tone) % 256).unwrap_or_default())
+            .collect();
+        let data_len = u32::try_from(samples.len()).expect("length");
+        let mut wav = Vec::with_capacity(44 + samples.len());
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&8000u32.to_le_bytes()); // bytes per second
+        wav.extend_from_slice(&1u16.to_le_bytes()); // block align
+        wav.extend_from_slice(&8u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.extend_from_slice(&samples);
+        wav
+    }
+
+    /// State over a fresh database and bucket, with the artist `Band`.
+    async fn setup() -> (AppState, MockBucket, Library) {
+        let bucket = MockBucket::default();
+        let mut state = AppState::for_tests(crate::db::test_pool().await, bucket.serve().await);
+        state.config.library.settle_secs = 60;
+
+        let user = queries::create_user(
+            &state.db,
+            CreateUser {
+                username: "band".to_string(),
+                email: "band@example.com".to_string(),
+                password_hash: "not-a-real-hash".to_string(),
+            }
+            .into_user(),
+            false,
+        )
+        .await
+        .expect("user");
+        let now = Utc::now();
+        let artist = Artist {
+            id: Uuid::new_v4().to_string(),
+            name: "Band".to_string(),
+            normalized_name: "band".to_string(),
+            bio: None,
+            website: None,
+            created_by: Some(user.id.clone()),
+            created_at: now,
+            updated_at: now,
+        };
+        queries::create_artist(&state.db, artist, &user.id)
+            .await
+            .expect("artist");
+
+        (state, bucket, Library::new())
+    }
+
+    async fn scan(state: &AppState, library: &Library, dry_run: bool) -> ScanReport {
+        let report = super::scan(state, &library.0, None, &ClientInfo::default(), dry_run)
+            .await
+            .expect("scanned");
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        report
+    }
+
+    fn paths(files: &[ScannedFile]) -> Vec<&str> {
+        files.iter().map(|file| file.path.as_str()).collect()
+    }
+
+    async fn library_paths(state: &AppState) -> Vec<String> {
+        queries::get_library_files(&state.db)
+            .await
+            .expect("library files")
+            .into_iter()
+            .map(|file| file.path)
+            .collect()
+    }
+
+    async fn track_count(state: &AppState) -> usize {
+        queries::get_all_tracks(&state.db)
+            .await
+            .expect("tracks")
+            .len()
+    }
+
+    const ONE: &str = "Band - Album/01 - One.wav";
+    const TWO: &str = "Band - Album/02 - Two.wav";
+
+    #[tokio::test]
+    async fn adds_new_files_as_tracks() {
+        let (state, bucket, library) = setup().await;
+        library.write(ONE, 1, 600);
+        library.write(TWO, 2, 600);
+        library.write("Band - Album/notes.txt", 3, 600);
+        // Still being copied.
+        library.write("Band - Album/03 - Three.wav", 3, 0);
+
+        let report = scan(&state, &library, false).await;
+        assert_eq!(paths(&report.added), [ONE, TWO]);
+        assert_eq!(report.pending, ["Band - Album/03 - Three.wav"]);
+
+        let track_id = report.added[0].track_id.as_deref().expect("track");
+        let track = queries::get_track_by_id(&state.db, track_id)
+            .await
+            .expect("lookup")
+            .expect("track");
+        assert_eq!(
+            (
+                track.title.as_str(),
+                track.album.as_str(),
+                track.track_number,
+                track.duration
+            ),
+            ("One", "Album", Some(1), 1)
+        );
+        assert_eq!(bucket.keys().len(), 2);
+        assert!(bucket.keys().contains(&track.file_path));
+
+        // Nothing changed, nothing to do.
+        let report = scan(&state, &library, false).await;
+        assert_eq!(report.changed(), 0);
+        assert_eq!(report.unchanged, 2);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_write_nothing() {
+        let (state, bucket, library) = setup().await;
+        library.write(ONE, 1, 600);
+        library.write(TWO, 2, 600);
+
+        let report = scan(&state, &library, true).await;
+        assert!(report.dry_run);
+        assert_eq!(paths(&report.added), [ONE, TWO]);
+        assert!(report.added.iter().all(|file| file.track_id.is_none()));
+        assert!(library_paths(&state).await.is_empty());
+        assert_eq!(track_count(&state).await, 0);
+        assert!(bucket.keys().is_empty());
+
+        scan(&state, &library, false).await;
+        let stored = bucket.keys();
+        library.rename(ONE, "Band - Album/Moved/01 - One.wav");
+        library.copy(TWO, "Band - Album/02 - Two (copy).wav", 600);
+        library.write(TWO, 4, 300);
+
+        let report = scan(&state, &library, true).await;
+        assert_eq!(paths(&report.moved), ["Band - Album/Moved/01 - One.wav"]);
+        assert_eq!(paths(&report.updated), [TWO]);
+        assert_eq!(paths(&report.linked), ["Band - Album/02 - Two (copy).wav"]);
+        assert_eq!(library_paths(&state).await, [ONE, TWO]);
+        assert_eq!(track_count(&state).await, 2);
+        assert_eq!(bucket.keys(), stored);
+
+        library.remove("Band - Album/Moved/01 - One.wav");
+        let report = scan(&state, &library, true).await;
+        assert_eq!(paths(&report.removed), [ONE]);
+        assert_eq!(library_paths(&state).await, [ONE, TWO]);
+        assert_eq!(track_count(&state).await, 2);
+    }
+
+    #[tokio::test]
+    async fn follows_moves_copies_and_deletions() {
+        let (state, _bucket, library) = setup().await;
+        library.write(ONE, 1, 600);
+        library.write(TWO, 2, 600);
+        let report = scan(&state, &library, false).await;
+        let one = report.added[0].track_id.clone().expect("track");
+        let two = report.added[1].track_id.clone().expect("track");
+
+        // A moved file keeps its track.
+        let moved = "Band - Album/Moved/01 - One.wav";
+        library.rename(ONE, moved);
+        let report = scan(&state, &library, false).await;
+        assert_eq!(paths(&report.moved), [moved]);
+        assert_eq!(report.moved[0].from.as_deref(), Some(ONE));
+        assert_eq!(report.moved[0].track_id.as_deref(), Some(one.as_str()));
+        assert_eq!(report.changed(), 1);
+
+        // A copy is linked to the same track, which stays while a copy is left.
+        let copy = "Band - Album/02 - Two (copy).wav";
+        library.copy(TWO, copy, 600);
+        let report = scan(&state, &library, false).await;
+        assert_eq!(paths(&report.linked), [copy]);
+        assert_eq!(report.linked[0].track_id.as_deref(), Some(two.as_str()));
+        assert_eq!(track_count(&state).await, 2);
+
+        library.remove(TWO);
+        let report = scan(&state, &library, false).await;
+        assert_eq!(paths(&report.removed), [TWO]);
+        assert!(queries::get_track_by_id(&state.db, &two)
+            .await
+            .expect("lookup")
+            .is_some());
+
+        library.remove(copy);
+        let report = scan(&state, &library, false).await;
+        assert_eq!(paths(&report.removed), [copy]);
+        assert!(queries::get_track_by_id(&state.db, &two)
+            .await
+            .expect("lookup")
+            .is_none());
+        assert_eq!(library_paths(&state).await, [moved]);
+    }
+
+    #[tokio::test]
+    async fn changed_files_replace_their_tracks_audio() {
+        let (state, bucket, library) = setup().await;
+        library.write(ONE, 1, 600);
+        let report = scan(&state, &library, false).await;
+        let track_id = report.added[0].track_id.clone().expect("track");
+        let before = queries::get_track_by_id(&state.db, &track_id)
+            .await
+            .expect("lookup")
+            .expect("track");
+
+        // Touched without a change is left alone.
+        library.write(ONE, 1, 500);
+        let report = scan(&state, &library, false).await;
+        assert_eq!((report.changed(), report.unchanged), (0, 1));
+
+        library.write(ONE, 5, 400);
+        let report = scan(&state, &library, false).await;
+        assert_eq!(paths(&report.updated), [ONE]);
+        assert_eq!(
+            report.updated[0].track_id.as_deref(),
+            Some(track_id.as_str())
+        );
+        let after = queries::get_track_by_id(&state.db, &track_id)
+            .await
+            .expect("lookup")
+            .expect("track");
+        assert_ne!(after.file_path, before.file_path);
+        // The earlier version keeps its audio.
+        assert_eq!(bucket.keys().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_empty_the_catalog_when_the_library_is_gone() {
+        let (state, _bucket, library) = setup().await;
+        library.write(ONE, 1, 600);
+        scan(&state, &library, false).await;
+
+        library.remove(ONE);
+        let scanned = super::scan(&state, &library.0, None, &ClientInfo::default(), false).await;
+        assert!(scanned.is_err());
+        assert_eq!(track_count(&state).await, 1);
+    }
+
+    /// The last scan started through the API, once it has finished.
+    async fn finished_scan(state: &AppState) -> ScanStatus {
+        for _ in 0..500 {
+            match state.library_scans.last() {
+                Some(status) if status.status != ScanState::Running => return status,
+                _ => tokio::time::sleep(StdDuration::from_millis(10)).await,
+            }
+        }
+        panic!("the scan didn't finish");
+    }
+
+    #[tokio::test]
+    async fn api_scans_run_in_the_background_one_at_a_time() {
+        let (state, _bucket, library) = setup().await;
+        let state = Arc::new(state);
+        library.write(ONE, 1, 600);
+        assert!(state.library_scans.last().is_none());
+
+        let running = state.library_scans.running.lock().await;
+        let refused = start_scan(
+            &state,
+            library.0.clone(),
+            "admin",
+            ClientInfo::default(),
+            true,
+        );
+        assert!(refused.is_none());
+        drop(running);
+
+        let started = start_scan(
+            &state,
+            library.0.clone(),
+            "admin",
+            ClientInfo::default(),
+            true,
+        )
+        .expect("started");
+        assert_eq!(
+            (started.status, started.dry_run),
+            (ScanState::Running, true)
+        );
+        let finished = finished_scan(&state).await;
+        assert_eq!(finished.started_by, "admin");
+        assert!(finished.finished_at.is_some());
+        assert_eq!(paths(&finished.report.expect("report").added), [ONE]);
+        assert_eq!(track_count(&state).await, 0);
+
+        let missing = library.0.join("missing");
+        start_scan(&state, missing, "admin", ClientInfo::default(), false).expect("started");
+        let finished = finished_scan(&state).await;
+        assert_eq!(finished.status, ScanState::Failed);
+        assert!(finished.report.is_none() && finished.error.is_some());
+    }
+}
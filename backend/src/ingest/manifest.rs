@@ -0,0 +1,139 @@
+//! `album.yaml` / `album.json` manifests shipped inside album ZIPs.
+//!
+//! See UPLOAD-SPEC.md for the format. Only `file` is required per track;
+//! anything the manifest leaves out falls back to the file's tags.
+
+use serde::{Deserialize, Serialize};
+
+/// File names recognised as a manifest, in the order they are looked for.
+pub const MANIFEST_NAMES: &[&str] = &["album.yaml", "album.yml", "album.json"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlbumManifest {
+    #[serde(default)]
+    pub album: ManifestAlbum,
+    #[serde(default)]
+    pub tracks: Vec<ManifestTrack>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestAlbum {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    /// Cover image inside the ZIP, named like a track's `file`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover: Option<String>,
+    /// Label for the version this upload makes, e.g. `demo-v3`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// `draft` or `released`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_status: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestTrack {
+    /// Audio file inside the ZIP this entry describes: its path, or as much
+    /// of the end of it as tells it apart, e.g. `CD2/01.flac` or `01.flac`.
+    pub file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<i32>,
+    /// Seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<i32>,
+}
+
+impl AlbumManifest {
+    /// Parses a manifest, as JSON or YAML depending on `file_name`.
+    pub fn parse(file_name: &str, contents: &[u8]) -> anyhow::Result<Self> {
+        let manifest = if file_name.to_ascii_lowercase().ends_with(".json") {
+            serde_json::from_slice(contents)?
+        } else {
+            serde_yaml::from_slice(contents)?
+        };
+
+        Ok(manifest)
+    }
+
+    /// The entry for the file at `path` in the ZIP, see [`names_file`]. The
+    /// entry giving the most of the path wins, so `CD1/01.flac` beats
+    /// `01.flac`.
+    pub fn track(&self, path: &str) -> Option<&ManifestTrack> {
+        self.tracks
+            .iter()
+            .filter(|track| names_file(&track.file, path))
+            .max_by_key(|track| track.file.len())
+    }
+}
+
+/// Whether a manifest's `file` names the file at `path` in the ZIP: the
+/// whole path first, else its last folders and name, down to just the name.
+/// `Album/CD1/01.flac` is named by itself, `CD1/01.flac` and `01.flac`, all
+/// ignoring case.
+pub fn names_file(file: &str, path: &str) -> bool {
+    let file = file.replace('\\', "/").to_lowercase();
+    let file = file.trim_start_matches("./").trim_start_matches('/');
+    let path = path.to_lowercase();
+    !file.is_empty()
+        && (path == file
+            || path
+                .strip_suffix(file)
+                .is_some_and(|folders| folders.ends_with('/')))
+}
+
+/// `my-album/01-song.flac` gives `01-song.flac`.
+pub fn base_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_files_by_path_then_name() {
+        for (file, path, named) in [
+            ("Album/CD1/01.flac", "Album/CD1/01.flac", true),
+            ("CD1/01.flac", "Album/CD1/01.flac", true),
+            ("cd1\\01.FLAC", "Album/CD1/01.flac", true),
+            ("./CD1/01.flac", "Album/CD1/01.flac", true),
+            ("01.flac", "Album/CD1/01.flac", true),
+            ("CD2/01.flac", "Album/CD1/01.flac", false),
+            ("1/01.flac", "Album/CD1/01.flac", false),
+            ("1.flac", "Album/CD1/01.flac", false),
+            ("", "Album/CD1/01.flac", false),
+        ] {
+            assert_eq!(names_file(file, path), named, "{file} for {path}");
+        }
+    }
+
+    #[test]
+    fn prefers_the_entry_giving_the_most_of_the_path() {
+        let manifest = AlbumManifest {
+            tracks: ["01.flac", "CD2/01.flac"]
+                .map(|file| ManifestTrack {
+                    file: file.to_string(),
+                    ..ManifestTrack::default()
+                })
+                .to_vec(),
+            ..AlbumManifest::default()
+        };
+
+        let file = |path: &str| manifest.track(path).map(|track| track.file.as_str());
+        assert_eq!(file("CD2/01.flac"), Some("CD2/01.flac"));
+        assert_eq!(file("CD1/01.flac"), Some("01.flac"));
+        assert_eq!(file("CD1/02.flac"), None);
+    }
+}
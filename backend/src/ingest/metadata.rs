@@ -0,0 +1,290 @@
+//! Embedded tags and duration read from audio files.
+//!
+//! Covers ID3 tags (MP3), Vorbis comments (FLAC, OGG), MP4 atoms (M4A) and RIFF
+//! INFO chunks (WAV). Reading is blocking, so async callers go through
+//! [`extract`].
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use symphonia::core::{
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::{MediaSourceStream, MediaSourceStreamOptions},
+    meta::{MetadataOptions, StandardTagKey, Tag},
+    probe::Hint,
+    units::{Time, TimeBase},
+};
+
+use super::format::AudioFormat;
+
+/// What the file says about itself. Tags that are absent or blank are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudioMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub track_number: Option<i32>,
+    /// Whole seconds, rounded.
+    pub duration: Option<i32>,
+}
+
+impl AudioMetadata {
+    /// Fills fields that are still empty from `tags`; earlier tags win.
+    fn add_tags(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            let value = tag.value.to_string();
+            let value = clean(&value);
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => set_once(&mut self.title, value.to_string()),
+                Some(StandardTagKey::Artist) => set_once(&mut self.artist, value.to_string()),
+                Some(StandardTagKey::Album) => set_once(&mut self.album, value.to_string()),
+                Some(StandardTagKey::Genre) => set_once(&mut self.genre, value.to_string()),
+                Some(
+                    StandardTagKey::Date
+                    | StandardTagKey::ReleaseDate
+                    | StandardTagKey::OriginalDate,
+                ) => {
+                    if let Some(year) =
+                        leading_number(value).filter(|year| (1000..=9999).contains(year))
+                    {
+                        set_once(&mut self.year, year);
+                    }
+                }
+                // Often written as `3/12`.
+                Some(StandardTagKey::TrackNumber) => {
+                    if let Some(number) = leading_number(value).filter(|number| *number > 0) {
+                        set_once(&mut self.track_number, number);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Album artist only stands in when there's no track artist.
+        if self.artist.is_none() {
+            let album_artist = tags
+                .iter()
+                .filter(|tag| tag.std_key == Some(StandardTagKey::AlbumArtist))
+                .map(|tag| clean(&tag.value.to_string()).to_string())
+                .find(|value| !value.is_empty());
+            self.artist = album_artist;
+        }
+    }
+}
+
+/// Reads the tags and duration of the file at `path` on the blocking pool.
+pub async fn extract(path: &Path, format: Option<AudioFormat>) -> anyhow::Result<AudioMetadata> {
+    let path: PathBuf = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read(&path, format)).await?
+}
+
+/// Reads the tags and duration of the file at `path`. `format` is a hint for
+/// the prober, the contents decide.
+pub fn read(path: &Path, format: Option<AudioFormat>) -> anyhow::Result<AudioMetadata> {
+    let source = MediaSourceStream::new(
+        Box::new(File::open(path)?),
+        MediaSourceStreamOptions::default(),
+    );
+    let mut hint = Hint::new();
+    if let Some(format) = format {
+        hint.with_extension(format.extension());
+    }
+
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|err| anyhow::anyhow!("Unrecognised audio file: {err}"))?;
+    let mut reader = probed.format;
+
+    // Tags inside the container take precedence over ones in front of it,
+    // e.g. an ID3v2 block ahead of a FLAC stream.
+    let mut metadata = AudioMetadata::default();
+    if let Some(revision) = reader.metadata().skip_to_latest() {
+        metadata.add_tags(revision.tags());
+    }
+    if let Some(revision) = probed
+        .metadata
+        .get()
+        .as_mut()
+        .and_then(|log| log.skip_to_latest().cloned())
+    {
+        metadata.add_tags(revision.tags());
+    }
+
+    let track = reader
+        .default_track()
+        .ok_or_else(|| anyhow::anyhow!("Audio file has no audio track"))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let time_base = params
+        .time_base
+        .or_else(|| params.sample_rate.map(|rate| TimeBase::new(1, rate)));
+
+    if let Some(time_base) = time_base {
+        // Without a frame count in the headers (e.g. MP3 without a Xing
+        // frame), add up the packets instead. They are not decoded.
+        let frames = match params.n_frames {
+            Some(frames) => frames,
+            None => count_frames(reader.as_mut(), track_id)?,
+        };
+
+        metadata.duration = whole_seconds(time_base.calc_time(frames));
+    }
+
+    Ok(metadata)
+}
+
+fn count_frames(reader: &mut dyn FormatReader, track_id: u32) -> anyhow::Result<u64> {
+    let mut frames = 0;
+    loop {
+        match reader.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => frames += packet.dur,
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(frames)
+            }
+            Err(err) => return Err(anyhow::anyhow!("Failed to read audio file: {err}")),
+        }
+    }
+}
+
+/// Rounded to the nearest second, half a second rounds up.
+fn whole_seconds(time: Time) -> Option<i32> {
+    i32::try_from(time.seconds)
+        .ok()
+        .map(|seconds| seconds + i32::from(time.frac >= 0.5))
+}
+
+/// RIFF INFO strings keep their NUL terminator.
+fn clean(value: &str) -> &str {
+    value.trim_matches(|c: char| c.is_whitespace() || c == '\0')
+}
+
+fn set_once<T>(field: &mut Option<T>, value: T) {
+    if field.is_none() {
+        *field = Some(value);
+    }
+}
+
+/// `2024-05-01` gives 2024, `3/12` gives 3.
+fn leading_number(value: &str) -> Option<i32> {
+    let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use symphonia::core::meta::Value;
+
+    use super::*;
+
+    fn tag(key: StandardTagKey, value: &str) -> Tag {
+        Tag::new(Some(key), "", Value::from(value))
+    }
+
+    fn tagged(tags: &[Tag]) -> AudioMetadata {
+        let mut metadata = AudioMetadata::default();
+        metadata.add_tags(tags);
+        metadata
+    }
+
+    #[test]
+    fn earlier_tags_win() {
+        let mut metadata = tagged(&[
+            tag(StandardTagKey::TrackTitle, "First"),
+            tag(StandardTagKey::TrackTitle, "Second"),
+            tag(StandardTagKey::Genre, "Jazz"),
+        ]);
+        // A later revision only fills what is still missing.
+        metadata.add_tags(&[
+            tag(StandardTagKey::TrackTitle, "Outer"),
+            tag(StandardTagKey::Album, "Outer Album"),
+        ]);
+
+        assert_eq!(metadata.title.as_deref(), Some("First"));
+        assert_eq!(metadata.genre.as_deref(), Some("Jazz"));
+        assert_eq!(metadata.album.as_deref(), Some("Outer Album"));
+    }
+
+    #[test]
+    fn blank_tags_are_skipped() {
+        let metadata = tagged(&[
+            tag(StandardTagKey::TrackTitle, " \0"),
+            tag(StandardTagKey::TrackTitle, "Title\0"),
+            tag(StandardTagKey::Genre, "  "),
+        ]);
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.genre, None);
+    }
+
+    #[test]
+    fn album_artist_only_stands_in_for_a_missing_artist() {
+        let metadata = tagged(&[
+            tag(StandardTagKey::AlbumArtist, "Various Artists"),
+            tag(StandardTagKey::Artist, "Track Artist"),
+        ]);
+        assert_eq!(metadata.artist.as_deref(), Some("Track Artist"));
+
+        let metadata = tagged(&[
+            tag(StandardTagKey::AlbumArtist, " "),
+            tag(StandardTagKey::AlbumArtist, "Album Artist"),
+        ]);
+        assert_eq!(metadata.artist.as_deref(), Some("Album Artist"));
+
+        assert_eq!(tagged(&[]).artist, None);
+    }
+
+    #[test]
+    fn reads_years_and_track_numbers_from_their_leading_digits() {
+        let metadata = tagged(&[
+            tag(StandardTagKey::Date, "2024-05-01"),
+            tag(StandardTagKey::TrackNumber, "3/12"),
+        ]);
+        assert_eq!(metadata.year, Some(2024));
+        assert_eq!(metadata.track_number, Some(3));
+
+        // Values that aren't a year or a track number fall through to the next tag.
+        let metadata = tagged(&[
+            tag(StandardTagKey::Date, "05/01/2024"),
+            tag(StandardTagKey::ReleaseDate, "unknown"),
+            tag(StandardTagKey::OriginalDate, "1999"),
+            tag(StandardTagKey::TrackNumber, "0/12"),
+            tag(StandardTagKey::TrackNumber, "A1"),
+            tag(StandardTagKey::TrackNumber, "7"),
+        ]);
+        assert_eq!(metadata.year, Some(1999));
+        assert_eq!(metadata.track_number, Some(7));
+
+        assert_eq!(leading_number("3/12"), Some(3));
+        assert_eq!(leading_number("2024-05-01"), Some(2024));
+        assert_eq!(leading_number("/12"), None);
+        assert_eq!(leading_number("99999999999"), None);
+    }
+
+    #[test]
+    fn rounds_durations_to_the_nearest_second() {
+        assert_eq!(whole_seconds(Time::new(179, 0.49)), Some(179));
+        assert_eq!(whole_seconds(Time::new(179, 0.5)), Some(180));
+        assert_eq!(whole_seconds(Time::new(0, 0.2)), Some(0));
+        assert_eq!(whole_seconds(Time::new(u64::MAX, 0.0)), None);
+
+        // 44.1kHz, a frame short of three minutes.
+        let time = TimeBase::new(1, 44_100).calc_time(180 * 44_100 - 1);
+        assert_eq!(whole_seconds(time), Some(180));
+    }
+}
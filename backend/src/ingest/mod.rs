@@ -0,0 +1,30 @@
+//! Turning uploaded audio into stored tracks.
+
+pub mod album;
+pub mod archive;
+pub mod chunked;
+pub mod duplicates;
+pub mod export;
+pub mod filename;
+pub mod fingerprint;
+pub mod flac;
+pub mod format;
+pub mod inotify;
+pub mod jobs;
+pub mod library;
+pub mod manifest;
+pub mod metadata;
+pub mod provenance;
+pub mod spool;
+pub mod store;
+pub mod transcode;
+pub mod validation;
+pub mod versions;
+
+/// Album title for tracks that don't name one.
+pub const DEFAULT_ALBUM_TITLE: &str = "Unknown Album";
+
+/// `None` for values that are missing or blank.
+pub fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|value| !value.is_empty())
+}
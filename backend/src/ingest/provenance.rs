@@ -0,0 +1,80 @@
+//! Where each piece of a track's or album's metadata came from, kept so the
+//! exported manifest can say which values were guessed.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The album's `album.yaml` / `album.json`.
+    Manifest,
+    /// The upload's `metadata` field or the request body.
+    Form,
+    /// The file's embedded tags.
+    Tags,
+    /// Parsed from the file name.
+    Filename,
+    /// Picked out of the album ZIP, e.g. a `cover.jpg`.
+    Archive,
+    /// Measured from the audio itself.
+    Measured,
+    /// Taken from the track's album.
+    Album,
+    /// Nothing said, a placeholder was used.
+    Default,
+    /// Changed through the API after upload.
+    Edited,
+}
+
+impl Source {
+    /// What the exported manifest says about a value from here.
+    pub const fn comment(self) -> &'static str {
+        match self {
+            Self::Manifest => "From: album manifest",
+            Self::Form => "From: upload form",
+            Self::Tags => "Extracted from: embedded tags",
+            Self::Filename => "Extracted from: filename",
+            Self::Archive => "Found in ZIP",
+            Self::Measured => "Extracted from: file",
+            Self::Album => "From: album",
+            Self::Default => "Please update",
+            Self::Edited => "Edited after upload",
+        }
+    }
+}
+
+/// Field name to source, stored as JSON next to the metadata it describes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FieldSources(BTreeMap<String, Source>);
+
+impl FieldSources {
+    /// Reads stored sources; anything unreadable counts as unknown.
+    pub fn parse(stored: Option<&str>) -> Self {
+        stored
+            .and_then(|stored| serde_json::from_str(stored).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.0).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    pub fn set(&mut self, field: &str, source: Source) {
+        self.0.insert(field.to_string(), source);
+    }
+
+    /// Records the source of the first of `candidates` that had a value, for
+    /// fields filled from several places in order.
+    pub fn set_first(&mut self, field: &str, candidates: &[(bool, Source)]) {
+        if let Some((_, source)) = candidates.iter().find(|(present, _)| *present) {
+            self.set(field, *source);
+        }
+    }
+
+    pub fn get(&self, field: &str) -> Option<Source> {
+        self.0.get(field).copied()
+    }
+}
@@ -0,0 +1,65 @@
+//! Temporary files holding uploads until they are checked and stored.
+
+use std::path::{Path, PathBuf};
+
+use tokio::{fs::File, io::AsyncReadExt};
+use uuid::Uuid;
+
+/// An upload written to the spool directory, deleted again when dropped.
+#[derive(Debug)]
+pub struct SpooledFile {
+    path: PathBuf,
+}
+
+impl SpooledFile {
+    /// Creates an empty spool file in `dir`, creating the directory if needed.
+    pub async fn create(dir: &Path) -> std::io::Result<(Self, File)> {
+        tokio::fs::create_dir_all(dir).await?;
+
+        let spooled = Self::reserve(dir);
+        let file = File::create(&spooled.path).await?;
+
+        Ok((spooled, file))
+    }
+
+    /// Picks a new path in `dir` without creating anything, for blocking code
+    /// that writes the file itself. The directory must already exist.
+    pub fn reserve(dir: &Path) -> Self {
+        Self {
+            path: dir.join(format!("{}.part", Uuid::new_v4())),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps the file when this value is dropped, for uploads processed
+    /// after the request, and returns its path.
+    pub fn keep(self) -> PathBuf {
+        let mut spooled = std::mem::ManuallyDrop::new(self);
+        std::mem::take(&mut spooled.path)
+    }
+
+    /// Reads up to `len` bytes from the start of the file.
+    pub async fn read_header(&self, len: usize) -> std::io::Result<Vec<u8>> {
+        let file = File::open(&self.path).await?;
+        let mut header = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut header).await?;
+
+        Ok(header)
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!(
+                    "Failed to remove spooled upload {}: {err}",
+                    self.path.display()
+                );
+            }
+        }
+    }
+}
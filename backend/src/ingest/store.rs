@@ -0,0 +1,105 @@
+//! Putting checked uploads into storage for the catalog.
+
+use std::path::Path;
+
+use axum::http::StatusCode;
+
+use crate::{
+    db::{
+        models::{Artist, Track},
+        queries,
+    },
+    handlers::ApiError,
+    AppState,
+};
+
+use super::{format::AudioFormat, non_empty};
+
+/// Uploads go to an existing artist the user can upload to, by id or else
+/// by name.
+pub async fn resolve_artist(
+    state: &AppState,
+    artist_id: Option<&str>,
+    name: Option<&str>,
+) -> Result<Artist, ApiError> {
+    if let Some(artist_id) = non_empty(artist_id) {
+        return queries::get_artist_by_id(&state.db, artist_id)
+            .await?
+            .ok_or_else(|| ApiError::not_found("Artist not found"));
+    }
+
+    let name =
+        name.ok_or_else(|| ApiError::bad_request("Metadata must include artist or artist_id"))?;
+    queries::get_artist_by_name(&state.db, name)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Artist '{name}' not found, create it first")))
+}
+
+pub fn track_key(artist_id: &str, album_id: &str, track_id: &str, format: AudioFormat) -> String {
+    format!(
+        "music/{artist_id}/{album_id}/{track_id}.{}",
+        format.extension()
+    )
+}
+
+pub async fn store(
+    state: &AppState,
+    key: &str,
+    path: &Path,
+    content_type: &str,
+) -> Result<(), ApiError> {
+    state
+        .storage
+        .upload_path(key, path, Some(content_type))
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to store upload {key}: {err:#}");
+            ApiError::new(StatusCode::BAD_GATEWAY, "Failed to store the uploaded file")
+        })
+}
+
+/// Deletes an album an upload created when none of its tracks could be, so a
+/// failed upload leaves no empty album behind. Another upload's track keeps it.
+pub async fn remove_empty_album(state: &AppState, album_id: &str) {
+    let removed = queries::delete_album_if_empty(&state.db, album_id).await;
+    if let Err(err) = removed {
+        tracing::warn!("Failed to remove empty album {album_id}: {err:#}");
+    }
+}
+
+/// Deletes the stored files of tracks that won't be created, so nothing is
+/// left behind that no track points to.
+pub async fn remove_stored(state: &AppState, tracks: &[Track]) {
+    for track in tracks {
+        remove_key(state, &track.file_path).await;
+    }
+}
+
+/// Deletes the stored object `key` and its fingerprint once no track or
+/// album version points to it. It is kept when that can't be checked.
+pub async fn remove_unreferenced(state: &AppState, key: &str) {
+    let referenced = queries::is_file_referenced(&state.db, key).await;
+    match referenced {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(err) => {
+            tracing::warn!("Keeping {key}, could not check whether it is still used: {err:#}");
+            return;
+        }
+    }
+
+    remove_key(state, key).await;
+}
+
+/// Deletes a stored object that nothing points to, and its fingerprint,
+/// logging failures.
+pub async fn remove_key(state: &AppState, key: &str) {
+    let deleted = state.storage.delete_file(key).await;
+    if let Err(err) = deleted {
+        tracing::warn!("Failed to remove orphaned upload {key}: {err:#}");
+    }
+    let forgotten = queries::delete_audio_fingerprint(&state.db, key).await;
+    if let Err(err) = forgotten {
+        tracing::warn!("Failed to forget the fingerprint of {key}: {err:#}");
+    }
+}
@@ -0,0 +1,623 @@
+//! Lossless conversion of uncompressed uploads (WAV, AIFF) to FLAC.
+//!
+//! The PCM is read straight from the container's packets and encoded with
+//! [`super::flac`]. The FLAC is then decoded again and compared frame by
+//! frame with symphonia's own decode of the original, so a mistake in reading
+//! the packets can't go unnoticed. It is only kept if it matches and is
+//! smaller than the original. Converting is blocking, so async callers go
+//! through [`to_flac`].
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use md5::{Digest, Md5};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::{
+        CodecType, Decoder, DecoderOptions, VerificationCheck, CODEC_TYPE_PCM_S16BE,
+        CODEC_TYPE_PCM_S16LE, CODEC_TYPE_PCM_S24BE, CODEC_TYPE_PCM_S24LE, CODEC_TYPE_PCM_S8,
+        CODEC_TYPE_PCM_U16BE, CODEC_TYPE_PCM_U16LE, CODEC_TYPE_PCM_U24BE, CODEC_TYPE_PCM_U24LE,
+        CODEC_TYPE_PCM_U8,
+    },
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::{MediaSourceStream, MediaSourceStreamOptions},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+use tracing::{info, warn};
+
+use super::{
+    flac::{FlacEncoder, MAX_BITS_PER_SAMPLE},
+    format::AudioFormat,
+    metadata::AudioMetadata,
+    spool::SpooledFile,
+};
+
+/// Sizes of a converted file, for the upload's storage savings.
+#[derive(Debug, Clone, Copy)]
+pub struct Transcoded {
+    pub original_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+/// How the samples sit in the container's packets.
+#[derive(Debug, Clone, Copy)]
+struct PcmLayout {
+    bytes: usize,
+    big_endian: bool,
+    unsigned: bool,
+}
+
+impl PcmLayout {
+    /// Integer PCM FLAC can hold; `None` for float, A-law, μ-law and 32-bit.
+    const fn of(codec: CodecType) -> Option<Self> {
+        let (bytes, big_endian, unsigned) = match codec {
+            CODEC_TYPE_PCM_S8 => (1, false, false),
+            CODEC_TYPE_PCM_U8 => (1, false, true),
+            CODEC_TYPE_PCM_S16LE => (2, false, false),
+            CODEC_TYPE_PCM_S16BE => (2, true, false),
+            CODEC_TYPE_PCM_U16LE => (2, false, true),
+            CODEC_TYPE_PCM_U16BE => (2, true, true),
+            CODEC_TYPE_PCM_S24LE => (3, false, false),
+            CODEC_TYPE_PCM_S24BE => (3, true, false),
+            CODEC_TYPE_PCM_U24LE => (3, false, true),
+            CODEC_TYPE_PCM_U24BE => (3, true, true),
+            _ => return None,
+        };
+        Some(Self {
+            bytes,
+            big_endian,
+            unsigned,
+        })
+    }
+
+    fn bits(self) -> u32 {
+        u32::try_from(self.bytes * 8).unwrap_or(MAX_BITS_PER_SAMPLE)
+    }
+
+    /// Splits interleaved packet bytes into signed samples per channel.
+    fn decode(self, data: &[u8], channels: &mut [Vec<i32>]) {
+        let bits = self.bits();
+        for channel in channels.iter_mut() {
+            channel.clear();
+        }
+        for frame in data.chunks_exact(self.bytes * channels.len()) {
+            for (channel, sample) in channels.iter_mut().zip(frame.chunks_exact(self.bytes)) {
+                let raw = sample.iter().enumerate().fold(0u32, |raw, (i, byte)| {
+                    let shift = if self.big_endian {
+                        self.bytes - 1 - i
+                    } else {
+                        i
+                    } * 8;
+                    raw | u32::from(*byte) << shift
+                });
+                // Move the sign bit to the top and back to sign-extend.
+                #[allow(clippy::cast_possible_wrap)]
+                let value = if self.unsigned {
+                    (raw ^ 1 << (bits - 1)) << (32 - bits)
+                } else {
+                    raw << (32 - bits)
+                } as i32
+                    >> (32 - bits);
+                channel.push(value);
+            }
+        }
+    }
+}
+
+/// Converts an uncompressed upload to FLAC next to it, returning the file to
+/// store and its format. Anything else, or a file that can't be converted, is
+/// stored as uploaded.
+pub async fn convert_upload(
+    spooled: SpooledFile,
+    format: AudioFormat,
+    tags: &AudioMetadata,
+) -> (SpooledFile, AudioFormat, Option<Transcoded>) {
+    let Some(dir) = spooled.path().parent().filter(|_| format.is_uncompressed()) else {
+        return (spooled, format, None);
+    };
+
+    let flac = SpooledFile::reserve(dir);
+    let converted = to_flac(spooled.path(), flac.path(), format, tags).await;
+    match converted {
+        Ok(Some(transcoded)) => {
+            info!(
+                "Converted {} to FLAC, {} bytes down to {}",
+                spooled.path().display(),
+                transcoded.original_bytes,
+                transcoded.stored_bytes
+            );
+            (flac, AudioFormat::Flac, Some(transcoded))
+        }
+        Ok(None) => (spooled, format, None),
+        Err(err) => {
+            warn!("Keeping {} as uploaded: {err:#}", spooled.path().display());
+            (spooled, format, None)
+        }
+    }
+}
+
+/// Converts the WAV or AIFF at `source` to FLAC at `dest` with `tags` as its
+/// Vorbis comments, on the blocking pool.
+///
+/// `None` when the file stays as it is: audio FLAC can't hold, or a FLAC no
+/// smaller than the original. Failing to verify the FLAC is an error.
+pub async fn to_flac(
+    source: &Path,
+    dest: &Path,
+    format: AudioFormat,
+    tags: &AudioMetadata,
+) -> anyhow::Result<Option<Transcoded>> {
+    let source: PathBuf = source.to_path_buf();
+    let dest: PathBuf = dest.to_path_buf();
+    let tags = vorbis_tags(tags);
+    tokio::task::spawn_blocking(move || convert(&source, &dest, format, &tags)).await?
+}
+
+fn convert(
+    source: &Path,
+    dest: &Path,
+    format: AudioFormat,
+    tags: &[(&str, String)],
+) -> anyhow::Result<Option<Transcoded>> {
+    let mut reader = open(source, format)?;
+    let track = reader
+        .default_track()
+        .ok_or_else(|| anyhow!("Audio file has no audio track"))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let Some(layout) = PcmLayout::of(params.codec) else {
+        info!(
+            "Keeping {} as uploaded, FLAC can't hold its samples",
+            source.display()
+        );
+        return Ok(None);
+    };
+    let channels = params.channels.map_or(0, Channels::count);
+    let sample_rate = params.sample_rate.unwrap_or_default();
+    let bits = layout.bits();
+
+    let mut encoder = FlacEncoder::new(
+        BufWriter::new(File::create(dest)?),
+        channels,
+        bits,
+        sample_rate,
+        tags,
+    )?;
+    let mut samples = vec![Vec::new(); channels];
+    while let Some(packet) = next_packet(reader.as_mut(), track_id)? {
+        layout.decode(&packet.data, &mut samples);
+        encoder.write(&samples.iter().map(Vec::as_slice).collect::<Vec<_>>())?;
+    }
+    let (writer, _) = encoder.finish()?;
+    writer
+        .into_inner()
+        .map_err(std::io::IntoInnerError::into_error)?
+        .sync_all()?;
+
+    verify(source, format, dest, bits)?;
+
+    let original_bytes = std::fs::metadata(source)?.len();
+    let stored_bytes = std::fs::metadata(dest)?.len();
+    if stored_bytes >= original_bytes {
+        info!(
+            "Keeping {} as uploaded, FLAC would not be smaller",
+            source.display()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(Transcoded {
+        original_bytes,
+        stored_bytes,
+    }))
+}
+
+/// Decodes the FLAC at `flac` and checks it against a decode of the
+/// `source` it was made from, frame by frame at `bits` bits, and against the
+/// MD5 of the audio in its `STREAMINFO`.
+fn verify(source: &Path, format: AudioFormat, flac: &Path, bits: u32) -> anyhow::Result<()> {
+    let mut original = Decoded::open(source, format)?;
+    let mut converted = Decoded::open(flac, AudioFormat::Flac)?;
+    let Some(VerificationCheck::Md5(streaminfo_md5)) =
+        converted.decoder.codec_params().verification_check
+    else {
+        bail!("FLAC has no MD5 of its audio");
+    };
+    let channels = original.channels();
+    if converted.channels() != channels {
+        bail!(
+            "FLAC has {} channels, the original {channels}",
+            converted.channels()
+        );
+    }
+
+    // Both decoders scale samples up to 32 bits.
+    let shift = 32 - bits;
+    let width = bits.div_ceil(8) as usize;
+    let mut md5 = Md5::new();
+    let mut bytes = Vec::new();
+    let mut expected: Vec<i32> = Vec::new();
+    let mut at = 0;
+    let mut compared = 0;
+    while let Some(samples) = converted.next()? {
+        bytes.clear();
+        for sample in samples {
+            while at == expected.len() {
+                let more = original
+                    .next()?
+                    .ok_or_else(|| anyhow!("FLAC is longer than the original"))?;
+                expected.clear();
+                expected.extend_from_slice(more);
+                at = 0;
+            }
+            if sample >> shift != expected[at] >> shift {
+                bail!(
+                    "FLAC differs from the original at frame {}",
+                    compared / channels
+                );
+            }
+            at += 1;
+            compared += 1;
+            bytes.extend_from_slice(&(sample >> shift).to_le_bytes()[..width]);
+        }
+        md5.update(&bytes);
+    }
+    if at < expected.len() {
+        bail!("FLAC is shorter than the original");
+    }
+    while let Some(rest) = original.next()? {
+        if !rest.is_empty() {
+            bail!("FLAC is shorter than the original");
+        }
+    }
+
+    let checksum: [u8; 16] = md5.finalize().into();
+    if checksum != streaminfo_md5 {
+        bail!("FLAC does not match the MD5 in its STREAMINFO");
+    }
+
+    Ok(())
+}
+
+/// A file decoded with symphonia, as interleaved samples scaled to 32 bits.
+struct Decoded {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    samples: Option<SampleBuffer<i32>>,
+}
+
+impl Decoded {
+    fn open(path: &Path, format: AudioFormat) -> anyhow::Result<Self> {
+        let reader = open(path, format)?;
+        let track = reader
+            .default_track()
+            .ok_or_else(|| anyhow!("{} has no audio track", path.display()))?;
+        let track_id = track.id;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|err| anyhow!("Could not decode {}: {err}", path.display()))?;
+
+        Ok(Self {
+            reader,
+            decoder,
+            track_id,
+            samples: None,
+        })
+    }
+
+    fn channels(&self) -> usize {
+        self.decoder
+            .codec_params()
+            .channels
+            .map_or(0, Channels::count)
+    }
+
+    /// The samples of the next packet, `None` at the end of the file.
+    fn next(&mut self) -> anyhow::Result<Option<&[i32]>> {
+        let Some(packet) = next_packet(self.reader.as_mut(), self.track_id)? else {
+            return Ok(None);
+        };
+        let audio = self
+            .decoder
+            .decode(&packet)
+            .map_err(|err| anyhow!("Could not decode: {err}"))?;
+
+        let spec = *audio.spec();
+        let needed = audio.capacity() * spec.channels.count();
+        if self
+            .samples
+            .as_ref()
+            .is_none_or(|samples| samples.capacity() < needed)
+        {
+            self.samples = Some(SampleBuffer::new(audio.capacity() as u64, spec));
+        }
+        let samples = self.samples.as_mut().expect("set above");
+        samples.copy_interleaved_ref(audio);
+
+        Ok(Some(samples.samples()))
+    }
+}
+
+fn open(path: &Path, format: AudioFormat) -> anyhow::Result<Box<dyn FormatReader>> {
+    let source = MediaSourceStream::new(
+        Box::new(File::open(path)?),
+        MediaSourceStreamOptions::default(),
+    );
+    let mut hint = Hint::new();
+    hint.with_extension(format.extension());
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|err| anyhow!("Unrecognised audio file: {err}"))?;
+
+    Ok(probed.format)
+}
+
+/// The next packet of `track_id`, `None` at the end of the file.
+fn next_packet(
+    reader: &mut dyn FormatReader,
+    track_id: u32,
+) -> anyhow::Result<Option<symphonia::core::formats::Packet>> {
+    loop {
+        match reader.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => return Ok(Some(packet)),
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(anyhow!("Failed to read audio file: {err}")),
+        }
+    }
+}
+
+/// What the upload's tags say, as Vorbis comments for the FLAC.
+fn vorbis_tags(metadata: &AudioMetadata) -> Vec<(&'static str, String)> {
+    [
+        ("TITLE", metadata.title.clone()),
+        ("ARTIST", metadata.artist.clone()),
+        ("ALBUM", metadata.album.clone()),
+        ("GENRE", metadata.genre.clone()),
+        ("DATE", metadata.year.map(|year| year.to_string())),
+        (
+            "TRACKNUMBER",
+            metadata.track_number.map(|number| number.to_string()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::flac::{
+            tests::{decode, Signal},
+            BLOCK_SIZE,
+        },
+        *,
+    };
+
+    #[test]
+    fn decodes_every_layout() {
+        #[rustfmt::skip]
+        let cases: &[(CodecType, &[u8], &[i32])] = &[
+            (CODEC_TYPE_PCM_U8, &[0x00, 0x80, 0xFF, 0x81], &[-128, 0, 127, 1]),
+            (CODEC_TYPE_PCM_S8, &[0x80, 0x00, 0x7F, 0xFF], &[-128, 0, 127, -1]),
+            (CODEC_TYPE_PCM_S16LE, &[0x00, 0x80, 0xFF, 0x7F, 0xFE, 0xFF], &[-32768, 32767, -2]),
+            (CODEC_TYPE_PCM_S16BE, &[0x80, 0x00, 0x7F, 0xFF, 0xFF, 0xFE], &[-32768, 32767, -2]),
+            (CODEC_TYPE_PCM_U16LE, &[0x00, 0x00, 0x00, 0x80, 0xFF, 0xFF], &[-32768, 0, 32767]),
+            (CODEC_TYPE_PCM_U16BE, &[0x00, 0x00, 0x80, 0x00, 0xFF, 0xFF], &[-32768, 0, 32767]),
+            (CODEC_TYPE_PCM_S24LE, &[0x00, 0x00, 0x80, 0xFF, 0xFF, 0x7F, 0x01, 0x02, 0x03], &[-8_388_608, 8_388_607, 0x03_0201]),
+            (CODEC_TYPE_PCM_S24BE, &[0x80, 0x00, 0x00, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE], &[-8_388_608, 8_388_607, -2]),
+            (CODEC_TYPE_PCM_U24LE, &[0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xFF, 0xFF, 0xFF], &[-8_388_608, 0, 8_388_607]),
+            (CODEC_TYPE_PCM_U24BE, &[0x00, 0x00, 0x00, 0x80, 0x00, 0x01, 0xFF, 0xFF, 0xFF], &[-8_388_608, 1, 8_388_607]),
+        ];
+        for (codec, data, expected) in cases {
+            let layout = PcmLayout::of(*codec).expect("integer PCM");
+            let mut channels = vec![Vec::new()];
+            layout.decode(data, &mut channels);
+            assert_eq!(channels[0], *expected, "{codec}");
+        }
+    }
+
+    #[test]
+    fn splits_frames_between_channels() {
+        let layout = PcmLayout::of(CODEC_TYPE_PCM_S16LE).expect("integer PCM");
+        let mut channels = vec![vec![99], Vec::new()];
+        // Two frames, and half of a third that is dropped.
+        layout.decode(&[1, 0, 2, 0, 3, 0, 4, 0, 5, 0], &mut channels);
+        assert_eq!(channels, [vec![1, 3], vec![2, 4]]);
+    }
+
+    /// A directory of its own, removed on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("navicore-transcode-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).expect("scratch directory");
+            Self(dir)
+        }
+
+        fn file(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    /// A WAV or AIFF file of `channels`, as the container stores them: WAV
+    /// little-endian with unsigned 8-bit samples, AIFF big-endian and signed.
+    fn container(format: AudioFormat, channels: &[Vec<i32>], bits: u32) -> Vec<u8> {
+        let width = bits.div_ceil(8) as usize;
+        let mut data = Vec::new();
+        for frame in 0..channels[0].len() {
+            for channel in channels {
+                let sample = channel[frame];
+                if format == AudioFormat::Aiff {
+                    data.extend_from_slice(&sample.to_be_bytes()[4 - width..]);
+                } else if bits == 8 {
+                    data.push(u8::try_from(sample + 128).expect("8-bit sample"));
+                } else {
+                    data.extend_from_slice(&sample.to_le_bytes()[..width]);
+                }
+            }
+        }
+
+        let count = u16::try_from(channels.len()).expect("channels");
+        let bits = u16::try_from(bits).expect("bits");
+        let data_len = u32::try_from(data.len()).expect("size");
+        let mut out = Vec::new();
+        if format == AudioFormat::Aiff {
+            let frames = u32::try_from(channels[0].len()).expect("frames");
+            // The sample rate is an 80-bit float: exponent, then mantissa.
+            let shift = SAMPLE_RATE.leading_zeros();
+            let exponent = u16::try_from(16383 + 31 - shift).expect("exponent");
+            let mantissa = u64::from(SAMPLE_RATE) << (32 + shift);
+
+            out.extend_from_slice(b"FORM");
+            out.extend_from_slice(&(4 + 26 + 16 + data_len).to_be_bytes());
+            out.extend_from_slice(b"AIFFCOMM");
+            out.extend_from_slice(&18u32.to_be_bytes());
+            out.extend_from_slice(&count.to_be_bytes());
+            out.extend_from_slice(&frames.to_be_bytes());
+            out.extend_from_slice(&bits.to_be_bytes());
+            out.extend_from_slice(&exponent.to_be_bytes());
+            out.extend_from_slice(&mantissa.to_be_bytes());
+            out.extend_from_slice(b"SSND");
+            out.extend_from_slice(&(8 + data_len).to_be_bytes());
+            out.extend_from_slice(&[0; 8]);
+        } else {
+            let block_align = count * bits.div_ceil(8);
+            out.extend_from_slice(b"RIFF");
+            out.extend_from_slice(&(4 + 24 + 8 + data_len).to_le_bytes());
+            out.extend_from_slice(b"WAVEfmt ");
+            out.extend_from_slice(&16u32.to_le_bytes());
+            out.extend_from_slice(&1u16.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+            out.extend_from_slice(&(SAMPLE_RATE * u32::from(block_align)).to_le_bytes());
+            out.extend_from_slice(&block_align.to_le_bytes());
+            out.extend_from_slice(&bits.to_le_bytes());
+            out.extend_from_slice(b"data");
+            out.extend_from_slice(&data_len.to_le_bytes());
+        }
+        out.extend_from_slice(&data);
+        out
+    }
+
+    fn frames() -> i32 {
+        // Two whole FLAC blocks and a partial one.
+        i32::try_from(2 * BLOCK_SIZE).expect("frames") + 777
+    }
+
+    #[test]
+    fn converts_wav_and_aiff_losslessly() {
+        let scratch = Scratch::new();
+        let tags = [("TITLE", "Orbit".to_string())];
+        for format in [AudioFormat::Wav, AudioFormat::Aiff] {
+            for bits in [8, 16, 24] {
+                for channels in [1, 2] {
+                    for signal in [Signal::Silence, Signal::Triangle, Signal::FullScale] {
+                        let case =
+                            format!("{format:?}, {signal:?}, {channels} channels of {bits} bits");
+                        let input = signal.generate(channels, bits, frames());
+                        let source = scratch.file(&format!("{case}.{}", format.extension()));
+                        let dest = scratch.file(&format!("{case}.flac"));
+                        std::fs::write(&source, container(format, &input, bits)).expect(&case);
+
+                        let converted = convert(&source, &dest, format, &tags)
+                            .expect(&case)
+                            .expect(&case);
+                        assert!(converted.stored_bytes < converted.original_bytes, "{case}");
+                        let decoded = decode(Box::new(File::open(&dest).expect(&case)), bits);
+                        assert!(decoded.channels == input, "{case}: decoded samples differ");
+                        assert_eq!(decoded.verified, Some(true), "{case}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_files_flac_would_not_shrink() {
+        let scratch = Scratch::new();
+        for bits in [8, 16, 24] {
+            let source = scratch.file(&format!("noise-{bits}.wav"));
+            std::fs::write(
+                &source,
+                container(
+                    AudioFormat::Wav,
+                    &Signal::Noise.generate(2, bits, frames()),
+                    bits,
+                ),
+            )
+            .expect("WAV");
+
+            let converted = convert(&source, &scratch.file("out.flac"), AudioFormat::Wav, &[])
+                .expect("verified");
+            assert!(converted.is_none(), "{bits} bits");
+        }
+    }
+
+    #[test]
+    fn verifies_against_the_source_it_is_given() {
+        let scratch = Scratch::new();
+        let input = Signal::Triangle.generate(2, 16, frames());
+        let source = scratch.file("source.wav");
+        let flac = scratch.file("source.flac");
+        std::fs::write(&source, container(AudioFormat::Wav, &input, 16)).expect("WAV");
+        convert(&source, &flac, AudioFormat::Wav, &[])
+            .expect("converted")
+            .expect("smaller");
+        verify(&source, AudioFormat::Wav, &flac, 16).expect("the FLAC matches its source");
+
+        let mut changed = input.clone();
+        changed[1][5000] += 1;
+        let mut shorter = input.clone();
+        let mut longer = input;
+        for channel in &mut shorter {
+            channel.pop();
+        }
+        for channel in &mut longer {
+            channel.push(0);
+        }
+        for (name, other, error) in [
+            (
+                "changed",
+                changed,
+                "FLAC differs from the original at frame 5000",
+            ),
+            ("shorter", shorter, "FLAC is longer than the original"),
+            ("longer", longer, "FLAC is shorter than the original"),
+        ] {
+            let other_source = scratch.file(&format!("{name}.wav"));
+            std::fs::write(&other_source, container(AudioFormat::Wav, &other, 16)).expect("WAV");
+            let err = verify(&other_source, AudioFormat::Wav, &flac, 16).expect_err(name);
+            assert_eq!(err.to_string(), error, "{name}");
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Checks the metadata given with an upload against the audio itself.
+//!
+//! Follows "Metadata Validation" in UPLOAD-SPEC.md: durations must be within a
+//! second of the file's, file names must not claim another format, and track
+//! numbers must be unique within the album. Every problem is reported at once
+//! so the uploader can fix them in one go.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::handlers::ApiError;
+
+use super::format::AudioFormat;
+
+/// Seconds a given duration may differ from the measured one.
+const DURATION_TOLERANCE: u32 = 1;
+
+/// One field of one track that doesn't match the file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationIssue {
+    /// File name of the track.
+    pub track: String,
+    pub field: &'static str,
+    /// What the metadata says.
+    pub expected: Value,
+    /// What the file, or the album, says.
+    pub actual: Value,
+    pub message: String,
+}
+
+/// An uploaded file as it was described and as it turned out to be.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackCheck<'a> {
+    pub file_name: &'a str,
+    /// Detected from the contents.
+    pub format: AudioFormat,
+    /// Duration given by the uploader, in seconds.
+    pub duration: Option<i32>,
+    /// Duration measured from the audio, in seconds.
+    pub measured_duration: Option<i32>,
+    /// The track number the track will get, from whichever source.
+    pub track_number: Option<i32>,
+}
+
+/// Checks `tracks`, which are going into an album that already has the
+/// `(track_number, title)` pairs in `existing`. Fails with `422` listing every
+/// issue found.
+pub fn check(tracks: &[TrackCheck<'_>], existing: &[(i32, String)]) -> Result<(), ApiError> {
+    let mut issues = Vec::new();
+    let mut numbered: HashMap<i32, &str> = HashMap::new();
+
+    for track in tracks {
+        if let Some(issue) = check_format(track) {
+            issues.push(issue);
+        }
+        if let Some(issue) = check_duration(track) {
+            issues.push(issue);
+        }
+
+        let Some(number) = track.track_number else {
+            continue;
+        };
+        let taken_by = existing
+            .iter()
+            .find(|(existing, _)| *existing == number)
+            .map(|(_, title)| format!("\"{title}\", already in the album"))
+            .or_else(|| {
+                numbered
+                    .get(&number)
+                    .map(|file_name| (*file_name).to_string())
+            });
+        match taken_by {
+            Some(taken_by) => issues.push(ValidationIssue {
+                track: track.file_name.to_string(),
+                field: "track_number",
+                expected: json!("unique"),
+                actual: json!(number),
+                message: format!("Duplicate track number: {number} is also used by {taken_by}"),
+            }),
+            None => {
+                numbered.insert(number, track.file_name);
+            }
+        }
+    }
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::validation_failed(&issues))
+    }
+}
+
+/// A file named `.mp3` has to be MP3. Names without a known audio extension
+/// claim nothing.
+fn check_format(track: &TrackCheck<'_>) -> Option<ValidationIssue> {
+    let claimed = Path::new(track.file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(AudioFormat::from_extension)?;
+    if claimed == track.format {
+        return None;
+    }
+
+    Some(ValidationIssue {
+        track: track.file_name.to_string(),
+        field: "format",
+        expected: json!(claimed.extension()),
+        actual: json!(track.format.extension()),
+        message: format!(
+            "Format mismatch: file is {}, its name says {}",
+            track.format.name(),
+            claimed.name()
+        ),
+    })
+}
+
+/// A given duration has to be positive and within a second of the file's.
+fn check_duration(track: &TrackCheck<'_>) -> Option<ValidationIssue> {
+    let given = track.duration?;
+    if given <= 0 {
+        return Some(ValidationIssue {
+            track: track.file_name.to_string(),
+            field: "duration",
+            expected: json!(given),
+            actual: json!(track.measured_duration),
+            message: format!("Invalid duration: {given}, it must be a positive number of seconds"),
+        });
+    }
+    let measured = track.measured_duration?;
+    if given.abs_diff(measured) <= DURATION_TOLERANCE {
+        return None;
+    }
+
+    Some(ValidationIssue {
+        track: track.file_name.to_string(),
+        field: "duration",
+        expected: json!(given),
+        actual: json!(measured),
+        message: format!(
+            "Duration mismatch: file is {}, metadata says {}",
+            minutes(measured),
+            minutes(given)
+        ),
+    })
+}
+
+/// `245` gives `4:05`.
+fn minutes(seconds: i32) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body, http::StatusCode, response::IntoResponse};
+
+    use super::*;
+
+    const fn track(file_name: &str) -> TrackCheck<'_> {
+        TrackCheck {
+            file_name,
+            format: AudioFormat::Flac,
+            duration: None,
+            measured_duration: None,
+            track_number: None,
+        }
+    }
+
+    /// The issues `check` reports, as `(track, field)`.
+    async fn issues(
+        tracks: &[TrackCheck<'_>],
+        existing: &[(i32, String)],
+    ) -> Vec<(String, String)> {
+        let Err(err) = check(tracks, existing) else {
+            return Vec::new();
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body");
+        let body: Value = serde_json::from_slice(&bytes).expect("json");
+        body["issues"]
+            .as_array()
+            .expect("issues")
+            .iter()
+            .map(|issue| {
+                let field = |name: &str| issue[name].as_str().expect(name).to_string();
+                (field("track"), field("field"))
+            })
+            .collect()
+    }
+
+    fn found(track: &str, field: &str) -> Vec<(String, String)> {
+        vec![(track.to_string(), field.to_string())]
+    }
+
+    #[tokio::test]
+    async fn allows_a_second_either_way() {
+        for given in [179, 180, 181] {
+            let checked = TrackCheck {
+                duration: Some(given),
+                measured_duration: Some(180),
+                ..track("a.flac")
+            };
+            assert!(issues(&[checked], &[]).await.is_empty(), "{given}");
+        }
+        for given in [178, 182, i32::MAX] {
+            let checked = TrackCheck {
+                duration: Some(given),
+                measured_duration: Some(180),
+                ..track("a.flac")
+            };
+            assert_eq!(
+                issues(&[checked], &[]).await,
+                found("a.flac", "duration"),
+                "{given}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_durations_that_are_not_positive() {
+        for given in [0, -1, i32::MIN] {
+            for measured in [None, Some(180)] {
+                let checked = TrackCheck {
+                    duration: Some(given),
+                    measured_duration: measured,
+                    ..track("a.flac")
+                };
+                assert_eq!(
+                    issues(&[checked], &[]).await,
+                    found("a.flac", "duration"),
+                    "{given}"
+                );
+            }
+        }
+        // Nothing to compare against.
+        let unmeasured = TrackCheck {
+            duration: Some(180),
+            ..track("a.flac")
+        };
+        assert!(issues(&[unmeasured], &[]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn names_must_not_claim_another_format() {
+        let mp3 = TrackCheck {
+            format: AudioFormat::Mp3,
+            ..track("song.MP3")
+        };
+        assert!(issues(&[mp3], &[]).await.is_empty());
+        let aiff = TrackCheck {
+            format: AudioFormat::Aiff,
+            ..track("song.aif")
+        };
+        assert!(issues(&[aiff], &[]).await.is_empty());
+        // No audio extension claims nothing.
+        assert!(issues(&[track("song"), track("song.bin")], &[])
+            .await
+            .is_empty());
+
+        assert_eq!(
+            issues(&[track("song.mp3")], &[]).await,
+            found("song.mp3", "format")
+        );
+    }
+
+    #[tokio::test]
+    async fn track_numbers_are_unique_in_the_album() {
+        let numbered = |file_name, number| TrackCheck {
+            track_number: Some(number),
+            ..track(file_name)
+        };
+        assert!(issues(
+            &[
+                numbered("1.flac", 1),
+                numbered("2.flac", 2),
+                track("x.flac")
+            ],
+            &[]
+        )
+        .await
+        .is_empty());
+
+        // The second file to use a number is the one reported.
+        assert_eq!(
+            issues(&[numbered("1.flac", 1), numbered("also-1.flac", 1)], &[]).await,
+            found("also-1.flac", "track_number")
+        );
+
+        let existing = [(3, "Already There".to_string())];
+        assert_eq!(
+            issues(&[numbered("3.flac", 3)], &existing).await,
+            found("3.flac", "track_number")
+        );
+        assert!(issues(&[numbered("4.flac", 4)], &existing).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_every_issue_at_once() {
+        let tracks = [
+            TrackCheck {
+                duration: Some(10),
+                measured_duration: Some(200),
+                track_number: Some(1),
+                ..track("a.mp3")
+            },
+            TrackCheck {
+                track_number: Some(1),
+                ..track("b.flac")
+            },
+        ];
+        assert_eq!(
+            issues(&tracks, &[]).await,
+            vec![
+                ("a.mp3".to_string(), "format".to_string()),
+                ("a.mp3".to_string(), "duration".to_string()),
+                ("b.flac".to_string(), "track_number".to_string()),
+            ]
+        );
+    }
+}
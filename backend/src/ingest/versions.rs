@@ -0,0 +1,332 @@
+//! Album versions made by uploading to an album that already has tracks.
+//!
+//! Every version records the album's tracks as they were, so re-uploads and
+//! single-track replacements keep a history that can be compared and rolled
+//! back. A replaced track keeps its id, so playlists follow it to the new
+//! audio, while the version it came from still points at the old file.
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::models::{AlbumVersionTrack, Track};
+
+use super::manifest::base_name;
+
+/// What an album upload does to an album that already has tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadMode {
+    /// The uploaded tracks replace their counterparts; the rest stay.
+    Update,
+    /// The upload is the whole album; tracks it leaves out are dropped.
+    NewVersion,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReleaseStatus {
+    Draft,
+    #[default]
+    Released,
+}
+
+impl ReleaseStatus {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Draft => "draft",
+            Self::Released => "released",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status.trim().to_ascii_lowercase().as_str() {
+            "draft" => Some(Self::Draft),
+            "released" => Some(Self::Released),
+            _ => None,
+        }
+    }
+}
+
+/// Pairs uploaded files with the current tracks they replace: the track with
+/// the same number, else the one uploaded under the same file name. Each
+/// track is replaced at most once. `files` are `(file_name, track_number)`.
+pub fn match_replaced<'a>(
+    current: &'a [Track],
+    files: &[(&str, Option<i32>)],
+) -> Vec<Option<&'a Track>> {
+    let mut claimed: Vec<&str> = Vec::new();
+    files
+        .iter()
+        .map(|(file_name, track_number)| {
+            let free = |track: &&Track| !claimed.contains(&track.id.as_str());
+            let by_number = track_number.and_then(|number| {
+                current
+                    .iter()
+                    .filter(free)
+                    .find(|track| track.track_number == Some(number))
+            });
+            let found = by_number.or_else(|| {
+                current.iter().filter(free).find(|track| {
+                    track.original_filename.as_deref().is_some_and(|name| {
+                        base_name(name).eq_ignore_ascii_case(base_name(file_name))
+                    })
+                })
+            });
+            if let Some(track) = found {
+                claimed.push(&track.id);
+            }
+            found
+        })
+        .collect()
+}
+
+/// One field of a track that differs between two versions.
+#[derive(Debug, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub from: serde_json::Value,
+    pub to: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangedTrack {
+    pub track_id: String,
+    /// The title in the newer version.
+    pub title: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// How the tracks of one version differ from another's.
+#[derive(Debug, Serialize)]
+pub struct VersionDiff {
+    pub added: Vec<AlbumVersionTrack>,
+    pub removed: Vec<AlbumVersionTrack>,
+    pub changed: Vec<ChangedTrack>,
+    pub unchanged: usize,
+}
+
+/// Compares the tracks of version `from` with those of version `to`, matching
+/// them by track id. A changed `file_path` means the audio was replaced.
+pub fn diff(from: Vec<AlbumVersionTrack>, to: Vec<AlbumVersionTrack>) -> VersionDiff {
+    let mut removed = from;
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    let mut unchanged = 0;
+    for track in to {
+        let Some(index) = removed
+            .iter()
+            .position(|old| old.track_id == track.track_id)
+        else {
+            added.push(track);
+            continue;
+        };
+        let old = removed.swap_remove(index);
+        let fields = field_changes(&old, &track);
+        if fields.is_empty() {
+            unchanged += 1;
+        } else {
+            changed.push(ChangedTrack {
+                track_id: track.track_id,
+                title: track.title,
+                changes: fields,
+            });
+        }
+    }
+    removed.sort_by_key(|track| track.track_number);
+
+    VersionDiff {
+        added,
+        removed,
+        changed,
+        unchanged,
+    }
+}
+
+fn field_changes(old: &AlbumVersionTrack, new: &AlbumVersionTrack) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    let mut compare = |field: &'static str, from: serde_json::Value, to: serde_json::Value| {
+        if from != to {
+            changes.push(FieldChange { field, from, to });
+        }
+    };
+    compare("title", old.title.clone().into(), new.title.clone().into());
+    compare(
+        "track_number",
+        old.track_number.into(),
+        new.track_number.into(),
+    );
+    compare("duration", old.duration.into(), new.duration.into());
+    compare(
+        "file_path",
+        old.file_path.clone().into(),
+        new.file_path.clone().into(),
+    );
+    compare(
+        "original_filename",
+        old.original_filename.clone().into(),
+        new.original_filename.clone().into(),
+    );
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn track(id: &str, number: Option<i32>, file_name: &str) -> Track {
+        let now = Utc::now();
+        Track {
+            id: id.to_string(),
+            title: format!("Song {id}"),
+            artist: "Artist".to_string(),
+            artist_id: None,
+            album: "Album".to_string(),
+            album_id: None,
+            duration: 180,
+            file_path: format!("music/{file_name}"),
+            original_filename: Some(file_name.to_string()),
+            original_format: None,
+            stored_format: None,
+            cover_art_path: None,
+            genre: None,
+            year: None,
+            track_number: number,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn version_track(id: &str, number: i32, file_path: &str) -> AlbumVersionTrack {
+        AlbumVersionTrack {
+            track_id: id.to_string(),
+            title: format!("Song {id}"),
+            track_number: Some(number),
+            duration: 180,
+            file_path: file_path.to_string(),
+            original_filename: None,
+        }
+    }
+
+    fn ids(matched: &[Option<&Track>]) -> Vec<Option<String>> {
+        matched
+            .iter()
+            .map(|track| track.map(|track| track.id.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn replaces_by_number_then_by_file_name() {
+        let current = [
+            track("a", Some(1), "01-intro.flac"),
+            track("b", Some(2), "02-song.flac"),
+            track("c", None, "bonus.flac"),
+        ];
+        let matched = match_replaced(
+            &current,
+            &[
+                ("new-name.flac", Some(2)),
+                ("extra/BONUS.flac", None),
+                ("01-intro.flac", Some(9)),
+                ("unknown.flac", None),
+            ],
+        );
+        assert_eq!(
+            ids(&matched),
+            [
+                Some("b".to_string()),
+                Some("c".to_string()),
+                Some("a".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn replaces_each_track_once() {
+        let current = [
+            track("a", Some(1), "song.flac"),
+            track("b", Some(1), "other.flac"),
+        ];
+        // Two files numbered 1 claim both tracks numbered 1, a third gets none.
+        let matched = match_replaced(
+            &current,
+            &[
+                ("x.flac", Some(1)),
+                ("y.flac", Some(1)),
+                ("z.flac", Some(1)),
+            ],
+        );
+        assert_eq!(
+            ids(&matched),
+            [Some("a".to_string()), Some("b".to_string()), None]
+        );
+
+        // The same file name twice only replaces its track the first time.
+        let matched = match_replaced(&current, &[("song.flac", None), ("song.flac", None)]);
+        assert_eq!(ids(&matched), [Some("a".to_string()), None]);
+    }
+
+    #[test]
+    fn diffs_versions_by_track_id() {
+        let from = vec![
+            version_track("kept", 1, "a.flac"),
+            version_track("replaced", 2, "b.flac"),
+            version_track("dropped-late", 4, "d.flac"),
+            version_track("dropped", 3, "c.flac"),
+        ];
+        let to = vec![
+            version_track("kept", 1, "a.flac"),
+            version_track("replaced", 2, "b-remaster.flac"),
+            version_track("new", 5, "e.flac"),
+        ];
+        let diff = diff(from, to);
+
+        assert_eq!(diff.unchanged, 1);
+        let added: Vec<&str> = diff
+            .added
+            .iter()
+            .map(|track| track.track_id.as_str())
+            .collect();
+        assert_eq!(added, ["new"]);
+        // Removed tracks come in album order.
+        let removed: Vec<&str> = diff
+            .removed
+            .iter()
+            .map(|track| track.track_id.as_str())
+            .collect();
+        assert_eq!(removed, ["dropped", "dropped-late"]);
+
+        assert_eq!(diff.changed.len(), 1);
+        let changed = &diff.changed[0];
+        assert_eq!(changed.track_id, "replaced");
+        assert_eq!(changed.changes.len(), 1);
+        assert_eq!(changed.changes[0].field, "file_path");
+        assert_eq!(changed.changes[0].from, "b.flac");
+        assert_eq!(changed.changes[0].to, "b-remaster.flac");
+    }
+
+    #[test]
+    fn lists_every_changed_field() {
+        let old = version_track("t", 1, "a.flac");
+        let new = AlbumVersionTrack {
+            title: "Renamed".to_string(),
+            track_number: Some(2),
+            duration: 181,
+            original_filename: Some("a.flac".to_string()),
+            ..old.clone()
+        };
+        let diff = diff(vec![old], vec![new]);
+        let fields: Vec<&str> = diff.changed[0]
+            .changes
+            .iter()
+            .map(|change| change.field)
+            .collect();
+        assert_eq!(
+            fields,
+            ["title", "track_number", "duration", "original_filename"]
+        );
+        assert_eq!(diff.changed[0].title, "Renamed");
+    }
+}
@@ -0,0 +1,56 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::{build_message, EmailMessage, Mailer};
+
+/// Writes every message to its own `.eml` file, for development and tests.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str, from: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            from: from.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, message: &EmailMessage) -> anyhow::Result<()> {
+        let email = build_message(&self.from, message)?;
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // Timestamp first so a directory listing is in send order.
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            Uuid::new_v4()
+        ));
+        tokio::fs::write(&path, email.formatted()).await?;
+
+        tracing::info!("Wrote email to {} at {}", message.to, path.display());
+        Ok(())
+    }
+}
+
+/// Only logs messages, including their body, so never use it in production.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, message: &EmailMessage) -> anyhow::Result<()> {
+        tracing::info!(
+            "Email to {}: {}\n{}",
+            message.to,
+            message.subject,
+            message.body
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! Outgoing email behind the [`Mailer`] trait.
+
+mod file;
+mod smtp;
+
+use async_trait::async_trait;
+use lettre::{message::header::ContentType, Message};
+use std::sync::Arc;
+
+use crate::config::{MailConfig, MailTransport};
+
+pub use file::{FileMailer, LogMailer};
+pub use smtp::SmtpMailer;
+
+/// A plain-text email.
+#[derive(Debug, Clone)]
+pub struct EmailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, message: &EmailMessage) -> anyhow::Result<()>;
+}
+
+/// Builds the mailer selected by `MAIL_TRANSPORT`.
+pub fn from_config(config: &MailConfig) -> anyhow::Result<Arc<dyn Mailer>> {
+    let mailer: Arc<dyn Mailer> = match config.transport {
+        MailTransport::Smtp => {
+            let smtp = config
+                .smtp
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("MAIL_TRANSPORT=smtp requires SMTP_HOST"))?;
+            Arc::new(SmtpMailer::new(smtp, &config.from)?)
+        }
+        MailTransport::File => Arc::new(FileMailer::new(&config.file_dir, &config.from)),
+        MailTransport::Log => Arc::new(LogMailer),
+    };
+
+    Ok(mailer)
+}
+
+/// Renders `message` as an RFC 5322 message from `from`.
+fn build_message(from: &str, message: &EmailMessage) -> anyhow::Result<Message> {
+    Ok(Message::builder()
+        .from(from.parse()?)
+        .to(message.to.parse()?)
+        .subject(&message.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(message.body.clone())?)
+}
@@ -1,14 +1,23 @@
 use anyhow::Result;
-use axum::{Json, Router, routing::{get, post, delete}};
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, patch, post, put},
+    Json, Router,
+};
 use serde_json::json;
 use std::{net::SocketAddr, sync::Arc};
 use tower_http::cors::CorsLayer;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod audit;
+mod auth;
 mod config;
 mod db;
 mod handlers;
+mod ingest;
+mod mail;
 mod storage;
 
 use config::Config;
@@ -19,6 +28,34 @@ pub struct AppState {
     pub config: Config,
     pub db: DbPool,
     pub storage: R2Storage,
+    pub oauth: auth::oauth::OAuthProviders,
+    pub mailer: Arc<dyn mail::Mailer>,
+    pub upload_queue: ingest::jobs::UploadQueue,
+    pub filename_parser: ingest::filename::FilenameParser,
+    /// Library scans, one at a time.
+    pub library_scans: ingest::library::LibraryScans,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for tests over `db` and `storage`, with [`Config::for_tests`].
+    pub fn for_tests(db: DbPool, storage: R2Storage) -> Self {
+        let config = Config::for_tests();
+        Self {
+            oauth: auth::oauth::OAuthProviders::from_config(&config.oauth)
+                .expect("login providers"),
+            mailer: mail::from_config(&config.mail).expect("mailer"),
+            upload_queue: ingest::jobs::UploadQueue::default(),
+            filename_parser: ingest::filename::FilenameParser::new(
+                &config.upload.filename_patterns,
+            )
+            .expect("file name patterns"),
+            library_scans: ingest::library::LibraryScans::default(),
+            config,
+            db,
+            storage,
+        }
+    }
 }
 
 #[tokio::main]
@@ -37,6 +74,10 @@ async fn main() -> Result<()> {
     let db = db::create_pool(&config.database.url).await?;
     info!("Connected to database");
 
+    if let Some(seed) = &config.auth.seed_admin {
+        auth::admin::ensure_seed_admin(&db, seed).await?;
+    }
+
     let access_key_id = std::env::var("R2_ACCESS_KEY_ID")
         .unwrap_or_else(|_| "dev-access-key".to_string());
     let secret_access_key = std::env::var("R2_SECRET_ACCESS_KEY")
@@ -47,47 +88,282 @@ async fn main() -> Result<()> {
         config.storage.endpoint_url.clone(),
         access_key_id,
         secret_access_key,
-    )
-    .await?;
+    );
     info!("Initialized R2 storage");
 
+    let oauth = auth::oauth::OAuthProviders::from_config(&config.oauth)?;
+    if !config.oauth.providers.is_empty() {
+        info!(
+            "Enabled login providers: {}",
+            oauth.names().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let mailer = mail::from_config(&config.mail)?;
+    info!(
+        "Sending email with the {:?} transport",
+        config.mail.transport
+    );
+
+    let filename_parser = ingest::filename::FilenameParser::new(&config.upload.filename_patterns)
+        .map_err(|err| anyhow::anyhow!("Invalid FILENAME_PATTERNS: {err}"))?;
+
     let app_state = Arc::new(AppState {
         config: config.clone(),
         db,
         storage,
+        oauth,
+        mailer,
+        upload_queue: ingest::jobs::UploadQueue::default(),
+        filename_parser,
+        library_scans: ingest::library::LibraryScans::default(),
     });
 
+    auth::session::spawn_cleanup_task(
+        app_state.db.clone(),
+        config.auth.session_cleanup_interval_secs,
+    );
+    auth::rate_limit::spawn_cleanup_task(
+        app_state.db.clone(),
+        &config.rate_limit,
+        config.auth.session_cleanup_interval_secs,
+    );
+
+    ingest::jobs::spawn_workers(&app_state, config.upload.workers);
+    ingest::chunked::spawn_cleanup_task(&app_state, config.upload.chunked_cleanup_interval_secs);
+    ingest::library::spawn_watcher(&app_state);
+    ingest::fingerprint::spawn_backfill_task(&app_state, config.auth.session_cleanup_interval_secs);
+
     let app = create_router(app_state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config.server.port));
     info!("Server running on http://{}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
 
+// Every route in one place is easier to scan than the table split up.
+#[allow(clippy::too_many_lines)]
 fn create_router(state: Arc<AppState>) -> Router {
-    Router::new()
+    let public = Router::new()
         .route("/", get(|| async { "Navicore Music API" }))
         .route("/health", get(health_check))
         .route("/api/v1/tracks", get(handlers::tracks::list_tracks))
-        .route("/api/v1/tracks", post(handlers::tracks::create_track))
-        .route("/api/v1/tracks/:id", get(handlers::tracks::get_track))
-        .route("/api/v1/tracks/:id", delete(handlers::tracks::delete_track))
-        .route("/api/v1/tracks/:id/stream", get(handlers::stream::get_stream_url))
-        .route("/api/v1/tracks/:id/play", post(handlers::stream::record_play))
+        .route("/api/v1/tracks/{id}", get(handlers::tracks::get_track))
+        .route("/api/v1/tracks/{id}/stream", get(handlers::stream::get_stream_url))
+        .route("/api/v1/tracks/{id}/play", post(handlers::stream::record_play))
         .route("/api/v1/playlists", get(handlers::playlists::list_playlists))
-        .route("/api/v1/playlists", post(handlers::playlists::create_playlist))
-        .route("/api/v1/playlists/:id", get(handlers::playlists::get_playlist))
-        .route("/api/v1/playlists/:id/tracks", post(handlers::playlists::add_track_to_playlist))
-        .route("/api/v1/playlists/:id/tracks/:track_id", delete(handlers::playlists::remove_track_from_playlist))
+        .route("/api/v1/playlists/{id}", get(handlers::playlists::get_playlist))
+        .route("/api/v1/artists", get(handlers::artists::list_artists))
+        .route("/api/v1/artists/{id}", get(handlers::artists::get_artist))
         .route("/api/v1/auth/login", post(handlers::auth::login))
+        .route("/api/v1/auth/login/mfa", post(handlers::auth::login_mfa))
+        .route("/api/v1/auth/register", post(handlers::auth::register))
+        .route(
+            "/api/v1/auth/verify-email/confirm",
+            post(handlers::account::confirm_email_verification),
+        )
+        .route(
+            "/api/v1/auth/password-reset",
+            post(handlers::account::request_password_reset),
+        )
+        .route(
+            "/api/v1/auth/password-reset/confirm",
+            post(handlers::account::confirm_password_reset),
+        )
+        .route(
+            "/api/v1/auth/oauth/providers",
+            get(handlers::oauth::list_providers),
+        )
+        .route(
+            "/api/v1/auth/oauth/{provider}/authorize",
+            get(handlers::oauth::authorize),
+        )
+        .route(
+            "/api/v1/auth/oauth/{provider}/callback",
+            get(handlers::oauth::callback),
+        );
+
+    let authenticated = Router::new()
+        .route("/api/v1/tracks", post(handlers::tracks::create_track))
+        .route("/api/v1/tracks/{id}", patch(handlers::tracks::update_track))
+        .route(
+            "/api/v1/tracks/{id}",
+            delete(handlers::tracks::delete_track),
+        )
+        .route(
+            "/api/v1/upload/file",
+            post(handlers::upload::upload_file).layer(DefaultBodyLimit::max(upload_body_limit(
+                state.config.upload.max_file_bytes,
+            ))),
+        )
+        .route(
+            "/api/v1/upload/album",
+            post(handlers::upload::upload_album).layer(DefaultBodyLimit::max(upload_body_limit(
+                state.config.upload.max_zip_bytes,
+            ))),
+        )
+        .route(
+            "/api/v1/upload/status/{upload_id}",
+            get(handlers::upload::upload_status),
+        )
+        .route(
+            "/api/v1/upload/chunked",
+            post(handlers::chunked_upload::start_upload),
+        )
+        .route(
+            "/api/v1/upload/chunked/{upload_id}",
+            get(handlers::chunked_upload::get_upload),
+        )
+        .route(
+            "/api/v1/upload/chunked/{upload_id}",
+            delete(handlers::chunked_upload::abort_upload),
+        )
+        .route(
+            "/api/v1/upload/chunked/{upload_id}/parts/{part_number}",
+            put(handlers::chunked_upload::upload_part).layer(DefaultBodyLimit::max(
+                usize::try_from(ingest::chunked::MAX_PART_BYTES).unwrap_or(usize::MAX),
+            )),
+        )
+        .route(
+            "/api/v1/upload/chunked/{upload_id}/complete",
+            post(handlers::chunked_upload::complete_upload),
+        )
+        .route(
+            "/api/v1/tracks/{id}/audio",
+            put(handlers::upload::replace_track_audio).layer(DefaultBodyLimit::max(
+                upload_body_limit(state.config.upload.max_file_bytes),
+            )),
+        )
+        .route(
+            "/api/v1/albums/{id}/versions",
+            get(handlers::albums::list_versions),
+        )
+        .route(
+            "/api/v1/albums/{id}/versions/{version_id}",
+            get(handlers::albums::get_version),
+        )
+        .route(
+            "/api/v1/albums/{id}/diff",
+            get(handlers::albums::diff_versions),
+        )
+        .route(
+            "/api/v1/albums/{id}/metadata",
+            get(handlers::albums::export_metadata),
+        )
+        .route(
+            "/api/v1/albums/{id}/download",
+            get(handlers::album_download::download_album),
+        )
+        .route(
+            "/api/v1/albums/{id}/current-version",
+            put(handlers::albums::set_current_version),
+        )
+        .route("/api/v1/artists", post(handlers::artists::create_artist))
+        .route("/api/v1/artists/{id}/members", get(handlers::artists::list_members))
+        .route("/api/v1/artists/{id}/members", post(handlers::artists::set_member))
+        .route("/api/v1/artists/{id}/members/{user_id}", delete(handlers::artists::remove_member))
+        .route("/api/v1/playlists", post(handlers::playlists::create_playlist))
+        .route("/api/v1/playlists/{id}/tracks", post(handlers::playlists::add_track_to_playlist))
+        .route("/api/v1/playlists/{id}/tracks/{track_id}", delete(handlers::playlists::remove_track_from_playlist))
+        .route("/api/v1/auth/logout", post(handlers::auth::logout))
+        .route(
+            "/api/v1/auth/verify-email",
+            post(handlers::account::request_email_verification),
+        )
+        .route("/api/v1/auth/2fa", get(handlers::mfa::get_status))
+        .route("/api/v1/auth/2fa/enroll", post(handlers::mfa::enroll))
+        .route("/api/v1/auth/2fa/activate", post(handlers::mfa::activate))
+        .route(
+            "/api/v1/auth/2fa/recovery-codes",
+            post(handlers::mfa::regenerate_recovery_codes),
+        )
+        .route("/api/v1/auth/2fa/disable", post(handlers::mfa::disable))
+        .route(
+            "/api/v1/auth/sessions",
+            get(handlers::sessions::list_sessions),
+        )
+        .route(
+            "/api/v1/auth/sessions/{id}",
+            delete(handlers::sessions::revoke_session),
+        )
+        .route(
+            "/api/v1/auth/api-keys",
+            get(handlers::api_keys::list_api_keys),
+        )
+        .route(
+            "/api/v1/auth/api-keys",
+            post(handlers::api_keys::create_api_key),
+        )
+        .route(
+            "/api/v1/auth/api-keys/{id}",
+            delete(handlers::api_keys::revoke_api_key),
+        )
+        .route(
+            "/api/v1/auth/oauth/identities",
+            get(handlers::oauth::list_identities),
+        )
+        .route(
+            "/api/v1/auth/oauth/identities/{provider}",
+            delete(handlers::oauth::unlink_identity),
+        )
+        .route("/api/v1/history", get(handlers::history::list_history))
+        .route("/api/v1/history", delete(handlers::history::clear_history))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ));
+
+    let admin = Router::new()
+        .route("/api/v1/admin/users", get(handlers::admin::list_users))
+        .route(
+            "/api/v1/admin/users/{id}/admin",
+            put(handlers::admin::set_user_admin),
+        )
+        .route("/api/v1/admin/audit", get(handlers::admin::list_audit_log))
+        .route(
+            "/api/v1/admin/library/scan",
+            get(handlers::admin::library_scan_status),
+        )
+        .route(
+            "/api/v1/admin/library/scan",
+            post(handlers::admin::scan_library),
+        )
+        .route(
+            "/api/v1/admin/duplicates",
+            get(handlers::admin::list_duplicates),
+        )
+        .route(
+            "/api/v1/admin/duplicates/merge",
+            post(handlers::admin::merge_duplicates),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_admin,
+        ));
+
+    Router::new()
+        .merge(public)
+        .merge(authenticated)
+        .merge(admin)
         .layer(CorsLayer::permissive())
         .with_state(state)
 }
 
+/// Room for the metadata field and multipart framing on top of the file itself.
+fn upload_body_limit(max_file_bytes: u64) -> usize {
+    const FORM_OVERHEAD_BYTES: u64 = 1024 * 1024;
+
+    usize::try_from(max_file_bytes.saturating_add(FORM_OVERHEAD_BYTES)).unwrap_or(usize::MAX)
+}
+
 async fn health_check() -> Json<serde_json::Value> {
     Json(json!({
         "status": "healthy",
@@ -0,0 +1,171 @@
+//! An in-memory bucket served over HTTP the way the S3 API serves objects,
+//! for tests that go through [`R2Storage`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use super::R2Storage;
+
+/// Objects by key.
+#[derive(Clone, Default)]
+pub struct MockBucket(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+impl MockBucket {
+    pub fn with<'a>(objects: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Self {
+        let bucket = Self::default();
+        for (key, data) in objects {
+            bucket.objects().insert(key.to_string(), data.to_vec());
+        }
+        bucket
+    }
+
+    /// Every key in the bucket, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.objects().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// Storage for this bucket, served on a port of its own until the test's
+    /// runtime shuts down.
+    pub async fn serve(&self) -> R2Storage {
+        let app = Router::new()
+            .route(
+                "/{bucket}/{*key}",
+                get(get_object)
+                    .head(head_object)
+                    .put(put_object)
+                    .delete(delete_object),
+            )
+            .with_state(self.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener");
+        let addr = listener.local_addr().expect("address");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        R2Storage::new(
+            "bucket".to_string(),
+            Some(format!("http://{addr}")),
+            "key".to_string(),
+            "secret".to_string(),
+        )
+    }
+
+    fn objects(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{:08x}\"", crc32fast::hash(data))
+}
+
+async fn get_object(
+    State(bucket): State<MockBucket>,
+    Path((_bucket, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(data) = bucket.objects().get(&key).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // Storage is only ever asked for `bytes=start-end`.
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok()?.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .map(|(start, end)| {
+            let start: usize = start.parse().expect("range start");
+            let end: usize = end.parse().expect("range end");
+            start..(end + 1).min(data.len())
+        });
+    let Some(range) = range else {
+        return ([(header::ETAG, etag(&data))], data).into_response();
+    };
+
+    let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, data.len());
+    (
+        StatusCode::PARTIAL_CONTENT,
+        [
+            (header::CONTENT_RANGE, content_range),
+            (header::ETAG, etag(&data)),
+        ],
+        data[range].to_vec(),
+    )
+        .into_response()
+}
+
+async fn head_object(
+    State(bucket): State<MockBucket>,
+    Path((_bucket, key)): Path<(String, String)>,
+) -> Response {
+    let Some(data) = bucket.objects().get(&key).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    ([
+        (header::CONTENT_LENGTH, data.len().to_string()),
+        (header::ETAG, etag(&data)),
+    ],)
+        .into_response()
+}
+
+async fn put_object(
+    State(bucket): State<MockBucket>,
+    Path((_bucket, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let chunked = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|encoding| encoding.contains("aws-chunked"));
+    let data = if chunked {
+        dechunk(&body)
+    } else {
+        body.to_vec()
+    };
+    let tag = etag(&data);
+    bucket.objects().insert(key, data);
+
+    ([(header::ETAG, tag)], StatusCode::OK).into_response()
+}
+
+async fn delete_object(
+    State(bucket): State<MockBucket>,
+    Path((_bucket, key)): Path<(String, String)>,
+) -> StatusCode {
+    bucket.objects().remove(&key);
+    StatusCode::NO_CONTENT
+}
+
+/// The data of an `aws-chunked` body: `size;extensions\r\ndata\r\n` chunks up
+/// to an empty one, then trailing checksums.
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .expect("chunk size line");
+        let line = std::str::from_utf8(&body[..line_end]).expect("chunk size");
+        let size = line.split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size.trim(), 16).expect("hex chunk size");
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return data;
+        }
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
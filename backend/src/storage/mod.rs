@@ -1,21 +1,29 @@
 use aws_config::{BehaviorVersion, Region};
 use aws_sdk_s3::{Client, presigning::PresigningConfig};
 use aws_sdk_s3::config::{Credentials, Builder};
-use std::time::Duration;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart};
+use chrono::{DateTime, Utc};
+use std::{ops::Range, path::Path, time::Duration};
+use tokio::io::{AsyncBufRead, AsyncWriteExt};
 use anyhow::Result;
 
+#[cfg(test)]
+pub mod mock;
+pub mod zip_stream;
+
 pub struct R2Storage {
     client: Client,
     bucket_name: String,
 }
 
 impl R2Storage {
-    pub async fn new(
+    pub fn new(
         bucket_name: String,
         endpoint_url: Option<String>,
         access_key_id: String,
         secret_access_key: String,
-    ) -> Result<Self> {
+    ) -> Self {
         let credentials = Credentials::new(
             access_key_id,
             secret_access_key,
@@ -36,10 +44,10 @@ impl R2Storage {
         let config = config_builder.build();
         let client = Client::from_conf(config);
 
-        Ok(Self {
+        Self {
             client,
             bucket_name,
-        })
+        }
     }
 
     pub async fn generate_presigned_url(
@@ -81,6 +89,191 @@ impl R2Storage {
         Ok(())
     }
 
+    /// Uploads a file from disk without reading it into memory.
+    pub async fn upload_path(
+        &self,
+        key: &str,
+        path: &Path,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        let body = ByteStream::from_path(path).await?;
+        let mut request = self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .body(body);
+
+        if let Some(ct) = content_type {
+            request = request.content_type(ct);
+        }
+
+        request.send().await?;
+        Ok(())
+    }
+
+    /// Streams an object into `file`, returning the number of bytes written.
+    pub async fn download_to(&self, key: &str, file: &mut tokio::fs::File) -> Result<u64> {
+        let object = self.client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await?;
+
+        let mut body = object.body.into_async_read();
+        let written = tokio::io::copy(&mut body, file).await?;
+        file.flush().await?;
+
+        Ok(written)
+    }
+
+    /// Streams an object, or only the bytes in `range` of it.
+    pub async fn read(&self, key: &str, range: Option<Range<u64>>) -> Result<impl AsyncBufRead + Send + Unpin + use<>> {
+        let mut request = self.client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key);
+
+        if let Some(range) = range.filter(|range| !range.is_empty()) {
+            request = request.range(format!("bytes={}-{}", range.start, range.end - 1));
+        }
+
+        let object = request.send().await?;
+        Ok(object.body.into_async_read())
+    }
+
+    /// The size and `ETag` of an object.
+    pub async fn head(&self, key: &str) -> Result<(u64, Option<String>)> {
+        let output = self.client
+            .head_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await?;
+
+        let size = output
+            .content_length()
+            .and_then(|size| u64::try_from(size).ok())
+            .ok_or_else(|| anyhow::anyhow!("Storage returned no size for {key}"))?;
+
+        Ok((size, output.e_tag().map(str::to_string)))
+    }
+
+    /// Starts a multipart upload to `key` whose parts carry SHA-256
+    /// checksums, returning its upload id.
+    pub async fn create_multipart_upload(&self, key: &str, content_type: Option<&str>) -> Result<String> {
+        let mut request = self.client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .checksum_algorithm(ChecksumAlgorithm::Sha256);
+
+        if let Some(ct) = content_type {
+            request = request.content_type(ct);
+        }
+
+        let output = request.send().await?;
+        output
+            .upload_id()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Storage returned no upload id for {key}"))
+    }
+
+    /// Uploads one part, `checksum_sha256` being the base64 SHA-256 of `body`,
+    /// and returns its `ETag`.
+    pub async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: Vec<u8>,
+        checksum_sha256: &str,
+    ) -> Result<String> {
+        let output = self.client
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .checksum_sha256(checksum_sha256)
+            .body(body.into())
+            .send()
+            .await?;
+
+        output
+            .e_tag()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Storage returned no ETag for part {part_number} of {key}"))
+    }
+
+    /// Joins the parts, given as `(part_number, etag, checksum_sha256)` in
+    /// order, into the object at `key`.
+    pub async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String, String)],
+    ) -> Result<()> {
+        let parts = parts
+            .iter()
+            .map(|(part_number, etag, checksum)| {
+                CompletedPart::builder()
+                    .part_number(*part_number)
+                    .e_tag(etag)
+                    .checksum_sha256(checksum)
+                    .build()
+            })
+            .collect();
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Discards a multipart upload and the parts stored for it.
+    pub async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Result<()> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Unfinished multipart uploads under `prefix` as `(key, upload_id,
+    /// initiated)`, up to 1000 at a time.
+    pub async fn list_multipart_uploads(&self, prefix: &str) -> Result<Vec<(String, String, Option<DateTime<Utc>>)>> {
+        let output = self.client
+            .list_multipart_uploads()
+            .bucket(&self.bucket_name)
+            .prefix(prefix)
+            .send()
+            .await?;
+
+        let uploads = output
+            .uploads()
+            .iter()
+            .filter_map(|upload| {
+                let initiated = upload
+                    .initiated()
+                    .and_then(|initiated| DateTime::from_timestamp(initiated.secs(), 0));
+                Some((upload.key()?.to_string(), upload.upload_id()?.to_string(), initiated))
+            })
+            .collect();
+
+        Ok(uploads)
+    }
+
     pub async fn delete_file(&self, key: &str) -> Result<()> {
         self.client
             .delete_object()
@@ -0,0 +1,698 @@
+//! ZIP archives written straight from storage, for downloads.
+//!
+//! Entries are stored uncompressed, audio gaining little from deflate, so the
+//! archive's layout, and with it its length, follows from the entries' names
+//! and sizes alone. That lets any byte range of the archive be written on its
+//! own, reading only the parts of stored files that fall in it. The CRC-32 of
+//! an entry goes in a data descriptor after its data and in the central
+//! directory; ones not known beforehand are worked out while the file is
+//! read, and kept by the caller for next time.
+
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::R2Storage;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+/// Sizes in the data descriptor, UTF-8 names.
+const FLAGS: u16 = 0x0808;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Made on Unix, so the external attributes below are file modes.
+const MADE_BY_UNIX: u16 = 3 << 8;
+const FILE_MODE: u32 = 0o100_644 << 16;
+const ZIP64_EXTRA: u16 = 0x0001;
+const U32_MARKER: u32 = u32::MAX;
+
+pub enum EntryData {
+    /// An object in storage.
+    Stored { key: String, etag: Option<String> },
+    /// Generated for the download, e.g. a manifest.
+    Inline(Vec<u8>),
+}
+
+pub struct ZipEntry {
+    name: String,
+    modified: DateTime<Utc>,
+    size: u64,
+    crc32: Option<u32>,
+    /// Whether `crc32` was worked out while writing.
+    learned: bool,
+    data: EntryData,
+}
+
+impl ZipEntry {
+    pub fn inline(name: impl Into<String>, modified: DateTime<Utc>, data: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            modified,
+            size: data.len() as u64,
+            crc32: Some(crc32fast::hash(&data)),
+            learned: false,
+            data: EntryData::Inline(data),
+        }
+    }
+
+    /// A stored object of `size` bytes, with its CRC-32 if known.
+    pub fn stored(
+        name: impl Into<String>,
+        modified: DateTime<Utc>,
+        key: impl Into<String>,
+        size: u64,
+        etag: Option<String>,
+        crc32: Option<u32>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            modified,
+            size,
+            crc32,
+            learned: false,
+            data: EntryData::Stored {
+                key: key.into(),
+                etag,
+            },
+        }
+    }
+
+    /// Whether offsets or sizes of this entry need ZIP64 fields.
+    fn zip64(&self, offset: u64) -> bool {
+        self.size >= u64::from(U32_MARKER) || offset >= u64::from(U32_MARKER)
+    }
+}
+
+/// Where an entry's records start in the archive.
+#[derive(Debug, Clone, Copy)]
+struct Placement {
+    offset: u64,
+    zip64: bool,
+    header_len: u64,
+    descriptor_len: u64,
+}
+
+impl Placement {
+    const fn data_start(&self) -> u64 {
+        self.offset + self.header_len
+    }
+}
+
+pub struct ZipStream {
+    entries: Vec<ZipEntry>,
+    placements: Vec<Placement>,
+    central_offset: u64,
+    central_len: u64,
+    len: u64,
+}
+
+impl ZipStream {
+    pub fn new(entries: Vec<ZipEntry>) -> Self {
+        let mut offset = 0;
+        let mut placements = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            let zip64 = entry.zip64(offset);
+            let placement = Placement {
+                offset,
+                zip64,
+                header_len: local_header(entry, zip64).len() as u64,
+                descriptor_len: if zip64 { 24 } else { 16 },
+            };
+            offset = placement.data_start() + entry.size + placement.descriptor_len;
+            placements.push(placement);
+        }
+
+        let central_offset = offset;
+        let central_len = entries
+            .iter()
+            .zip(&placements)
+            .map(|(entry, placement)| central_header(entry, placement, 0).len() as u64)
+            .sum();
+        let end_len = end_records(entries.len(), central_offset, central_len).len() as u64;
+
+        Self {
+            entries,
+            placements,
+            central_offset,
+            central_len,
+            len: central_offset + central_len + end_len,
+        }
+    }
+
+    /// Length of the whole archive in bytes.
+    pub const fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Identifies this exact archive, for `ETag` and `If-Range`: the same
+    /// entries with the same contents always give the same bytes.
+    pub fn etag(&self) -> String {
+        let mut hasher = Sha256::new();
+        for entry in &self.entries {
+            hasher.update(entry.name.as_bytes());
+            hasher.update(entry.size.to_le_bytes());
+            hasher.update(entry.modified.timestamp().to_le_bytes());
+            match &entry.data {
+                EntryData::Stored { key, etag } => {
+                    hasher.update(key.as_bytes());
+                    hasher.update(etag.as_deref().unwrap_or_default().as_bytes());
+                }
+                EntryData::Inline(data) => hasher.update(data),
+            }
+        }
+
+        format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+    }
+
+    /// Checksums of stored files worked out by [`Self::write_range`], as
+    /// `(key, etag, crc32)`, including any from a write that failed part way.
+    pub fn learned(&self) -> impl Iterator<Item = (&str, &str, u32)> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.learned)
+            .filter_map(|entry| match &entry.data {
+                EntryData::Stored {
+                    key,
+                    etag: Some(etag),
+                } => Some((key.as_str(), etag.as_str(), entry.crc32?)),
+                _ => None,
+            })
+    }
+
+    /// Writes the bytes in `range` of the archive to `out`.
+    pub async fn write_range<W: AsyncWrite + Unpin>(
+        &mut self,
+        storage: &R2Storage,
+        range: Range<u64>,
+        out: &mut W,
+    ) -> Result<()> {
+        let mut sink = RangeSink { out, pos: 0, range };
+        // Checksums only matter to the records that hold them.
+        let central_wanted = sink.overlaps(self.central_offset, self.central_len);
+
+        for (entry, placement) in self.entries.iter_mut().zip(&self.placements) {
+            sink.put(&local_header(entry, placement.zip64)).await?;
+
+            let descriptor_start = placement.data_start() + entry.size;
+            let crc_needed =
+                central_wanted || sink.overlaps(descriptor_start, placement.descriptor_len);
+            write_data(storage, entry, crc_needed, &mut sink).await?;
+
+            if sink.overlaps(descriptor_start, placement.descriptor_len) {
+                sink.put(&data_descriptor(entry, placement.zip64)).await?;
+            } else {
+                sink.skip(placement.descriptor_len);
+            }
+        }
+
+        if central_wanted {
+            for (entry, placement) in self.entries.iter().zip(&self.placements) {
+                let crc32 = entry.crc32.unwrap_or_default();
+                sink.put(&central_header(entry, placement, crc32)).await?;
+            }
+        } else {
+            sink.skip(self.central_len);
+        }
+        sink.put(&end_records(
+            self.entries.len(),
+            self.central_offset,
+            self.central_len,
+        ))
+        .await?;
+        sink.out.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Writes an entry's data, or the part of it in range. A file whose checksum
+/// is needed but unknown is read whole, as is one wanted whole anyway.
+async fn write_data<W: AsyncWrite + Unpin>(
+    storage: &R2Storage,
+    entry: &mut ZipEntry,
+    crc_needed: bool,
+    sink: &mut RangeSink<'_, W>,
+) -> Result<()> {
+    let key = match &entry.data {
+        EntryData::Inline(data) => return Ok(sink.put(data).await?),
+        EntryData::Stored { key, .. } => key.clone(),
+    };
+    let wanted = sink.overlap(entry.size);
+    let whole = wanted.start == 0 && wanted.end == entry.size;
+
+    if entry.crc32.is_none() && (crc_needed || whole) {
+        let mut hasher = crc32fast::Hasher::new();
+        let read = copy(storage, &key, None, sink, Some(&mut hasher)).await?;
+        if read != entry.size {
+            bail!("{key} changed size while being archived");
+        }
+        entry.crc32 = Some(hasher.finalize());
+        entry.learned = true;
+    } else if wanted.is_empty() {
+        sink.skip(entry.size);
+    } else {
+        sink.skip(wanted.start);
+        let read = copy(storage, &key, Some(wanted.clone()), sink, None).await?;
+        if read != wanted.end - wanted.start {
+            bail!("{key} changed size while being archived");
+        }
+        sink.skip(entry.size - wanted.end);
+    }
+
+    Ok(())
+}
+
+/// Copies an object, or `range` of it, into the sink, returning the bytes read.
+async fn copy<W: AsyncWrite + Unpin>(
+    storage: &R2Storage,
+    key: &str,
+    range: Option<Range<u64>>,
+    sink: &mut RangeSink<'_, W>,
+    mut hasher: Option<&mut crc32fast::Hasher>,
+) -> Result<u64> {
+    let mut reader = storage.read(key, range).await?;
+    let mut read = 0;
+    loop {
+        let chunk = reader.fill_buf().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(chunk);
+        }
+        sink.put(chunk).await?;
+        let len = chunk.len();
+        reader.consume(len);
+        read += len as u64;
+    }
+
+    Ok(read)
+}
+
+/// Tracks the position in the archive, passing on only the bytes in range.
+struct RangeSink<'a, W> {
+    out: &'a mut W,
+    pos: u64,
+    range: Range<u64>,
+}
+
+impl<W: AsyncWrite + Unpin> RangeSink<'_, W> {
+    /// The part of the next `len` bytes in range, relative to the position.
+    fn overlap(&self, len: u64) -> Range<u64> {
+        let start = self.range.start.clamp(self.pos, self.pos + len) - self.pos;
+        let end = self.range.end.clamp(self.pos, self.pos + len) - self.pos;
+        start..end.max(start)
+    }
+
+    /// Whether any of the `len` bytes at `start` are in range.
+    const fn overlaps(&self, start: u64, len: u64) -> bool {
+        start < self.range.end && start + len > self.range.start
+    }
+
+    async fn put(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let wanted = self.overlap(bytes.len() as u64);
+        if !wanted.is_empty() {
+            // Both ends lie within `bytes`, whose length fits in usize.
+            #[allow(clippy::cast_possible_truncation)]
+            let wanted = wanted.start as usize..wanted.end as usize;
+            self.out.write_all(&bytes[wanted]).await?;
+        }
+        self.pos += bytes.len() as u64;
+
+        Ok(())
+    }
+
+    const fn skip(&mut self, len: u64) {
+        self.pos += len;
+    }
+}
+
+fn local_header(entry: &ZipEntry, zip64: bool) -> Vec<u8> {
+    let (time, date) = dos_time(entry.modified);
+    let mut header = Vec::with_capacity(30 + entry.name.len() + 20);
+    put_u32(&mut header, LOCAL_HEADER);
+    put_u16(&mut header, if zip64 { VERSION_ZIP64 } else { VERSION });
+    put_u16(&mut header, FLAGS);
+    put_u16(&mut header, 0); // stored
+    put_u16(&mut header, time);
+    put_u16(&mut header, date);
+    // The checksum and sizes follow the data.
+    put_u32(&mut header, 0);
+    put_u32(&mut header, if zip64 { U32_MARKER } else { 0 });
+    put_u32(&mut header, if zip64 { U32_MARKER } else { 0 });
+    put_u16(&mut header, name_len(entry));
+    put_u16(&mut header, if zip64 { 20 } else { 0 });
+    header.extend_from_slice(entry.name.as_bytes());
+    if zip64 {
+        put_u16(&mut header, ZIP64_EXTRA);
+        put_u16(&mut header, 16);
+        put_u64(&mut header, 0);
+        put_u64(&mut header, 0);
+    }
+
+    header
+}
+
+fn data_descriptor(entry: &ZipEntry, zip64: bool) -> Vec<u8> {
+    let mut descriptor = Vec::with_capacity(24);
+    put_u32(&mut descriptor, DATA_DESCRIPTOR);
+    put_u32(&mut descriptor, entry.crc32.unwrap_or_default());
+    if zip64 {
+        put_u64(&mut descriptor, entry.size);
+        put_u64(&mut descriptor, entry.size);
+    } else {
+        put_u32(&mut descriptor, small(entry.size));
+        put_u32(&mut descriptor, small(entry.size));
+    }
+
+    descriptor
+}
+
+fn central_header(entry: &ZipEntry, placement: &Placement, crc32: u32) -> Vec<u8> {
+    let zip64 = placement.zip64;
+    let version = if zip64 { VERSION_ZIP64 } else { VERSION };
+    let (time, date) = dos_time(entry.modified);
+    let mut header = Vec::with_capacity(46 + entry.name.len() + 28);
+    put_u32(&mut header, CENTRAL_HEADER);
+    put_u16(&mut header, MADE_BY_UNIX | version);
+    put_u16(&mut header, version);
+    put_u16(&mut header, FLAGS);
+    put_u16(&mut header, 0); // stored
+    put_u16(&mut header, time);
+    put_u16(&mut header, date);
+    put_u32(&mut header, crc32);
+    put_u32(
+        &mut header,
+        if zip64 { U32_MARKER } else { small(entry.size) },
+    );
+    put_u32(
+        &mut header,
+        if zip64 { U32_MARKER } else { small(entry.size) },
+    );
+    put_u16(&mut header, name_len(entry));
+    put_u16(&mut header, if zip64 { 28 } else { 0 });
+    put_u16(&mut header, 0); // comment
+    put_u16(&mut header, 0); // disk
+    put_u16(&mut header, 0); // internal attributes
+    put_u32(&mut header, FILE_MODE);
+    put_u32(
+        &mut header,
+        if zip64 {
+            U32_MARKER
+        } else {
+            small(placement.offset)
+        },
+    );
+    header.extend_from_slice(entry.name.as_bytes());
+    if zip64 {
+        put_u16(&mut header, ZIP64_EXTRA);
+        put_u16(&mut header, 24);
+        put_u64(&mut header, entry.size);
+        put_u64(&mut header, entry.size);
+        put_u64(&mut header, placement.offset);
+    }
+
+    header
+}
+
+/// The end of central directory record, preceded by its ZIP64 versions when
+/// the entry count or central directory don't fit the original.
+fn end_records(count: usize, central_offset: u64, central_len: u64) -> Vec<u8> {
+    let zip64 = count >= usize::from(u16::MAX)
+        || central_offset >= u64::from(U32_MARKER)
+        || central_len >= u64::from(U32_MARKER);
+    let mut records = Vec::with_capacity(98);
+    if zip64 {
+        put_u32(&mut records, ZIP64_END);
+        put_u64(&mut records, 44); // size of the rest of the record
+        put_u16(&mut records, MADE_BY_UNIX | VERSION_ZIP64);
+        put_u16(&mut records, VERSION_ZIP64);
+        put_u32(&mut records, 0); // disk
+        put_u32(&mut records, 0); // disk with the central directory
+        put_u64(&mut records, count as u64);
+        put_u64(&mut records, count as u64);
+        put_u64(&mut records, central_len);
+        put_u64(&mut records, central_offset);
+
+        put_u32(&mut records, ZIP64_LOCATOR);
+        put_u32(&mut records, 0);
+        put_u64(&mut records, central_offset + central_len);
+        put_u32(&mut records, 1); // disks
+    }
+
+    let count = if zip64 {
+        u16::MAX
+    } else {
+        u16::try_from(count).unwrap_or(u16::MAX)
+    };
+    put_u32(&mut records, END);
+    put_u16(&mut records, 0);
+    put_u16(&mut records, 0);
+    put_u16(&mut records, count);
+    put_u16(&mut records, count);
+    put_u32(
+        &mut records,
+        if zip64 {
+            U32_MARKER
+        } else {
+            small(central_len)
+        },
+    );
+    put_u32(
+        &mut records,
+        if zip64 {
+            U32_MARKER
+        } else {
+            small(central_offset)
+        },
+    );
+    put_u16(&mut records, 0); // comment
+
+    records
+}
+
+/// MS-DOS time and date, which can't go before 1980.
+fn dos_time(at: DateTime<Utc>) -> (u16, u16) {
+    let year = u16::try_from(at.year().clamp(1980, 2107) - 1980).unwrap_or_default();
+    // Every other field is bounded well within u16 by chrono.
+    #[allow(clippy::cast_possible_truncation)]
+    let time = ((at.hour() << 11) | (at.minute() << 5) | (at.second() / 2)) as u16;
+    #[allow(clippy::cast_possible_truncation)]
+    let date = (year << 9) | ((at.month() << 5) | at.day()) as u16;
+
+    (time, date)
+}
+
+/// File names are limited to 64 KiB by the format.
+fn name_len(entry: &ZipEntry) -> u16 {
+    u16::try_from(entry.name.len()).unwrap_or(u16::MAX)
+}
+
+/// A size or offset already known to fit, being below the ZIP64 threshold.
+fn small(value: u64) -> u32 {
+    u32::try_from(value).unwrap_or(U32_MARKER)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use chrono::TimeZone;
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::storage::mock::MockBucket;
+
+    const FIRST: &[u8] = b"first track, not really audio";
+    const SECOND: &[u8] = b"second track, a little longer than the first one";
+
+    async fn storage() -> R2Storage {
+        MockBucket::with([("music/01.flac", FIRST), ("music/02.flac", SECOND)])
+            .serve()
+            .await
+    }
+
+    fn modified() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 10).unwrap()
+    }
+
+    /// A manifest, a file whose checksum is known and one whose isn't.
+    fn archive() -> ZipStream {
+        ZipStream::new(vec![
+            ZipEntry::inline(
+                "album.yaml",
+                modified(),
+                b"album:\n  title: Test\n".to_vec(),
+            ),
+            ZipEntry::stored(
+                "01 – first.flac",
+                modified(),
+                "music/01.flac",
+                FIRST.len() as u64,
+                Some("\"etag-1\"".to_string()),
+                None,
+            ),
+            ZipEntry::stored(
+                "02.flac",
+                modified(),
+                "music/02.flac",
+                SECOND.len() as u64,
+                Some("\"etag-2\"".to_string()),
+                Some(crc32fast::hash(SECOND)),
+            ),
+        ])
+    }
+
+    async fn write(storage: &R2Storage, zip: &mut ZipStream, range: Range<u64>) -> Vec<u8> {
+        let mut out = Vec::new();
+        zip.write_range(storage, range, &mut out)
+            .await
+            .expect("written");
+        out
+    }
+
+    #[tokio::test]
+    async fn writes_an_archive_zip_can_read() {
+        let storage = storage().await;
+        let mut zip = archive();
+        let len = zip.len();
+        let bytes = write(&storage, &mut zip, 0..len).await;
+        assert_eq!(bytes.len() as u64, len);
+
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).expect("readable archive");
+        let mut files = Vec::new();
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index).expect("entry");
+            let mut contents = Vec::new();
+            // Fails on a wrong CRC-32.
+            file.read_to_end(&mut contents).expect("contents");
+            assert_eq!(file.compression(), zip::CompressionMethod::Stored);
+            files.push((file.name().to_string(), contents));
+        }
+        assert_eq!(
+            files,
+            [
+                (
+                    "album.yaml".to_string(),
+                    b"album:\n  title: Test\n".to_vec()
+                ),
+                ("01 – first.flac".to_string(), FIRST.to_vec()),
+                ("02.flac".to_string(), SECOND.to_vec()),
+            ]
+        );
+
+        // Only the checksum that wasn't known is handed back to keep.
+        let learned: Vec<_> = zip.learned().collect();
+        assert_eq!(
+            learned,
+            [("music/01.flac", "\"etag-1\"", crc32fast::hash(FIRST))]
+        );
+    }
+
+    #[tokio::test]
+    async fn ranges_put_together_give_the_whole_archive() {
+        let storage = storage().await;
+        let len = archive().len();
+        let whole = write(&storage, &mut archive(), 0..len).await;
+
+        for step in [1, 7, 64, len] {
+            let mut joined = Vec::new();
+            let mut start = 0;
+            while start < len {
+                let end = (start + step).min(len);
+                // A fresh archive each time, as for a resumed download.
+                let part = write(&storage, &mut archive(), start..end).await;
+                assert_eq!(part.len() as u64, end - start, "{start}..{end}");
+                joined.extend(part);
+                start = end;
+            }
+            assert!(joined == whole, "{step}-byte ranges");
+        }
+
+        assert!(write(&storage, &mut archive(), len..len).await.is_empty());
+    }
+
+    #[test]
+    fn length_and_etag_follow_the_entries() {
+        let zip = archive();
+        assert_eq!(zip.len(), archive().len());
+        assert_eq!(zip.etag(), archive().etag());
+        assert!(zip.etag().starts_with('"') && zip.etag().ends_with('"'));
+
+        // A stored file that changed, or a later date, is another archive.
+        let changed = ZipStream::new(vec![ZipEntry::stored(
+            "01.flac",
+            modified(),
+            "music/01.flac",
+            10,
+            Some("\"etag-3\"".to_string()),
+            None,
+        )]);
+        let original = ZipStream::new(vec![ZipEntry::stored(
+            "01.flac",
+            modified(),
+            "music/01.flac",
+            10,
+            Some("\"etag-1\"".to_string()),
+            None,
+        )]);
+        let later = ZipStream::new(vec![ZipEntry::stored(
+            "01.flac",
+            modified() + chrono::Duration::seconds(2),
+            "music/01.flac",
+            10,
+            Some("\"etag-1\"".to_string()),
+            None,
+        )]);
+        assert_ne!(changed.etag(), original.etag());
+        assert_ne!(later.etag(), original.etag());
+        assert_eq!(changed.len(), original.len());
+    }
+
+    #[tokio::test]
+    async fn length_and_etag_do_not_change_while_writing() {
+        let storage = storage().await;
+        let mut zip = archive();
+        let (len, etag) = (zip.len(), zip.etag());
+        write(&storage, &mut zip, 0..len).await;
+        assert_eq!((zip.len(), zip.etag()), (len, etag));
+    }
+
+    #[test]
+    fn large_entries_get_zip64_records() {
+        let small = ZipStream::new(vec![ZipEntry::inline("a", modified(), Vec::new())]);
+        let large = ZipStream::new(vec![ZipEntry::stored(
+            "a",
+            modified(),
+            "music/a",
+            u64::from(u32::MAX),
+            None,
+            None,
+        )]);
+        // Extra fields, a larger descriptor and the ZIP64 end records.
+        assert_eq!(
+            large.len() - u64::from(u32::MAX) - small.len(),
+            20 + 8 + 28 + 76
+        );
+    }
+}
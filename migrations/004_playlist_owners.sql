@@ -0,0 +1,5 @@
+-- Playlists belong to the user who created them.
+-- Existing playlists keep a NULL owner and can only be changed by admins.
+ALTER TABLE playlists ADD COLUMN user_id TEXT REFERENCES users(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_playlists_user ON playlists(user_id);
@@ -0,0 +1,12 @@
+-- Login and registration attempts, used for rate limiting and lockouts.
+CREATE TABLE IF NOT EXISTS auth_attempts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    action TEXT NOT NULL CHECK (action IN ('login', 'register')),
+    username TEXT,   -- normalized (trimmed, lowercase) login name
+    ip_address TEXT,
+    success BOOLEAN NOT NULL,
+    created_at DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_attempts_username ON auth_attempts(action, username, created_at);
+CREATE INDEX IF NOT EXISTS idx_auth_attempts_ip ON auth_attempts(action, ip_address, created_at);
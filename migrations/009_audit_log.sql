@@ -0,0 +1,18 @@
+-- Security-relevant and catalog-changing actions
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor_id TEXT, -- NULL when nobody is logged in, e.g. a failed login
+    action TEXT NOT NULL, -- e.g. 'auth.login', 'track.delete'
+    target_type TEXT,
+    target_id TEXT,
+    ip_address TEXT,
+    user_agent TEXT,
+    diff TEXT, -- JSON, usually {"before": ..., "after": ...}
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (actor_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_created ON audit_log(created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_actor ON audit_log(actor_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_action ON audit_log(action, created_at);
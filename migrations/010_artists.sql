@@ -0,0 +1,39 @@
+-- Artists as first-class entities, managed by their members
+
+CREATE TABLE IF NOT EXISTS artists (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    normalized_name TEXT NOT NULL UNIQUE, -- lowercase, trimmed, for duplicate checks
+    bio TEXT,
+    website TEXT,
+    created_by TEXT,
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+-- One row per permission a member holds on an artist
+CREATE TABLE IF NOT EXISTS artist_permissions (
+    artist_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    permission TEXT NOT NULL CHECK (permission IN (
+        'upload',         -- Add tracks
+        'edit',           -- Change track metadata
+        'delete',         -- Remove tracks
+        'manage_members', -- Invite and remove collaborators
+        'owner'           -- Everything, including managing owners
+    )),
+    granted_by TEXT,
+    granted_at DATETIME NOT NULL,
+    PRIMARY KEY (artist_id, user_id, permission),
+    FOREIGN KEY (artist_id) REFERENCES artists(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (granted_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_artist_permissions_user ON artist_permissions(user_id);
+
+-- Existing tracks keep their free-text artist and stay admin-managed until
+-- they are assigned to an artist.
+ALTER TABLE tracks ADD COLUMN artist_id TEXT REFERENCES artists(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS idx_tracks_artist_id ON tracks(artist_id);
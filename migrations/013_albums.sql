@@ -0,0 +1,22 @@
+-- Albums belong to an artist and group its tracks; uploads are stored under
+-- music/{artist_id}/{album_id}/
+
+CREATE TABLE IF NOT EXISTS albums (
+    id TEXT PRIMARY KEY,
+    artist_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    normalized_title TEXT NOT NULL, -- lowercase, trimmed, for matching uploads
+    cover_art_path TEXT,
+    year INTEGER,
+    genre TEXT,
+    created_by TEXT,
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    UNIQUE (artist_id, normalized_title),
+    FOREIGN KEY (artist_id) REFERENCES artists(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+-- Existing tracks keep their free-text album
+ALTER TABLE tracks ADD COLUMN album_id TEXT REFERENCES albums(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS idx_tracks_album_id ON tracks(album_id);
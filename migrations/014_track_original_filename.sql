@@ -0,0 +1,2 @@
+-- Keeps the uploaded file's name so album manifests can refer to tracks by file
+ALTER TABLE tracks ADD COLUMN original_filename TEXT;
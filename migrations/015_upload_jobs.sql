@@ -0,0 +1,27 @@
+-- Album uploads are processed in the background; each row is one upload and
+-- its progress. The uploaded ZIP stays in the spool directory until the job
+-- is finished.
+
+CREATE TABLE IF NOT EXISTS upload_jobs (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    credential TEXT NOT NULL, -- JSON, how the uploader authenticated
+    ip_address TEXT,
+    user_agent TEXT,
+    status TEXT NOT NULL, -- 'queued', 'processing', 'completed' or 'failed'
+    stage TEXT, -- 'extract', 'probe', 'transcode', 'store' or 'index'
+    zip_path TEXT NOT NULL,
+    options TEXT NOT NULL, -- JSON, the upload's metadata form field
+    files TEXT NOT NULL DEFAULT '[]', -- JSON, progress of each audio file
+    album_id TEXT,
+    error TEXT, -- JSON, the error response when failed
+    attempts INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    finished_at DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (album_id) REFERENCES albums(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_upload_jobs_status ON upload_jobs(status, created_at);
+CREATE INDEX IF NOT EXISTS idx_upload_jobs_user ON upload_jobs(user_id, created_at);
@@ -0,0 +1,35 @@
+-- Large files can be uploaded in parts over several requests. Each part goes
+-- straight into a multipart upload in storage, under uploads/{id}, and the
+-- assembled file is processed like a normal upload once complete.
+
+CREATE TABLE IF NOT EXISTS chunked_uploads (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL, -- 'file' or 'album'
+    file_name TEXT NOT NULL,
+    total_bytes INTEGER NOT NULL,
+    part_bytes INTEGER NOT NULL, -- every part but the last is this size
+    storage_key TEXT NOT NULL,
+    storage_upload_id TEXT NOT NULL,
+    metadata TEXT, -- JSON, the metadata form field used when completing
+    status TEXT NOT NULL, -- 'open', 'completing', 'completed', 'aborted' or 'expired'
+    assembled_at DATETIME, -- set once the parts are joined in storage
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL, -- pushed back by every part received
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_chunked_uploads_status ON chunked_uploads(status, expires_at);
+CREATE INDEX IF NOT EXISTS idx_chunked_uploads_storage ON chunked_uploads(storage_upload_id);
+
+CREATE TABLE IF NOT EXISTS chunked_upload_parts (
+    upload_id TEXT NOT NULL,
+    part_number INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    checksum_sha256 TEXT NOT NULL, -- base64
+    etag TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    PRIMARY KEY (upload_id, part_number),
+    FOREIGN KEY (upload_id) REFERENCES chunked_uploads(id) ON DELETE CASCADE
+);
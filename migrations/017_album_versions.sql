@@ -0,0 +1,50 @@
+-- Every upload to an album is kept as a version: which tracks it had, and the
+-- audio and metadata of each at the time. Track rows always hold the current
+-- version; tracks it leaves out are marked superseded and hidden from listings.
+
+CREATE TABLE IF NOT EXISTS album_versions (
+    id TEXT PRIMARY KEY,
+    album_id TEXT NOT NULL,
+    version_number INTEGER NOT NULL, -- 1, 2, ... within the album
+    label TEXT, -- the manifest's version, e.g. 'demo-v3'
+    release_status TEXT NOT NULL DEFAULT 'released', -- 'draft' or 'released'
+    upload_id TEXT, -- the album upload that made it
+    created_by TEXT,
+    created_at DATETIME NOT NULL,
+    UNIQUE (album_id, version_number),
+    FOREIGN KEY (album_id) REFERENCES albums(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_album_versions_upload ON album_versions(upload_id);
+
+-- track_id is not a foreign key so history survives deleting the track
+CREATE TABLE IF NOT EXISTS album_version_tracks (
+    version_id TEXT NOT NULL,
+    track_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    track_number INTEGER,
+    duration INTEGER NOT NULL,
+    file_path TEXT NOT NULL,
+    original_filename TEXT,
+    PRIMARY KEY (version_id, track_id),
+    FOREIGN KEY (version_id) REFERENCES album_versions(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_album_version_tracks_track ON album_version_tracks(track_id);
+
+ALTER TABLE albums ADD COLUMN current_version_id TEXT REFERENCES album_versions(id) ON DELETE SET NULL;
+ALTER TABLE tracks ADD COLUMN superseded_at DATETIME;
+
+-- Existing albums start at version 1 with the tracks they have
+INSERT INTO album_versions (id, album_id, version_number, release_status, created_by, created_at)
+SELECT lower(hex(randomblob(16))), id, 1, 'released', created_by, created_at FROM albums;
+
+UPDATE albums SET current_version_id = (
+    SELECT v.id FROM album_versions v WHERE v.album_id = albums.id AND v.version_number = 1
+);
+
+INSERT INTO album_version_tracks (version_id, track_id, title, track_number, duration, file_path, original_filename)
+SELECT a.current_version_id, t.id, t.title, t.track_number, t.duration, t.file_path, t.original_filename
+FROM tracks t
+JOIN albums a ON a.id = t.album_id;
@@ -0,0 +1,8 @@
+-- Where each metadata field came from (manifest, tags, file name, ...), as a
+-- JSON object of field name to source, for the comments in exported manifests.
+-- NULL for rows from before this was recorded.
+
+ALTER TABLE tracks ADD COLUMN metadata_sources TEXT;
+ALTER TABLE albums ADD COLUMN metadata_sources TEXT;
+-- Versions keep the sources of the values they hold, so a rollback restores both
+ALTER TABLE album_version_tracks ADD COLUMN metadata_sources TEXT;
@@ -0,0 +1,12 @@
+-- CRC-32s of stored files, which album ZIP downloads need for their headers.
+-- Kept so a resumed download doesn't have to read every file before the
+-- resumed range again. Keyed by ETag as well, since an album's cover is
+-- overwritten in place when it gets a new one.
+
+CREATE TABLE IF NOT EXISTS file_checksums (
+    file_key TEXT NOT NULL,
+    etag TEXT NOT NULL,
+    crc32 INTEGER NOT NULL,
+    created_at DATETIME NOT NULL,
+    PRIMARY KEY (file_key, etag)
+);
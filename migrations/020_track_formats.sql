@@ -0,0 +1,29 @@
+-- The format each track was uploaded in and the one its file is stored in,
+-- which differ when a WAV or AIFF upload was converted to FLAC. Tracks from
+-- before this were stored as uploaded, so both come from the file's extension.
+
+ALTER TABLE tracks ADD COLUMN original_format TEXT;
+ALTER TABLE tracks ADD COLUMN stored_format TEXT;
+-- Versions keep them with the file they describe
+ALTER TABLE album_version_tracks ADD COLUMN original_format TEXT;
+ALTER TABLE album_version_tracks ADD COLUMN stored_format TEXT;
+
+UPDATE tracks SET stored_format = CASE
+    WHEN lower(file_path) LIKE '%.flac' THEN 'flac'
+    WHEN lower(file_path) LIKE '%.mp3' THEN 'mp3'
+    WHEN lower(file_path) LIKE '%.ogg' THEN 'ogg'
+    WHEN lower(file_path) LIKE '%.m4a' THEN 'm4a'
+    WHEN lower(file_path) LIKE '%.wav' THEN 'wav'
+    WHEN lower(file_path) LIKE '%.aiff' THEN 'aiff'
+END;
+UPDATE tracks SET original_format = stored_format;
+
+UPDATE album_version_tracks SET stored_format = CASE
+    WHEN lower(file_path) LIKE '%.flac' THEN 'flac'
+    WHEN lower(file_path) LIKE '%.mp3' THEN 'mp3'
+    WHEN lower(file_path) LIKE '%.ogg' THEN 'ogg'
+    WHEN lower(file_path) LIKE '%.m4a' THEN 'm4a'
+    WHEN lower(file_path) LIKE '%.wav' THEN 'wav'
+    WHEN lower(file_path) LIKE '%.aiff' THEN 'aiff'
+END;
+UPDATE album_version_tracks SET original_format = stored_format;
@@ -0,0 +1,17 @@
+-- Files the library scanner has seen under LIBRARY_DIR, by path relative to
+-- it. The content hash ties a file to its track, so a file that moves keeps
+-- its track and an edited one has its track's audio replaced. Size and
+-- modification time let a rescan skip hashing files that haven't changed.
+
+CREATE TABLE IF NOT EXISTS library_files (
+    path TEXT PRIMARY KEY,
+    content_hash TEXT NOT NULL, -- hex SHA-256 of the file
+    size INTEGER NOT NULL,
+    modified_at DATETIME NOT NULL,
+    track_id TEXT,
+    scanned_at DATETIME NOT NULL,
+    FOREIGN KEY (track_id) REFERENCES tracks(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_library_files_hash ON library_files(content_hash);
+CREATE INDEX IF NOT EXISTS idx_library_files_track ON library_files(track_id);
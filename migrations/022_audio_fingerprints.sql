@@ -0,0 +1,12 @@
+-- Content hashes and acoustic fingerprints of stored audio files, by storage
+-- key like file_checksums, for finding tracks uploaded twice. Keyed by file
+-- rather than track so earlier album versions keep theirs.
+
+CREATE TABLE IF NOT EXISTS audio_fingerprints (
+    file_key TEXT PRIMARY KEY,
+    content_hash TEXT NOT NULL, -- hex SHA-256 of the file as uploaded, before any conversion
+    fingerprint BLOB, -- 32 bits per frame, little-endian; NULL if the audio couldn't be decoded
+    created_at DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audio_fingerprints_hash ON audio_fingerprints(content_hash);
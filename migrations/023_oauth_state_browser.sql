@@ -0,0 +1,6 @@
+-- Ties each in-flight authorization to the browser that started it, so a
+-- callback URL handed to someone else can't log them in or link their
+-- provider account. Holds the SHA-256 of a random cookie set by /authorize;
+-- rows from before this never match.
+
+ALTER TABLE oauth_states ADD COLUMN browser_hash TEXT NOT NULL DEFAULT '';
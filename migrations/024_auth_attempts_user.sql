@@ -0,0 +1,14 @@
+-- Lockouts follow the account rather than the name typed at login, so
+-- switching between username and email doesn't get a fresh set of tries.
+-- Logins for names that match no account keep only the typed name.
+
+ALTER TABLE auth_attempts ADD COLUMN user_id TEXT;
+
+UPDATE auth_attempts SET user_id = (
+    SELECT u.id FROM users u
+    WHERE lower(u.username) = auth_attempts.username OR u.email = auth_attempts.username
+    LIMIT 1
+)
+WHERE action = 'login';
+
+CREATE INDEX IF NOT EXISTS idx_auth_attempts_user ON auth_attempts(action, user_id, created_at);
@@ -33,6 +33,8 @@ pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    #[serde(default)]
+    pub email_verified: bool,
     #[serde(skip_serializing)]
     pub password_hash: String,
     pub is_admin: bool,